@@ -0,0 +1,70 @@
+use crate::auth::guard::CurrentUser;
+use log::warn;
+use rocket::http::Status;
+
+/// One of the flags stored in the `permissions` table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Dashboard,
+    SeeSelfHistory,
+    SeeOthersHistory,
+    AdminPanel,
+    EditPermissions,
+}
+
+impl CurrentUser {
+    pub fn has(&self, permission: Permission) -> bool {
+        let Some(permissions) = &self.permissions else {
+            return false;
+        };
+        match permission {
+            Permission::Dashboard => permissions.dashboard,
+            Permission::SeeSelfHistory => permissions.see_self_history,
+            Permission::SeeOthersHistory => permissions.see_others_history,
+            Permission::AdminPanel => permissions.admin_panel,
+            Permission::EditPermissions => permissions.edit_permissions,
+        }
+    }
+
+    pub fn is(&self, person_id: &str) -> bool {
+        self.person.id == person_id
+    }
+
+    /// Fails with 403 unless the user holds `permission`
+    pub fn require(&self, permission: Permission) -> Result<(), Status> {
+        self.require_any(&[permission])
+    }
+
+    /// Fails with 403 unless the user holds at least one of `permissions`
+    pub fn require_any(&self, permissions: &[Permission]) -> Result<(), Status> {
+        if permissions.iter().any(|p| self.has(*p)) {
+            return Ok(());
+        }
+        warn!(
+            "Forbidden: person {} lacks any of {:?}",
+            self.person.id, permissions
+        );
+        Err(Status::Forbidden)
+    }
+
+    /// Allows the user to act on their own data, or on anyone's with one of `permissions`
+    pub fn require_self_or(
+        &self,
+        person_id: &str,
+        permissions: &[Permission],
+    ) -> Result<(), Status> {
+        if self.is(person_id) {
+            return Ok(());
+        }
+        self.require_any(permissions)
+    }
+
+    /// Reading the attendance history of `person_id`
+    pub fn require_history_of(&self, person_id: &str) -> Result<(), Status> {
+        if self.is(person_id) {
+            self.require_any(&[Permission::SeeSelfHistory, Permission::SeeOthersHistory])
+        } else {
+            self.require(Permission::SeeOthersHistory)
+        }
+    }
+}
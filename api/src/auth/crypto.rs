@@ -1,27 +1,289 @@
 use hex::encode;
 use hmac::{Hmac, Mac};
 use once_cell::sync::Lazy;
-use sha2::Sha256;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::env::var;
+use std::fmt;
+use std::sync::Mutex;
 
-static API_SECRET: Lazy<String> =
-    Lazy::new(|| var("SYN_API_SECRET").unwrap_or_else(|_| "secret".to_string()));
+type HmacSha256 = Hmac<Sha256>;
 
-pub fn verify_api_key(api_key: &str, uri: &str) -> bool {
-    let expected_hmac = compute_hmac(uri);
+/// Shared secret for clients that don't identify themselves with `X-Syn-Client-Id`
+pub static API_SECRET: Lazy<Option<String>> = Lazy::new(|| match var("SYN_API_SECRET") {
+    Ok(secret) if !secret.is_empty() => Some(secret),
+    _ => {
+        log::warn!("SYN_API_SECRET not set, only named API clients can authenticate");
+        None
+    }
+});
 
-    expected_hmac == api_key
+/// Derives the signing secrets of named clients from their stored salts, kept out of the database
+pub static API_CLIENT_PEPPER: Lazy<Option<String>> =
+    Lazy::new(|| match var("SYN_API_CLIENT_PEPPER") {
+        Ok(pepper) if !pepper.is_empty() => Some(pepper),
+        _ => {
+            log::warn!("SYN_API_CLIENT_PEPPER not set, named API clients can't authenticate");
+            None
+        }
+    });
+
+/// Accept the legacy URI-only `X-Syn-Api-Key` signature while clients migrate to v2
+pub static ALLOW_LEGACY_SIGNATURES: Lazy<bool> =
+    Lazy::new(|| var("SYN_ALLOW_LEGACY_SIGNATURES").unwrap_or("0".to_string()) == "1");
+
+/// How far (in seconds) `X-Syn-Timestamp` may drift from the server clock
+static MAX_CLOCK_SKEW: Lazy<i64> = Lazy::new(|| {
+    var("SYN_SIGNATURE_MAX_SKEW")
+        .ok()
+        .and_then(|skew| skew.parse().ok())
+        .unwrap_or(300)
+});
+
+/// Nonces seen inside the clock skew window, with the timestamp they were signed at
+static SEEN_NONCES: Lazy<Mutex<HashMap<String, i64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The parts of a request covered by a v2 signature
+pub struct SignedRequest<'a> {
+    pub method: &'a str,
+    pub path_and_query: &'a str,
+    pub timestamp: &'a str,
+    pub nonce: &'a str,
+    pub body_sha256: &'a str,
+}
+
+impl SignedRequest<'_> {
+    fn canonical(&self) -> String {
+        format!(
+            "v2\n{}\n{}\n{}\n{}\n{}",
+            self.method.to_uppercase(),
+            self.path_and_query,
+            self.timestamp,
+            self.nonce,
+            self.body_sha256.to_lowercase()
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SignatureError {
+    Malformed,
+    StaleTimestamp,
+    ReplayedNonce,
+    Mismatch,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignatureError::Malformed => write!(f, "malformed signature headers"),
+            SignatureError::StaleTimestamp => write!(f, "timestamp outside the allowed window"),
+            SignatureError::ReplayedNonce => write!(f, "nonce already used"),
+            SignatureError::Mismatch => write!(f, "signature mismatch"),
+        }
+    }
+}
+
+/// Legacy scheme: HMAC of the URI only
+pub fn verify_api_key(key: &[u8], api_key: &str, uri: &str) -> bool {
+    verify_hmac(key, uri.as_bytes(), api_key)
+}
+
+/// v2 scheme: HMAC over method, path+query, timestamp, nonce and body digest
+pub fn verify_signature(
+    key: &[u8],
+    signature: &str,
+    request: &SignedRequest,
+) -> Result<(), SignatureError> {
+    let timestamp: i64 = request
+        .timestamp
+        .parse()
+        .map_err(|_| SignatureError::Malformed)?;
+    if request.nonce.is_empty() || request.body_sha256.len() != 64 {
+        return Err(SignatureError::Malformed);
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    if (now - timestamp).abs() > *MAX_CLOCK_SKEW {
+        return Err(SignatureError::StaleTimestamp);
+    }
+
+    if !verify_hmac(key, request.canonical().as_bytes(), signature) {
+        return Err(SignatureError::Mismatch);
+    }
+
+    // Only remember nonces of correctly signed requests, so garbage can't fill the store
+    let mut nonces = SEEN_NONCES.lock().unwrap();
+    nonces.retain(|_, signed_at| (now - *signed_at).abs() <= *MAX_CLOCK_SKEW);
+    if nonces
+        .insert(request.nonce.to_string(), timestamp)
+        .is_some()
+    {
+        return Err(SignatureError::ReplayedNonce);
+    }
+
+    Ok(())
+}
+
+/// Hex encoded SHA-256 of a request body
+pub fn body_digest(body: &[u8]) -> String {
+    encode(Sha256::digest(body))
+}
+
+/// Constant time comparison of a hex encoded HMAC against the expected one
+fn verify_hmac(key: &[u8], message: &[u8], signature: &str) -> bool {
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC can take key of any size");
+    mac.update(message);
+
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// Scope a client needs for a request, e.g. `GET /api/entry/1` needs `entries:read`
+pub fn required_scope(method: &str, path: &str) -> String {
+    let resource = path
+        .trim_start_matches('/')
+        .split('/')
+        .find(|segment| *segment != "api")
+        .unwrap_or_default();
+    let resource = match resource {
+        "entry" => "entries",
+        "person" => "persons",
+        "permission" => "permissions",
+        other => other,
+    };
+    let access = match method {
+        "GET" | "HEAD" | "OPTIONS" => "read",
+        _ => "write",
+    };
+    format!("{resource}:{access}")
 }
 
-fn compute_hmac(uri: &str) -> String {
-    type HmacSha256 = Hmac<Sha256>;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"client secret";
+
+    fn sign(key: &[u8], request: &SignedRequest) -> String {
+        let mut mac = HmacSha256::new_from_slice(key).unwrap();
+        mac.update(request.canonical().as_bytes());
+        encode(mac.finalize().into_bytes())
+    }
+
+    fn check(timestamp: i64, nonce: &str, key: &[u8]) -> Result<(), SignatureError> {
+        let timestamp = timestamp.to_string();
+        let body_sha256 = body_digest(b"{}");
+        let request = SignedRequest {
+            method: "post",
+            path_and_query: "/api/entry?flagged=true",
+            timestamp: &timestamp,
+            nonce,
+            body_sha256: &body_sha256,
+        };
+        verify_signature(KEY, &sign(key, &request), &request)
+    }
+
+    fn nonce() -> String {
+        uuid::Uuid::new_v4().to_string()
+    }
+
+    #[test]
+    fn accepts_a_fresh_signature_once() {
+        let now = chrono::Utc::now().timestamp();
+        let nonce = nonce();
+        assert_eq!(check(now, &nonce, KEY), Ok(()));
+        assert_eq!(check(now, &nonce, KEY), Err(SignatureError::ReplayedNonce));
+    }
+
+    #[test]
+    fn rejects_another_key() {
+        let now = chrono::Utc::now().timestamp();
+        assert_eq!(
+            check(now, &nonce(), b"other secret"),
+            Err(SignatureError::Mismatch)
+        );
+    }
+
+    #[test]
+    fn rejects_timestamps_outside_the_skew() {
+        let now = chrono::Utc::now().timestamp();
+        assert_eq!(check(now - 250, &nonce(), KEY), Ok(()));
+        assert_eq!(
+            check(now - 301, &nonce(), KEY),
+            Err(SignatureError::StaleTimestamp)
+        );
+        assert_eq!(
+            check(now + 301, &nonce(), KEY),
+            Err(SignatureError::StaleTimestamp)
+        );
+    }
+
+    #[test]
+    fn rejects_tampered_requests() {
+        let timestamp = chrono::Utc::now().timestamp().to_string();
+        let body_sha256 = body_digest(b"{}");
+        let nonce = nonce();
+        let request = SignedRequest {
+            method: "POST",
+            path_and_query: "/api/entry",
+            timestamp: &timestamp,
+            nonce: &nonce,
+            body_sha256: &body_sha256,
+        };
+        let signature = sign(KEY, &request);
+        let tampered = SignedRequest {
+            path_and_query: "/api/entry?flagged=true",
+            ..request
+        };
+        assert_eq!(
+            verify_signature(KEY, &signature, &tampered),
+            Err(SignatureError::Mismatch)
+        );
+    }
 
-    let mut mac =
-        HmacSha256::new_from_slice(API_SECRET.as_bytes()).expect("HMAC can take key of any size");
-    mac.update(uri.as_bytes());
+    #[test]
+    fn rejects_malformed_headers() {
+        let body_sha256 = body_digest(b"");
+        let malformed = |timestamp: &str, nonce: &str, body_sha256: &str| {
+            let request = SignedRequest {
+                method: "GET",
+                path_and_query: "/api/entry",
+                timestamp,
+                nonce,
+                body_sha256,
+            };
+            verify_signature(KEY, &sign(KEY, &request), &request)
+        };
+        let now = chrono::Utc::now().timestamp().to_string();
+        assert_eq!(
+            malformed("yesterday", "n", &body_sha256),
+            Err(SignatureError::Malformed)
+        );
+        assert_eq!(
+            malformed(&now, "", &body_sha256),
+            Err(SignatureError::Malformed)
+        );
+        assert_eq!(malformed(&now, "n", "abc"), Err(SignatureError::Malformed));
+    }
 
-    let result = mac.finalize();
-    let code_bytes = result.into_bytes();
+    #[test]
+    fn legacy_key_covers_the_uri() {
+        let mut mac = HmacSha256::new_from_slice(KEY).unwrap();
+        mac.update(b"/api/person");
+        let api_key = encode(mac.finalize().into_bytes());
+        assert!(verify_api_key(KEY, &api_key, "/api/person"));
+        assert!(!verify_api_key(KEY, &api_key, "/api/entry"));
+        assert!(!verify_api_key(KEY, "not hex", "/api/person"));
+    }
 
-    encode(code_bytes)
+    #[test]
+    fn scopes_follow_resource_and_method() {
+        assert_eq!(required_scope("GET", "/api/entry/1"), "entries:read");
+        assert_eq!(required_scope("PATCH", "/api/person/1"), "persons:write");
+        assert_eq!(required_scope("POST", "/api/auth/login"), "auth:write");
+    }
 }
@@ -1,13 +1,23 @@
-use crate::auth::crypto;
+use crate::auth::{crypto, token};
+use crate::models::Database;
+use db::DbConnection;
+use db::interactions::api_client::ApiClientInteractor;
+use db::models::{ApiClient, Permissions, Person};
+use log::{error, warn};
+use once_cell::sync::Lazy;
 use rocket::{
+    State,
     http::Status,
     request::{FromRequest, Outcome, Request},
 };
 use rocket_okapi::request::OpenApiFromRequest;
-use std::{env, error::Error, fmt};
+use std::{env, error::Error, fmt, net::IpAddr};
 
 #[derive(OpenApiFromRequest)]
-pub struct ApiKey;
+pub struct ApiKey {
+    /// The named client that signed the request, `None` when the shared secret was used
+    pub client: Option<ApiClient>,
+}
 
 #[derive(Debug)]
 pub struct UnAuthorizedError {
@@ -41,27 +51,262 @@ impl fmt::Display for UnAuthorizedError {
     }
 }
 
+/// Which signing scheme authenticated the request, cached for the `SignedJson` data guard
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureScheme {
+    Unverified,
+    Disabled,
+    Legacy,
+    V2,
+}
+
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for ApiKey {
     type Error = UnAuthorizedError;
 
     async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let unauthorized = || {
+            Outcome::Error((
+                Status::Unauthorized,
+                UnAuthorizedError::new(&req.uri().to_string()),
+            ))
+        };
+
         if env::var("SYN_DISABLE_AUTH").unwrap_or("0".to_string()) == "1" {
-            return Outcome::Success(ApiKey);
+            req.local_cache(|| SignatureScheme::Disabled);
+            return Outcome::Success(ApiKey { client: None });
+        }
+
+        let Some(client_id) = req.headers().get_one("X-Syn-Client-Id") else {
+            let Some(secret) = crypto::API_SECRET.as_deref() else {
+                return unauthorized();
+            };
+            return match verify_request(req, secret.as_bytes()) {
+                Some(scheme) => {
+                    req.local_cache(|| scheme);
+                    Outcome::Success(ApiKey { client: None })
+                }
+                None => unauthorized(),
+            };
+        };
+
+        let db = match req.guard::<&State<Database>>().await {
+            Outcome::Success(db) => db,
+            _ => {
+                return Outcome::Error((
+                    Status::InternalServerError,
+                    UnAuthorizedError::new(&req.uri().to_string()),
+                ));
+            }
+        };
+        let c_id = client_id.to_string();
+        let client = match db
+            .run(move |conn| ApiClientInteractor::get_by_id(conn, &c_id))
+            .await
+        {
+            Err(status) => {
+                return Outcome::Error((status, UnAuthorizedError::new(&req.uri().to_string())));
+            }
+            Ok(Ok(client)) if client.is_active() => client,
+            Ok(Ok(_)) => {
+                warn!(
+                    "Revoked or expired API client {} for {}",
+                    client_id,
+                    req.uri()
+                );
+                return unauthorized();
+            }
+            Ok(Err(_)) => {
+                warn!("Unknown API client {} for {}", client_id, req.uri());
+                return unauthorized();
+            }
+        };
+
+        let Some(pepper) = crypto::API_CLIENT_PEPPER.as_deref() else {
+            return unauthorized();
+        };
+        let secret = db::crypto::api_client_secret(pepper, &client.secret_salt);
+        let Some(scheme) = verify_request(req, secret.as_bytes()) else {
+            return unauthorized();
+        };
+
+        let scope = crypto::required_scope(req.method().as_str(), req.uri().path().as_str());
+        if !client.has_scope(&scope) {
+            warn!(
+                "API client {} lacks scope {} for {}",
+                client.name,
+                scope,
+                req.uri()
+            );
+            return Outcome::Error((
+                Status::Forbidden,
+                UnAuthorizedError::new(&req.uri().to_string()),
+            ));
+        }
+
+        let c_id = client.id.clone();
+        let touched = db
+            .run(move |conn| ApiClientInteractor::touch(conn, &c_id))
+            .await;
+        if let Ok(Err(e)) = touched {
+            warn!("Failed to record use of API client {}: {}", client.id, e);
+        }
+        req.local_cache(|| scheme);
+        Outcome::Success(ApiKey {
+            client: Some(client),
+        })
+    }
+}
+
+/// Checks the v2 signature headers, or the legacy `X-Syn-Api-Key` when allowed
+fn verify_request(req: &Request<'_>, key: &[u8]) -> Option<SignatureScheme> {
+    let headers = req.headers();
+    if let Some(signature) = headers.get_one("X-Syn-Signature") {
+        let Some(signature) = signature.strip_prefix("v2=") else {
+            warn!("Unsupported signature version for {}", req.uri());
+            return None;
+        };
+        let path_and_query = req.uri().to_string();
+        let signed = crypto::SignedRequest {
+            method: req.method().as_str(),
+            path_and_query: &path_and_query,
+            timestamp: headers.get_one("X-Syn-Timestamp").unwrap_or_default(),
+            nonce: headers.get_one("X-Syn-Nonce").unwrap_or_default(),
+            body_sha256: headers.get_one("X-Syn-Content-Sha256").unwrap_or_default(),
+        };
+
+        return match crypto::verify_signature(key, signature, &signed) {
+            Ok(()) => Some(SignatureScheme::V2),
+            Err(e) => {
+                warn!(
+                    "Rejected signature for {} {}: {}",
+                    req.method(),
+                    req.uri(),
+                    e
+                );
+                None
+            }
+        };
+    }
+
+    if let Some(api_key) = headers.get_one("X-Syn-Api-Key") {
+        if !*crypto::ALLOW_LEGACY_SIGNATURES {
+            warn!("Legacy signature rejected for {}", req.uri());
+            return None;
+        }
+        if crypto::verify_api_key(key, api_key, &req.uri().to_string()) {
+            return Some(SignatureScheme::Legacy);
         }
-        if let Some(api_key) = req.headers().get_one("X-Syn-Api-Key") {
-            if crypto::verify_api_key(api_key, &req.uri().to_string()) {
-                return Outcome::Success(ApiKey);
-            } else {
+    }
+    None
+}
+
+/// The person making the request, resolved from the `Authorization: Bearer` access token
+#[derive(OpenApiFromRequest)]
+pub struct CurrentUser {
+    pub person: Person,
+    pub permissions: Option<Permissions>,
+    /// When the access token expires, in seconds since the epoch
+    pub expires_at: i64,
+}
+
+impl CurrentUser {
+    /// The person and permissions as currently stored, `None` once the person is deleted
+    pub fn load(conn: &mut DbConnection, person_id: &str, expires_at: i64) -> Option<Self> {
+        let person = db::interactions::person::PersonInteractor::get_by_id(conn, person_id).ok()?;
+        let permissions =
+            db::interactions::permissions::PermissionsInteractor::get_by_p_id(conn, &person.id)
+                .ok()
+                .and_then(|mut permissions| permissions.pop());
+        Some(CurrentUser {
+            person,
+            permissions,
+            expires_at,
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CurrentUser {
+    type Error = UnAuthorizedError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let unauthorized = || {
+            Outcome::Error((
+                Status::Unauthorized,
+                UnAuthorizedError::new(&req.uri().to_string()),
+            ))
+        };
+
+        let Some(token) = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "))
+        else {
+            return unauthorized();
+        };
+
+        let claims = match token::verify(token.trim(), token::TokenKind::Access) {
+            Ok(claims) => claims,
+            Err(e) => {
+                warn!("Rejected access token: {}", e);
+                return unauthorized();
+            }
+        };
+
+        let db = match req.guard::<&State<Database>>().await {
+            Outcome::Success(db) => db,
+            _ => {
                 return Outcome::Error((
-                    Status::Unauthorized,
+                    Status::InternalServerError,
                     UnAuthorizedError::new(&req.uri().to_string()),
                 ));
             }
+        };
+        let loaded = db
+            .run(move |conn| CurrentUser::load(conn, &claims.sub, claims.exp))
+            .await;
+        match loaded {
+            Ok(Some(user)) => Outcome::Success(user),
+            Ok(None) => unauthorized(),
+            Err(status) => Outcome::Error((status, UnAuthorizedError::new(&req.uri().to_string()))),
         }
-        Outcome::Error((
-            Status::Unauthorized,
-            UnAuthorizedError::new(&req.uri().to_string()),
-        ))
+    }
+}
+
+/// Proxies whose `X-Real-IP` header (Rocket's `ip_header`) is believed, from the comma
+/// separated `SYN_TRUSTED_PROXIES`; none by default
+static TRUSTED_PROXIES: Lazy<Vec<IpAddr>> = Lazy::new(|| {
+    env::var("SYN_TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|proxy| !proxy.is_empty())
+        .filter_map(|proxy| match proxy.parse() {
+            Ok(proxy) => Some(proxy),
+            Err(e) => {
+                error!("Ignoring SYN_TRUSTED_PROXIES entry '{}': {}", proxy, e);
+                None
+            }
+        })
+        .collect()
+});
+
+/// The address of the client, as far as it can be trusted: the peer of the connection,
+/// or the address a trusted proxy forwarded for it
+#[derive(OpenApiFromRequest)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientIp {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let peer = req.remote().map(|remote| remote.ip());
+        let ip = match peer {
+            Some(peer) if TRUSTED_PROXIES.contains(&peer) => req.real_ip().or(Some(peer)),
+            _ => peer,
+        };
+        Outcome::Success(ClientIp(ip))
     }
 }
@@ -1,2 +1,8 @@
+pub mod access;
 mod crypto;
 pub mod guard;
+pub mod oidc;
+pub mod signed;
+pub mod throttle;
+pub mod token;
+pub mod two_factor;
@@ -0,0 +1,378 @@
+use std::env::var;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode, decode_header};
+use log::{debug, error, info, warn};
+use rocket::tokio::sync::RwLock;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::error::ApiError;
+
+/// Used by a provider named `google` unless it sets its own issuer
+const GOOGLE_ISSUERS: &[&str] = &["https://accounts.google.com", "accounts.google.com"];
+const GOOGLE_CERTS_URL: &str = "https://www.googleapis.com/oauth2/v3/certs";
+/// How long fetched keys are kept when the provider's answer has no `max-age`
+const DEFAULT_KEYS_TTL: Duration = Duration::from_secs(60 * 60);
+/// Keys are not refetched for an unknown `kid` more often than this
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Only asymmetric algorithms, with HMAC anyone knowing the client id could sign tokens
+const ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+];
+
+/// The account at a provider an ID token was issued for
+#[derive(Debug, Clone)]
+pub struct ExternalIdentity {
+    /// The `sub` claim
+    pub subject: String,
+    pub email: String,
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+    jwks_uri: String,
+}
+
+enum KeySource {
+    /// `SYN_OIDC_<NAME>_JWKS_FILE`, read once and never refreshed
+    File(PathBuf),
+    Url(String),
+    /// The `jwks_uri` of the issuer's `/.well-known/openid-configuration`
+    Discovery(String),
+}
+
+struct CachedKeys {
+    keys: JwkSet,
+    fetched_at: Instant,
+    /// `None` for keys read from a file
+    expires_at: Option<Instant>,
+}
+
+impl CachedKeys {
+    fn is_fresh(&self) -> bool {
+        self.expires_at
+            .is_none_or(|expires_at| Instant::now() < expires_at)
+    }
+}
+
+/// `SYN_OIDC_<NAME>_<key>`, with the provider name upper-cased and dashes turned into underscores
+fn setting(provider: &str, key: &str) -> Option<String> {
+    let provider = provider.to_uppercase().replace('-', "_");
+    var(format!("SYN_OIDC_{provider}_{key}"))
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// An OpenID Connect provider whose ID tokens sign people in, with its signing keys cached
+/// between requests
+pub struct OidcProvider {
+    pub name: String,
+    /// Accepted `aud` values, one per client app
+    client_ids: Vec<String>,
+    /// Accepted `iss` values
+    issuers: Vec<String>,
+    /// Claim holding the email, `email` unless the provider puts it elsewhere
+    email_claim: String,
+    require_verified_email: bool,
+    source: KeySource,
+    http: reqwest::Client,
+    cache: RwLock<Option<CachedKeys>>,
+}
+
+impl OidcProvider {
+    /// Reads the `SYN_OIDC_<NAME>_*` settings, `None` when they are incomplete
+    fn from_env(name: &str, http: reqwest::Client) -> Option<Self> {
+        let Some(client_ids) = setting(name, "CLIENT_ID").map(|ids| list(&ids)) else {
+            error!(
+                "Identity provider {} has no SYN_OIDC_*_CLIENT_ID, skipping it",
+                name
+            );
+            return None;
+        };
+        let issuers = match setting(name, "ISSUER") {
+            Some(issuers) => list(&issuers),
+            None if name == "google" => GOOGLE_ISSUERS.iter().map(|i| i.to_string()).collect(),
+            None => {
+                error!(
+                    "Identity provider {} has no SYN_OIDC_*_ISSUER, skipping it",
+                    name
+                );
+                return None;
+            }
+        };
+        let source = if let Some(path) = setting(name, "JWKS_FILE") {
+            KeySource::File(PathBuf::from(path))
+        } else if let Some(url) = setting(name, "JWKS_URL") {
+            KeySource::Url(url)
+        } else if name == "google" && setting(name, "ISSUER").is_none() {
+            KeySource::Url(GOOGLE_CERTS_URL.to_string())
+        } else {
+            KeySource::Discovery(issuers[0].clone())
+        };
+        let require_verified_email = setting(name, "REQUIRE_VERIFIED_EMAIL")
+            .is_none_or(|value| !matches!(value.to_lowercase().as_str(), "0" | "false" | "no"));
+
+        Some(OidcProvider {
+            name: name.to_string(),
+            client_ids,
+            issuers,
+            email_claim: setting(name, "EMAIL_CLAIM").unwrap_or_else(|| "email".to_string()),
+            require_verified_email,
+            source,
+            http,
+            cache: RwLock::new(None),
+        })
+    }
+
+    /// Checks the signature and the `aud`, `iss`, `exp` and `email_verified` claims of `id_token`
+    pub async fn verify(&self, id_token: &str) -> Result<ExternalIdentity, ApiError> {
+        let header = decode_header(id_token).map_err(|e| self.invalid_token(&e))?;
+        if !ALGORITHMS.contains(&header.alg) {
+            return Err(self.invalid_token(&format!("unexpected algorithm {:?}", header.alg)));
+        }
+        let Some(kid) = header.kid else {
+            return Err(self.invalid_token(&"no key id"));
+        };
+        let key = self.key_for(&kid).await?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_audience(&self.client_ids);
+        validation.set_issuer(&self.issuers);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        let mut claims = decode::<Map<String, Value>>(id_token, &key, &validation)
+            .map_err(|e| self.invalid_token(&e))?
+            .claims;
+
+        let mut text = |claim: &str| match claims.remove(claim) {
+            Some(Value::String(value)) => Some(value),
+            _ => None,
+        };
+        let (Some(subject), Some(email)) = (text("sub"), text(&self.email_claim)) else {
+            return Err(ApiError::Unauthorized(format!(
+                "{} token has no subject or email",
+                self.name
+            )));
+        };
+        let (given_name, family_name) = (text("given_name"), text("family_name"));
+        let email_verified = match claims.get("email_verified") {
+            Some(Value::Bool(verified)) => *verified,
+            // Some providers send it as a string
+            Some(Value::String(verified)) => verified.eq_ignore_ascii_case("true"),
+            _ => false,
+        };
+        if self.require_verified_email && !email_verified {
+            return Err(ApiError::Unauthorized(format!(
+                "{} email is not verified",
+                self.name
+            )));
+        }
+
+        Ok(ExternalIdentity {
+            subject,
+            email,
+            given_name,
+            family_name,
+        })
+    }
+
+    /// The key named `kid`, fetching the keys again when they expired or `kid` is new to us,
+    /// since providers rotate them
+    async fn key_for(&self, kid: &str) -> Result<DecodingKey, ApiError> {
+        {
+            let cache = self.cache.read().await;
+            if let Some(cached) = cache.as_ref().filter(|cached| cached.is_fresh())
+                && let Some(key) = self.find_key(cached, kid)?
+            {
+                return Ok(key);
+            }
+        }
+
+        let mut cache = self.cache.write().await;
+        // Another request may have refreshed the keys while we waited for the lock
+        let refresh = match cache.as_ref() {
+            Some(cached) if cached.is_fresh() => {
+                if let Some(key) = self.find_key(cached, kid)? {
+                    return Ok(key);
+                }
+                !matches!(self.source, KeySource::File(_))
+                    && cached.fetched_at.elapsed() >= MIN_REFRESH_INTERVAL
+            }
+            _ => true,
+        };
+        if refresh {
+            *cache = Some(self.load().await?);
+        }
+        match cache.as_ref() {
+            Some(cached) => self.find_key(cached, kid)?,
+            None => None,
+        }
+        .ok_or_else(|| self.invalid_token(&format!("unknown key id {}", kid)))
+    }
+
+    fn find_key(&self, cached: &CachedKeys, kid: &str) -> Result<Option<DecodingKey>, ApiError> {
+        let Some(jwk) = cached.keys.find(kid) else {
+            return Ok(None);
+        };
+        DecodingKey::from_jwk(jwk).map(Some).map_err(|e| {
+            error!("Unusable {} signing key {}: {}", self.name, kid, e);
+            self.unavailable()
+        })
+    }
+
+    async fn load(&self) -> Result<CachedKeys, ApiError> {
+        let url = match &self.source {
+            KeySource::File(path) => {
+                let json = rocket::tokio::fs::read_to_string(path).await.map_err(|e| {
+                    error!("Failed to read {}: {}", path.display(), e);
+                    self.unavailable()
+                })?;
+                let keys = serde_json::from_str(&json).map_err(|e| {
+                    error!("Invalid JWK set in {}: {}", path.display(), e);
+                    self.unavailable()
+                })?;
+                return Ok(CachedKeys {
+                    keys,
+                    fetched_at: Instant::now(),
+                    expires_at: None,
+                });
+            }
+            KeySource::Url(url) => url.clone(),
+            KeySource::Discovery(issuer) => {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    issuer.trim_end_matches('/')
+                );
+                let (discovery, _) = self.fetch::<Discovery>(&url).await?;
+                discovery.jwks_uri
+            }
+        };
+
+        let (keys, ttl) = self.fetch::<JwkSet>(&url).await?;
+        let ttl = ttl.unwrap_or(DEFAULT_KEYS_TTL);
+        debug!("Fetched the {} signing keys, kept for {:?}", self.name, ttl);
+        let fetched_at = Instant::now();
+        Ok(CachedKeys {
+            keys,
+            fetched_at,
+            expires_at: Some(fetched_at + ttl),
+        })
+    }
+
+    /// GETs a JSON document along with the `max-age` of its `Cache-Control` header
+    async fn fetch<T: serde::de::DeserializeOwned>(
+        &self,
+        url: &str,
+    ) -> Result<(T, Option<Duration>), ApiError> {
+        let response = self
+            .http
+            .get(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| {
+                error!("Failed to fetch {}: {}", url, e);
+                self.unavailable()
+            })?;
+        let max_age = response
+            .headers()
+            .get(reqwest::header::CACHE_CONTROL)
+            .and_then(|value| value.to_str().ok())
+            .and_then(max_age);
+        let json = response.text().await.map_err(|e| {
+            error!("Failed to read {}: {}", url, e);
+            self.unavailable()
+        })?;
+        let document = serde_json::from_str(&json).map_err(|e| {
+            error!("Unexpected answer from {}: {}", url, e);
+            self.unavailable()
+        })?;
+        Ok((document, max_age))
+    }
+
+    fn invalid_token(&self, reason: &dyn std::fmt::Display) -> ApiError {
+        warn!("Rejected {} ID token: {}", self.name, reason);
+        ApiError::Unauthorized(format!("Invalid {} token", self.name))
+    }
+
+    fn unavailable(&self) -> ApiError {
+        ApiError::Unavailable(format!("Could not load the {} signing keys", self.name))
+    }
+}
+
+/// The `max-age` of a `Cache-Control` header
+fn max_age(cache_control: &str) -> Option<Duration> {
+    cache_control
+        .split(',')
+        .filter_map(|directive| directive.trim().strip_prefix("max-age="))
+        .find_map(|seconds| seconds.parse().ok())
+        .map(Duration::from_secs)
+}
+
+/// The identity providers people can sign in with, named in the comma separated
+/// `SYN_OIDC_PROVIDERS`
+pub struct OidcProviders {
+    providers: Vec<OidcProvider>,
+}
+
+impl OidcProviders {
+    pub fn from_env() -> Self {
+        let http = reqwest::Client::builder()
+            .timeout(FETCH_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        let providers = list(&var("SYN_OIDC_PROVIDERS").unwrap_or_default())
+            .into_iter()
+            .map(|name| name.to_lowercase())
+            .filter(|name| {
+                let valid = name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+                if !valid {
+                    error!("Invalid identity provider name {:?}, skipping it", name);
+                }
+                valid
+            })
+            .filter_map(|name| OidcProvider::from_env(&name, http.clone()))
+            .collect::<Vec<_>>();
+        if providers.is_empty() {
+            info!("No identity providers configured in SYN_OIDC_PROVIDERS");
+        }
+        OidcProviders { providers }
+    }
+
+    /// Fails with 404 for providers that are not configured
+    pub fn get(&self, name: &str) -> Result<&OidcProvider, ApiError> {
+        self.providers
+            .iter()
+            .find(|provider| provider.name == name)
+            .ok_or_else(|| ApiError::NotFound(format!("Unknown identity provider {}", name)))
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.providers
+            .iter()
+            .map(|provider| provider.name.clone())
+            .collect()
+    }
+}
@@ -0,0 +1,110 @@
+use crate::auth::crypto;
+use crate::auth::guard::SignatureScheme;
+use log::warn;
+use rocket::data::{self, Data, FromData, ToByteUnit};
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::serde::json::Json;
+use rocket_okapi::r#gen::OpenApiGenerator;
+use rocket_okapi::okapi::openapi3::RequestBody;
+use rocket_okapi::request::OpenApiFromData;
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use std::ops::{Deref, DerefMut};
+use std::{error::Error, fmt};
+
+/// A JSON body whose SHA-256 digest must match the `X-Syn-Content-Sha256` header
+/// covered by a v2 request signature. Use it instead of `Json<T>` on signed routes.
+pub struct SignedJson<T>(pub T);
+
+impl<T> Deref for SignedJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for SignedJson<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+/// Why the body of the current request was rejected, kept for the error catcher
+pub struct InvalidBody(pub Option<String>);
+
+#[derive(Debug)]
+pub enum SignedBodyError {
+    Io(std::io::Error),
+    TooLarge,
+    DigestMismatch,
+    Parse(serde_json::Error),
+}
+
+impl Error for SignedBodyError {}
+
+impl fmt::Display for SignedBodyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignedBodyError::Io(e) => write!(f, "Failed to read body: {}", e),
+            SignedBodyError::TooLarge => write!(f, "Body exceeds the JSON size limit"),
+            SignedBodyError::DigestMismatch => write!(f, "Body does not match signed digest"),
+            SignedBodyError::Parse(e) => write!(f, "Invalid JSON body: {}", e),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, T: DeserializeOwned> FromData<'r> for SignedJson<T> {
+    type Error = SignedBodyError;
+
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let limit = req.limits().get("json").unwrap_or(1.mebibytes());
+        let body = match data.open(limit).into_bytes().await {
+            Ok(body) if body.is_complete() => body.into_inner(),
+            Ok(_) => {
+                return data::Outcome::Error((Status::PayloadTooLarge, SignedBodyError::TooLarge));
+            }
+            Err(e) => return data::Outcome::Error((Status::BadRequest, SignedBodyError::Io(e))),
+        };
+
+        match req.local_cache(|| SignatureScheme::Unverified) {
+            SignatureScheme::Disabled | SignatureScheme::Legacy => {}
+            SignatureScheme::V2 => {
+                let declared = req
+                    .headers()
+                    .get_one("X-Syn-Content-Sha256")
+                    .unwrap_or_default();
+                if !declared.eq_ignore_ascii_case(&crypto::body_digest(&body)) {
+                    warn!("Body digest mismatch for {} {}", req.method(), req.uri());
+                    return data::Outcome::Error((
+                        Status::Unauthorized,
+                        SignedBodyError::DigestMismatch,
+                    ));
+                }
+            }
+            SignatureScheme::Unverified => {
+                return data::Outcome::Error((
+                    Status::Unauthorized,
+                    SignedBodyError::DigestMismatch,
+                ));
+            }
+        }
+
+        match serde_json::from_slice(&body) {
+            Ok(value) => data::Outcome::Success(SignedJson(value)),
+            Err(e) => {
+                let error = SignedBodyError::Parse(e);
+                req.local_cache(|| InvalidBody(Some(error.to_string())));
+                data::Outcome::Error((Status::UnprocessableEntity, error))
+            }
+        }
+    }
+}
+
+impl<'r, T: DeserializeOwned + JsonSchema> OpenApiFromData<'r> for SignedJson<T> {
+    fn request_body(r#gen: &mut OpenApiGenerator) -> rocket_okapi::Result<RequestBody> {
+        Json::<T>::request_body(r#gen)
+    }
+}
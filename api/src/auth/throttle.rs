@@ -0,0 +1,163 @@
+use std::env::var;
+use std::net::IpAddr;
+
+use chrono::Duration;
+use db::DbConnection;
+use db::interactions::login_throttle::{
+    CountedAttempt, LoginThrottleInteractor, ThrottlePolicy, ThrottleScope,
+};
+use log::warn;
+use once_cell::sync::Lazy;
+
+use crate::error::ApiError;
+
+/// Answer to every wrong email or password, so it doesn't tell which accounts exist
+pub const INVALID_CREDENTIALS: &str = "Invalid email or password";
+
+/// Reads `SYN_LOGIN_<SCOPE>_{FREE_FAILURES,LOCKOUT_AFTER,LOCKOUT_SECONDS}` over `defaults`
+fn policy_from_env(scope: &str, defaults: ThrottlePolicy) -> ThrottlePolicy {
+    let setting = |name: &str| {
+        var(format!("SYN_LOGIN_{}_{}", scope, name))
+            .ok()
+            .and_then(|value| value.parse::<i32>().ok())
+    };
+    ThrottlePolicy {
+        free_failures: setting("FREE_FAILURES").unwrap_or(defaults.free_failures),
+        lockout_after: setting("LOCKOUT_AFTER").unwrap_or(defaults.lockout_after),
+        lockout: setting("LOCKOUT_SECONDS")
+            .map(|seconds| Duration::seconds(seconds.into()))
+            .unwrap_or(defaults.lockout),
+        ..defaults
+    }
+}
+
+static ACCOUNT_POLICY: Lazy<ThrottlePolicy> =
+    Lazy::new(|| policy_from_env("ACCOUNT", ThrottlePolicy::default()));
+
+/// Addresses get more room, a whole school can sign in from behind one NAT
+static IP_POLICY: Lazy<ThrottlePolicy> = Lazy::new(|| {
+    policy_from_env(
+        "IP",
+        ThrottlePolicy {
+            free_failures: 20,
+            lockout_after: 100,
+            ..ThrottlePolicy::default()
+        },
+    )
+});
+
+/// A password (or second factor) check, counted against the account and the client address
+/// as a failure from the start, so checks made at the same time can't all get past the wait
+pub struct Attempt {
+    account: String,
+    ip: Option<String>,
+    counted: Vec<CountedAttempt>,
+}
+
+impl Attempt {
+    /// Counts the attempt, or refuses it while the account or the address has to wait
+    pub fn start(
+        conn: &mut DbConnection,
+        email: &str,
+        ip: Option<IpAddr>,
+    ) -> Result<Self, ApiError> {
+        let account = email.trim().to_lowercase();
+        let ip = ip.map(ip_subject);
+        let mut keys = vec![(ThrottleScope::Account, account.as_str(), &*ACCOUNT_POLICY)];
+        if let Some(ip) = &ip {
+            keys.push((ThrottleScope::Ip, ip.as_str(), &*IP_POLICY));
+        }
+
+        match LoginThrottleInteractor::start_attempt(conn, &keys)? {
+            Ok(counted) => Ok(Attempt {
+                account,
+                ip,
+                counted,
+            }),
+            Err(locked_until) => {
+                // Rounded up, so clients waiting that long are let through
+                let now = chrono::Utc::now().naive_utc();
+                let seconds = (locked_until - now).num_seconds() + 1;
+                Err(ApiError::TooManyRequests(
+                    format!("Too many failed attempts, try again in {} seconds", seconds),
+                    seconds,
+                ))
+            }
+        }
+    }
+
+    fn policy(scope: ThrottleScope) -> &'static ThrottlePolicy {
+        match scope {
+            ThrottleScope::Account => &ACCOUNT_POLICY,
+            ThrottleScope::Ip => &IP_POLICY,
+        }
+    }
+
+    /// Logs the failure, which is already counted; answer with `error`
+    pub fn failed(&self, conn: &mut DbConnection, error: ApiError) -> ApiError {
+        warn!(
+            "Failed sign-in for {} from {}",
+            self.account,
+            self.ip.as_deref().unwrap_or("unknown")
+        );
+        for counted in &self.counted {
+            if counted.failures == Self::policy(counted.scope).lockout_after {
+                warn!(
+                    "Locked {} {} after {} failed sign-ins",
+                    counted.scope.as_str(),
+                    counted.subject(),
+                    counted.failures
+                );
+            }
+        }
+        let _ = LoginThrottleInteractor::delete_stale(conn, ACCOUNT_POLICY.window);
+        error
+    }
+
+    /// Takes back the failure counted for the attempt, which didn't get to check anything or
+    /// got the password right but still needs a second factor; earlier failures are kept
+    pub fn take_back(&self, conn: &mut DbConnection) -> Result<(), ApiError> {
+        for counted in &self.counted {
+            LoginThrottleInteractor::take_back(conn, counted)?;
+        }
+        Ok(())
+    }
+
+    /// Forgets the failures of the account. Those of the address are kept, or anyone could
+    /// clear them by signing in to their own account between guesses.
+    pub fn succeeded(&self, conn: &mut DbConnection) -> Result<(), ApiError> {
+        for counted in &self.counted {
+            match counted.scope {
+                ThrottleScope::Account => {
+                    LoginThrottleInteractor::clear(conn, counted.scope, counted.subject())?;
+                }
+                ThrottleScope::Ip => LoginThrottleInteractor::take_back(conn, counted)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Lifts the lockout of the account signing in with `email`, false when it had no failures
+pub fn unlock(conn: &mut DbConnection, email: &str) -> Result<bool, ApiError> {
+    let cleared =
+        LoginThrottleInteractor::clear(conn, ThrottleScope::Account, &email.trim().to_lowercase())?;
+    Ok(cleared > 0)
+}
+
+/// IPv6 clients usually own a whole /64, so they are throttled by prefix
+fn ip_subject(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => ip.to_string(),
+            None => {
+                let segments = ip.segments();
+                format!(
+                    "{:x}:{:x}:{:x}:{:x}::/64",
+                    segments[0], segments[1], segments[2], segments[3]
+                )
+            }
+        },
+    }
+}
@@ -0,0 +1,187 @@
+use db::models::{Permissions, Person, RefreshToken};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, encode};
+use once_cell::sync::Lazy;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::env::var;
+
+/// Required, the server refuses to start without it (see [`check_secret`])
+static TOKEN_SECRET: Lazy<Option<String>> = Lazy::new(|| {
+    var("SYN_TOKEN_SECRET")
+        .ok()
+        .filter(|secret| !secret.trim().is_empty())
+});
+
+/// Lifetime of an access token in seconds
+static ACCESS_TOKEN_TTL: Lazy<i64> = Lazy::new(|| {
+    var("SYN_ACCESS_TOKEN_TTL")
+        .ok()
+        .and_then(|ttl| ttl.parse().ok())
+        .unwrap_or(15 * 60)
+});
+
+/// Lifetime of a two-factor challenge in seconds
+pub const CHALLENGE_TTL: i64 = 5 * 60;
+
+/// Lifetime of a refresh token in seconds
+static REFRESH_TOKEN_TTL: Lazy<i64> = Lazy::new(|| {
+    var("SYN_REFRESH_TOKEN_TTL")
+        .ok()
+        .and_then(|ttl| ttl.parse().ok())
+        .unwrap_or(30 * 24 * 60 * 60)
+});
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenKind {
+    Access,
+    Refresh,
+    /// Proves the password was right while the second factor is still to be checked
+    Challenge,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
+pub struct PermissionClaims {
+    pub dashboard: bool,
+    pub see_self_history: bool,
+    pub see_others_history: bool,
+    pub admin_panel: bool,
+    pub edit_permissions: bool,
+}
+
+impl From<&Permissions> for PermissionClaims {
+    fn from(permissions: &Permissions) -> Self {
+        Self {
+            dashboard: permissions.dashboard,
+            see_self_history: permissions.see_self_history,
+            see_others_history: permissions.see_others_history,
+            admin_panel: permissions.admin_panel,
+            edit_permissions: permissions.edit_permissions,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Claims {
+    /// Person ID
+    pub sub: String,
+    pub role: String,
+    pub permissions: PermissionClaims,
+    pub kind: TokenKind,
+    pub iat: i64,
+    pub exp: i64,
+    /// Unique token ID
+    pub jti: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct SessionTokens {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub token_type: String,
+    /// Seconds until the access token expires
+    pub expires_in: i64,
+}
+
+/// Fails when `SYN_TOKEN_SECRET` is missing, as tokens signed with a made-up secret would
+/// all be rejected after a restart
+pub fn check_secret() -> Result<(), &'static str> {
+    match TOKEN_SECRET.as_ref() {
+        Some(_) => Ok(()),
+        None => Err("SYN_TOKEN_SECRET must be set to sign session tokens"),
+    }
+}
+
+fn secret() -> &'static [u8] {
+    TOKEN_SECRET
+        .as_deref()
+        .expect("SYN_TOKEN_SECRET is checked on ignite")
+        .as_bytes()
+}
+
+/// Refresh token to store before handing it out, rotated from `family` or starting a new one
+pub fn new_refresh_token(person_id: &str, family: Option<&str>) -> RefreshToken {
+    RefreshToken::new(
+        person_id,
+        family,
+        chrono::Duration::seconds(*REFRESH_TOKEN_TTL),
+    )
+}
+
+/// Signs an access token and the stored `refresh` token
+pub fn issue_session(
+    person: &Person,
+    permissions: Option<&Permissions>,
+    refresh: &RefreshToken,
+) -> jsonwebtoken::errors::Result<SessionTokens> {
+    let permissions = permissions.map(PermissionClaims::from).unwrap_or_default();
+    let now = chrono::Utc::now().timestamp();
+
+    Ok(SessionTokens {
+        access_token: sign(
+            person,
+            &permissions,
+            TokenKind::Access,
+            uuid::Uuid::new_v4().to_string(),
+            now + *ACCESS_TOKEN_TTL,
+        )?,
+        refresh_token: sign(
+            person,
+            &permissions,
+            TokenKind::Refresh,
+            refresh.id.clone(),
+            refresh.expires_at.and_utc().timestamp(),
+        )?,
+        token_type: "Bearer".to_string(),
+        expires_in: *ACCESS_TOKEN_TTL,
+    })
+}
+
+/// Token to send back with the second factor, carrying no permissions
+pub fn issue_challenge(person: &Person) -> jsonwebtoken::errors::Result<String> {
+    sign(
+        person,
+        &PermissionClaims::default(),
+        TokenKind::Challenge,
+        uuid::Uuid::new_v4().to_string(),
+        chrono::Utc::now().timestamp() + CHALLENGE_TTL,
+    )
+}
+
+pub fn verify(token: &str, kind: TokenKind) -> jsonwebtoken::errors::Result<Claims> {
+    let claims = decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(secret()),
+        &Validation::new(Algorithm::HS256),
+    )?
+    .claims;
+
+    if claims.kind != kind {
+        return Err(jsonwebtoken::errors::ErrorKind::InvalidToken.into());
+    }
+    Ok(claims)
+}
+
+fn sign(
+    person: &Person,
+    permissions: &PermissionClaims,
+    kind: TokenKind,
+    jti: String,
+    exp: i64,
+) -> jsonwebtoken::errors::Result<String> {
+    let claims = Claims {
+        sub: person.id.clone(),
+        role: person.role.clone(),
+        permissions: permissions.clone(),
+        kind,
+        iat: chrono::Utc::now().timestamp(),
+        exp,
+        jti,
+    };
+
+    encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &EncodingKey::from_secret(secret()),
+    )
+}
@@ -0,0 +1,164 @@
+use std::env::var;
+
+use db::models::{Permissions, Person, RecoveryCode, Role};
+use log::{error, warn};
+use once_cell::sync::Lazy;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::error::ApiError;
+
+/// Codes from the previous and next 30 seconds are accepted too, for clock drift
+const SKEW_STEPS: i64 = 1;
+const STEP_SECONDS: u64 = 30;
+const RECOVERY_CODES: usize = 10;
+
+/// Roles that can't sign in with a password alone, from the comma separated
+/// `SYN_2FA_REQUIRED_ROLES` (`Admin` by default, empty for none)
+static REQUIRED_ROLES: Lazy<Vec<Role>> = Lazy::new(|| {
+    let roles = var("SYN_2FA_REQUIRED_ROLES").unwrap_or_else(|_| "Admin".to_string());
+    roles
+        .split(',')
+        .map(str::trim)
+        .filter(|role| !role.is_empty())
+        .filter_map(|role| match role.parse() {
+            Ok(role) => Some(role),
+            Err(e) => {
+                error!("Ignoring SYN_2FA_REQUIRED_ROLES entry: {}", e);
+                None
+            }
+        })
+        .collect()
+});
+
+/// Name authenticator apps show next to the account
+static ISSUER: Lazy<String> =
+    Lazy::new(|| var("SYN_2FA_ISSUER").unwrap_or_else(|_| "Synnapse".to_string()));
+
+/// Whether two-factor authentication is mandatory for `person`: their role requires it, or
+/// `permissions` let them manage persons or permissions, whatever their role
+pub fn required_for(person: &Person, permissions: Option<&Permissions>) -> bool {
+    permissions.is_some_and(|permissions| permissions.admin_panel || permissions.edit_permissions)
+        || REQUIRED_ROLES
+            .iter()
+            .any(|role| role.to_string() == person.role)
+}
+
+/// A new random secret, base32 encoded
+pub fn new_secret() -> String {
+    Secret::generate_secret().to_encoded().to_string()
+}
+
+fn totp(secret: &str, account: &str) -> Result<TOTP, ApiError> {
+    let bytes = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|e| ApiError::Internal(format!("Invalid TOTP secret: {:?}", e)))?;
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        0,
+        STEP_SECONDS,
+        bytes,
+        Some(ISSUER.clone()),
+        account.to_string(),
+    )
+    .map_err(|e| ApiError::Internal(format!("Invalid TOTP parameters: {:?}", e)))
+}
+
+/// The `otpauth://` URI to show as a QR code, labelled with the person's email
+pub fn otpauth_uri(secret: &str, person: &Person) -> Result<String, ApiError> {
+    Ok(totp(secret, &person.email)?.get_url())
+}
+
+/// The time step `code` belongs to, if it is valid around now
+pub fn matching_step(secret: &str, code: &str) -> Result<Option<i64>, ApiError> {
+    matching_step_at(secret, code, chrono::Utc::now().timestamp())
+}
+
+/// The time step `code` belongs to, if it is valid around `now` (seconds since the epoch)
+fn matching_step_at(secret: &str, code: &str, now: i64) -> Result<Option<i64>, ApiError> {
+    let totp = totp(secret, "")?;
+    let code = code.trim();
+    let step = now / STEP_SECONDS as i64;
+    Ok((-SKEW_STEPS..=SKEW_STEPS)
+        .map(|offset| step + offset)
+        .find(|step| totp.check(code, *step as u64 * STEP_SECONDS)))
+}
+
+/// Fresh recovery codes to show the person once, along with the rows storing their digests
+pub fn new_recovery_codes(person_id: &str) -> (Vec<String>, Vec<RecoveryCode>) {
+    let codes: Vec<String> = (0..RECOVERY_CODES)
+        .map(|_| {
+            let code = db::crypto::random_token(10).to_lowercase();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect();
+    let rows = codes
+        .iter()
+        .map(|code| RecoveryCode::new(person_id, code))
+        .collect();
+    (codes, rows)
+}
+
+pub fn invalid_code(person_id: &str) -> ApiError {
+    warn!("Wrong two-factor code for person {}", person_id);
+    ApiError::Unauthorized("Invalid two-factor code".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_760_781_615;
+
+    fn code_at(secret: &str, time: i64) -> String {
+        totp(secret, "").unwrap().generate(time as u64)
+    }
+
+    #[test]
+    fn codes_of_neighbouring_steps_are_accepted() {
+        let secret = new_secret();
+        let step = NOW / STEP_SECONDS as i64;
+        for offset in -SKEW_STEPS..=SKEW_STEPS {
+            let code = code_at(&secret, NOW + offset * STEP_SECONDS as i64);
+            assert_eq!(
+                matching_step_at(&secret, &code, NOW).unwrap(),
+                Some(step + offset)
+            );
+        }
+        let code = code_at(&secret, NOW);
+        assert_eq!(
+            matching_step_at(&secret, &format!(" {code}\n"), NOW).unwrap(),
+            Some(step)
+        );
+    }
+
+    #[test]
+    fn codes_beyond_the_skew_are_rejected() {
+        let secret = new_secret();
+        let drift = (SKEW_STEPS + 1) * STEP_SECONDS as i64;
+        for time in [NOW - drift, NOW + drift] {
+            let code = code_at(&secret, time);
+            // A 6 digit code may happen to repeat within the window
+            if code != code_at(&secret, NOW) {
+                assert_eq!(matching_step_at(&secret, &code, NOW).unwrap(), None);
+            }
+        }
+        assert_eq!(matching_step_at(&secret, "12345x", NOW).unwrap(), None);
+    }
+
+    #[test]
+    fn required_by_role_or_admin_flags() {
+        let person = Person::new("Ada", "Lovelace", "ada@example.com", Role::Alumno, None);
+        let mut permissions = Permissions::for_new_account(&person.id);
+        assert!(!required_for(&person, None));
+        assert!(!required_for(&person, Some(&permissions)));
+        permissions.admin_panel = true;
+        assert!(required_for(&person, Some(&permissions)));
+        permissions.admin_panel = false;
+        permissions.edit_permissions = true;
+        assert!(required_for(&person, Some(&permissions)));
+
+        let admin = Person::new("Grace", "Hopper", "grace@example.com", Role::Admin, None);
+        assert!(required_for(&admin, None));
+    }
+}
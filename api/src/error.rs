@@ -0,0 +1,184 @@
+use db::date::DateRangeError;
+use db::pagination::PageError;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use log::error;
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::Json;
+use rocket_okapi::r#gen::OpenApiGenerator;
+use rocket_okapi::okapi::openapi3::Responses;
+use rocket_okapi::response::OpenApiResponderInner;
+use rocket_okapi::util::add_schema_response;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::fmt;
+
+/// Result of a route that answers with a JSON body or an [`ApiError`]
+pub type ApiResult<T> = Result<Json<T>, ApiError>;
+
+/// Every way a route can fail, each mapped to its HTTP status
+#[derive(Debug)]
+pub enum ApiError {
+    /// 400, the request is malformed (bad query parameter, date, ...)
+    BadRequest(String),
+    /// 401, missing or invalid credentials
+    Unauthorized(String),
+    /// 403, authenticated but not allowed
+    Forbidden(String),
+    /// 404
+    NotFound(String),
+    /// 409, clashes with existing data (duplicate email, ...)
+    Conflict(String),
+    /// 412, `If-Match` names an outdated version
+    PreconditionFailed(String),
+    /// 422, well-formed but semantically invalid body
+    Unprocessable(String),
+    /// 429, with the seconds to wait before trying again, sent as `Retry-After`
+    TooManyRequests(String, i64),
+    /// 503, the database is unreachable
+    Unavailable(String),
+    /// 500, details are logged, not returned
+    Internal(String),
+}
+
+impl ApiError {
+    pub fn status(&self) -> Status {
+        match self {
+            ApiError::BadRequest(_) => Status::BadRequest,
+            ApiError::Unauthorized(_) => Status::Unauthorized,
+            ApiError::Forbidden(_) => Status::Forbidden,
+            ApiError::NotFound(_) => Status::NotFound,
+            ApiError::Conflict(_) => Status::Conflict,
+            ApiError::PreconditionFailed(_) => Status::PreconditionFailed,
+            ApiError::Unprocessable(_) => Status::UnprocessableEntity,
+            ApiError::TooManyRequests(..) => Status::TooManyRequests,
+            ApiError::Unavailable(_) => Status::ServiceUnavailable,
+            ApiError::Internal(_) => Status::InternalServerError,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            ApiError::BadRequest(message)
+            | ApiError::Unauthorized(message)
+            | ApiError::Forbidden(message)
+            | ApiError::NotFound(message)
+            | ApiError::Conflict(message)
+            | ApiError::PreconditionFailed(message)
+            | ApiError::Unprocessable(message)
+            | ApiError::TooManyRequests(message, _)
+            | ApiError::Unavailable(message)
+            | ApiError::Internal(message) => message,
+        }
+    }
+
+    pub fn not_found(what: &str) -> Self {
+        ApiError::NotFound(format!("{} not found", what))
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.status(), self.message())
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Body of every error response, including the catchers'
+#[derive(Serialize, JsonSchema)]
+pub struct ErrorBody {
+    /// Always `"error"`
+    pub status: String,
+    pub message: String,
+    pub status_code: u16,
+    /// Method and URI of the failed request
+    pub path: String,
+}
+
+impl ErrorBody {
+    pub fn new(status: Status, message: &str, req: &Request<'_>) -> Self {
+        ErrorBody {
+            status: "error".to_string(),
+            message: message.to_string(),
+            status_code: status.code,
+            path: format!("{} {}", req.method(), req.uri()),
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let status = self.status();
+        if let ApiError::Internal(message) = &self {
+            error!("{} {}: {}", req.method(), req.uri(), message);
+        }
+        let message = match &self {
+            ApiError::Internal(_) => "Internal server error",
+            other => other.message(),
+        };
+        let mut response =
+            Response::build_from(Json(ErrorBody::new(status, message, req)).respond_to(req)?);
+        if let ApiError::TooManyRequests(_, retry_after) = &self {
+            response.raw_header("Retry-After", retry_after.to_string());
+        }
+        response.status(status).ok()
+    }
+}
+
+impl OpenApiResponderInner for ApiError {
+    fn responses(r#gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses> {
+        let mut responses = Responses::default();
+        let schema = r#gen.json_schema::<ErrorBody>();
+        for status in [400, 401, 403, 404, 409, 412, 422, 429, 500, 503] {
+            add_schema_response(&mut responses, status, "application/json", schema.clone())?;
+        }
+        Ok(responses)
+    }
+}
+
+impl From<DieselError> for ApiError {
+    fn from(e: DieselError) -> Self {
+        match e {
+            DieselError::NotFound => ApiError::NotFound("Not found".to_string()),
+            DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, info) => {
+                ApiError::Conflict(format!("Already exists: {}", info.message()))
+            }
+            DieselError::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, info) => {
+                ApiError::Conflict(format!("Still referenced: {}", info.message()))
+            }
+            e => ApiError::Internal(e.to_string()),
+        }
+    }
+}
+
+/// Lets routes use `?` on guards and helpers that fail with a bare status
+impl From<Status> for ApiError {
+    fn from(status: Status) -> Self {
+        let message = status.reason().unwrap_or("Error").to_string();
+        match status.code {
+            400 => ApiError::BadRequest(message),
+            401 => ApiError::Unauthorized(message),
+            403 => ApiError::Forbidden("Insufficient permissions".to_string()),
+            404 => ApiError::NotFound(message),
+            409 => ApiError::Conflict(message),
+            412 => ApiError::PreconditionFailed(message),
+            422 => ApiError::Unprocessable(message),
+            503 => ApiError::Unavailable("Database unavailable".to_string()),
+            _ => ApiError::Internal(message),
+        }
+    }
+}
+
+impl From<DateRangeError> for ApiError {
+    fn from(e: DateRangeError) -> Self {
+        ApiError::BadRequest(e.to_string())
+    }
+}
+
+impl From<PageError> for ApiError {
+    fn from(e: PageError) -> Self {
+        ApiError::BadRequest(e.to_string())
+    }
+}
@@ -0,0 +1,167 @@
+use rocket::http::{Header, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::Json;
+use rocket_okapi::r#gen::OpenApiGenerator;
+use rocket_okapi::okapi::openapi3::Responses;
+use rocket_okapi::request::OpenApiFromRequest;
+use rocket_okapi::response::OpenApiResponderInner;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::error::ApiError;
+
+/// Result of a route that answers with a versioned JSON body or an [`ApiError`]
+pub type TaggedResult<T> = Result<Tagged<T>, ApiError>;
+
+/// Strong ETag of a row version
+pub fn etag(version: i32) -> String {
+    format!("\"{}\"", version)
+}
+
+/// Parses a list of entity tags (`"3", W/"4"`); `*` matches any version
+fn matches(header: &str, version: i32) -> bool {
+    let tag = etag(version);
+    header.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == tag
+    })
+}
+
+/// A JSON body sent with the `ETag` of its row version. A GET whose `If-None-Match`
+/// already names that version gets an empty 304 instead.
+pub struct Tagged<T> {
+    version: i32,
+    body: T,
+}
+
+impl<T> Tagged<T> {
+    pub fn new(version: i32, body: T) -> Self {
+        Tagged { version, body }
+    }
+}
+
+impl<'r, T: Serialize> Responder<'r, 'static> for Tagged<T> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let tag = Header::new("ETag", etag(self.version));
+        if req.method() == rocket::http::Method::Get
+            && let Some(header) = req.headers().get_one("If-None-Match")
+            && matches(header, self.version)
+        {
+            return Response::build()
+                .status(Status::NotModified)
+                .header(tag)
+                .ok();
+        }
+        Response::build_from(Json(self.body).respond_to(req)?)
+            .header(tag)
+            .ok()
+    }
+}
+
+impl<T: Serialize + JsonSchema + Send> OpenApiResponderInner for Tagged<T> {
+    fn responses(r#gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses> {
+        Json::<T>::responses(r#gen)
+    }
+}
+
+/// The versions named by the `If-Match` header, parsed when the route asks for them so a
+/// malformed header gets the usual [`ApiError`] body
+#[derive(OpenApiFromRequest)]
+pub struct IfMatch(Result<Option<Vec<i32>>, ApiError>);
+
+impl IfMatch {
+    /// `None` when the header is absent or `*`, so any version may be overwritten
+    pub fn versions(self) -> Result<Option<Vec<i32>>, ApiError> {
+        self.0
+    }
+}
+
+/// Parses a list of entity tags (`"3", "4"`). Weak tags never match for `If-Match`, and
+/// neither do tags that aren't a version, so they are left out rather than rejected.
+fn parse_if_match(header: &str) -> Result<Option<Vec<i32>>, ApiError> {
+    let mut versions = Vec::new();
+    for candidate in header.split(',').map(str::trim) {
+        if candidate == "*" {
+            return Ok(None);
+        }
+        let tag = candidate.strip_prefix("W/").unwrap_or(candidate);
+        let Some(opaque) = tag.strip_prefix('"').and_then(|tag| tag.strip_suffix('"')) else {
+            return Err(ApiError::PreconditionFailed(format!(
+                "Unsupported If-Match value: {}",
+                header
+            )));
+        };
+        if tag.len() == candidate.len()
+            && let Ok(version) = opaque.parse()
+        {
+            versions.push(version);
+        }
+    }
+    Ok(Some(versions))
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfMatch {
+    type Error = ApiError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(IfMatch(match req.headers().get_one("If-Match") {
+            Some(header) => parse_if_match(header),
+            None => Ok(None),
+        }))
+    }
+}
+
+/// Error for an update that matched no row: the `If-Match` version was stale if the
+/// row still exists, otherwise it is gone
+pub fn update_miss(exists: bool, what: &str) -> ApiError {
+    if exists {
+        ApiError::PreconditionFailed(format!(
+            "{} was modified by someone else, reload it and retry",
+            what
+        ))
+    } else {
+        ApiError::not_found(what)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn if_match_takes_a_list_of_strong_tags() {
+        assert_eq!(parse_if_match("\"3\"").unwrap(), Some(vec![3]));
+        assert_eq!(parse_if_match("\"3\", \"4\"").unwrap(), Some(vec![3, 4]));
+        assert_eq!(
+            parse_if_match("W/\"3\", \"abc\", \"4\"").unwrap(),
+            Some(vec![4])
+        );
+        assert_eq!(parse_if_match("W/\"3\"").unwrap(), Some(vec![]));
+    }
+
+    #[test]
+    fn if_match_star_matches_any_version() {
+        assert_eq!(parse_if_match("*").unwrap(), None);
+        assert_eq!(parse_if_match(" * ").unwrap(), None);
+    }
+
+    #[test]
+    fn if_match_rejects_unquoted_tags() {
+        assert!(matches!(
+            parse_if_match("3"),
+            Err(ApiError::PreconditionFailed(_))
+        ));
+        assert!(matches!(
+            parse_if_match("\"3\", 4"),
+            Err(ApiError::PreconditionFailed(_))
+        ));
+    }
+
+    #[test]
+    fn if_none_match_ignores_weakness() {
+        assert!(matches("W/\"3\", \"5\"", 3));
+        assert!(matches("*", 7));
+        assert!(!matches("\"4\"", 3));
+    }
+}
@@ -0,0 +1,267 @@
+use std::collections::VecDeque;
+use std::env;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::{error, warn};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::tokio::sync::broadcast;
+use rocket_okapi::request::OpenApiFromRequest;
+use serde::Serialize;
+
+use crate::auth::access::Permission;
+use crate::auth::guard::CurrentUser;
+use crate::models::{Database, PersonView};
+
+/// Events kept for clients reconnecting with `Last-Event-ID`, unless `SYN_EVENT_HISTORY` says otherwise
+const DEFAULT_HISTORY: usize = 500;
+
+/// Seconds between checks that a stream's user still exists with the same permissions,
+/// unless `SYN_EVENT_RECHECK_SECS` says otherwise
+const DEFAULT_RECHECK_SECS: u64 = 30;
+
+/// What changed, sent as the SSE `event` field
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    EntryCreated,
+    EntryUpdated,
+    EntryDeleted,
+    PersonChanged,
+}
+
+impl EventKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            EventKind::EntryCreated => "entry-created",
+            EventKind::EntryUpdated => "entry-updated",
+            EventKind::EntryDeleted => "entry-deleted",
+            EventKind::PersonChanged => "person-changed",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LiveEvent {
+    /// Increases by one per event since the server started; sent prefixed with the start
+    /// time (see [`EventBus::event_id`]) so ids from before a restart aren't mistaken for new ones
+    pub id: u64,
+    pub kind: EventKind,
+    /// Whose data changed, used to decide who may see the event
+    pub person_id: String,
+    /// JSON payload
+    pub data: String,
+}
+
+impl LiveEvent {
+    /// Entry events follow the history permissions of `GET /api/entry`, person events the
+    /// ones of `GET /api/person`
+    pub fn visible_to(&self, user: &CurrentUser) -> bool {
+        match self.kind {
+            EventKind::EntryCreated | EventKind::EntryUpdated | EventKind::EntryDeleted => {
+                user.require_history_of(&self.person_id).is_ok()
+            }
+            EventKind::PersonChanged => {
+                user.is(&self.person_id)
+                    || user.has(Permission::SeeOthersHistory)
+                    || user.has(Permission::AdminPanel)
+            }
+        }
+    }
+}
+
+/// Payload of `person-changed`; `person` is left out once it has been purged
+#[derive(Serialize)]
+struct PersonChanged<'a> {
+    id: &'a str,
+    /// `created`, `updated`, `deleted`, `restored` or `purged`
+    change: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    person: Option<&'a PersonView>,
+}
+
+struct History {
+    events: VecDeque<LiveEvent>,
+    last_id: u64,
+}
+
+/// Fans change events out to the open `/api/events` streams and remembers the latest ones
+pub struct EventBus {
+    sender: broadcast::Sender<LiveEvent>,
+    history: Mutex<History>,
+    capacity: usize,
+    /// When the server started, in milliseconds since the epoch
+    epoch: i64,
+    /// How often open streams check their user
+    pub recheck: Duration,
+    db: Database,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize, recheck: Duration, db: Database) -> Self {
+        let capacity = capacity.max(1);
+        EventBus {
+            sender: broadcast::channel(capacity).0,
+            history: Mutex::new(History {
+                events: VecDeque::with_capacity(capacity),
+                last_id: 0,
+            }),
+            capacity,
+            epoch: chrono::Utc::now().timestamp_millis(),
+            recheck,
+            db,
+        }
+    }
+
+    pub fn from_env(db: Database) -> Self {
+        let capacity = env::var("SYN_EVENT_HISTORY")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_HISTORY);
+        let recheck = env::var("SYN_EVENT_RECHECK_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|secs| *secs > 0)
+            .unwrap_or(DEFAULT_RECHECK_SECS);
+        Self::new(capacity, Duration::from_secs(recheck), db)
+    }
+
+    pub fn publish(&self, kind: EventKind, person_id: &str, data: &impl Serialize) {
+        let data = match serde_json::to_string(data) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Failed to serialize {} event: {}", kind.name(), e);
+                return;
+            }
+        };
+        let mut history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+        history.last_id += 1;
+        let event = LiveEvent {
+            id: history.last_id,
+            kind,
+            person_id: person_id.to_string(),
+            data,
+        };
+        if history.events.len() == self.capacity {
+            history.events.pop_front();
+        }
+        history.events.push_back(event.clone());
+        // Sending only fails when nobody is listening
+        let _ = self.sender.send(event);
+    }
+
+    pub fn person_changed(&self, change: &str, id: &str, person: Option<&PersonView>) {
+        let data = PersonChanged { id, change, person };
+        self.publish(EventKind::PersonChanged, id, &data);
+    }
+
+    /// A receiver for the events published from now on, and the id of the last one before it
+    pub fn subscribe(&self) -> (broadcast::Receiver<LiveEvent>, u64) {
+        let history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+        (self.sender.subscribe(), history.last_id)
+    }
+
+    /// Whether a stream opened by `user` may stay open: the access token hasn't expired, and
+    /// the person still exists with the same permissions
+    pub async fn still_allowed(&self, user: &CurrentUser) -> bool {
+        if user.expires_at <= chrono::Utc::now().timestamp() {
+            return false;
+        }
+        let person_id = user.person.id.clone();
+        let expires_at = user.expires_at;
+        let Ok(Some(current)) = self
+            .db
+            .run(move |conn| CurrentUser::load(conn, &person_id, expires_at))
+            .await
+        else {
+            return false;
+        };
+        [
+            Permission::Dashboard,
+            Permission::SeeSelfHistory,
+            Permission::SeeOthersHistory,
+            Permission::AdminPanel,
+            Permission::EditPermissions,
+        ]
+        .into_iter()
+        .all(|permission| current.has(permission) == user.has(permission))
+    }
+
+    /// The id sent to clients for the event numbered `id`, e.g. `1760781600000-42`
+    pub fn event_id(&self, id: u64) -> String {
+        format!("{}-{}", self.epoch, id)
+    }
+
+    /// The number of the event a client last received, `None` when the id was sent before a
+    /// restart (or isn't one of ours) and nothing can be resumed from it
+    pub fn parse_event_id(&self, event_id: &str) -> Option<u64> {
+        sequence_of(self.epoch, event_id)
+    }
+
+    /// The id of the latest event published
+    pub fn last_id(&self) -> u64 {
+        self.history
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .last_id
+    }
+
+    /// The events published after `last_id`, or `None` when some of them are no longer kept
+    /// and the client has to reload everything
+    pub fn since(&self, last_id: u64) -> Option<Vec<LiveEvent>> {
+        let history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+        if last_id > history.last_id {
+            return None;
+        }
+        let oldest = history.events.front().map_or(history.last_id + 1, |e| e.id);
+        if last_id + 1 < oldest {
+            warn!("Event {} is no longer in the history", last_id);
+            return None;
+        }
+        Some(
+            history
+                .events
+                .iter()
+                .filter(|event| event.id > last_id)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+/// The number in an event id, if it was sent since the server started at `epoch`
+fn sequence_of(epoch: i64, event_id: &str) -> Option<u64> {
+    let (sent_epoch, id) = event_id.trim().split_once('-')?;
+    if sent_epoch.parse::<i64>().ok()? != epoch {
+        return None;
+    }
+    id.parse().ok()
+}
+
+/// The id sent back by a reconnecting client in the `Last-Event-ID` header, see
+/// [`EventBus::parse_event_id`]
+#[derive(OpenApiFromRequest)]
+pub struct LastEventId(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let id = req.headers().get_one("Last-Event-ID").map(str::to_string);
+        Outcome::Success(LastEventId(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_ids_only_resume_within_the_same_run() {
+        assert_eq!(sequence_of(1000, "1000-42"), Some(42));
+        assert_eq!(sequence_of(1000, " 1000-7 "), Some(7));
+        assert_eq!(sequence_of(1000, "999-42"), None);
+        assert_eq!(sequence_of(1000, "42"), None);
+        assert_eq!(sequence_of(1000, "1000-x"), None);
+    }
+}
@@ -1,16 +1,29 @@
 mod auth;
 mod cors;
 mod email;
+mod error;
+mod etag;
+mod events;
 mod models;
 mod req_logger;
 mod routes;
+mod webhooks;
 
+use crate::auth::oidc::OidcProviders;
+use crate::auth::signed::InvalidBody;
 use crate::cors::CORS;
+use crate::error::ErrorBody;
+use crate::events::EventBus;
 use crate::models::Database;
-use crate::routes::{auth::*, entries::*, google_auth::*, misc::*, permissions::*, person::*};
+use crate::routes::{
+    attendance::*, auth::*, entries::*, events::*, misc::*, oidc::*, permissions::*, person::*,
+    two_factor::*, webhooks::*,
+};
+use crate::webhooks::WebhookDispatcher;
 use log::{error, info, warn};
 use req_logger::ReqLogger;
-use rocket::response::content::RawJson;
+use rocket::fairing::AdHoc;
+use rocket::serde::json::Json;
 use rocket::{Request, catch, catchers, http::Status, options};
 use rocket_okapi::{
     openapi_get_routes,
@@ -18,58 +31,68 @@ use rocket_okapi::{
 };
 
 #[catch(404)]
-fn not_found(req: &Request) -> RawJson<String> {
+fn not_found(req: &Request) -> Json<ErrorBody> {
     warn!("Not found: {} {}", req.method(), req.uri());
-    RawJson(format!(
-        "{{\"status\": \"error\",\
-        \"message\": \"Not found\",\
-        \"path\": \"{} {}\"}}",
-        req.method(),
-        req.uri()
-    ))
+    Json(ErrorBody::new(Status::NotFound, "Not found", req))
 }
 
 #[catch(401)]
-fn unauthorized(req: &Request) -> RawJson<String> {
+fn unauthorized(req: &Request) -> Json<ErrorBody> {
     error!("Unauthorized: {} {}", req.method(), req.uri());
-    RawJson(format!(
-        "{{\"status\": \"error\",\
-        \"message\": \"Unauthorized access\",\
-        \"path\": \"{} {}\"}}",
-        req.method(),
-        req.uri()
+    Json(ErrorBody::new(
+        Status::Unauthorized,
+        "Unauthorized access",
+        req,
+    ))
+}
+
+#[catch(403)]
+fn forbidden(req: &Request) -> Json<ErrorBody> {
+    warn!("Forbidden: {} {}", req.method(), req.uri());
+    Json(ErrorBody::new(
+        Status::Forbidden,
+        "Insufficient permissions",
+        req,
     ))
 }
 
 #[catch(default)]
-fn default_catcher(status: Status, req: &Request) -> RawJson<String> {
+fn default_catcher(status: Status, req: &Request) -> Json<ErrorBody> {
     error!("Error: {} {} {}", status, req.method(), req.uri());
-    RawJson(format!(
-        "{{\"status\": \"error\",\
-        \"message\": \"An error occurred\",\
-        \"status_code\": \"{}\",\
-        \"path\": \"{} {}\"}}",
-        status,
-        req.method(),
-        req.uri()
-    ))
+    let message = match req.local_cache(|| InvalidBody(None)) {
+        InvalidBody(Some(reason)) => reason.as_str(),
+        InvalidBody(None) => status.reason().unwrap_or("An error occurred"),
+    };
+    Json(ErrorBody::new(status, message, req))
 }
 
 #[options("/<_..>")]
 fn all_options() {}
 
 pub async fn run_server(db_url: &str) -> Result<(), rocket::Error> {
-    // Initialize WebSocketManager
     info!("Starting server with database: {}", db_url);
 
-    let app_state = Database {
-        db_url: db_url.to_string(),
-    };
+    let app_state = Database::new(db_url);
     let build = rocket::build()
+        .manage(WebhookDispatcher::start(app_state.clone()))
+        .manage(EventBus::from_env(app_state.clone()))
         .manage(app_state)
+        .manage(OidcProviders::from_env())
+        .attach(AdHoc::try_on_ignite("Token secret", |rocket| async {
+            match auth::token::check_secret() {
+                Ok(()) => Ok(rocket),
+                Err(e) => {
+                    error!("{}", e);
+                    Err(rocket)
+                }
+            }
+        }))
         .attach(ReqLogger {})
         .attach(CORS {})
-        .register("/", catchers![not_found, default_catcher, unauthorized])
+        .register(
+            "/",
+            catchers![not_found, default_catcher, unauthorized, forbidden],
+        )
         .mount("/", rocket::routes![all_options])
         .mount(
             "/",
@@ -84,34 +107,66 @@ pub async fn run_server(db_url: &str) -> Result<(), rocket::Error> {
                 get_entry_by_action_and_person_id,
                 get_entry_by_date,
                 update_entry,
+                patch_entry,
                 delete_entry,
+                // Events
+                event_stream,
+                // Attendance
+                get_stays,
+                get_summary,
+                get_summary_by_role,
+                get_occupancy,
                 // Permissions
                 get_permissions,
                 get_permissions_by_person_id,
                 get_permissions_by_id,
                 create_permissions,
                 update_permissions,
+                patch_permissions,
                 delete_permissions,
                 // Person
                 create_person,
                 get_persons,
                 get_person_by_id,
-                get_person_by_google_id,
+                get_person_by_identity,
                 update_person,
+                patch_person,
                 delete_person,
+                restore_person,
+                purge_person,
+                // Webhooks
+                get_webhooks,
+                get_webhook,
+                create_webhook,
+                patch_webhook,
+                delete_webhook,
+                get_webhook_deliveries,
                 // Auth
                 login,
+                refresh_session,
+                logout,
+                me,
                 register,
                 change_password,
                 forgot_password,
                 verify_reset_token,
                 reset_password,
                 set_password,
-                // Google Auth
-                google_login,
-                google_register,
-                link_google_account,
-                update_google_id,
+                unlock_account,
+                // Two-factor authentication
+                verify_two_factor,
+                get_two_factor,
+                enroll_two_factor,
+                confirm_two_factor,
+                disable_two_factor,
+                reset_two_factor,
+                // OpenID Connect
+                get_oidc_providers,
+                oidc_login,
+                oidc_register,
+                oidc_link,
+                get_identities,
+                unlink_identity,
                 // Misc
                 health_check,
             ],
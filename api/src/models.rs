@@ -1,3 +1,223 @@
+use crate::error::ApiError;
+use db::DbConnection;
+use db::models::{Person, PersonChanges, Role};
+use db::pool::{DbPool, PoolConfig, build_pool};
+use log::error;
+use rocket::http::Status;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone)]
 pub struct Database {
-    pub db_url: String,
+    pub pool: DbPool,
+}
+
+impl Database {
+    pub fn new(db_url: &str) -> Self {
+        Database {
+            pool: build_pool(db_url, &PoolConfig::from_env()),
+        }
+    }
+
+    /// Runs blocking Diesel work on a pooled connection, off the async workers.
+    /// Fails with 503 when no connection can be checked out in time.
+    pub async fn run<T, F>(&self, f: F) -> Result<T, Status>
+    where
+        F: FnOnce(&mut DbConnection) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let pool = self.pool.clone();
+        rocket::tokio::task::spawn_blocking(move || {
+            let mut conn = pool.get().map_err(|e| {
+                error!("No database connection available: {}", e);
+                Status::ServiceUnavailable
+            })?;
+            Ok(f(&mut conn))
+        })
+        .await
+        .map_err(|e| {
+            error!("Database task failed: {}", e);
+            Status::InternalServerError
+        })?
+    }
+}
+
+/// Body of routes that only report success
+#[derive(Serialize, JsonSchema)]
+pub struct Message {
+    /// Always `"ok"`
+    pub status: String,
+    pub message: String,
+}
+
+impl Message {
+    pub fn ok(message: &str) -> Self {
+        Message {
+            status: "ok".to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Public view of a person, without credentials
+#[derive(Serialize, JsonSchema)]
+pub struct PersonView {
+    pub id: String,
+    pub name: String,
+    pub surname: String,
+    pub email: String,
+    pub role: String,
+    /// Also sent as the `ETag` header
+    pub version: i32,
+    /// When the person was soft-deleted, left out for active persons
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<chrono::NaiveDateTime>,
+}
+
+impl From<Person> for PersonView {
+    fn from(person: Person) -> Self {
+        PersonView {
+            id: person.id,
+            name: person.name,
+            surname: person.surname,
+            email: person.email,
+            role: person.role,
+            version: person.version,
+            deleted_at: person.deleted_at,
+        }
+    }
+}
+
+/// Body of `POST /api/person`; the id is generated and the password hashed by the server
+#[derive(Deserialize, JsonSchema)]
+pub struct CreatePerson {
+    pub name: String,
+    pub surname: String,
+    pub email: String,
+    pub role: Role,
+    /// Leave out for accounts that only sign in with an identity provider
+    pub password: Option<String>,
+}
+
+impl CreatePerson {
+    /// Hashes the password, which is slow: call it inside `Database::run`
+    pub fn into_person(self) -> Person {
+        let password_hash = self.password.as_deref().map(db::crypto::to_hash);
+        Person::new(
+            &self.name,
+            &self.surname,
+            &self.email,
+            self.role,
+            password_hash.as_deref(),
+        )
+    }
+}
+
+/// Body of `PUT /api/person/<id>`
+#[derive(Deserialize, JsonSchema)]
+pub struct UpdatePerson {
+    pub name: String,
+    pub surname: String,
+    pub email: String,
+    pub role: Role,
+    /// New password, leave out to keep the current one
+    pub password: Option<String>,
+}
+
+impl UpdatePerson {
+    /// Applies the changes, keeping the id and (unless replaced) password hash. Hashing is
+    /// slow: call it inside `Database::run`.
+    pub fn apply(self, person: &mut Person) {
+        person.name = self.name;
+        person.surname = self.surname;
+        person.email = self.email;
+        person.role = self.role.to_string();
+        if let Some(password) = &self.password {
+            person.password_hash = Some(db::crypto::to_hash(password));
+        }
+    }
+}
+
+/// Body of `PATCH /api/person/<id>`, a JSON merge patch: only the fields present are changed.
+/// None of them can be removed with `null`.
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct PatchPerson {
+    #[serde(default, deserialize_with = "nullable")]
+    pub name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub surname: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub email: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub role: Option<Option<Role>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub password: Option<Option<String>>,
+}
+
+impl PatchPerson {
+    /// Fails with 422 when a field is set to `null`. Hashes the password, which is slow: call
+    /// it inside `Database::run`.
+    pub fn into_changes(self) -> Result<PersonChanges, ApiError> {
+        Ok(PersonChanges {
+            name: required(self.name, "name")?,
+            surname: required(self.surname, "surname")?,
+            email: required(self.email, "email")?,
+            role: required(self.role, "role")?.map(|role| role.to_string()),
+            password_hash: required(self.password, "password")?
+                .as_deref()
+                .map(db::crypto::to_hash),
+        })
+    }
+}
+
+/// Tells a JSON merge patch field set to `null` (`Some(None)`) from a missing one (`None`),
+/// used with `#[serde(default, deserialize_with = "nullable")]`
+pub fn nullable<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
+/// A merge patch field that can't be removed with `null`
+pub fn required<T>(field: Option<Option<T>>, name: &str) -> Result<Option<T>, ApiError> {
+    match field {
+        Some(None) => Err(ApiError::Unprocessable(format!(
+            "'{}' can't be removed",
+            name
+        ))),
+        field => Ok(field.flatten()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patch(json: &str) -> Result<PersonChanges, ApiError> {
+        serde_json::from_str::<PatchPerson>(json)
+            .unwrap()
+            .into_changes()
+    }
+
+    #[test]
+    fn person_patches_change_only_the_fields_present() {
+        let changes = patch(r#"{"name": "Ada"}"#).unwrap();
+        assert_eq!(changes.name.as_deref(), Some("Ada"));
+        assert_eq!(changes.surname, None);
+        assert_eq!(changes.password_hash, None);
+    }
+
+    #[test]
+    fn person_patches_cant_remove_fields() {
+        for field in ["name", "surname", "email", "role", "password"] {
+            let json = format!(r#"{{"{field}": null}}"#);
+            assert!(
+                matches!(patch(&json), Err(ApiError::Unprocessable(_))),
+                "{field}"
+            );
+        }
+    }
 }
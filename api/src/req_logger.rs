@@ -66,6 +66,7 @@ impl Fairing for ReqLogger {
             },
             res.status()
         );
+        #[allow(clippy::collapsible_if)]
         if let Ok(mut lock) = TIMINGS.lock() {
             if let Some(start_time) = lock.remove(&id) {
                 let duration = now.duration_since(start_time);
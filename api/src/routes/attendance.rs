@@ -0,0 +1,153 @@
+use db::attendance::occupancy::{self, Occupancy};
+use db::attendance::summary::{self, Period, Summary};
+use db::attendance::{self, Stay};
+use db::date;
+use db::models::Role;
+use rocket::serde::json::Json;
+use rocket::{State, get};
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::auth::access::Permission;
+use crate::auth::guard::{ApiKey, CurrentUser};
+use crate::error::{ApiError, ApiResult};
+use crate::models::Database;
+use crate::routes::parse_range;
+
+#[derive(Serialize, JsonSchema)]
+pub struct StaysReport {
+    person_id: String,
+    stays: Vec<Stay>,
+    /// Sum of the stays with both ends known
+    total_seconds: i64,
+    anomalies: usize,
+}
+
+/// Get the stays (Enter/Exit pairs) of a person
+///
+/// `from` and `to` take `YYYY-MM-DD`, a day in `SYN_TIMEZONE`, or `YYYY-MM-DDTHH:MM:SS` in UTC;
+/// a bare `to` date includes that whole day
+#[openapi(tag = "Attendance")]
+#[get("/api/attendance/stays/<person_id>?<from>&<to>")]
+pub async fn get_stays(
+    db: &State<Database>,
+    person_id: String,
+    from: Option<String>,
+    to: Option<String>,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> ApiResult<StaysReport> {
+    user.require_history_of(&person_id)?;
+
+    let range = parse_range(from, to)?;
+
+    let p_id = person_id.clone();
+    let stays = db
+        .run(move |conn| attendance::stays(conn, &p_id, range))
+        .await??;
+    Ok(Json(StaysReport {
+        total_seconds: stays.iter().filter_map(|s| s.duration_seconds).sum(),
+        anomalies: stays.iter().filter(|s| !s.anomalies.is_empty()).count(),
+        person_id,
+        stays,
+    }))
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct PersonSummary {
+    name: String,
+    surname: String,
+    #[serde(flatten)]
+    summary: Summary,
+}
+
+/// Get the time a person spent inside per day, week or month
+///
+/// `period` is `day` (default), `week` or `month`. Stays crossing midnight are split between days.
+#[openapi(tag = "Attendance")]
+#[get("/api/attendance/summary/<person_id>?<period>&<from>&<to>")]
+pub async fn get_summary(
+    db: &State<Database>,
+    person_id: String,
+    period: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> ApiResult<Summary> {
+    user.require_history_of(&person_id)?;
+
+    let period = parse_period(period)?;
+    let range = parse_range(from, to)?;
+
+    let summary = db
+        .run(move |conn| summary::person_summary(conn, &person_id, period, range))
+        .await??;
+    Ok(Json(summary))
+}
+
+/// Get the time spent inside by every person with a role (Profesor, Alumno, Admin)
+#[openapi(tag = "Attendance")]
+#[get("/api/attendance/summary/by-role/<role>?<period>&<from>&<to>")]
+pub async fn get_summary_by_role(
+    db: &State<Database>,
+    role: String,
+    period: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> ApiResult<Vec<PersonSummary>> {
+    user.require(Permission::SeeOthersHistory)?;
+
+    let role: Role = role.parse().map_err(ApiError::BadRequest)?;
+    let period = parse_period(period)?;
+    let range = parse_range(from, to)?;
+
+    let summaries = db
+        .run(move |conn| summary::role_summary(conn, &role, period, range))
+        .await??;
+    Ok(Json(
+        summaries
+            .into_iter()
+            .map(|(person, summary)| PersonSummary {
+                name: person.name,
+                surname: person.surname,
+                summary,
+            })
+            .collect(),
+    ))
+}
+
+/// Who is inside the building right now, grouped by role
+///
+/// Everyone whose latest entry is an Enter counts; the ones who entered on an earlier day and
+/// never checked out (in `SYN_TIMEZONE`) are flagged as `stale`
+#[openapi(tag = "Attendance")]
+#[get("/api/occupancy")]
+pub async fn get_occupancy(
+    db: &State<Database>,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> ApiResult<Occupancy> {
+    user.require_any(&[
+        Permission::Dashboard,
+        Permission::SeeOthersHistory,
+        Permission::AdminPanel,
+    ])?;
+
+    let occupancy = db
+        .run(|conn| occupancy::occupancy(conn, chrono::Utc::now().naive_utc(), date::timezone()))
+        .await??;
+    Ok(Json(occupancy))
+}
+
+fn parse_period(period: Option<String>) -> Result<Period, ApiError> {
+    match period {
+        None => Ok(Period::Day),
+        Some(period) => period
+            .parse()
+            .map_err(|e| ApiError::BadRequest(format!("{}, expected day, week or month", e))),
+    }
+}
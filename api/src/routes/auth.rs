@@ -1,9 +1,18 @@
-use crate::auth::guard::ApiKey;
-use crate::models::Database;
-use log::error;
-use rocket::response::content::RawJson;
+use crate::auth::access::Permission;
+use crate::auth::guard::{ApiKey, ClientIp, CurrentUser};
+use crate::auth::signed::SignedJson;
+use crate::auth::throttle::{self, Attempt, INVALID_CREDENTIALS};
+use crate::auth::token;
+use crate::error::{ApiError, ApiResult};
+use crate::events::EventBus;
+use crate::models::{Database, Message, PersonView};
+use crate::routes::two_factor::{LoginResponse, login_response};
+use crate::webhooks::{self, WebhookDispatcher};
+use db::interactions::password_reset::PasswordReset as ResetOutcome;
+use db::interactions::refresh_token::{RefreshTokenInteractor, RefreshUse};
+use log::warn;
 use rocket::serde::json::Json;
-use rocket::{State, post};
+use rocket::{State, delete, get, post};
 use rocket_okapi::openapi;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
@@ -14,26 +23,186 @@ pub struct Login {
     pub password: String,
 }
 
+/// Sign in with email and password
+///
+/// Wrong credentials count against the account and the client address, which have to wait
+/// longer after each failure past a few, and are locked for a while after many.
 #[openapi(tag = "Authentication")]
 #[post("/api/auth/login", format = "json", data = "<login>")]
-pub async fn login(db: &State<Database>, login: Json<Login>, _api_key: ApiKey) -> RawJson<String> {
-    let conn = &mut db::establish_connection(&db.db_url);
-
-    // First try to get user by email
-    if let Ok(person) = db::interactions::person::PersonInteractor::get_by_email(conn, &login.email)
-    {
-        if let Some(password_hash) = &person.password_hash {
-            if db::crypto::check_hash(&login.password, password_hash) {
-                return RawJson("{\"status\":\"ok\"}".into());
+pub async fn login(
+    db: &State<Database>,
+    login: SignedJson<Login>,
+    ip: ClientIp,
+    _api_key: ApiKey,
+) -> ApiResult<LoginResponse> {
+    let response = db
+        .run(move |conn| {
+            let attempt = Attempt::start(conn, &login.email, ip.0)?;
+
+            // Unknown emails, accounts without password and wrong passwords look the same
+            let person =
+                db::interactions::person::PersonInteractor::get_by_email(conn, &login.email).ok();
+            let password_hash = person.as_ref().and_then(|p| p.password_hash.as_deref());
+            let valid = db::crypto::check_password(&login.password, password_hash);
+            let Some(person) = person.filter(|_| valid) else {
+                return Err(attempt.failed(
+                    conn,
+                    ApiError::Unauthorized(INVALID_CREDENTIALS.to_string()),
+                ));
+            };
+
+            let response = login_response(conn, &person, None)?;
+            // A pending second factor keeps the failures, so guessing it can't start over
+            match response {
+                LoginResponse::Session(_) => attempt.succeeded(conn)?,
+                LoginResponse::TwoFactor(_) => attempt.take_back(conn)?,
             }
-            return RawJson("{\"status\":\"error\",\"message\":\"Invalid Password\"}".into());
-        } else {
-            return RawJson(
-                "{\"status\":\"error\",\"message\":\"This account uses social login\"}".into(),
-            );
-        }
+            Ok(response)
+        })
+        .await??;
+    Ok(Json(response))
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct RefreshSession {
+    pub refresh_token: String,
+}
+
+/// Exchange a refresh token for a new pair of session tokens
+#[openapi(tag = "Authentication")]
+#[post("/api/auth/refresh", format = "json", data = "<refresh>")]
+pub async fn refresh_session(
+    db: &State<Database>,
+    refresh: SignedJson<RefreshSession>,
+    _api_key: ApiKey,
+) -> ApiResult<SessionResponse> {
+    let claims = token::verify(&refresh.refresh_token, token::TokenKind::Refresh).map_err(|e| {
+        warn!("Rejected refresh token: {}", e);
+        ApiError::Unauthorized("Invalid refresh token".to_string())
+    })?;
+
+    let response = db
+        .run(move |conn| {
+            // Each refresh token works once; the new one carries on the same sign-in
+            let refresh = match RefreshTokenInteractor::use_token(conn, &claims.jti)? {
+                RefreshUse::Valid(refresh) => refresh,
+                RefreshUse::Reused | RefreshUse::Unknown => {
+                    return Err(ApiError::Unauthorized("Invalid refresh token".to_string()));
+                }
+            };
+            let person =
+                db::interactions::person::PersonInteractor::get_by_id(conn, &refresh.person_id)
+                    .map_err(|_| ApiError::Unauthorized("User not found".to_string()))?;
+            issue_tokens(conn, &person, Some(&refresh.family_id), None)
+        })
+        .await??;
+    Ok(Json(response))
+}
+
+/// Sign out, revoking the refresh token and every token rotated from the same sign-in
+///
+/// Access tokens already handed out stay valid until they expire.
+#[openapi(tag = "Authentication")]
+#[post("/api/auth/logout", format = "json", data = "<refresh>")]
+pub async fn logout(
+    db: &State<Database>,
+    refresh: SignedJson<RefreshSession>,
+    _api_key: ApiKey,
+) -> ApiResult<Message> {
+    let claims = token::verify(&refresh.refresh_token, token::TokenKind::Refresh).map_err(|e| {
+        warn!("Rejected refresh token: {}", e);
+        ApiError::Unauthorized("Invalid refresh token".to_string())
+    })?;
+
+    db.run(move |conn| RefreshTokenInteractor::revoke_family_of(conn, &claims.jti))
+        .await??;
+    Ok(Json(Message::ok("Signed out")))
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct CurrentUserView {
+    #[serde(flatten)]
+    pub user: SessionUser,
+    pub permissions: token::PermissionClaims,
+}
+
+/// Get the person behind the current access token
+#[openapi(tag = "Authentication")]
+#[get("/api/auth/me")]
+pub async fn me(user: CurrentUser, _api_key: ApiKey) -> Json<CurrentUserView> {
+    Json(CurrentUserView {
+        permissions: user
+            .permissions
+            .as_ref()
+            .map(token::PermissionClaims::from)
+            .unwrap_or_default(),
+        user: SessionUser {
+            id: user.person.id,
+            name: user.person.name,
+            email: user.person.email,
+            role: user.person.role,
+        },
+    })
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct SessionUser {
+    pub id: String,
+    pub name: String,
+    pub email: String,
+    pub role: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct SessionResponse {
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    pub user: SessionUser,
+    #[serde(flatten)]
+    pub tokens: token::SessionTokens,
+}
+
+/// Issue session tokens for a person that has just been authenticated
+pub(crate) fn session_response(
+    conn: &mut db::DbConnection,
+    person: &db::models::Person,
+    message: Option<&str>,
+) -> Result<SessionResponse, ApiError> {
+    if let Err(e) = RefreshTokenInteractor::delete_expired(conn) {
+        warn!("Failed to delete expired refresh tokens: {}", e);
     }
-    RawJson("{\"status\":\"error\",\"message\":\"Invalid Email\"}".into())
+    issue_tokens(conn, person, None, message)
+}
+
+/// Issue session tokens, with a refresh token rotated from `family` or starting a new one
+fn issue_tokens(
+    conn: &mut db::DbConnection,
+    person: &db::models::Person,
+    family: Option<&str>,
+    message: Option<&str>,
+) -> Result<SessionResponse, ApiError> {
+    let permissions =
+        db::interactions::permissions::PermissionsInteractor::get_by_p_id(conn, &person.id)
+            .ok()
+            .and_then(|mut permissions| permissions.pop());
+
+    let refresh = token::new_refresh_token(&person.id, family);
+    RefreshTokenInteractor::create(conn, &refresh)?;
+    let tokens = token::issue_session(person, permissions.as_ref(), &refresh)
+        .map_err(|e| ApiError::Internal(format!("Failed to issue session tokens: {}", e)))?;
+
+    Ok(SessionResponse {
+        status: "ok".to_string(),
+        message: message.map(|m| m.to_string()),
+        user: SessionUser {
+            id: person.id.clone(),
+            name: person.name.clone(),
+            email: person.email.clone(),
+            role: person.role.clone(),
+        },
+        tokens,
+    })
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -42,70 +211,51 @@ pub struct Register {
     pub surname: String,
     pub email: String,
     pub password: Option<String>,
-    pub google_id: Option<String>,
 }
 
 #[openapi(tag = "Authentication")]
 #[post("/api/auth/register", format = "json", data = "<register>")]
 pub async fn register(
     db: &State<Database>,
-    register: Json<Register>,
+    events: &State<EventBus>,
+    webhooks: &State<WebhookDispatcher>,
+    register: SignedJson<Register>,
     _api_key: ApiKey,
-) -> RawJson<String> {
-    let conn = &mut db::establish_connection(&db.db_url);
-
-    // If there's a Google ID, check if a user with this Google ID already exists
-    if let Some(g_id) = &register.google_id {
-        if db::interactions::person::PersonInteractor::get_by_google_id(conn, g_id).is_ok() {
-            return RawJson(
-                "{\"status\":\"error\",\"message\":\"Google account already registered\"}".into(),
-            );
-        }
-    }
-
-    // Check if user with this email already exists
-    if let Ok(existing_user) =
-        db::interactions::person::PersonInteractor::get_by_email(conn, &register.email)
-    {
-        // If a Google ID is provided and the existing user doesn't have one, update the user
-        if let Some(_g_id) = &register.google_id {
-            if existing_user.google_id.is_none() {
-                // Here we could implement a way to update the user's Google ID
-                // But for now, just return an informative message
-                return RawJson("{\"status\":\"ok\",\"message\":\"User already exists but does not have a Google ID.\"}".into());
+) -> ApiResult<Message> {
+    let registered = db
+        .run(move |conn| {
+            // Check if user with this email already exists
+            if db::interactions::person::PersonInteractor::get_by_email(conn, &register.email)
+                .is_ok()
+            {
+                return Err(ApiError::Conflict("Email already registered".to_string()));
             }
-        }
-        return RawJson("{\"status\":\"error\",\"message\":\"Email already registered\"}".into());
-    }
-
-    // Create the new person
-    let password_hash = register.password.as_ref().map(|p| db::crypto::to_hash(p));
-
-    let person = db::models::Person::new(
-        &register.name,
-        &register.surname,
-        &register.email,
-        db::models::Role::Alumno,
-        password_hash.as_deref(),
-        register.google_id.as_deref(),
-    );
-
-    // Insert the new person
-    if let Err(e) = db::interactions::person::PersonInteractor::new(conn, &person) {
-        return RawJson(format!(
-            "{{\"status\":\"error\",\"message\":\"Failed to create user: {e}\"}}"
-        ));
-    }
 
-    let permissions = db::models::Permissions::new(&person.id, true, false, true, false, false);
+            // Create the new person
+            let password_hash = register.password.as_ref().map(|p| db::crypto::to_hash(p));
 
-    if let Err(e) = db::interactions::permissions::PermissionsInteractor::new(conn, &permissions) {
-        return RawJson(format!(
-            "{{\"status\":\"error\",\"message\":\"Failed to create permissions: {e}\"}}"
-        ));
-    }
+            let person = db::models::Person::new(
+                &register.name,
+                &register.surname,
+                &register.email,
+                db::models::Role::Alumno,
+                password_hash.as_deref(),
+            );
 
-    RawJson("{\"status\":\"ok\",\"message\":\"User registered successfully\"}".into())
+            // Insert the new person along with its permissions
+            let permissions = db::models::Permissions::for_new_account(&person.id);
+            db::interactions::person::PersonInteractor::create_with_permissions(
+                conn,
+                &person,
+                &permissions,
+            )?;
+
+            Ok::<_, ApiError>(PersonView::from(person))
+        })
+        .await??;
+    events.person_changed("created", &registered.id, Some(&registered));
+    webhooks.dispatch(webhooks::PERSON_REGISTERED, &registered);
+    Ok(Json(Message::ok("User registered successfully")))
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -115,46 +265,38 @@ pub struct ChangePassword {
     pub new_password: String,
 }
 
+/// Change the password of an account, throttled like `/api/auth/login`
 #[openapi(tag = "Authentication")]
 #[post("/api/auth/change-password", format = "json", data = "<change_pw>")]
 pub async fn change_password(
     db: &State<Database>,
-    change_pw: Json<ChangePassword>,
+    change_pw: SignedJson<ChangePassword>,
+    ip: ClientIp,
     _api_key: ApiKey,
-) -> RawJson<String> {
-    let conn = &mut db::establish_connection(&db.db_url);
-
-    // Check if the user exists
-    if let Ok(mut person) =
-        db::interactions::person::PersonInteractor::get_by_email(conn, &change_pw.email)
-    {
-        // Verify old password
-        if let Some(password_hash) = &person.password_hash {
-            if !db::crypto::check_hash(&change_pw.old_password, password_hash) {
-                return RawJson(
-                    "{\"status\":\"error\",\"message\":\"Current password is incorrect\"}".into(),
-                );
-            }
-        } else {
-            return RawJson(
-                "{\"status\":\"error\",\"message\":\"No password set for this account\"}".into(),
-            );
-        }
+) -> ApiResult<Message> {
+    db.run(move |conn| {
+        let attempt = Attempt::start(conn, &change_pw.email, ip.0)?;
+
+        // Verify old password, answering the same for unknown emails and accounts without one
+        let person =
+            db::interactions::person::PersonInteractor::get_by_email(conn, &change_pw.email).ok();
+        let password_hash = person.as_ref().and_then(|p| p.password_hash.as_deref());
+        let valid = db::crypto::check_password(&change_pw.old_password, password_hash);
+        let Some(mut person) = person.filter(|_| valid) else {
+            return Err(attempt.failed(
+                conn,
+                ApiError::Unauthorized(INVALID_CREDENTIALS.to_string()),
+            ));
+        };
+        attempt.succeeded(conn)?;
 
         // Update with new password
         person.password_hash = Some(db::crypto::to_hash(&change_pw.new_password));
-        if db::interactions::person::PersonInteractor::update(conn, &person.id, &person).is_ok() {
-            return RawJson(
-                "{\"status\":\"ok\",\"message\":\"Password changed successfully\"}".into(),
-            );
-        } else {
-            return RawJson(
-                "{\"status\":\"error\",\"message\":\"Failed to update password\"}".into(),
-            );
-        }
-    }
-
-    RawJson("{\"status\":\"error\",\"message\":\"User not found\"}".into())
+        db::interactions::person::PersonInteractor::update(conn, &person.id, &person, None)?;
+        Ok(())
+    })
+    .await??;
+    Ok(Json(Message::ok("Password changed successfully")))
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -181,213 +323,131 @@ pub struct PasswordReset {
 )]
 pub async fn forgot_password(
     db: &State<Database>,
-    password_reset_req: Json<PasswordResetRequest>,
+    password_reset_req: SignedJson<PasswordResetRequest>,
     _api_key: ApiKey,
-) -> RawJson<String> {
-    let conn = &mut db::establish_connection(&db.db_url);
-
-    // Check if the user exists
-    if let Ok(person) =
-        db::interactions::person::PersonInteractor::get_by_email(conn, &password_reset_req.email)
-    {
-        // Create a new password reset token
-        match db::interactions::password_reset::PasswordResetTokenInteractor::create(
-            conn,
-            &person.email,
-        ) {
-            Ok(token) => {
-                // Clean up expired tokens
-                let _ =
-                    db::interactions::password_reset::PasswordResetTokenInteractor::delete_expired(
-                        conn,
-                    );
-
-                // Send an email with the reset token
-                match crate::email::send_password_reset_email(&person.email, &token.token).await {
-                    Ok(_) => {
-                        return RawJson(
-                            "{\"status\":\"ok\",\"message\":\"Password reset email sent\"}".into(),
-                        );
-                    }
-                    Err(e) => {
-                        error!("Failed to send password reset email: {}", e);
-                        return RawJson(
-                            "{\"status\":\"error\",\"message\":\"Failed to send email\"}".into(),
-                        );
-                    }
-                }
-            }
-            Err(e) => {
-                error!("Failed to create password reset token: {}", e);
-                return RawJson(
-                    "{\"status\":\"error\",\"message\":\"Internal server error\"}".into(),
-                );
-            }
-        }
+) -> ApiResult<Message> {
+    // Check if the user exists and create a new password reset token
+    let token = db
+        .run(move |conn| {
+            let person = db::interactions::person::PersonInteractor::get_by_email(
+                conn,
+                &password_reset_req.email,
+            )
+            .ok()?;
+            let token = db::interactions::password_reset::PasswordResetTokenInteractor::create(
+                conn,
+                &person.email,
+            );
+            // Clean up expired tokens
+            let _ = db::interactions::password_reset::PasswordResetTokenInteractor::delete_expired(
+                conn,
+            );
+            Some((person.email, token))
+        })
+        .await?;
+
+    // Answer the same even if the email doesn't exist to prevent email enumeration
+    if let Some((email, token)) = token {
+        let token = token.map_err(|e| {
+            ApiError::Internal(format!("Failed to create password reset token: {}", e))
+        })?;
+        // Send an email with the reset token
+        crate::email::send_password_reset_email(&email, &token.token)
+            .await
+            .map_err(|e| {
+                ApiError::Internal(format!("Failed to send password reset email: {}", e))
+            })?;
     }
+    Ok(Json(Message::ok("Password reset email sent")))
+}
 
-    // Return a success message even if the email doesn't exist to prevent email enumeration
-    RawJson("{\"status\":\"ok\",\"message\":\"Password reset email sent\"}".into())
+#[derive(Serialize, JsonSchema)]
+pub struct ResetTokenStatus {
+    pub valid: bool,
+    /// Why the token is not valid
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
 }
 
 #[openapi(tag = "Authentication")]
 #[post("/api/auth/verify-reset-token", format = "json", data = "<verify>")]
 pub async fn verify_reset_token(
     db: &State<Database>,
-    verify: Json<PasswordResetVerify>,
+    verify: SignedJson<PasswordResetVerify>,
     _api_key: ApiKey,
-) -> RawJson<String> {
-    let conn = &mut db::establish_connection(&db.db_url);
-
-    // Find the token
-    match db::interactions::password_reset::PasswordResetTokenInteractor::find_by_token(
-        conn,
-        &verify.token,
-    ) {
-        Ok(token) => {
-            if token.is_valid() {
-                RawJson("{\"status\":\"ok\",\"valid\":true}".into())
-            } else {
-                // Delete expired token
-                let _ =
+) -> ApiResult<ResetTokenStatus> {
+    let status = db
+        .run(move |conn| {
+            // Find the token
+            let invalid = |message: &str| ResetTokenStatus {
+                valid: false,
+                message: Some(message.to_string()),
+            };
+            match db::interactions::password_reset::PasswordResetTokenInteractor::find_by_token(
+                conn,
+                &verify.token,
+            ) {
+                Ok(token) if token.is_valid() => ResetTokenStatus {
+                    valid: true,
+                    message: None,
+                },
+                Ok(_) => {
+                    // Delete expired token
+                    let _ =
                     db::interactions::password_reset::PasswordResetTokenInteractor::delete_by_token(
                         conn,
                         &verify.token,
                     );
-                RawJson("{\"status\":\"ok\",\"valid\":false,\"message\":\"Token expired\"}".into())
-            }
-        }
-        Err(_) => {
-            RawJson("{\"status\":\"ok\",\"valid\":false,\"message\":\"Invalid token\"}".into())
-        }
-    }
-}
-
-#[openapi(tag = "Authentication")]
-#[post("/api/auth/reset-password", format = "json", data = "<reset>")]
-pub async fn reset_password(
-    db: &State<Database>,
-    reset: Json<PasswordReset>,
-    _api_key: ApiKey,
-) -> RawJson<String> {
-    let conn = &mut db::establish_connection(&db.db_url);
-
-    // Find the token and verify it
-    match db::interactions::password_reset::PasswordResetTokenInteractor::find_by_token(
-        conn,
-        &reset.token,
-    ) {
-        Ok(token) => {
-            if !token.is_valid() {
-                // Delete expired token
-                let _ =
-                    db::interactions::password_reset::PasswordResetTokenInteractor::delete_by_token(
-                        conn,
-                        &reset.token,
-                    );
-                return RawJson("{\"status\":\"error\",\"message\":\"Token expired\"}".into());
-            }
-
-            // Find the user associated with the token
-            match db::interactions::person::PersonInteractor::get_by_email(conn, &token.email) {
-                Ok(mut person) => {
-                    // Update the password
-                    person.password_hash = Some(db::crypto::to_hash(&reset.new_password));
-
-                    // Save the updated user
-                    match db::interactions::person::PersonInteractor::update(
-                        conn, &person.id, &person,
-                    ) {
-                        Ok(_) => {
-                            // Delete the used token
-                            let _ = db::interactions::password_reset::PasswordResetTokenInteractor::delete_by_token(
-                                conn,
-                                &reset.token,
-                            );
-                            RawJson(
-                                "{\"status\":\"ok\",\"message\":\"Password reset successfully\"}"
-                                    .into(),
-                            )
-                        }
-                        Err(e) => {
-                            error!("Failed to update user: {}", e);
-                            RawJson(
-                                "{\"status\":\"error\",\"message\":\"Failed to reset password\"}"
-                                    .into(),
-                            )
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!("Failed to find user: {}", e);
-                    RawJson("{\"status\":\"error\",\"message\":\"User not found\"}".into())
+                    invalid("Token expired")
                 }
+                Err(_) => invalid("Invalid token"),
             }
-        }
-        Err(_) => RawJson("{\"status\":\"error\",\"message\":\"Invalid token\"}".into()),
-    }
+        })
+        .await?;
+    Ok(Json(status))
 }
 
-#[derive(Serialize, Deserialize, JsonSchema)]
-pub struct LinkGoogleAccount {
-    pub email: String,
-    pub google_email: String,
-    pub password: String, // To verify the existing account before linking
+/// Payload of the `password-reset` webhook
+#[derive(Serialize)]
+struct PasswordResetDone {
+    person_id: String,
+    email: String,
 }
 
 #[openapi(tag = "Authentication")]
-#[post("/api/auth/link-google", format = "json", data = "<link_request>")]
-pub async fn link_google_account(
+#[post("/api/auth/reset-password", format = "json", data = "<reset>")]
+pub async fn reset_password(
     db: &State<Database>,
-    link_request: Json<LinkGoogleAccount>,
+    webhooks: &State<WebhookDispatcher>,
+    reset: SignedJson<PasswordReset>,
     _api_key: ApiKey,
-) -> RawJson<String> {
-    let conn = &mut db::establish_connection(&db.db_url);
-
-    // Check if the user exists
-    if let Ok(mut person) =
-        db::interactions::person::PersonInteractor::get_by_email(conn, &link_request.email)
-    {
-        // Verify password
-        if let Some(password_hash) = &person.password_hash {
-            if !db::crypto::check_hash(&link_request.password, password_hash) {
-                return RawJson(
-                    "{\"status\":\"error\",\"message\":\"Password is incorrect\"}".into(),
-                );
-            }
-        } else {
-            return RawJson(
-                "{\"status\":\"error\",\"message\":\"This account has no password set\"}".into(),
+) -> ApiResult<Message> {
+    let outcome = db
+        .run(move |conn| {
+            let password_hash = db::crypto::to_hash(&reset.new_password);
+            db::interactions::password_reset::PasswordResetTokenInteractor::reset_password(
+                conn,
+                &reset.token,
+                &password_hash,
+            )
+        })
+        .await??;
+    match outcome {
+        ResetOutcome::Done { person_id, email } => {
+            webhooks.dispatch(
+                webhooks::PASSWORD_RESET,
+                &PasswordResetDone { person_id, email },
             );
         }
-
-        // Check if Google account already exists
-        if db::interactions::person::PersonInteractor::get_by_email(
-            conn,
-            &link_request.google_email,
-        )
-        .is_ok()
-        {
-            return RawJson(
-                "{\"status\":\"error\",\"message\":\"This Google account is already linked to another user\"}".into(),
-            );
+        ResetOutcome::InvalidToken => {
+            return Err(ApiError::BadRequest("Invalid token".to_string()));
         }
-
-        // Update email to the Google email
-        // In a production system, you'd want to store both emails and have a proper account linking system
-        // This is a simplified approach
-        person.email = link_request.google_email.clone();
-
-        if db::interactions::person::PersonInteractor::update(conn, &person.id, &person).is_ok() {
-            return RawJson(
-                "{\"status\":\"ok\",\"message\":\"Google account linked successfully\"}".into(),
-            );
-        } else {
-            return RawJson("{\"status\":\"error\",\"message\":\"Failed to link account\"}".into());
+        ResetOutcome::Expired => return Err(ApiError::BadRequest("Token expired".to_string())),
+        ResetOutcome::UnknownPerson => {
+            return Err(ApiError::NotFound("User not found".to_string()));
         }
     }
-
-    RawJson("{\"status\":\"error\",\"message\":\"User not found\"}".into())
+    Ok(Json(Message::ok("Password reset successfully")))
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -400,24 +460,51 @@ pub struct SetPassword {
 #[post("/api/auth/set-password", format = "json", data = "<set_password>")]
 pub async fn set_password(
     db: &State<Database>,
-    set_password: Json<SetPassword>,
+    set_password: SignedJson<SetPassword>,
+    user: CurrentUser,
     _api_key: ApiKey,
-) -> RawJson<String> {
-    let conn = &mut db::establish_connection(&db.db_url);
+) -> ApiResult<Message> {
+    db.run(move |conn| {
+        // Check if the user exists
+        let Ok(mut person) =
+            db::interactions::person::PersonInteractor::get_by_email(conn, &set_password.email)
+        else {
+            user.require(Permission::AdminPanel)?;
+            return Err(ApiError::NotFound("User not found".to_string()));
+        };
+        user.require_self_or(&person.id, &[Permission::AdminPanel])?;
 
-    // Check if the user exists
-    if let Ok(mut person) =
-        db::interactions::person::PersonInteractor::get_by_email(conn, &set_password.email)
-    {
         // Update with new password
         person.password_hash = Some(db::crypto::to_hash(&set_password.new_password));
+        db::interactions::person::PersonInteractor::update(conn, &person.id, &person, None)?;
+        Ok(())
+    })
+    .await??;
+    Ok(Json(Message::ok("Password set successfully")))
+}
 
-        if db::interactions::person::PersonInteractor::update(conn, &person.id, &person).is_ok() {
-            return RawJson("{\"status\":\"ok\",\"message\":\"Password set successfully\"}".into());
-        } else {
-            return RawJson("{\"status\":\"error\",\"message\":\"Failed to set password\"}".into());
-        }
+/// Lift the lockout of an account after too many failed sign-ins
+#[openapi(tag = "Authentication")]
+#[delete("/api/person/<person_id>/lockout")]
+pub async fn unlock_account(
+    db: &State<Database>,
+    person_id: String,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> ApiResult<Message> {
+    user.require(Permission::AdminPanel)?;
+
+    let unlocked = db
+        .run(move |conn| {
+            let person = db::interactions::person::PersonInteractor::get_by_id(conn, &person_id)
+                .map_err(|_| ApiError::not_found("Person"))?;
+            throttle::unlock(conn, &person.email)
+        })
+        .await??;
+    if !unlocked {
+        return Err(ApiError::NotFound(
+            "This account has no failed sign-ins".to_string(),
+        ));
     }
-
-    RawJson("{\"status\":\"error\",\"message\":\"User not found\"}".into())
+    Ok(Json(Message::ok("Account unlocked")))
 }
@@ -1,27 +1,92 @@
-use db::establish_connection;
-use db::interactions::entries::{Action, EntriesInteractor};
-use db::models::Entry;
+use chrono::{NaiveDate, NaiveDateTime};
+use db::date::{self, DateRange, parse_iso_date};
+use db::interactions::entries::{
+    Action, EntriesInteractor, EntryFilter, SequencePolicy, SequencedEntry, SequencedUpdate,
+};
+use db::models::{Entry, EntryChanges};
+use db::pagination::Page;
+use log::warn;
+use once_cell::sync::Lazy;
 use rocket::serde::json::Json;
-use rocket::{State, response::content::RawJson};
-use rocket::{delete, get, post, put};
+use rocket::{FromForm, State, delete, get, patch, post, put};
 use rocket_okapi::openapi;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::env::var;
 
-use crate::auth::guard::ApiKey;
-use crate::models::Database;
+use crate::auth::access::Permission;
+use crate::auth::guard::{ApiKey, CurrentUser};
+use crate::auth::signed::SignedJson;
+use crate::error::{ApiError, ApiResult};
+use crate::etag::{IfMatch, Tagged, TaggedResult, update_miss};
+use crate::events::{EventBus, EventKind};
+use crate::models::{Database, Message, nullable, required};
+use crate::routes::{parse_page, parse_range};
+use crate::webhooks::{self, WebhookDispatcher};
 
-/// Get all entries
+/// What `create_entry` does with an entry repeating the person's last action, from
+/// `SYN_ENTRY_SEQUENCE_POLICY` (`reject`, `autocorrect` or `flag`)
+static SEQUENCE_POLICY: Lazy<SequencePolicy> =
+    Lazy::new(|| match var("SYN_ENTRY_SEQUENCE_POLICY") {
+        Ok(policy) => policy.parse().unwrap_or_else(|e| {
+            warn!("{}, rejecting out of sequence entries", e);
+            SequencePolicy::Reject
+        }),
+        Err(_) => SequencePolicy::default(),
+    });
+
+#[derive(FromForm, JsonSchema)]
+pub struct EntryQuery {
+    /// `YYYY-MM-DD`, a day in `SYN_TIMEZONE`, or `YYYY-MM-DDTHH:MM:SS` in UTC
+    from: Option<String>,
+    /// `YYYY-MM-DD` or `YYYY-MM-DDTHH:MM:SS` like `from`; a bare date includes that whole day
+    to: Option<String>,
+    person_id: Option<String>,
+    /// `Enter` or `Exit`
+    action: Option<String>,
+    /// Only the entries flagged for review, or only the unflagged ones
+    flagged: Option<bool>,
+    /// Page size, 1 to 500 (default 50)
+    limit: Option<i64>,
+    offset: Option<i64>,
+    /// `next_cursor` of the previous page, takes precedence over `offset`
+    cursor: Option<String>,
+    /// `instant` (default), `action` or `person_id`; prefix with `-` for descending order
+    sort: Option<String>,
+}
+
+/// Get a page of entries, optionally filtered
 #[openapi(tag = "Entries")]
-#[get("/api/entry")]
-pub async fn get_entries(db: &State<Database>, _api_key: ApiKey) -> RawJson<String> {
-    let conn = &mut establish_connection(&db.db_url);
-    match EntriesInteractor::get(conn) {
-        Ok(entries) => RawJson(serde_json::to_string(&entries).unwrap()),
-        Err(_) => RawJson(
-            "{\"status\": \"error\", \"message\": \"Failed to retrieve entries\"}".to_string(),
-        ),
+#[get("/api/entry?<query..>")]
+pub async fn get_entries(
+    db: &State<Database>,
+    query: EntryQuery,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> ApiResult<Page<Entry>> {
+    match &query.person_id {
+        Some(person_id) => user.require_history_of(person_id)?,
+        None => user.require(Permission::SeeOthersHistory)?,
     }
+
+    let range = parse_range(query.from, query.to)?;
+    let action = query
+        .action
+        .map(|action| parse_action(&action))
+        .transpose()?;
+    let filter = EntryFilter {
+        range,
+        person_id: query.person_id,
+        action,
+        flagged: query.flagged,
+    };
+    let paging = EntryPageQuery {
+        limit: query.limit,
+        offset: query.offset,
+        cursor: query.cursor,
+        sort: query.sort,
+    };
+    entry_page(db, filter, paging).await
 }
 
 /// Get a single entry by ID
@@ -30,104 +95,139 @@ pub async fn get_entries(db: &State<Database>, _api_key: ApiKey) -> RawJson<Stri
 pub async fn get_entry(
     db: &State<Database>,
     entry_id: String,
+    user: CurrentUser,
     _api_key: ApiKey,
-) -> RawJson<String> {
-    let conn = &mut establish_connection(&db.db_url);
-    match EntriesInteractor::get_by_id(conn, &entry_id) {
-        Ok(entry) => RawJson(serde_json::to_string(&entry).unwrap()),
-        Err(_) => RawJson("{\"status\": \"error\", \"message\": \"Entry not found\"}".to_string()),
-    }
+) -> TaggedResult<Entry> {
+    let entry = db
+        .run(move |conn| EntriesInteractor::get_by_id(conn, &entry_id))
+        .await?
+        .map_err(|_| ApiError::not_found("Entry"))?;
+    user.require_history_of(&entry.person_id)?;
+    Ok(Tagged::new(entry.version, entry))
+}
+
+/// Paging of the `/api/entry/by-*` shortcuts for filters of `/api/entry`
+#[derive(FromForm, JsonSchema)]
+pub struct EntryPageQuery {
+    /// Page size, 1 to 500 (default 50)
+    limit: Option<i64>,
+    offset: Option<i64>,
+    /// `next_cursor` of the previous page, takes precedence over `offset`
+    cursor: Option<String>,
+    /// `instant` (default), `action` or `person_id`; prefix with `-` for descending order
+    sort: Option<String>,
+}
+
+async fn entry_page(
+    db: &State<Database>,
+    filter: EntryFilter,
+    paging: EntryPageQuery,
+) -> ApiResult<Page<Entry>> {
+    let (page, sort) = parse_page(
+        paging.limit,
+        paging.offset,
+        paging.cursor.as_deref(),
+        paging.sort.as_deref(),
+    )?;
+    let entries = db
+        .run(move |conn| EntriesInteractor::page(conn, &filter, sort, &page))
+        .await??;
+    Ok(Json(entries))
 }
 
-/// Get a single entry by person ID
+/// Get a page of the entries of a person, like `/api/entry?person_id=`
 #[openapi(tag = "Entries")]
-#[get("/api/entry/by-person/<person_id>")]
+#[get("/api/entry/by-person/<person_id>?<paging..>")]
 pub async fn get_entry_by_person_id(
     db: &State<Database>,
     person_id: String,
+    paging: EntryPageQuery,
+    user: CurrentUser,
     _api_key: ApiKey,
-) -> RawJson<String> {
-    let conn = &mut establish_connection(&db.db_url);
-    match EntriesInteractor::get_by_p_id(conn, &person_id) {
-        Ok(entry) => RawJson(serde_json::to_string(&entry).unwrap()),
-        Err(_) => RawJson(
-            "{\"status\": \"error\", \"message\": \"No entries found for this person\"}"
-                .to_string(),
-        ),
-    }
+) -> ApiResult<Page<Entry>> {
+    user.require_history_of(&person_id)?;
+
+    let filter = EntryFilter {
+        person_id: Some(person_id),
+        ..Default::default()
+    };
+    entry_page(db, filter, paging).await
 }
 
-/// Get a single entry by date
+/// Get a page of the entries of a single day (`YYYY-MM-DD` in `SYN_TIMEZONE`), like `/api/entry?from=&to=`
 #[openapi(tag = "Entries")]
-#[get("/api/entry/by-date/<date>")]
+#[get("/api/entry/by-date/<date>?<paging..>")]
 pub async fn get_entry_by_date(
     db: &State<Database>,
     date: String,
+    paging: EntryPageQuery,
+    user: CurrentUser,
     _api_key: ApiKey,
-) -> RawJson<String> {
-    let conn = &mut establish_connection(&db.db_url);
-    match EntriesInteractor::get_by_date(conn, &date) {
-        Ok(entries) => RawJson(serde_json::to_string(&entries).unwrap()),
-        Err(_) => RawJson(
-            "{\"status\": \"error\", \"message\": \"No entries found for this date\"}".to_string(),
-        ),
-    }
+) -> ApiResult<Page<Entry>> {
+    user.require(Permission::SeeOthersHistory)?;
+
+    let filter = EntryFilter::range(DateRange::day(parse_day(&date)?, date::timezone()));
+    entry_page(db, filter, paging).await
 }
 
-/// Get a single entry by date and person ID
+/// Get a page of the entries of a person on a single day (`YYYY-MM-DD` in `SYN_TIMEZONE`)
 #[openapi(tag = "Entries")]
-#[get("/api/entry/by-date/<date>/<person_id>")]
+#[get("/api/entry/by-date/<date>/<person_id>?<paging..>")]
 pub async fn get_entry_by_date_and_person_id(
     db: &State<Database>,
     date: String,
     person_id: String,
+    paging: EntryPageQuery,
+    user: CurrentUser,
     _api_key: ApiKey,
-) -> RawJson<String> {
-    let conn = &mut establish_connection(&db.db_url);
-    match EntriesInteractor::get_by_date_and_p_id(conn, &date, &person_id) {
-        Ok(entries) => RawJson(serde_json::to_string(&entries).unwrap()),
-        Err(_) => RawJson(
-            "{\"status\": \"error\", \"message\": \"No entries found for this date and person\"}"
-                .to_string(),
-        ),
-    }
+) -> ApiResult<Page<Entry>> {
+    user.require_history_of(&person_id)?;
+
+    let filter = EntryFilter {
+        person_id: Some(person_id),
+        ..EntryFilter::range(DateRange::day(parse_day(&date)?, date::timezone()))
+    };
+    entry_page(db, filter, paging).await
 }
 
-/// Get a single entry by action
+/// Get a page of the entries with an action, like `/api/entry?action=`
 #[openapi(tag = "Entries")]
-#[get("/api/entry/by-action/<action>")]
+#[get("/api/entry/by-action/<action>?<paging..>")]
 pub async fn get_entry_by_action(
     db: &State<Database>,
     action: String,
+    paging: EntryPageQuery,
+    user: CurrentUser,
     _api_key: ApiKey,
-) -> RawJson<String> {
-    let conn = &mut establish_connection(&db.db_url);
-    match EntriesInteractor::get_by_action(conn, &action) {
-        Ok(entries) => RawJson(serde_json::to_string(&entries).unwrap()),
-        Err(_) => RawJson(
-            "{\"status\": \"error\", \"message\": \"No entries found for this action\"}"
-                .to_string(),
-        ),
-    }
+) -> ApiResult<Page<Entry>> {
+    user.require(Permission::SeeOthersHistory)?;
+
+    let filter = EntryFilter {
+        action: Some(parse_action(&action)?),
+        ..Default::default()
+    };
+    entry_page(db, filter, paging).await
 }
 
-/// Get a single entry by action and person ID
+/// Get a page of the entries of a person with an action
 #[openapi(tag = "Entries")]
-#[get("/api/entry/by-action/<action>/<person_id>")]
+#[get("/api/entry/by-action/<action>/<person_id>?<paging..>")]
 pub async fn get_entry_by_action_and_person_id(
     db: &State<Database>,
     action: String,
     person_id: String,
+    paging: EntryPageQuery,
+    user: CurrentUser,
     _api_key: ApiKey,
-) -> RawJson<String> {
-    let conn = &mut establish_connection(&db.db_url);
-    match EntriesInteractor::get_by_action_and_p_id(conn, &action, &person_id) {
-        Ok(entries) => RawJson(serde_json::to_string(&entries).unwrap()),
-        Err(_) => RawJson(
-            "{\"status\": \"error\", \"message\": \"No entries found for this action and person\"}"
-                .to_string(),
-        ),
-    }
+) -> ApiResult<Page<Entry>> {
+    user.require_history_of(&person_id)?;
+
+    let filter = EntryFilter {
+        person_id: Some(person_id),
+        action: Some(parse_action(&action)?),
+        ..Default::default()
+    };
+    entry_page(db, filter, paging).await
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -137,50 +237,171 @@ pub struct APIEntry {
 }
 
 /// Create a new entry
+///
+/// Needs a user token, unless the request is signed by a named API client with `entries:write`.
+/// An entry repeating the person's last action is rejected with 409, corrected or flagged
+/// depending on `SYN_ENTRY_SEQUENCE_POLICY`.
 #[openapi(tag = "Entries")]
 #[post("/api/entry", format = "json", data = "<entry>")]
 pub async fn create_entry(
     db: &State<Database>,
-    entry: Json<APIEntry>,
-    _api_key: ApiKey,
-) -> RawJson<String> {
-    let action = match entry.action.to_lowercase().as_str() {
-        "entrada" | "enter" => Action::Enter,
-        "salida" | "exit" => Action::Exit,
-        _ => {
-            return RawJson(
-                "{\"status\": \"error\", \"message\": \"Invalid  Action\"}".to_string(),
-            );
+    events: &State<EventBus>,
+    webhooks: &State<WebhookDispatcher>,
+    entry: SignedJson<APIEntry>,
+    user: Option<CurrentUser>,
+    api_key: ApiKey,
+) -> TaggedResult<Entry> {
+    match user {
+        Some(user) => user.require_self_or(&entry.person_id, &[Permission::AdminPanel])?,
+        // Kiosks record entries for anyone; their client already passed the entries:write scope
+        None if api_key.client.is_some() => {}
+        None => return Err(ApiError::Unauthorized("Missing session token".to_string())),
+    }
+
+    let action = parse_action(&entry.action)?;
+    let entry = Entry::new(&entry.person_id, action);
+    let person_id = entry.person_id.clone();
+    let outcome = db
+        .run(move |conn| EntriesInteractor::create_in_sequence(conn, entry, *SEQUENCE_POLICY))
+        .await??;
+    let (created, correction) = match outcome {
+        SequencedEntry::Created { entry, correction } => (entry, correction),
+        SequencedEntry::OutOfSequence { last: Some(last) } => {
+            return Err(ApiError::Conflict(format!(
+                "Out of sequence: the last entry of this person is an {} at {}",
+                last.action, last.instant
+            )));
+        }
+        SequencedEntry::OutOfSequence { last: None } => {
+            return Err(ApiError::Conflict(
+                "Out of sequence: the first entry of a person must be an Enter".to_string(),
+            ));
+        }
+        SequencedEntry::UnknownPerson => {
+            return Err(ApiError::Unprocessable(format!(
+                "Unknown person '{}'",
+                person_id
+            )));
         }
     };
-    let entry = Entry::new(&entry.person_id, action);
-    let conn = &mut establish_connection(&db.db_url);
-    match EntriesInteractor::new(conn, &entry) {
-        Ok(new_entry) => RawJson(serde_json::to_string(&new_entry).unwrap()),
-        Err(e) => RawJson(format!(
-            "{{\"status\": \"error\", \"message\": \"Failed to create entry: {}\"}}",
-            e
-        )),
+    for entry in correction.iter().chain([&created]) {
+        events.publish(EventKind::EntryCreated, &entry.person_id, entry);
+        webhooks.dispatch(webhooks::ENTRY_CREATED, entry);
     }
+    Ok(Tagged::new(created.version, created))
+}
+
+/// Body of `PUT /api/entry/<id>`; the id and version come from the path and `If-Match`
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateEntry {
+    person_id: String,
+    /// `YYYY-MM-DDTHH:MM:SS`, optionally with fractional seconds
+    instant: NaiveDateTime,
+    action: Action,
+    #[serde(default)]
+    flagged: bool,
 }
 
 /// Update an existing entry
+///
+/// Send the `ETag` of the version being edited as `If-Match` to get a 412 instead of
+/// overwriting someone else's changes. Answers 409 if the change would put the history of
+/// the person out of sequence.
 #[openapi(tag = "Entries")]
 #[put("/api/entry/<entry_id>", format = "json", data = "<entry>")]
 pub async fn update_entry(
     db: &State<Database>,
+    events: &State<EventBus>,
     entry_id: String,
-    entry: Json<Entry>,
+    entry: SignedJson<UpdateEntry>,
+    if_match: IfMatch,
+    user: CurrentUser,
     _api_key: ApiKey,
-) -> RawJson<String> {
-    let conn = &mut establish_connection(&db.db_url);
-    match EntriesInteractor::update(conn, &entry_id, &entry) {
-        Ok(updated_entry) => RawJson(serde_json::to_string(&updated_entry).unwrap()),
-        Err(_) => RawJson(
-            "{\"status\": \"error\", \"message\": \"Entry not found or update failed\"}"
-                .to_string(),
-        ),
-    }
+) -> TaggedResult<Entry> {
+    user.require(Permission::AdminPanel)?;
+
+    let entry = entry.0;
+    let changes = EntryChanges {
+        person_id: Some(entry.person_id),
+        instant: Some(entry.instant),
+        action: Some(entry.action.to_string()),
+        flagged: Some(entry.flagged),
+    };
+    let updated = update_in_sequence(db, entry_id, changes, if_match).await?;
+    events.publish(EventKind::EntryUpdated, &updated.person_id, &updated);
+    Ok(Tagged::new(updated.version, updated))
+}
+
+/// Body of `PATCH /api/entry/<id>`, a JSON merge patch: only the fields present are changed
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct PatchEntry {
+    #[serde(default, deserialize_with = "nullable")]
+    person_id: Option<Option<String>>,
+    /// `YYYY-MM-DDTHH:MM:SS`, optionally with fractional seconds
+    #[serde(default, deserialize_with = "nullable")]
+    instant: Option<Option<NaiveDateTime>>,
+    #[serde(default, deserialize_with = "nullable")]
+    action: Option<Option<Action>>,
+    /// Clear it once the entry has been reviewed; `null` clears it too
+    #[serde(default, deserialize_with = "nullable")]
+    flagged: Option<Option<bool>>,
+}
+
+/// Change some fields of an entry, leaving the rest as they are
+///
+/// Honors `If-Match` and the entry sequence like the PUT route. Only `flagged` can be
+/// removed with `null`.
+#[openapi(tag = "Entries")]
+#[patch("/api/entry/<entry_id>", format = "json", data = "<entry>")]
+pub async fn patch_entry(
+    db: &State<Database>,
+    events: &State<EventBus>,
+    entry_id: String,
+    entry: SignedJson<PatchEntry>,
+    if_match: IfMatch,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> TaggedResult<Entry> {
+    user.require(Permission::AdminPanel)?;
+
+    let entry = entry.0;
+    let changes = EntryChanges {
+        person_id: required(entry.person_id, "person_id")?,
+        instant: required(entry.instant, "instant")?,
+        action: required(entry.action, "action")?.map(|action| action.to_string()),
+        flagged: entry.flagged.map(|flagged| flagged.unwrap_or(false)),
+    };
+    let updated = update_in_sequence(db, entry_id, changes, if_match).await?;
+    events.publish(EventKind::EntryUpdated, &updated.person_id, &updated);
+    Ok(Tagged::new(updated.version, updated))
+}
+
+async fn update_in_sequence(
+    db: &Database,
+    entry_id: String,
+    changes: EntryChanges,
+    if_match: IfMatch,
+) -> Result<Entry, ApiError> {
+    let expected = if_match.versions()?;
+    db.run(move |conn| {
+        match EntriesInteractor::update_in_sequence(conn, &entry_id, &changes, expected.as_deref())?
+        {
+            SequencedUpdate::Updated(entry) => Ok(entry),
+            SequencedUpdate::Missed { exists } => Err(update_miss(exists, "Entry")),
+            SequencedUpdate::UnknownPerson => Err(ApiError::Unprocessable(format!(
+                "Unknown person '{}'",
+                changes.person_id.unwrap_or_default()
+            ))),
+            SequencedUpdate::OutOfSequence => Err(ApiError::Conflict(
+                "Out of sequence: a person's entries must alternate Enter and Exit, starting \
+                 with an Enter"
+                    .to_string(),
+            )),
+        }
+    })
+    .await?
 }
 
 /// Delete an entry
@@ -188,15 +409,36 @@ pub async fn update_entry(
 #[delete("/api/entry/<entry_id>")]
 pub async fn delete_entry(
     db: &State<Database>,
+    events: &State<EventBus>,
     entry_id: String,
+    user: CurrentUser,
     _api_key: ApiKey,
-) -> RawJson<String> {
-    let conn = &mut establish_connection(&db.db_url);
-    match EntriesInteractor::delete(conn, &entry_id) {
-        Ok(deleted_entry) => RawJson(serde_json::to_string(&deleted_entry).unwrap()),
-        Err(_) => RawJson(
-            "{\"status\": \"error\", \"message\": \"Entry not found or delete failed\"}"
-                .to_string(),
-        ),
-    }
+) -> ApiResult<Message> {
+    user.require(Permission::AdminPanel)?;
+
+    let deleted = db
+        .run(move |conn| {
+            // Loaded first so the event can tell whose entry it was
+            let entry = EntriesInteractor::get_by_id(conn, &entry_id)
+                .map_err(|_| ApiError::not_found("Entry"))?;
+            match EntriesInteractor::delete(conn, &entry_id)? {
+                0 => Err(ApiError::not_found("Entry")),
+                _ => Ok(entry),
+            }
+        })
+        .await??;
+    events.publish(EventKind::EntryDeleted, &deleted.person_id, &deleted);
+    Ok(Json(Message::ok("Entry deleted")))
+}
+
+fn parse_day(date: &str) -> Result<NaiveDate, ApiError> {
+    parse_iso_date(date).ok_or_else(|| {
+        ApiError::BadRequest(format!("Invalid date '{}', expected YYYY-MM-DD", date))
+    })
+}
+
+fn parse_action(action: &str) -> Result<Action, ApiError> {
+    action
+        .parse()
+        .map_err(|e| ApiError::BadRequest(format!("{}, expected Enter or Exit", e)))
 }
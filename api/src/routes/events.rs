@@ -0,0 +1,109 @@
+use rocket::futures::stream::BoxStream;
+use rocket::response::stream::{Event, EventStream, stream};
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::tokio::time::{Instant, interval_at, sleep};
+use rocket::{Shutdown, State, get};
+use rocket_okapi::openapi;
+use std::time::Duration;
+
+use crate::auth::guard::{ApiKey, CurrentUser};
+use crate::events::{EventBus, LastEventId, LiveEvent};
+
+fn to_sse(bus: &EventBus, event: &LiveEvent) -> Event {
+    Event::data(event.data.clone())
+        .event(event.kind.name())
+        .id(bus.event_id(event.id))
+}
+
+/// Tells the client that events were missed and it has to reload its data
+fn resync() -> Event {
+    Event::data("{}").event("resync")
+}
+
+/// Live feed of entry and person changes, as Server-Sent Events
+///
+/// Events are `entry-created`, `entry-updated`, `entry-deleted` and `person-changed`, limited
+/// to what the caller may read. Reconnect with the last received id as `Last-Event-ID` to get
+/// the events missed in between, or a `resync` event when they are no longer available.
+/// The stream is closed when the access token expires, or once the person is deleted or
+/// their permissions change; reconnect with a fresh token.
+#[openapi(tag = "Events")]
+#[get("/api/events")]
+pub async fn event_stream(
+    bus: &State<EventBus>,
+    last_event_id: LastEventId,
+    user: CurrentUser,
+    _api_key: ApiKey,
+    mut shutdown: Shutdown,
+) -> EventStream<BoxStream<'_, Event>> {
+    let (mut receiver, mut last_seen) = bus.subscribe();
+    let backlog = last_event_id
+        .0
+        .map(|id| bus.parse_event_id(&id).map(|id| (id, bus.since(id))));
+    let expiry =
+        Duration::from_secs((user.expires_at - chrono::Utc::now().timestamp()).max(0) as u64);
+    let expired = sleep(expiry);
+    let mut recheck = interval_at(Instant::now() + bus.recheck, bus.recheck);
+
+    let stream = stream! {
+        match backlog {
+            Some(Some((id, Some(missed)))) => {
+                last_seen = missed.last().map_or(id, |event| event.id);
+                for event in missed.iter().filter(|event| event.visible_to(&user)) {
+                    yield to_sse(bus, event);
+                }
+            }
+            Some(_) => yield resync(),
+            None => {}
+        }
+
+        rocket::tokio::pin!(expired);
+        loop {
+            let event = select! {
+                received = receiver.recv() => match received {
+                    Ok(event) => event,
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(_)) => {
+                        // Too slow to keep up with the channel, catch up from the history
+                        match bus.since(last_seen) {
+                            Some(missed) => {
+                                for event in &missed {
+                                    if event.visible_to(&user) {
+                                        yield to_sse(bus, event);
+                                    }
+                                }
+                                if let Some(event) = missed.last() {
+                                    last_seen = event.id;
+                                }
+                            }
+                            None => {
+                                yield resync();
+                                last_seen = bus.last_id();
+                            }
+                        }
+                        continue;
+                    }
+                },
+                _ = &mut shutdown => break,
+                _ = &mut expired => break,
+                _ = recheck.tick() => {
+                    if !bus.still_allowed(&user).await {
+                        break;
+                    }
+                    continue;
+                }
+            };
+
+            // Already sent from the history
+            if event.id <= last_seen {
+                continue;
+            }
+            last_seen = event.id;
+            if event.visible_to(&user) {
+                yield to_sse(bus, &event);
+            }
+        }
+    };
+    EventStream::from(Box::pin(stream) as BoxStream<'_, Event>)
+}
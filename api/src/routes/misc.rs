@@ -1,24 +1,54 @@
-use db::establish_connection;
 use db::interactions::person::PersonInteractor;
-use rocket::get;
-use rocket::{State, response::content::RawJson};
+use db::pagination::PageRequest;
+use rocket::serde::json::Json;
+use rocket::{State, get};
 use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::Serialize;
 
 use crate::models::Database;
 
+#[derive(Serialize, JsonSchema)]
+pub struct PoolStatus {
+    connections: u32,
+    idle: u32,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct Health {
+    /// Always `"ok"`, the server answered
+    status: String,
+    /// `"ok"` or the database error
+    db_status: String,
+    pool: PoolStatus,
+}
+
 /// Test api health
 #[openapi(tag = "Health")]
 #[get("/health")]
-pub async fn health_check(db: &State<Database>) -> RawJson<String> {
-    let conn = &mut establish_connection(&db.db_url);
-    let _ = match PersonInteractor::get(conn) {
-        Ok(p) => p,
-        Err(e) => {
-            return RawJson(format!(
-                "{{\"status\": \"ok\", \"db_status\": \"Error: {}\"}}",
-                e.to_string().replace("\"", "'")
-            ));
-        }
+pub async fn health_check(db: &State<Database>) -> Json<Health> {
+    let pool = db.pool.state();
+    let result = db
+        .run(|conn| {
+            PersonInteractor::page(
+                conn,
+                &Default::default(),
+                Default::default(),
+                &PageRequest::new(Some(1), None, None).unwrap(),
+            )
+        })
+        .await;
+    let db_status = match result {
+        Ok(Ok(_)) => "ok".to_string(),
+        Ok(Err(e)) => format!("Error: {}", e),
+        Err(status) => format!("Error: {}", status),
     };
-    RawJson("{\"status\": \"ok\", \"db_status\": \"ok\"}".to_string())
+    Json(Health {
+        status: "ok".to_string(),
+        db_status,
+        pool: PoolStatus {
+            connections: pool.connections,
+            idle: pool.idle_connections,
+        },
+    })
 }
@@ -1,6 +1,39 @@
+pub mod attendance;
 pub mod auth;
 pub mod entries;
-pub mod google_auth;
+pub mod events;
 pub mod misc;
+pub mod oidc;
 pub mod permissions;
 pub mod person;
+pub mod two_factor;
+pub mod webhooks;
+
+use crate::error::ApiError;
+use db::date::{self, DateRange};
+use db::pagination::{PageRequest, Sort};
+use std::str::FromStr;
+
+/// Parses ISO `from`/`to` query parameters, bare dates being days in `SYN_TIMEZONE`
+pub(crate) fn parse_range(from: Option<String>, to: Option<String>) -> Result<DateRange, ApiError> {
+    Ok(DateRange::parse(
+        from.as_deref(),
+        to.as_deref(),
+        date::timezone(),
+    )?)
+}
+
+/// Validates the pagination query parameters shared by all list routes
+pub(crate) fn parse_page<C: FromStr<Err = String> + Default>(
+    limit: Option<i64>,
+    offset: Option<i64>,
+    cursor: Option<&str>,
+    sort: Option<&str>,
+) -> Result<(PageRequest, Sort<C>), ApiError> {
+    let page = PageRequest::new(limit, offset, cursor)?;
+    let sort = match sort {
+        Some(sort) => sort.parse()?,
+        None => Sort::default(),
+    };
+    Ok((page, sort))
+}
@@ -0,0 +1,335 @@
+use crate::auth::access::Permission;
+use crate::auth::guard::{ApiKey, ClientIp, CurrentUser};
+use crate::auth::oidc::{ExternalIdentity, OidcProviders};
+use crate::auth::signed::SignedJson;
+use crate::auth::throttle::{Attempt, INVALID_CREDENTIALS};
+use crate::error::{ApiError, ApiResult};
+use crate::events::EventBus;
+use crate::models::{Database, Message, PersonView};
+use crate::routes::two_factor::{LoginResponse, login_response};
+use crate::webhooks::{self, WebhookDispatcher};
+use db::interactions::identity::{IdentityInteractor, Unlink};
+use db::interactions::person::PersonInteractor;
+use db::models::{Permissions, Person, PersonIdentity, Role};
+use log::warn;
+use rocket::serde::json::Json;
+use rocket::{State, delete, get, post};
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Names of the identity providers that can be used in the `/api/auth/oidc/<provider>` routes
+#[openapi(tag = "Authentication")]
+#[get("/api/auth/oidc/providers")]
+pub async fn get_oidc_providers(
+    providers: &State<OidcProviders>,
+    _api_key: ApiKey,
+) -> Json<Vec<String>> {
+    Json(providers.names())
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct OidcLogin {
+    /// ID token issued by the provider
+    pub id_token: String,
+}
+
+#[openapi(tag = "Authentication")]
+#[post("/api/auth/oidc/<provider>/login", format = "json", data = "<login>")]
+pub async fn oidc_login(
+    db: &State<Database>,
+    providers: &State<OidcProviders>,
+    provider: String,
+    login: SignedJson<OidcLogin>,
+    _api_key: ApiKey,
+) -> ApiResult<LoginResponse> {
+    let identity = providers.get(&provider)?.verify(&login.id_token).await?;
+    let response = db
+        .run(move |conn| {
+            // First, try to find the user by their identity at the provider
+            if let Ok(person) = IdentityInteractor::get_person(conn, &provider, &identity.subject) {
+                return login_response(conn, &person, None);
+            }
+
+            // Not linked, whether or not someone has that email: answer like a wrong password,
+            // so the route can't be used to find out who has an account
+            match PersonInteractor::get_by_email(conn, &identity.email) {
+                Ok(person) => warn!(
+                    "{} account {} of person {} is not linked",
+                    provider, identity.subject, person.id
+                ),
+                Err(_) => warn!("No person for {} account {}", provider, identity.subject),
+            }
+            Err(ApiError::Unauthorized(INVALID_CREDENTIALS.to_string()))
+        })
+        .await??;
+    Ok(Json(response))
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct OidcRegister {
+    /// ID token issued by the provider
+    pub id_token: String,
+    /// Defaults to the `given_name` of the account
+    pub name: Option<String>,
+    /// Defaults to the `family_name` of the account
+    pub surname: Option<String>,
+}
+
+/// Create a person for an account at the provider, and sign them in
+///
+/// An account that is already linked signs in as its person, asking for their second factor
+/// like `/api/auth/oidc/<provider>/login` when they have it on or must use it.
+#[openapi(tag = "Authentication")]
+#[post(
+    "/api/auth/oidc/<provider>/register",
+    format = "json",
+    data = "<register>"
+)]
+pub async fn oidc_register(
+    db: &State<Database>,
+    events: &State<EventBus>,
+    webhooks: &State<WebhookDispatcher>,
+    providers: &State<OidcProviders>,
+    provider: String,
+    register: SignedJson<OidcRegister>,
+    _api_key: ApiKey,
+) -> ApiResult<LoginResponse> {
+    let identity = providers.get(&provider)?.verify(&register.id_token).await?;
+    let register = register.0;
+    let (response, created) = db
+        .run(move |conn| register_identity(conn, &provider, identity, register))
+        .await??;
+    if let Some(person) = created {
+        events.person_changed("created", &person.id, Some(&person));
+        webhooks.dispatch(webhooks::PERSON_REGISTERED, &person);
+    }
+    Ok(Json(response))
+}
+
+/// Signs in the person linked to `identity`, or creates one for it; the person is returned
+/// when created
+fn register_identity(
+    conn: &mut db::DbConnection,
+    provider: &str,
+    identity: ExternalIdentity,
+    register: OidcRegister,
+) -> Result<(LoginResponse, Option<PersonView>), ApiError> {
+    // Check if the user already exists
+    if let Ok(person) = IdentityInteractor::get_person(conn, provider, &identity.subject) {
+        return Ok((login_response(conn, &person, None)?, None));
+    }
+    if PersonInteractor::get_by_email(conn, &identity.email).is_ok() {
+        return Err(ApiError::Conflict(
+            "Email already registered, log in and link the account instead".to_string(),
+        ));
+    }
+
+    let (Some(name), Some(surname)) = (
+        register.name.or(identity.given_name),
+        register.surname.or(identity.family_name),
+    ) else {
+        return Err(ApiError::Unprocessable(
+            "name and surname are required".to_string(),
+        ));
+    };
+    let person = Person::new(&name, &surname, &identity.email, Role::Alumno, None);
+
+    // Create a new user with default permissions
+    let permissions = Permissions::for_new_account(&person.id);
+    let linked = PersonIdentity::new(
+        &person.id,
+        provider,
+        &identity.subject,
+        Some(&identity.email),
+    );
+    IdentityInteractor::create_person(conn, &person, &permissions, &linked)?;
+
+    // Return the created user data along with a session
+    let response = login_response(conn, &person, Some("User created successfully"))?;
+    Ok((response, Some(PersonView::from(person))))
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct OidcLink {
+    /// Password of the signed-in person
+    pub password: String,
+    /// ID token of the account to link
+    pub id_token: String,
+}
+
+/// Links an account at the provider to the signed-in person, replacing the one they had there.
+/// Their email stays as it is.
+#[openapi(tag = "Authentication")]
+#[post("/api/auth/oidc/<provider>/link", format = "json", data = "<link>")]
+pub async fn oidc_link(
+    db: &State<Database>,
+    providers: &State<OidcProviders>,
+    provider: String,
+    link: SignedJson<OidcLink>,
+    ip: ClientIp,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> ApiResult<Message> {
+    let identity = providers.get(&provider)?.verify(&link.id_token).await?;
+    let person_id = user.person.id.clone();
+
+    db.run(move |conn| {
+        let person = PersonInteractor::get_by_id(conn, &person_id)
+            .map_err(|_| ApiError::NotFound("User not found".to_string()))?;
+
+        // Proof that the person owns this account too, throttled like `/api/auth/login`
+        let Some(password_hash) = &person.password_hash else {
+            return Err(ApiError::Unprocessable(
+                "Set a password before linking another account".to_string(),
+            ));
+        };
+        let attempt = Attempt::start(conn, &person.email, ip.0)?;
+        if !db::crypto::check_password(&link.password, Some(password_hash)) {
+            return Err(attempt.failed(
+                conn,
+                ApiError::Unauthorized(INVALID_CREDENTIALS.to_string()),
+            ));
+        }
+        attempt.succeeded(conn)?;
+
+        // The account can only sign in as one person
+        if let Ok(linked) = IdentityInteractor::get_person(conn, &provider, &identity.subject) {
+            if linked.id == person.id {
+                return Ok(());
+            }
+            return Err(ApiError::Conflict(format!(
+                "This {} account is already linked to another user",
+                provider
+            )));
+        }
+
+        let linked = PersonIdentity::new(
+            &person.id,
+            &provider,
+            &identity.subject,
+            Some(&identity.email),
+        );
+        IdentityInteractor::link(conn, &linked)?;
+        Ok::<_, ApiError>(())
+    })
+    .await??;
+    Ok(Json(Message::ok("Account linked successfully")))
+}
+
+/// The accounts at identity providers linked to a person
+#[openapi(tag = "Authentication")]
+#[get("/api/person/<person_id>/identities")]
+pub async fn get_identities(
+    db: &State<Database>,
+    person_id: String,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> ApiResult<Vec<PersonIdentity>> {
+    user.require_self_or(&person_id, &[Permission::AdminPanel])?;
+
+    let identities = db
+        .run(move |conn| {
+            PersonInteractor::get_by_id(conn, &person_id)
+                .map_err(|_| ApiError::not_found("Person"))?;
+            Ok::<_, ApiError>(IdentityInteractor::get_by_person(conn, &person_id)?)
+        })
+        .await??;
+    Ok(Json(identities))
+}
+
+/// Unlinks the account a person has at `provider`, refused when it is their last way to sign in
+#[openapi(tag = "Authentication")]
+#[delete("/api/person/<person_id>/identities/<provider>")]
+pub async fn unlink_identity(
+    db: &State<Database>,
+    person_id: String,
+    provider: String,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> ApiResult<Message> {
+    user.require_self_or(&person_id, &[Permission::AdminPanel])?;
+
+    let outcome = db
+        .run(move |conn| IdentityInteractor::unlink(conn, &person_id, &provider))
+        .await?
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => ApiError::not_found("Person"),
+            e => ApiError::from(e),
+        })?;
+    match outcome {
+        Unlink::Done => Ok(Json(Message::ok("Account unlinked successfully"))),
+        Unlink::NotLinked => Err(ApiError::not_found("Linked account")),
+        Unlink::LastLoginMethod => Err(ApiError::Conflict(
+            "This is the only way left to sign in, set a password or link another account first"
+                .to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::{Connection, SqliteConnection};
+
+    fn db() -> db::DbConnection {
+        let mut conn = db::DbConnection::Sqlite(SqliteConnection::establish(":memory:").unwrap());
+        db::migrations::run_pending(&mut conn).unwrap();
+        // Checked on ignite when serving
+        unsafe { std::env::set_var("SYN_TOKEN_SECRET", "test-secret") };
+        conn
+    }
+
+    fn identity(subject: &str, email: &str) -> ExternalIdentity {
+        ExternalIdentity {
+            subject: subject.to_string(),
+            email: email.to_string(),
+            given_name: Some("Ada".to_string()),
+            family_name: Some("Lovelace".to_string()),
+        }
+    }
+
+    fn register() -> OidcRegister {
+        OidcRegister {
+            id_token: String::new(),
+            name: None,
+            surname: None,
+        }
+    }
+
+    #[test]
+    fn registering_a_linked_account_asks_for_the_second_factor() {
+        let mut conn = db();
+        let admin = Person::new("Ada", "Lovelace", "ada@example.com", Role::Admin, None);
+        let permissions = Permissions::for_new_account(&admin.id);
+        let linked = PersonIdentity::new(&admin.id, "google", "sub-1", Some(&admin.email));
+        IdentityInteractor::create_person(&mut conn, &admin, &permissions, &linked).unwrap();
+
+        let (response, created) = register_identity(
+            &mut conn,
+            "google",
+            identity("sub-1", "ada@example.com"),
+            register(),
+        )
+        .unwrap();
+        assert!(created.is_none());
+        let LoginResponse::TwoFactor(challenge) = response else {
+            panic!("signed in without a second factor");
+        };
+        assert_eq!(challenge.status, "two_factor_enrollment_required");
+    }
+
+    #[test]
+    fn registering_a_new_account_signs_in() {
+        let mut conn = db();
+        let (response, created) = register_identity(
+            &mut conn,
+            "google",
+            identity("sub-2", "grace@example.com"),
+            register(),
+        )
+        .unwrap();
+        assert!(created.is_some());
+        assert!(matches!(response, LoginResponse::Session(_)));
+    }
+}
@@ -1,42 +1,86 @@
-use crate::auth::guard::ApiKey;
-use crate::models::Database;
-use db::establish_connection;
-use db::interactions::permissions::PermissionsInteractor;
-use db::models::Permissions;
+use crate::auth::access::Permission;
+use crate::auth::guard::{ApiKey, CurrentUser};
+use crate::auth::signed::SignedJson;
+use crate::error::{ApiError, ApiResult};
+use crate::etag::{IfMatch, Tagged, TaggedResult, update_miss};
+use crate::models::{Database, Message};
+use crate::routes::parse_page;
+use db::interactions::permissions::{PermissionsFilter, PermissionsInteractor};
+use db::models::{Permissions, PermissionsChanges};
+use db::pagination::Page;
 use rocket::serde::json::Json;
-use rocket::{State, response::content::RawJson};
-use rocket::{delete, get, post, put};
+use rocket::{FromForm, State, delete, get, patch, post, put};
 use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::Deserialize;
 
-/// Get all permissions
+#[derive(FromForm, JsonSchema)]
+pub struct PermissionsQuery {
+    person_id: Option<String>,
+    dashboard: Option<bool>,
+    see_self_history: Option<bool>,
+    see_others_history: Option<bool>,
+    admin_panel: Option<bool>,
+    edit_permissions: Option<bool>,
+    /// Page size, 1 to 500 (default 50)
+    limit: Option<i64>,
+    offset: Option<i64>,
+    /// `next_cursor` of the previous page, takes precedence over `offset`
+    cursor: Option<String>,
+    /// `person_id` (default) or `id`; prefix with `-` for descending order
+    sort: Option<String>,
+}
+
+/// Get a page of permissions, optionally filtered
 #[openapi(tag = "Permissions")]
-#[get("/api/permission")]
-pub async fn get_permissions(db: &State<Database>, _api_key: ApiKey) -> RawJson<String> {
-    let conn = &mut establish_connection(&db.db_url);
-    match PermissionsInteractor::get(conn) {
-        Ok(permissions) => RawJson(serde_json::to_string(&permissions).unwrap()),
-        Err(_) => RawJson(
-            "{\"status\": \"error\", \"message\": \"Failed to retrieve permissions\"}".to_string(),
-        ),
-    }
+#[get("/api/permission?<query..>")]
+pub async fn get_permissions(
+    db: &State<Database>,
+    query: PermissionsQuery,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> ApiResult<Page<Permissions>> {
+    user.require_any(&[Permission::EditPermissions, Permission::AdminPanel])?;
+
+    let (page, sort) = parse_page(
+        query.limit,
+        query.offset,
+        query.cursor.as_deref(),
+        query.sort.as_deref(),
+    )?;
+    let filter = PermissionsFilter {
+        person_id: query.person_id,
+        dashboard: query.dashboard,
+        see_self_history: query.see_self_history,
+        see_others_history: query.see_others_history,
+        admin_panel: query.admin_panel,
+        edit_permissions: query.edit_permissions,
+    };
+
+    let permissions = db
+        .run(move |conn| PermissionsInteractor::page(conn, &filter, sort, &page))
+        .await??;
+    Ok(Json(permissions))
 }
 
-/// Get a single permission by ID
+/// Get the permissions of a person
 #[openapi(tag = "Permissions")]
 #[get("/api/permission/by-person/<person_id>")]
 pub async fn get_permissions_by_person_id(
     db: &State<Database>,
     person_id: String,
+    user: CurrentUser,
     _api_key: ApiKey,
-) -> RawJson<String> {
-    let conn = &mut establish_connection(&db.db_url);
-    match PermissionsInteractor::get_by_p_id(conn, &person_id) {
-        Ok(permissions) => RawJson(serde_json::to_string(&permissions).unwrap()),
-        Err(_) => RawJson(
-            "{\"status\": \"error\", \"message\": \"Permissions not found for this person\"}"
-                .to_string(),
-        ),
-    }
+) -> ApiResult<Vec<Permissions>> {
+    user.require_self_or(
+        &person_id,
+        &[Permission::EditPermissions, Permission::AdminPanel],
+    )?;
+
+    let permissions = db
+        .run(move |conn| PermissionsInteractor::get_by_p_id(conn, &person_id))
+        .await??;
+    Ok(Json(permissions))
 }
 
 /// Get a single permission by ID
@@ -45,35 +89,84 @@ pub async fn get_permissions_by_person_id(
 pub async fn get_permissions_by_id(
     db: &State<Database>,
     permission_id: String,
+    user: CurrentUser,
     _api_key: ApiKey,
-) -> RawJson<String> {
-    let conn = &mut establish_connection(&db.db_url);
-    match PermissionsInteractor::get_by_id(conn, &permission_id) {
-        Ok(permissions) => RawJson(serde_json::to_string(&permissions).unwrap()),
-        Err(_) => {
-            RawJson("{\"status\": \"error\", \"message\": \"Permission not found\"}".to_string())
-        }
-    }
+) -> TaggedResult<Permissions> {
+    let permissions = db
+        .run(move |conn| PermissionsInteractor::get_by_id(conn, &permission_id))
+        .await?
+        .map_err(|_| ApiError::not_found("Permission"))?;
+    user.require_self_or(
+        &permissions.person_id,
+        &[Permission::EditPermissions, Permission::AdminPanel],
+    )?;
+    Ok(Tagged::new(permissions.version, permissions))
+}
+
+/// Body of `POST /api/permission`; the id is generated by the server
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CreatePermissions {
+    person_id: String,
+    dashboard: bool,
+    see_self_history: bool,
+    see_others_history: bool,
+    admin_panel: bool,
+    edit_permissions: bool,
 }
+
 /// Create a new permission
 #[openapi(tag = "Permissions")]
 #[post("/api/permission", format = "json", data = "<permissions>")]
 pub async fn create_permissions(
     db: &State<Database>,
-    permissions: Json<Permissions>,
+    permissions: SignedJson<CreatePermissions>,
+    user: CurrentUser,
     _api_key: ApiKey,
-) -> RawJson<String> {
-    let conn = &mut establish_connection(&db.db_url);
-    match PermissionsInteractor::new(conn, &permissions) {
-        Ok(new_permissions) => RawJson(serde_json::to_string(&new_permissions).unwrap()),
-        Err(e) => RawJson(format!(
-            "{{\"status\": \"error\", \"message\": \"Failed to create permissions: {}\"}}",
-            e
-        )),
+) -> ApiResult<Permissions> {
+    user.require(Permission::EditPermissions)?;
+
+    let permissions = permissions.0;
+    let permissions = Permissions::new(
+        &permissions.person_id,
+        permissions.dashboard,
+        permissions.see_self_history,
+        permissions.see_others_history,
+        permissions.admin_panel,
+        permissions.edit_permissions,
+    );
+    let created = db
+        .run(move |conn| PermissionsInteractor::new(conn, &permissions).map(|_| permissions))
+        .await??;
+    Ok(Json(created))
+}
+
+/// Body of `PUT /api/permission/<id>`; the id and version come from the path and `If-Match`,
+/// and the person can't be changed
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct UpdatePermissions {
+    dashboard: bool,
+    see_self_history: bool,
+    see_others_history: bool,
+    admin_panel: bool,
+    edit_permissions: bool,
+}
+
+impl UpdatePermissions {
+    fn apply(self, permissions: &mut Permissions) {
+        permissions.dashboard = self.dashboard;
+        permissions.see_self_history = self.see_self_history;
+        permissions.see_others_history = self.see_others_history;
+        permissions.admin_panel = self.admin_panel;
+        permissions.edit_permissions = self.edit_permissions;
     }
 }
 
 /// Update an existing permission
+///
+/// Send the `ETag` of the version being edited as `If-Match` to get a 412 instead of
+/// overwriting someone else's changes
 #[openapi(tag = "Permissions")]
 #[put(
     "/api/permission/<permission_id>",
@@ -83,17 +176,86 @@ pub async fn create_permissions(
 pub async fn update_permissions(
     db: &State<Database>,
     permission_id: String,
-    permissions: Json<Permissions>,
+    permissions: SignedJson<UpdatePermissions>,
+    if_match: IfMatch,
+    user: CurrentUser,
     _api_key: ApiKey,
-) -> RawJson<String> {
-    let conn = &mut establish_connection(&db.db_url);
-    match PermissionsInteractor::update(conn, &permission_id, &permissions) {
-        Ok(updated_permissions) => RawJson(serde_json::to_string(&updated_permissions).unwrap()),
-        Err(_) => RawJson(
-            "{\"status\": \"error\", \"message\": \"Permission not found or update failed\"}"
-                .to_string(),
-        ),
-    }
+) -> TaggedResult<Permissions> {
+    user.require(Permission::EditPermissions)?;
+
+    let changes = permissions.0;
+    let expected = if_match.versions()?;
+    let updated = db
+        .run(move |conn| {
+            let mut permissions = PermissionsInteractor::get_by_id(conn, &permission_id)
+                .map_err(|_| ApiError::not_found("Permission"))?;
+            changes.apply(&mut permissions);
+            if PermissionsInteractor::update(
+                conn,
+                &permission_id,
+                &permissions,
+                expected.as_deref(),
+            )? == 0
+            {
+                return Err(update_miss(true, "Permission"));
+            }
+            Ok(PermissionsInteractor::get_by_id(conn, &permission_id)?)
+        })
+        .await??;
+    Ok(Tagged::new(updated.version, updated))
+}
+
+/// Body of `PATCH /api/permission/<id>`; only the flags present are changed
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct PatchPermissions {
+    dashboard: Option<bool>,
+    see_self_history: Option<bool>,
+    see_others_history: Option<bool>,
+    admin_panel: Option<bool>,
+    edit_permissions: Option<bool>,
+}
+
+/// Change some flags of a permission set, leaving the rest as they are
+///
+/// Honors `If-Match` like the PUT route
+#[openapi(tag = "Permissions")]
+#[patch(
+    "/api/permission/<permission_id>",
+    format = "json",
+    data = "<permissions>"
+)]
+pub async fn patch_permissions(
+    db: &State<Database>,
+    permission_id: String,
+    permissions: SignedJson<PatchPermissions>,
+    if_match: IfMatch,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> TaggedResult<Permissions> {
+    user.require(Permission::EditPermissions)?;
+
+    let permissions = permissions.0;
+    let changes = PermissionsChanges {
+        dashboard: permissions.dashboard,
+        see_self_history: permissions.see_self_history,
+        see_others_history: permissions.see_others_history,
+        admin_panel: permissions.admin_panel,
+        edit_permissions: permissions.edit_permissions,
+    };
+    let expected = if_match.versions()?;
+    let updated = db
+        .run(move |conn| {
+            if PermissionsInteractor::patch(conn, &permission_id, &changes, expected.as_deref())?
+                == 0
+            {
+                let exists = PermissionsInteractor::get_by_id(conn, &permission_id).is_ok();
+                return Err(update_miss(exists, "Permission"));
+            }
+            Ok(PermissionsInteractor::get_by_id(conn, &permission_id)?)
+        })
+        .await??;
+    Ok(Tagged::new(updated.version, updated))
 }
 
 /// Delete a permission
@@ -102,14 +264,16 @@ pub async fn update_permissions(
 pub async fn delete_permissions(
     db: &State<Database>,
     permission_id: String,
+    user: CurrentUser,
     _api_key: ApiKey,
-) -> RawJson<String> {
-    let conn = &mut establish_connection(&db.db_url);
-    match PermissionsInteractor::delete(conn, &permission_id) {
-        Ok(deleted_permissions) => RawJson(serde_json::to_string(&deleted_permissions).unwrap()),
-        Err(_) => RawJson(
-            "{\"status\": \"error\", \"message\": \"Permission not found or delete failed\"}"
-                .to_string(),
-        ),
+) -> ApiResult<Message> {
+    user.require(Permission::EditPermissions)?;
+
+    let deleted = db
+        .run(move |conn| PermissionsInteractor::delete(conn, &permission_id))
+        .await??;
+    match deleted {
+        0 => Err(ApiError::not_found("Permission")),
+        _ => Ok(Json(Message::ok("Permission deleted"))),
     }
 }
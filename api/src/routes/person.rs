@@ -1,25 +1,83 @@
-use db::establish_connection;
-use db::interactions::person::PersonInteractor;
-use db::models::Person;
+use db::interactions::identity::IdentityInteractor;
+use db::interactions::person::{DeletedFilter, PersonFilter, PersonInteractor};
+use db::models::Role;
+use db::pagination::Page;
 use rocket::serde::json::Json;
-use rocket::{State, response::content::RawJson};
-use rocket::{delete, get, post, put};
+use rocket::{FromForm, State, delete, get, patch, post, put};
 use rocket_okapi::openapi;
+use schemars::JsonSchema;
 
-use crate::auth::guard::ApiKey;
-use crate::models::Database;
+use crate::auth::access::Permission;
+use crate::auth::guard::{ApiKey, CurrentUser};
+use crate::auth::signed::SignedJson;
+use crate::error::{ApiError, ApiResult};
+use crate::etag::{IfMatch, Tagged, TaggedResult, update_miss};
+use crate::events::EventBus;
+use crate::models::{CreatePerson, Database, Message, PatchPerson, PersonView, UpdatePerson};
+use crate::routes::parse_page;
 
-/// Get all persons
+#[derive(FromForm, JsonSchema)]
+pub struct PersonQuery {
+    /// `Admin`, `Profesor` or `Alumno`
+    role: Option<String>,
+    /// Part of the name, surname or email
+    search: Option<String>,
+    /// `exclude` (default), `include` or `only`; soft-deleted persons are listed to admins only
+    deleted: Option<String>,
+    /// Page size, 1 to 500 (default 50)
+    limit: Option<i64>,
+    offset: Option<i64>,
+    /// `next_cursor` of the previous page, takes precedence over `offset`
+    cursor: Option<String>,
+    /// `surname` (default), `name`, `email` or `role`; prefix with `-` for descending order
+    sort: Option<String>,
+}
+
+/// Get a page of persons, optionally filtered
 #[openapi(tag = "Persons")]
-#[get("/api/person")]
-pub async fn get_persons(db: &State<Database>, _api_key: ApiKey) -> RawJson<String> {
-    let conn = &mut establish_connection(&db.db_url);
-    match PersonInteractor::get(conn) {
-        Ok(persons) => RawJson(serde_json::to_string(&persons).unwrap()),
-        Err(_) => RawJson(
-            "{\"status\": \"error\", \"message\": \"Failed to retrieve persons\"}".to_string(),
-        ),
+#[get("/api/person?<query..>")]
+pub async fn get_persons(
+    db: &State<Database>,
+    query: PersonQuery,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> ApiResult<Page<PersonView>> {
+    user.require_any(&[Permission::AdminPanel, Permission::SeeOthersHistory])?;
+
+    let role = query
+        .role
+        .map(|role| role.parse::<Role>())
+        .transpose()
+        .map_err(ApiError::BadRequest)?;
+    let deleted = match query.deleted.as_deref() {
+        None | Some("exclude") => DeletedFilter::Exclude,
+        Some("include") => DeletedFilter::Include,
+        Some("only") => DeletedFilter::Only,
+        Some(other) => {
+            return Err(ApiError::BadRequest(format!(
+                "Invalid deleted filter '{other}', expected exclude, include or only"
+            )));
+        }
+    };
+    if deleted != DeletedFilter::Exclude {
+        user.require(Permission::AdminPanel)?;
     }
+    let (page, sort) = parse_page(
+        query.limit,
+        query.offset,
+        query.cursor.as_deref(),
+        query.sort.as_deref(),
+    )?;
+    let filter = PersonFilter {
+        role,
+        search: query.search,
+        deleted,
+    };
+
+    let persons = db
+        .run(move |conn| PersonInteractor::page(conn, &filter, sort, &page))
+        .await??;
+    Ok(Json(persons.map(PersonView::from)))
 }
 
 /// Get a single person by ID
@@ -28,28 +86,38 @@ pub async fn get_persons(db: &State<Database>, _api_key: ApiKey) -> RawJson<Stri
 pub async fn get_person_by_id(
     db: &State<Database>,
     person_id: String,
+    user: CurrentUser,
     _api_key: ApiKey,
-) -> RawJson<String> {
-    let conn = &mut establish_connection(&db.db_url);
-    match PersonInteractor::get_by_id(conn, &person_id) {
-        Ok(person) => RawJson(serde_json::to_string(&person).unwrap()),
-        Err(_) => RawJson("{\"status\": \"error\", \"message\": \"Person not found\"}".to_string()),
-    }
+) -> TaggedResult<PersonView> {
+    user.require_self_or(
+        &person_id,
+        &[Permission::AdminPanel, Permission::SeeOthersHistory],
+    )?;
+
+    let person = db
+        .run(move |conn| PersonInteractor::get_by_id(conn, &person_id))
+        .await?
+        .map_err(|_| ApiError::not_found("Person"))?;
+    Ok(Tagged::new(person.version, person.into()))
 }
 
-/// Get a single person by Google ID
+/// Get a single person by their account at an identity provider
 #[openapi(tag = "Persons")]
-#[get("/api/person/by-google-id/<google_id>")]
-pub async fn get_person_by_google_id(
+#[get("/api/person/by-identity/<provider>/<subject>")]
+pub async fn get_person_by_identity(
     db: &State<Database>,
-    google_id: String,
+    provider: String,
+    subject: String,
+    user: CurrentUser,
     _api_key: ApiKey,
-) -> RawJson<String> {
-    let conn = &mut establish_connection(&db.db_url);
-    match PersonInteractor::get_by_google_id(conn, &google_id) {
-        Ok(person) => RawJson(serde_json::to_string(&person).unwrap()),
-        Err(_) => RawJson("{\"status\": \"error\", \"message\": \"Person not found by Google ID\"}".to_string()),
-    }
+) -> TaggedResult<PersonView> {
+    user.require(Permission::AdminPanel)?;
+
+    let person = db
+        .run(move |conn| IdentityInteractor::get_person(conn, &provider, &subject))
+        .await?
+        .map_err(|_| ApiError::not_found("Person"))?;
+    Ok(Tagged::new(person.version, person.into()))
 }
 
 /// Create a new person
@@ -57,52 +125,163 @@ pub async fn get_person_by_google_id(
 #[post("/api/person", format = "json", data = "<person>")]
 pub async fn create_person(
     db: &State<Database>,
-    person: Json<Person>,
+    events: &State<EventBus>,
+    person: SignedJson<CreatePerson>,
+    user: CurrentUser,
     _api_key: ApiKey,
-) -> RawJson<String> {
-    let conn = &mut establish_connection(&db.db_url);
-    match PersonInteractor::new(conn, &person) {
-        Ok(new_person) => RawJson(serde_json::to_string(&new_person).unwrap()),
-        Err(e) => RawJson(format!(
-            "{{\"status\": \"error\", \"message\": \"Failed to create person: {}\"}}",
-            e
-        )),
-    }
+) -> TaggedResult<PersonView> {
+    user.require(Permission::AdminPanel)?;
+
+    let created = db
+        .run(move |conn| {
+            let person = person.0.into_person();
+            PersonInteractor::new(conn, &person).map(|_| person)
+        })
+        .await??;
+    let view = PersonView::from(created);
+    events.person_changed("created", &view.id, Some(&view));
+    Ok(Tagged::new(view.version, view))
 }
 
 /// Update an existing person
+///
+/// Send the `ETag` of the version being edited as `If-Match` to get a 412 instead of
+/// overwriting someone else's changes
 #[openapi(tag = "Persons")]
 #[put("/api/person/<person_id>", format = "json", data = "<person>")]
 pub async fn update_person(
     db: &State<Database>,
+    events: &State<EventBus>,
     person_id: String,
-    person: Json<Person>,
+    person: SignedJson<UpdatePerson>,
+    if_match: IfMatch,
+    user: CurrentUser,
     _api_key: ApiKey,
-) -> RawJson<String> {
-    let conn = &mut establish_connection(&db.db_url);
-    match PersonInteractor::update(conn, &person_id, &person) {
-        Ok(updated_person) => RawJson(serde_json::to_string(&updated_person).unwrap()),
-        Err(_) => RawJson(
-            "{\"status\": \"error\", \"message\": \"Person not found or update failed\"}"
-                .to_string(),
-        ),
-    }
+) -> TaggedResult<PersonView> {
+    user.require(Permission::AdminPanel)?;
+
+    let changes = person.0;
+    let expected = if_match.versions()?;
+    let updated = db
+        .run(move |conn| {
+            let mut person = PersonInteractor::get_by_id(conn, &person_id)
+                .map_err(|_| ApiError::not_found("Person"))?;
+            changes.apply(&mut person);
+            if PersonInteractor::update(conn, &person_id, &person, expected.as_deref())? == 0 {
+                return Err(update_miss(true, "Person"));
+            }
+            Ok(PersonInteractor::get_by_id(conn, &person_id)?)
+        })
+        .await??;
+    let view = PersonView::from(updated);
+    events.person_changed("updated", &view.id, Some(&view));
+    Ok(Tagged::new(view.version, view))
 }
 
-/// Delete a person
+/// Change some fields of a person, leaving the rest as they are
+///
+/// Honors `If-Match` like the PUT route. Fields set to `null` are refused with 422, as none
+/// can be removed.
+#[openapi(tag = "Persons")]
+#[patch("/api/person/<person_id>", format = "json", data = "<person>")]
+pub async fn patch_person(
+    db: &State<Database>,
+    events: &State<EventBus>,
+    person_id: String,
+    person: SignedJson<PatchPerson>,
+    if_match: IfMatch,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> TaggedResult<PersonView> {
+    user.require(Permission::AdminPanel)?;
+
+    let expected = if_match.versions()?;
+    let updated = db
+        .run(move |conn| {
+            let changes = person.0.into_changes()?;
+            if PersonInteractor::patch(conn, &person_id, &changes, expected.as_deref())? == 0 {
+                let exists = PersonInteractor::get_by_id(conn, &person_id).is_ok();
+                return Err(update_miss(exists, "Person"));
+            }
+            Ok(PersonInteractor::get_by_id(conn, &person_id)?)
+        })
+        .await??;
+    let view = PersonView::from(updated);
+    events.person_changed("updated", &view.id, Some(&view));
+    Ok(Tagged::new(view.version, view))
+}
+
+/// Soft-delete a person
+///
+/// Their entries and permissions are kept and they can no longer log in, until restored
 #[openapi(tag = "Persons")]
 #[delete("/api/person/<person_id>")]
 pub async fn delete_person(
     db: &State<Database>,
+    events: &State<EventBus>,
+    person_id: String,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> ApiResult<Message> {
+    user.require(Permission::AdminPanel)?;
+
+    let id = person_id.clone();
+    let deleted = db
+        .run(move |conn| PersonInteractor::soft_delete(conn, &id))
+        .await??;
+    if deleted == 0 {
+        return Err(ApiError::not_found("Person"));
+    }
+    events.person_changed("deleted", &person_id, None);
+    Ok(Json(Message::ok("Person deleted")))
+}
+
+/// Restore a soft-deleted person
+#[openapi(tag = "Persons")]
+#[post("/api/person/<person_id>/restore")]
+pub async fn restore_person(
+    db: &State<Database>,
+    events: &State<EventBus>,
     person_id: String,
+    user: CurrentUser,
     _api_key: ApiKey,
-) -> RawJson<String> {
-    let conn = &mut establish_connection(&db.db_url);
-    match PersonInteractor::delete(conn, &person_id) {
-        Ok(deleted_person) => RawJson(serde_json::to_string(&deleted_person).unwrap()),
-        Err(_) => RawJson(
-            "{\"status\": \"error\", \"message\": \"Person not found or delete failed\"}"
-                .to_string(),
-        ),
+) -> TaggedResult<PersonView> {
+    user.require(Permission::AdminPanel)?;
+
+    let restored = db
+        .run(move |conn| {
+            if PersonInteractor::restore(conn, &person_id)? == 0 {
+                return Err(ApiError::not_found("Deleted person"));
+            }
+            Ok(PersonInteractor::get_by_id(conn, &person_id)?)
+        })
+        .await??;
+    let view = PersonView::from(restored);
+    events.person_changed("restored", &view.id, Some(&view));
+    Ok(Tagged::new(view.version, view))
+}
+
+/// Permanently delete a person with their entries, permissions, tokens and failed sign-ins
+///
+/// Works on active and soft-deleted persons alike and cannot be undone
+#[openapi(tag = "Persons")]
+#[delete("/api/person/<person_id>/purge")]
+pub async fn purge_person(
+    db: &State<Database>,
+    events: &State<EventBus>,
+    person_id: String,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> ApiResult<Message> {
+    user.require(Permission::AdminPanel)?;
+
+    let id = person_id.clone();
+    let purged = db
+        .run(move |conn| PersonInteractor::purge(conn, &id))
+        .await??;
+    if purged == 0 {
+        return Err(ApiError::not_found("Person"));
     }
+    events.person_changed("purged", &person_id, None);
+    Ok(Json(Message::ok("Person purged")))
 }
@@ -0,0 +1,382 @@
+use crate::auth::access::Permission;
+use crate::auth::guard::{ApiKey, ClientIp, CurrentUser};
+use crate::auth::signed::SignedJson;
+use crate::auth::throttle::Attempt;
+use crate::auth::token;
+use crate::auth::two_factor::{self, invalid_code};
+use crate::error::{ApiError, ApiResult};
+use crate::models::{Database, Message};
+use crate::routes::auth::{SessionResponse, session_response};
+use db::interactions::permissions::PermissionsInteractor;
+use db::interactions::person::PersonInteractor;
+use db::interactions::two_factor::TwoFactorInteractor;
+use db::models::{Person, TotpSecret};
+use log::warn;
+use rocket::serde::json::Json;
+use rocket::{State, delete, get, post};
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Answer to a successful sign-in: a session, or a challenge when a second factor is needed
+#[derive(Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Session(SessionResponse),
+    TwoFactor(TwoFactorChallenge),
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct TwoFactorChallenge {
+    /// `two_factor_required`, or `two_factor_enrollment_required` when the person must use
+    /// two-factor authentication and hasn't set it up yet
+    pub status: String,
+    /// Send it back to `/api/auth/2fa/verify` along with a code
+    pub challenge_token: String,
+    /// Seconds until the challenge expires
+    pub expires_in: i64,
+    /// The secret to add to an authenticator app, when enrollment is required; it replaces
+    /// the one of any earlier unfinished enrollment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enrollment: Option<Enrollment>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct Enrollment {
+    /// Base32 secret, for typing it into an authenticator app
+    pub secret: String,
+    /// `otpauth://` URI, to show as a QR code
+    pub otpauth_uri: String,
+}
+
+/// Session tokens for a person that has just been authenticated, or a challenge when they
+/// have two-factor authentication on or it is required for them
+///
+/// Until someone who must use it has confirmed a first code, each sign-in starts their
+/// enrollment over with a new secret, so the last one shown is the only one that works.
+/// Their password alone is what protects the enrollment.
+pub(crate) fn login_response(
+    conn: &mut db::DbConnection,
+    person: &Person,
+    message: Option<&str>,
+) -> Result<LoginResponse, ApiError> {
+    let secret = TwoFactorInteractor::get(conn, &person.id)?;
+    let permissions = PermissionsInteractor::get_by_p_id(conn, &person.id)?.pop();
+    let enrollment = match secret {
+        Some(secret) if secret.is_confirmed() => None,
+        _ if two_factor::required_for(person, permissions.as_ref()) => {
+            Some(begin_enrollment(conn, person)?)
+        }
+        _ => {
+            return Ok(LoginResponse::Session(session_response(
+                conn, person, message,
+            )?));
+        }
+    };
+
+    let challenge_token = token::issue_challenge(person)
+        .map_err(|e| ApiError::Internal(format!("Failed to issue challenge token: {}", e)))?;
+    let status = match enrollment {
+        Some(_) => "two_factor_enrollment_required",
+        None => "two_factor_required",
+    };
+    Ok(LoginResponse::TwoFactor(TwoFactorChallenge {
+        status: status.to_string(),
+        challenge_token,
+        expires_in: token::CHALLENGE_TTL,
+        enrollment,
+    }))
+}
+
+fn begin_enrollment(conn: &mut db::DbConnection, person: &Person) -> Result<Enrollment, ApiError> {
+    let secret = two_factor::new_secret();
+    if !TwoFactorInteractor::begin_enrollment(conn, &TotpSecret::new(&person.id, &secret))? {
+        return Err(ApiError::Conflict(
+            "Two-factor authentication is already enabled".to_string(),
+        ));
+    }
+    Ok(Enrollment {
+        otpauth_uri: two_factor::otpauth_uri(&secret, person)?,
+        secret,
+    })
+}
+
+/// Checks a TOTP code, which can't be used twice, or spends a recovery code. A wrong one
+/// counts as a failure of `attempt`, which the caller marks as succeeded otherwise.
+fn check_second_factor(
+    conn: &mut db::DbConnection,
+    attempt: &Attempt,
+    secret: &TotpSecret,
+    code: Option<&str>,
+    recovery_code: Option<&str>,
+) -> Result<(), ApiError> {
+    let person_id = &secret.person_id;
+    let accepted = match (code, recovery_code) {
+        (Some(code), _) => match two_factor::matching_step(&secret.secret, code)? {
+            Some(step) => TwoFactorInteractor::use_step(conn, person_id, step)?,
+            None => false,
+        },
+        (None, Some(recovery_code)) => {
+            TwoFactorInteractor::use_recovery_code(conn, person_id, recovery_code)?
+        }
+        (None, None) => {
+            attempt.take_back(conn)?;
+            return Err(ApiError::Unprocessable(
+                "code or recovery_code is required".to_string(),
+            ));
+        }
+    };
+    if !accepted {
+        return Err(attempt.failed(conn, invalid_code(person_id)));
+    }
+    Ok(())
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct VerifyTwoFactor {
+    pub challenge_token: String,
+    /// Current code of the authenticator app
+    pub code: Option<String>,
+    /// One of the recovery codes, instead of `code`
+    pub recovery_code: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct TwoFactorSession {
+    #[serde(flatten)]
+    pub session: SessionResponse,
+    /// Shown only once, when this sign-in completed the enrollment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recovery_codes: Option<Vec<String>>,
+}
+
+/// Finish a sign-in that answered with a two-factor challenge
+#[openapi(tag = "Authentication")]
+#[post("/api/auth/2fa/verify", format = "json", data = "<verify>")]
+pub async fn verify_two_factor(
+    db: &State<Database>,
+    verify: SignedJson<VerifyTwoFactor>,
+    ip: ClientIp,
+    _api_key: ApiKey,
+) -> ApiResult<TwoFactorSession> {
+    let claims =
+        token::verify(&verify.challenge_token, token::TokenKind::Challenge).map_err(|e| {
+            warn!("Rejected challenge token: {}", e);
+            ApiError::Unauthorized("Invalid or expired challenge".to_string())
+        })?;
+
+    let response = db
+        .run(move |conn| {
+            let person = PersonInteractor::get_by_id(conn, &claims.sub)
+                .map_err(|_| ApiError::Unauthorized("User not found".to_string()))?;
+            let Some(secret) = TwoFactorInteractor::get(conn, &person.id)? else {
+                return Err(ApiError::Unauthorized(
+                    "Two-factor authentication is not set up".to_string(),
+                ));
+            };
+
+            // Wrong codes count as failed sign-ins, the password alone can't reset them
+            let attempt = Attempt::start(conn, &person.email, ip.0)?;
+
+            if secret.is_confirmed() {
+                check_second_factor(
+                    conn,
+                    &attempt,
+                    &secret,
+                    verify.code.as_deref(),
+                    verify.recovery_code.as_deref(),
+                )?;
+                attempt.succeeded(conn)?;
+                return Ok(TwoFactorSession {
+                    session: session_response(conn, &person, None)?,
+                    recovery_codes: None,
+                });
+            }
+
+            // Enrollment required for the person, the first code turns it on
+            let Some(code) = verify.code.as_deref() else {
+                attempt.take_back(conn)?;
+                return Err(ApiError::Unprocessable("code is required".to_string()));
+            };
+            let Some(step) = two_factor::matching_step(&secret.secret, code)? else {
+                return Err(attempt.failed(conn, invalid_code(&person.id)));
+            };
+            let (codes, rows) = two_factor::new_recovery_codes(&person.id);
+            if !TwoFactorInteractor::confirm(conn, &person.id, step, &rows)? {
+                return Err(attempt.failed(conn, invalid_code(&person.id)));
+            }
+            attempt.succeeded(conn)?;
+            Ok(TwoFactorSession {
+                session: session_response(
+                    conn,
+                    &person,
+                    Some("Two-factor authentication enabled"),
+                )?,
+                recovery_codes: Some(codes),
+            })
+        })
+        .await??;
+    Ok(Json(response))
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct TwoFactorStatus {
+    pub enabled: bool,
+    /// Whether it is mandatory, for the person's role or their admin permissions
+    pub required: bool,
+    pub recovery_codes_left: i64,
+}
+
+/// Two-factor authentication state of the current user
+#[openapi(tag = "Authentication")]
+#[get("/api/auth/2fa")]
+pub async fn get_two_factor(
+    db: &State<Database>,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> ApiResult<TwoFactorStatus> {
+    let required = two_factor::required_for(&user.person, user.permissions.as_ref());
+    let person_id = user.person.id;
+    let status = db
+        .run(move |conn| {
+            let enabled = TwoFactorInteractor::get(conn, &person_id)?
+                .is_some_and(|secret| secret.is_confirmed());
+            Ok::<_, ApiError>(TwoFactorStatus {
+                enabled,
+                required,
+                recovery_codes_left: TwoFactorInteractor::count_unused_recovery_codes(
+                    conn, &person_id,
+                )?,
+            })
+        })
+        .await??;
+    Ok(Json(status))
+}
+
+/// Start setting up two-factor authentication; it is on once confirmed with a first code
+#[openapi(tag = "Authentication")]
+#[post("/api/auth/2fa/enroll")]
+pub async fn enroll_two_factor(
+    db: &State<Database>,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> ApiResult<Enrollment> {
+    let enrollment = db
+        .run(move |conn| begin_enrollment(conn, &user.person))
+        .await??;
+    Ok(Json(enrollment))
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct ConfirmTwoFactor {
+    /// Current code of the authenticator app
+    pub code: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct RecoveryCodes {
+    /// One-time codes to sign in without the authenticator app, shown only this once
+    pub recovery_codes: Vec<String>,
+}
+
+/// Turn two-factor authentication on with the first code of the authenticator app
+#[openapi(tag = "Authentication")]
+#[post("/api/auth/2fa/confirm", format = "json", data = "<confirm>")]
+pub async fn confirm_two_factor(
+    db: &State<Database>,
+    confirm: SignedJson<ConfirmTwoFactor>,
+    ip: ClientIp,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> ApiResult<RecoveryCodes> {
+    let person = user.person;
+    let codes = db
+        .run(move |conn| {
+            let person_id = &person.id;
+            let secret = TwoFactorInteractor::get(conn, person_id)?
+                .filter(|secret| !secret.is_confirmed())
+                .ok_or_else(|| {
+                    ApiError::Conflict("No two-factor enrollment in progress".to_string())
+                })?;
+            let attempt = Attempt::start(conn, &person.email, ip.0)?;
+            let Some(step) = two_factor::matching_step(&secret.secret, &confirm.code)? else {
+                return Err(attempt.failed(conn, invalid_code(person_id)));
+            };
+            let (codes, rows) = two_factor::new_recovery_codes(person_id);
+            if !TwoFactorInteractor::confirm(conn, person_id, step, &rows)? {
+                return Err(attempt.failed(conn, invalid_code(person_id)));
+            }
+            attempt.succeeded(conn)?;
+            Ok(codes)
+        })
+        .await??;
+    Ok(Json(RecoveryCodes {
+        recovery_codes: codes,
+    }))
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct DisableTwoFactor {
+    /// Current code of the authenticator app
+    pub code: Option<String>,
+    /// One of the recovery codes, instead of `code`
+    pub recovery_code: Option<String>,
+}
+
+/// Turn two-factor authentication off, unless it is mandatory for the user
+#[openapi(tag = "Authentication")]
+#[post("/api/auth/2fa/disable", format = "json", data = "<disable>")]
+pub async fn disable_two_factor(
+    db: &State<Database>,
+    disable: SignedJson<DisableTwoFactor>,
+    ip: ClientIp,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> ApiResult<Message> {
+    if two_factor::required_for(&user.person, user.permissions.as_ref()) {
+        return Err(ApiError::Forbidden(
+            "Two-factor authentication is mandatory for your account".to_string(),
+        ));
+    }
+    let person = user.person;
+    db.run(move |conn| {
+        let secret = TwoFactorInteractor::get(conn, &person.id)?
+            .filter(|secret| secret.is_confirmed())
+            .ok_or_else(|| ApiError::not_found("Two-factor authentication"))?;
+        // Throttled like signing in, or a stolen session could guess its way to turning it off
+        let attempt = Attempt::start(conn, &person.email, ip.0)?;
+        check_second_factor(
+            conn,
+            &attempt,
+            &secret,
+            disable.code.as_deref(),
+            disable.recovery_code.as_deref(),
+        )?;
+        attempt.succeeded(conn)?;
+        TwoFactorInteractor::disable(conn, &person.id)?;
+        Ok::<_, ApiError>(())
+    })
+    .await??;
+    Ok(Json(Message::ok("Two-factor authentication disabled")))
+}
+
+/// Reset the two-factor authentication of a person who lost their authenticator app and
+/// recovery codes. If it is mandatory for them, they enroll again at their next sign-in.
+#[openapi(tag = "Authentication")]
+#[delete("/api/person/<person_id>/2fa")]
+pub async fn reset_two_factor(
+    db: &State<Database>,
+    person_id: String,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> ApiResult<Message> {
+    user.require(Permission::AdminPanel)?;
+
+    let removed = db
+        .run(move |conn| TwoFactorInteractor::disable(conn, &person_id))
+        .await??;
+    if removed == 0 {
+        return Err(ApiError::not_found("Two-factor authentication"));
+    }
+    Ok(Json(Message::ok("Two-factor authentication reset")))
+}
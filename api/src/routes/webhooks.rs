@@ -0,0 +1,205 @@
+use db::interactions::webhook::WebhookInteractor;
+use db::models::{Webhook, WebhookChanges, WebhookDelivery};
+use rocket::serde::json::Json;
+use rocket::{State, delete, get, patch, post};
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::access::Permission;
+use crate::auth::guard::{ApiKey, CurrentUser};
+use crate::auth::signed::SignedJson;
+use crate::error::{ApiError, ApiResult};
+use crate::models::{Database, Message};
+use crate::webhooks::EVENTS;
+
+/// Only http(s) URLs can be called
+fn check_url(url: &str) -> Result<(), ApiError> {
+    if url.starts_with("https://") || url.starts_with("http://") {
+        return Ok(());
+    }
+    Err(ApiError::Unprocessable(format!(
+        "Webhook URL must start with http:// or https://, got '{}'",
+        url
+    )))
+}
+
+fn check_events(events: &[String]) -> Result<(), ApiError> {
+    if events.is_empty() {
+        return Err(ApiError::Unprocessable(
+            "Subscribe to at least one event".to_string(),
+        ));
+    }
+    match events
+        .iter()
+        .find(|event| *event != "*" && !EVENTS.contains(&event.as_str()))
+    {
+        Some(unknown) => Err(ApiError::Unprocessable(format!(
+            "Unknown event '{}', expected one of {} or *",
+            unknown,
+            EVENTS.join(", ")
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// List the webhooks, without their secrets
+#[openapi(tag = "Webhooks")]
+#[get("/api/webhook")]
+pub async fn get_webhooks(
+    db: &State<Database>,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> ApiResult<Vec<Webhook>> {
+    user.require(Permission::AdminPanel)?;
+
+    let webhooks = db.run(WebhookInteractor::get).await??;
+    Ok(Json(webhooks))
+}
+
+/// Get a single webhook by ID
+#[openapi(tag = "Webhooks")]
+#[get("/api/webhook/<webhook_id>")]
+pub async fn get_webhook(
+    db: &State<Database>,
+    webhook_id: String,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> ApiResult<Webhook> {
+    user.require(Permission::AdminPanel)?;
+
+    let webhook = db
+        .run(move |conn| WebhookInteractor::get_by_id(conn, &webhook_id))
+        .await?
+        .map_err(|_| ApiError::not_found("Webhook"))?;
+    Ok(Json(webhook))
+}
+
+/// Body of `POST /api/webhook`
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CreateWebhook {
+    pub url: String,
+    /// `entry-created`, `person-registered`, `password-reset`, or `*` for all of them
+    pub events: Vec<String>,
+}
+
+/// A new webhook along with the secret its deliveries are signed with
+#[derive(Serialize, JsonSchema)]
+pub struct CreatedWebhook {
+    #[serde(flatten)]
+    pub webhook: Webhook,
+    /// Only shown here, keep it to verify `X-Syn-Webhook-Signature`
+    pub secret: String,
+}
+
+/// Register a webhook
+#[openapi(tag = "Webhooks")]
+#[post("/api/webhook", format = "json", data = "<webhook>")]
+pub async fn create_webhook(
+    db: &State<Database>,
+    webhook: SignedJson<CreateWebhook>,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> ApiResult<CreatedWebhook> {
+    user.require(Permission::AdminPanel)?;
+
+    check_url(&webhook.url)?;
+    check_events(&webhook.events)?;
+    let webhook = Webhook::new(&webhook.url, &webhook.events);
+    let created = db
+        .run(move |conn| WebhookInteractor::new(conn, &webhook).map(|_| webhook))
+        .await??;
+    Ok(Json(CreatedWebhook {
+        secret: created.secret.clone(),
+        webhook: created,
+    }))
+}
+
+/// Body of `PATCH /api/webhook/<id>`; only the fields present are changed
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct PatchWebhook {
+    url: Option<String>,
+    events: Option<Vec<String>>,
+    /// Inactive webhooks receive nothing
+    active: Option<bool>,
+}
+
+/// Change the URL, events or state of a webhook
+#[openapi(tag = "Webhooks")]
+#[patch("/api/webhook/<webhook_id>", format = "json", data = "<webhook>")]
+pub async fn patch_webhook(
+    db: &State<Database>,
+    webhook_id: String,
+    webhook: SignedJson<PatchWebhook>,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> ApiResult<Webhook> {
+    user.require(Permission::AdminPanel)?;
+
+    let webhook = webhook.0;
+    if let Some(url) = &webhook.url {
+        check_url(url)?;
+    }
+    if let Some(events) = &webhook.events {
+        check_events(events)?;
+    }
+    let changes = WebhookChanges {
+        url: webhook.url,
+        events: webhook.events.map(|events| events.join(" ")),
+        active: webhook.active,
+    };
+    let updated = db
+        .run(move |conn| {
+            if WebhookInteractor::patch(conn, &webhook_id, &changes)? == 0 {
+                return Err(ApiError::not_found("Webhook"));
+            }
+            Ok(WebhookInteractor::get_by_id(conn, &webhook_id)?)
+        })
+        .await??;
+    Ok(Json(updated))
+}
+
+/// Delete a webhook and its delivery log
+#[openapi(tag = "Webhooks")]
+#[delete("/api/webhook/<webhook_id>")]
+pub async fn delete_webhook(
+    db: &State<Database>,
+    webhook_id: String,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> ApiResult<Message> {
+    user.require(Permission::AdminPanel)?;
+
+    let deleted = db
+        .run(move |conn| WebhookInteractor::delete(conn, &webhook_id))
+        .await??;
+    match deleted {
+        0 => Err(ApiError::not_found("Webhook")),
+        _ => Ok(Json(Message::ok("Webhook deleted"))),
+    }
+}
+
+/// The latest delivery attempts of a webhook, newest first
+#[openapi(tag = "Webhooks")]
+#[get("/api/webhook/<webhook_id>/deliveries?<limit>")]
+pub async fn get_webhook_deliveries(
+    db: &State<Database>,
+    webhook_id: String,
+    limit: Option<i64>,
+    user: CurrentUser,
+    _api_key: ApiKey,
+) -> ApiResult<Vec<WebhookDelivery>> {
+    user.require(Permission::AdminPanel)?;
+
+    let limit = limit.unwrap_or(50).clamp(1, 500);
+    let deliveries = db
+        .run(move |conn| {
+            WebhookInteractor::get_by_id(conn, &webhook_id)
+                .map_err(|_| ApiError::not_found("Webhook"))?;
+            Ok::<_, ApiError>(WebhookInteractor::get_deliveries(conn, &webhook_id, limit)?)
+        })
+        .await??;
+    Ok(Json(deliveries))
+}
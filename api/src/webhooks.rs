@@ -0,0 +1,308 @@
+use std::env;
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+use db::interactions::webhook::WebhookInteractor;
+use db::models::{Webhook, WebhookDelivery};
+use hmac::{Hmac, Mac};
+use log::{debug, error, warn};
+use rocket::tokio::{
+    self,
+    sync::mpsc::{self, error::TrySendError},
+};
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::models::Database;
+
+pub const ENTRY_CREATED: &str = "entry-created";
+pub const PERSON_REGISTERED: &str = "person-registered";
+pub const PASSWORD_RESET: &str = "password-reset";
+/// Every event a webhook can subscribe to
+pub const EVENTS: &[&str] = &[ENTRY_CREATED, PERSON_REGISTERED, PASSWORD_RESET];
+
+/// How long an endpoint gets to answer a single attempt
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a claimed attempt may take before it is considered lost and made again
+const CLAIM_LEASE: Duration = Duration::from_secs(60);
+
+/// Pending attempts started at once
+const CLAIM_BATCH: i64 = 100;
+
+/// How often pending attempts are checked for being due
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Events waiting to be queued; more are dropped until the delivery task catches up
+const QUEUE_CAPACITY: usize = 1024;
+
+struct Job {
+    event: &'static str,
+    occurred_at: NaiveDateTime,
+    data: serde_json::Value,
+}
+
+/// Body POSTed to the webhook URLs
+#[derive(Serialize)]
+struct Payload<'a> {
+    event: &'a str,
+    occurred_at: NaiveDateTime,
+    data: &'a serde_json::Value,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RetryPolicy {
+    max_attempts: u32,
+    /// Wait before the first retry, doubled for each one after it
+    base_delay: Duration,
+}
+
+impl RetryPolicy {
+    fn from_env() -> Self {
+        let max_attempts = env::var("SYN_WEBHOOK_MAX_ATTEMPTS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(5);
+        let base_delay = env::var("SYN_WEBHOOK_RETRY_DELAY")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(5);
+        RetryPolicy {
+            max_attempts: u32::max(max_attempts, 1),
+            base_delay: Duration::from_secs(base_delay),
+        }
+    }
+
+    fn delay_after(&self, attempt: u32) -> Duration {
+        self.base_delay * 2u32.saturating_pow(attempt - 1)
+    }
+}
+
+/// Hands events over to a background task that POSTs them to the subscribed webhooks
+pub struct WebhookDispatcher {
+    sender: mpsc::Sender<Job>,
+}
+
+impl WebhookDispatcher {
+    /// Starts the delivery task, so it has to be called inside the Tokio runtime. Attempts
+    /// still pending from before a restart are picked up again.
+    pub fn start(db: Database) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(run(db, receiver, RetryPolicy::from_env()));
+        WebhookDispatcher { sender }
+    }
+
+    /// Queues `event` for every active webhook subscribed to it, without waiting for the deliveries
+    pub fn dispatch(&self, event: &'static str, data: &impl Serialize) {
+        let data = match serde_json::to_value(data) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Failed to serialize {} webhook payload: {}", event, e);
+                return;
+            }
+        };
+        let job = Job {
+            event,
+            occurred_at: chrono::Utc::now().naive_utc(),
+            data,
+        };
+        match self.sender.try_send(job) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                error!("Webhook queue is full, dropping {} event", event)
+            }
+            Err(TrySendError::Closed(_)) => {
+                error!("Webhook dispatcher is gone, dropping {} event", event)
+            }
+        }
+    }
+}
+
+async fn run(db: Database, mut receiver: mpsc::Receiver<Job>, policy: RetryPolicy) {
+    // Following redirects would let an endpoint send the signed payload to another host
+    let client = match reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Failed to build the webhook HTTP client: {}", e);
+            return;
+        }
+    };
+
+    loop {
+        for (delivery, webhook) in claim_due(&db).await {
+            tokio::spawn(deliver(
+                db.clone(),
+                client.clone(),
+                webhook,
+                delivery,
+                policy,
+            ));
+        }
+
+        tokio::select! {
+            job = receiver.recv() => match job {
+                Some(job) => queue(&db, job).await,
+                None => break,
+            },
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+        }
+    }
+}
+
+/// Stores the first attempt at delivering `job` to each subscribed webhook, due right away
+async fn queue(db: &Database, job: Job) {
+    let event = job.event;
+    let payload = Payload {
+        event,
+        occurred_at: job.occurred_at,
+        data: &job.data,
+    };
+    let body = match serde_json::to_string(&payload) {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Failed to serialize {} webhook payload: {}", event, e);
+            return;
+        }
+    };
+    let queued = db
+        .run(move |conn| {
+            let webhooks = WebhookInteractor::get_subscribed(conn, event)?;
+            let now = chrono::Utc::now().naive_utc();
+            for webhook in &webhooks {
+                let delivery_id = uuid::Uuid::new_v4().to_string();
+                let delivery =
+                    WebhookDelivery::pending(&webhook.id, &delivery_id, event, 1, &body, now);
+                WebhookInteractor::record_delivery(conn, &delivery)?;
+            }
+            Ok::<_, diesel::result::Error>(webhooks.len())
+        })
+        .await;
+    match queued {
+        Ok(Ok(count)) => debug!("Queued {} for {} webhooks", event, count),
+        Ok(Err(e)) => error!("Failed to queue {} for the webhooks: {}", event, e),
+        Err(_) => error!("No database connection to queue {} for the webhooks", event),
+    }
+}
+
+/// The pending attempts that are due, claimed for as long as an attempt can take
+async fn claim_due(db: &Database) -> Vec<(WebhookDelivery, Webhook)> {
+    let lease_until = chrono::Utc::now().naive_utc()
+        + chrono::Duration::from_std(CLAIM_LEASE).unwrap_or_default();
+    match db
+        .run(move |conn| WebhookInteractor::claim_due(conn, lease_until, CLAIM_BATCH))
+        .await
+    {
+        Ok(Ok(due)) => due,
+        Ok(Err(e)) => {
+            error!("Failed to load the pending webhook deliveries: {}", e);
+            Vec::new()
+        }
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Hex HMAC-SHA256 of `v1\n<timestamp>\n<delivery id>\n<body>` keyed with the webhook secret
+fn sign(secret: &str, timestamp: &str, delivery_id: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(format!("v1\n{timestamp}\n{delivery_id}\n{body}").as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Makes one claimed attempt, and stores the next one if it failed and may be retried
+async fn deliver(
+    db: Database,
+    client: reqwest::Client,
+    webhook: Webhook,
+    delivery: WebhookDelivery,
+    policy: RetryPolicy,
+) {
+    let body = delivery.body.clone().unwrap_or_default();
+    let (status_code, error) = if webhook.active {
+        let timestamp = chrono::Utc::now().timestamp().to_string();
+        let signature = sign(&webhook.secret, &timestamp, &delivery.delivery_id, &body);
+        let response = client
+            .post(&webhook.url)
+            .header("Content-Type", "application/json")
+            .header("X-Syn-Webhook-Event", &delivery.event)
+            .header("X-Syn-Webhook-Id", &delivery.delivery_id)
+            .header("X-Syn-Webhook-Timestamp", &timestamp)
+            .header("X-Syn-Webhook-Signature", format!("v1={signature}"))
+            .body(body.clone())
+            .send()
+            .await;
+        match response {
+            Ok(response) if response.status().is_success() => {
+                (Some(response.status().as_u16()), None)
+            }
+            Ok(response) => (
+                Some(response.status().as_u16()),
+                Some(format!("Endpoint answered {}", response.status())),
+            ),
+            Err(e) => (None, Some(e.to_string())),
+        }
+    } else {
+        (None, Some("Webhook was deactivated".to_string()))
+    };
+
+    let attempt = delivery.attempt.max(1) as u32;
+    let retry = match &error {
+        Some(_) if webhook.active && attempt < policy.max_attempts => {
+            let delay = policy.delay_after(attempt);
+            warn!(
+                "Webhook {} attempt {} failed ({}), retrying in {:?}",
+                webhook.id,
+                attempt,
+                error.as_deref().unwrap_or_default(),
+                delay
+            );
+            let due = chrono::Utc::now().naive_utc()
+                + chrono::Duration::from_std(delay).unwrap_or_default();
+            Some(WebhookDelivery::pending(
+                &webhook.id,
+                &delivery.delivery_id,
+                &delivery.event,
+                delivery.attempt + 1,
+                &body,
+                due,
+            ))
+        }
+        Some(_) => {
+            error!(
+                "Giving up on delivering {} to webhook {} after {} attempts",
+                delivery.event, webhook.id, attempt
+            );
+            None
+        }
+        None => {
+            debug!(
+                "Delivered {} to webhook {} on attempt {}",
+                delivery.event, webhook.id, attempt
+            );
+            None
+        }
+    };
+
+    let d_id = delivery.id.clone();
+    let logged = db
+        .run(move |conn| {
+            WebhookInteractor::finish_attempt(
+                conn,
+                &d_id,
+                status_code.map(i32::from),
+                error.as_deref(),
+                retry.as_ref(),
+            )
+        })
+        .await;
+    if let Ok(Err(e)) = logged {
+        error!(
+            "Failed to log webhook delivery {}: {}",
+            delivery.delivery_id, e
+        );
+    }
+}
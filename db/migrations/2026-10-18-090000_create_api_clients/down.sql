@@ -0,0 +1,2 @@
+-- Drop API clients table
+DROP TABLE api_clients;
@@ -0,0 +1,4 @@
+-- Bumped on every update, used for ETags and If-Match
+ALTER TABLE person ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE entries ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE permissions ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
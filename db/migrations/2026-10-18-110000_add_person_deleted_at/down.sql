@@ -0,0 +1,2 @@
+-- Drop the soft-delete marker
+ALTER TABLE person DROP COLUMN deleted_at;
@@ -0,0 +1,2 @@
+-- Set when a person is soft-deleted, NULL while active
+ALTER TABLE person ADD COLUMN deleted_at TIMESTAMP;
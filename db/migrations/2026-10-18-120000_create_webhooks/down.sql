@@ -0,0 +1,3 @@
+-- Drop the webhook tables
+DROP TABLE webhook_deliveries;
+DROP TABLE webhooks;
@@ -0,0 +1,24 @@
+-- Endpoints notified of attendance and account events
+CREATE TABLE webhooks (
+    id CHAR(36) PRIMARY KEY NOT NULL,
+    url VARCHAR(500) NOT NULL,
+    secret VARCHAR(64) NOT NULL,
+    events VARCHAR(255) NOT NULL,
+    active BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- One row per delivery attempt; retries of the same event share the delivery_id
+CREATE TABLE webhook_deliveries (
+    id CHAR(36) PRIMARY KEY NOT NULL,
+    webhook_id CHAR(36) NOT NULL REFERENCES webhooks(id),
+    delivery_id CHAR(36) NOT NULL,
+    event VARCHAR(50) NOT NULL,
+    attempt INTEGER NOT NULL,
+    status_code INTEGER NULL,
+    error TEXT NULL,
+    success BOOLEAN NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX webhook_deliveries_webhook_id ON webhook_deliveries (webhook_id);
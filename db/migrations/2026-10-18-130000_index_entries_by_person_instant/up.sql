@@ -0,0 +1,2 @@
+-- Serves the latest-entry-per-person lookups of the occupancy report
+CREATE INDEX entries_person_id_instant ON entries (person_id, instant);
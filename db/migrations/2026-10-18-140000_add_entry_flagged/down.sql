@@ -0,0 +1,2 @@
+-- Drop the review flag
+ALTER TABLE entries DROP COLUMN flagged;
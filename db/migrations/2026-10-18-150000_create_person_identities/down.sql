@@ -0,0 +1,11 @@
+-- Move the Google identities back into person.google_id and drop the rest
+ALTER TABLE person ADD COLUMN google_id VARCHAR(100) NULL;
+
+UPDATE person
+SET google_id = (
+    SELECT subject
+    FROM person_identities
+    WHERE person_identities.person_id = person.id AND provider = 'google'
+);
+
+DROP TABLE person_identities;
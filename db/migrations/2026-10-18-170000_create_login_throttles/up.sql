@@ -0,0 +1,10 @@
+-- Recent failed sign-ins of an account (by email, so unknown ones are throttled alike) or a client address
+CREATE TABLE login_throttles (
+    scope VARCHAR(10) NOT NULL,
+    subject VARCHAR(255) NOT NULL,
+    failures INTEGER NOT NULL DEFAULT 0,
+    last_failure_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    -- No attempt is checked before this time
+    locked_until TIMESTAMP NULL,
+    PRIMARY KEY (scope, subject)
+);
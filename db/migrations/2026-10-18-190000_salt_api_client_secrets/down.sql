@@ -0,0 +1,2 @@
+-- Restore the column name; rotate the clients again after reverting
+ALTER TABLE api_clients RENAME COLUMN secret_salt TO secret_hash;
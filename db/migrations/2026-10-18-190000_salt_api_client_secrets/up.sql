@@ -0,0 +1,3 @@
+-- Client secrets are now derived from this salt and SYN_API_CLIENT_PEPPER, which stays out of
+-- the database. The old digests don't derive the same secrets: rotate existing clients.
+ALTER TABLE api_clients RENAME COLUMN secret_hash TO secret_salt;
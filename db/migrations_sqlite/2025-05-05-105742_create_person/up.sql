@@ -0,0 +1,9 @@
+-- Your SQL goes here
+CREATE TABLE person (
+    id CHAR(36) PRIMARY KEY NOT NULL,
+    name VARCHAR(100) NOT NULL,
+    surname VARCHAR(100) NOT NULL,
+    email VARCHAR(100) UNIQUE NOT NULL,
+    role VARCHAR(20) NOT NULL,
+    password_hash VARCHAR(100) NOT NULL
+);
\ No newline at end of file
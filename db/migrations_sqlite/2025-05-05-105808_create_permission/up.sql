@@ -0,0 +1,10 @@
+CREATE TABLE permissions(
+    id CHAR(36) PRIMARY KEY NOT NULL,
+    person_id CHAR(36) NOT NULL,
+    dashboard BOOLEAN NOT NULL DEFAULT FALSE,
+    see_self_history BOOLEAN NOT NULL DEFAULT FALSE,
+    see_others_history BOOLEAN NOT NULL DEFAULT FALSE,
+    admin_panel BOOLEAN NOT NULL DEFAULT FALSE,
+    edit_permissions BOOLEAN NOT NULL DEFAULT FALSE,
+    FOREIGN KEY (person_id) REFERENCES Person (id)
+);
\ No newline at end of file
@@ -0,0 +1,12 @@
+-- Revert password_hash back to NOT NULL
+CREATE TABLE person_new (
+    id CHAR(36) PRIMARY KEY NOT NULL,
+    name VARCHAR(100) NOT NULL,
+    surname VARCHAR(100) NOT NULL,
+    email VARCHAR(100) UNIQUE NOT NULL,
+    role VARCHAR(20) NOT NULL,
+    password_hash VARCHAR(100) NOT NULL
+);
+INSERT INTO person_new SELECT id, name, surname, email, role, password_hash FROM person;
+DROP TABLE person;
+ALTER TABLE person_new RENAME TO person;
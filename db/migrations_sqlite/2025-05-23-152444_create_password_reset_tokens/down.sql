@@ -0,0 +1,2 @@
+-- Drop password reset tokens table
+DROP TABLE password_reset_tokens;
\ No newline at end of file
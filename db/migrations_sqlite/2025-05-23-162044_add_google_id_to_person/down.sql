@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE person DROP COLUMN google_id;
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE person ADD COLUMN google_id VARCHAR(100) NULL;
\ No newline at end of file
@@ -0,0 +1,11 @@
+-- Named API clients, each with its own signing secret and scopes
+CREATE TABLE api_clients (
+    id CHAR(36) PRIMARY KEY NOT NULL,
+    name VARCHAR(100) UNIQUE NOT NULL,
+    secret_hash VARCHAR(64) NOT NULL,
+    scopes VARCHAR(255) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used_at TIMESTAMP NULL,
+    expires_at TIMESTAMP NULL,
+    revoked BOOLEAN NOT NULL DEFAULT FALSE
+);
@@ -0,0 +1,4 @@
+-- Drop the version columns
+ALTER TABLE permissions DROP COLUMN version;
+ALTER TABLE entries DROP COLUMN version;
+ALTER TABLE person DROP COLUMN version;
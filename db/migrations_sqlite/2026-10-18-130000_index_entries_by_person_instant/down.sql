@@ -0,0 +1,2 @@
+-- Drop the index
+DROP INDEX entries_person_id_instant;
@@ -0,0 +1,2 @@
+-- Entries accepted out of sequence, or inserted to correct one, for an admin to review
+ALTER TABLE entries ADD COLUMN flagged BOOLEAN NOT NULL DEFAULT FALSE;
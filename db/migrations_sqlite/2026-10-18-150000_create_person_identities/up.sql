@@ -0,0 +1,19 @@
+-- Accounts at external identity providers (Google, Microsoft, ...) a person can sign in with
+CREATE TABLE person_identities (
+    id CHAR(36) PRIMARY KEY NOT NULL,
+    person_id CHAR(36) NOT NULL REFERENCES person(id),
+    provider VARCHAR(50) NOT NULL,
+    subject VARCHAR(255) NOT NULL,
+    email VARCHAR(100) NULL,
+    linked_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (provider, subject),
+    UNIQUE (person_id, provider)
+);
+
+-- The Google accounts linked so far become identities of the google provider
+INSERT INTO person_identities (id, person_id, provider, subject, email)
+SELECT lower(hex(randomblob(4)) || '-' || hex(randomblob(2)) || '-4' || substr(hex(randomblob(2)), 2) || '-' || substr('89ab', 1 + abs(random()) % 4, 1) || substr(hex(randomblob(2)), 2) || '-' || hex(randomblob(6))), id, 'google', google_id, email
+FROM person
+WHERE google_id IS NOT NULL;
+
+ALTER TABLE person DROP COLUMN google_id;
@@ -0,0 +1,3 @@
+-- Drop the two-factor tables
+DROP TABLE recovery_codes;
+DROP TABLE totp_secrets;
@@ -0,0 +1,19 @@
+-- TOTP secret of a person, enabled once the first code from their authenticator app is confirmed
+CREATE TABLE totp_secrets (
+    person_id CHAR(36) PRIMARY KEY NOT NULL REFERENCES person(id),
+    secret VARCHAR(64) NOT NULL,
+    confirmed_at TIMESTAMP NULL,
+    -- Time step of the last accepted code, so a code can't be used twice
+    last_used_step BIGINT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- SHA-256 digests of the one-time recovery codes
+CREATE TABLE recovery_codes (
+    id CHAR(36) PRIMARY KEY NOT NULL,
+    person_id CHAR(36) NOT NULL REFERENCES person(id),
+    code_hash CHAR(64) NOT NULL,
+    used_at TIMESTAMP NULL
+);
+
+CREATE INDEX recovery_codes_person_id ON recovery_codes (person_id);
@@ -0,0 +1,2 @@
+-- Drop the sign-in throttling table
+DROP TABLE login_throttles;
@@ -0,0 +1,2 @@
+-- Drop the refresh token table
+DROP TABLE refresh_tokens;
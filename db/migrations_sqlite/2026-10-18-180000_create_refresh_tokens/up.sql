@@ -0,0 +1,14 @@
+-- Refresh tokens handed out, so they can be rotated on use and revoked
+CREATE TABLE refresh_tokens (
+    -- The `jti` of the token
+    id CHAR(36) PRIMARY KEY NOT NULL,
+    -- Tokens rotated from the same sign-in; reusing a rotated one revokes them all
+    family_id CHAR(36) NOT NULL,
+    person_id CHAR(36) NOT NULL REFERENCES person(id),
+    expires_at TIMESTAMP NOT NULL,
+    used_at TIMESTAMP NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX refresh_tokens_family_id ON refresh_tokens (family_id);
+CREATE INDEX refresh_tokens_person_id ON refresh_tokens (person_id);
@@ -0,0 +1,4 @@
+-- Pending attempts are lost
+DROP INDEX webhook_deliveries_next_attempt_at;
+ALTER TABLE webhook_deliveries DROP COLUMN next_attempt_at;
+ALTER TABLE webhook_deliveries DROP COLUMN body;
@@ -0,0 +1,6 @@
+-- Attempts are stored before they are made, so retries survive a restart. A row with
+-- next_attempt_at set is pending, and keeps the body to send until then.
+ALTER TABLE webhook_deliveries ADD COLUMN body TEXT NULL;
+ALTER TABLE webhook_deliveries ADD COLUMN next_attempt_at TIMESTAMP NULL;
+
+CREATE INDEX webhook_deliveries_next_attempt_at ON webhook_deliveries (next_attempt_at);
@@ -0,0 +1,236 @@
+use crate::DbConnection;
+use crate::date::{self, DateRange};
+use crate::interactions::entries::{Action, EntriesInteractor, EntryFilter};
+use crate::models::Entry;
+use chrono::NaiveDateTime;
+use chrono_tz::Tz;
+use diesel::QueryResult;
+use log::warn;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+pub mod occupancy;
+pub mod summary;
+
+#[derive(Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Anomaly {
+    /// An Enter followed by another Enter; the first stay never got an exit
+    DoubleEnter,
+    /// An Exit with no Enter before it
+    ExitWithoutEnter,
+    /// The stay was not closed on the day it started
+    OpenAtMidnight,
+}
+
+/// Time spent inside between an Enter and the following Exit
+#[derive(Serialize, JsonSchema, Debug, Clone)]
+pub struct Stay {
+    pub enter: Option<NaiveDateTime>,
+    pub exit: Option<NaiveDateTime>,
+    /// Only set when both ends are known
+    pub duration_seconds: Option<i64>,
+    /// Still inside right now, which is not an anomaly
+    pub ongoing: bool,
+    pub anomalies: Vec<Anomaly>,
+}
+
+impl Stay {
+    fn new(enter: Option<NaiveDateTime>, exit: Option<NaiveDateTime>, tz: Tz) -> Self {
+        let duration_seconds = match (enter, exit) {
+            (Some(enter), Some(exit)) => Some((exit - enter).num_seconds()),
+            _ => None,
+        };
+        let mut anomalies = Vec::new();
+        if let (Some(enter), Some(exit)) = (enter, exit)
+            && date::local_date(exit, tz) > date::local_date(enter, tz)
+        {
+            anomalies.push(Anomaly::OpenAtMidnight);
+        }
+        Stay {
+            enter,
+            exit,
+            duration_seconds,
+            ongoing: false,
+            anomalies,
+        }
+    }
+}
+
+/// Folds entries into stays, ordered by instant. `now` decides whether a trailing open stay
+/// is ongoing (entered today) or was left open past midnight, days being those of `tz`.
+pub fn fold(entries: &[Entry], now: NaiveDateTime, tz: Tz) -> Vec<Stay> {
+    let mut entries: Vec<&Entry> = entries.iter().collect();
+    entries.sort_by_key(|entry| entry.instant);
+
+    let mut stays = Vec::new();
+    let mut open: Option<NaiveDateTime> = None;
+    for entry in entries {
+        let action: Action = match entry.action.parse() {
+            Ok(action) => action,
+            Err(e) => {
+                warn!("Skipping entry {}: {}", entry.id, e);
+                continue;
+            }
+        };
+        match (action, open) {
+            (Action::Enter, Some(enter)) => {
+                let mut stay = Stay::new(Some(enter), None, tz);
+                stay.anomalies.push(Anomaly::DoubleEnter);
+                if date::local_date(entry.instant, tz) > date::local_date(enter, tz) {
+                    stay.anomalies.push(Anomaly::OpenAtMidnight);
+                }
+                stays.push(stay);
+                open = Some(entry.instant);
+            }
+            (Action::Enter, None) => open = Some(entry.instant),
+            (Action::Exit, Some(enter)) => {
+                stays.push(Stay::new(Some(enter), Some(entry.instant), tz));
+                open = None;
+            }
+            (Action::Exit, None) => {
+                let mut stay = Stay::new(None, Some(entry.instant), tz);
+                stay.anomalies.push(Anomaly::ExitWithoutEnter);
+                stays.push(stay);
+            }
+        }
+    }
+
+    if let Some(enter) = open {
+        let mut stay = Stay::new(Some(enter), None, tz);
+        if date::local_date(enter, tz) < date::local_date(now, tz) {
+            stay.anomalies.push(Anomaly::OpenAtMidnight);
+        } else {
+            stay.ongoing = true;
+        }
+        stays.push(stay);
+    }
+
+    stays
+}
+
+/// Stays of a person with entries inside `range`. A stay entered before `from`
+/// and closed inside the range is kept whole instead of showing up as an orphan exit.
+pub fn stays(conn: &mut DbConnection, person_id: &str, range: DateRange) -> QueryResult<Vec<Stay>> {
+    let mut entries = Vec::new();
+    if let Some(from) = range.from {
+        match EntriesInteractor::get_last_before(conn, person_id, from) {
+            Ok(entry) if entry.action.parse() == Ok(Action::Enter) => entries.push(entry),
+            Ok(_) | Err(diesel::result::Error::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let filter = EntryFilter {
+        person_id: Some(person_id.to_string()),
+        ..EntryFilter::range(range)
+    };
+    entries.extend(EntriesInteractor::filter(conn, &filter)?);
+
+    Ok(fold(
+        &entries,
+        chrono::Utc::now().naive_utc(),
+        date::timezone(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2026, 7, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    fn entry(action: Action, instant: NaiveDateTime) -> Entry {
+        Entry::new_with_timestamp("person", action, instant)
+    }
+
+    #[test]
+    fn empty_input_has_no_stays() {
+        assert!(fold(&[], at(1, 12, 0), Tz::UTC).is_empty());
+    }
+
+    #[test]
+    fn pairs_enters_with_exits_in_order() {
+        let entries = [
+            entry(Action::Exit, at(1, 12, 0)),
+            entry(Action::Enter, at(1, 8, 0)),
+        ];
+        let stays = fold(&entries, at(1, 18, 0), Tz::UTC);
+        assert_eq!(stays.len(), 1);
+        assert_eq!(stays[0].duration_seconds, Some(4 * 3600));
+        assert!(stays[0].anomalies.is_empty());
+        assert!(!stays[0].ongoing);
+    }
+
+    #[test]
+    fn two_enters_in_a_row() {
+        let entries = [
+            entry(Action::Enter, at(1, 8, 0)),
+            entry(Action::Enter, at(1, 9, 0)),
+            entry(Action::Exit, at(1, 10, 0)),
+        ];
+        let stays = fold(&entries, at(1, 18, 0), Tz::UTC);
+        assert_eq!(stays.len(), 2);
+        assert_eq!(stays[0].enter, Some(at(1, 8, 0)));
+        assert_eq!(stays[0].exit, None);
+        assert_eq!(stays[0].anomalies, [Anomaly::DoubleEnter]);
+        assert_eq!(stays[1].duration_seconds, Some(3600));
+        assert!(stays[1].anomalies.is_empty());
+    }
+
+    #[test]
+    fn exit_without_enter() {
+        let stays = fold(&[entry(Action::Exit, at(1, 10, 0))], at(1, 18, 0), Tz::UTC);
+        assert_eq!(stays.len(), 1);
+        assert_eq!(stays[0].enter, None);
+        assert_eq!(stays[0].duration_seconds, None);
+        assert_eq!(stays[0].anomalies, [Anomaly::ExitWithoutEnter]);
+    }
+
+    #[test]
+    fn stay_open_across_midnight() {
+        let entries = [
+            entry(Action::Enter, at(1, 22, 0)),
+            entry(Action::Exit, at(2, 1, 0)),
+        ];
+        let stays = fold(&entries, at(2, 12, 0), Tz::UTC);
+        assert_eq!(stays[0].duration_seconds, Some(3 * 3600));
+        assert_eq!(stays[0].anomalies, [Anomaly::OpenAtMidnight]);
+    }
+
+    #[test]
+    fn midnight_is_the_local_one() {
+        // 21:00 to 23:00 UTC is 23:00 to 01:00 in Madrid during summer time
+        let entries = [
+            entry(Action::Enter, at(1, 21, 0)),
+            entry(Action::Exit, at(1, 23, 0)),
+        ];
+        assert!(
+            fold(&entries, at(2, 12, 0), Tz::UTC)[0]
+                .anomalies
+                .is_empty()
+        );
+        assert_eq!(
+            fold(&entries, at(2, 12, 0), Tz::Europe__Madrid)[0].anomalies,
+            [Anomaly::OpenAtMidnight]
+        );
+    }
+
+    #[test]
+    fn trailing_enter_is_ongoing_only_on_its_day() {
+        let entries = [entry(Action::Enter, at(1, 21, 0))];
+        let today = fold(&entries, at(1, 21, 30), Tz::UTC);
+        assert!(today[0].ongoing);
+        assert!(today[0].anomalies.is_empty());
+
+        // Past local midnight (22:00 UTC in Madrid) it was left open
+        let next_day = fold(&entries, at(1, 22, 30), Tz::Europe__Madrid);
+        assert!(!next_day[0].ongoing);
+        assert_eq!(next_day[0].anomalies, [Anomaly::OpenAtMidnight]);
+    }
+}
@@ -0,0 +1,123 @@
+use crate::DbConnection;
+use crate::date::local_date;
+use crate::interactions::entries::{Action, EntriesInteractor};
+use crate::models::Role;
+use chrono::NaiveDateTime;
+use chrono_tz::Tz;
+use diesel::QueryResult;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Someone whose latest entry is an Enter
+#[derive(Serialize, JsonSchema, Debug, Clone)]
+pub struct Occupant {
+    pub person_id: String,
+    pub name: String,
+    pub surname: String,
+    /// Instant of that Enter
+    pub since: NaiveDateTime,
+    /// Entered on an earlier day and never checked out, so probably not inside anymore
+    pub stale: bool,
+}
+
+#[derive(Serialize, JsonSchema, Debug, Clone)]
+pub struct RoleOccupancy {
+    pub role: String,
+    pub count: usize,
+    /// Ordered by surname
+    pub occupants: Vec<Occupant>,
+}
+
+#[derive(Serialize, JsonSchema, Debug, Clone)]
+pub struct Occupancy {
+    pub at: NaiveDateTime,
+    pub total: usize,
+    /// Every role, including the ones with nobody inside
+    pub roles: Vec<RoleOccupancy>,
+}
+
+/// Who is inside at `now`, grouped by role, going by the entries up to `now`. Occupants are
+/// stale when they entered before the local day of `now` in `tz`.
+pub fn occupancy(conn: &mut DbConnection, now: NaiveDateTime, tz: Tz) -> QueryResult<Occupancy> {
+    let today = local_date(now, tz);
+    let mut roles: BTreeMap<String, Vec<Occupant>> = [Role::Admin, Role::Profesor, Role::Alumno]
+        .iter()
+        .map(|role| (role.to_string(), Vec::new()))
+        .collect();
+
+    for (entry, person) in EntriesInteractor::get_latest_per_person(conn, now)? {
+        if entry.action.parse() != Ok(Action::Enter) {
+            continue;
+        }
+        roles.entry(person.role).or_default().push(Occupant {
+            person_id: person.id,
+            name: person.name,
+            surname: person.surname,
+            since: entry.instant,
+            stale: local_date(entry.instant, tz) < today,
+        });
+    }
+
+    let roles: Vec<RoleOccupancy> = roles
+        .into_iter()
+        .map(|(role, occupants)| RoleOccupancy {
+            role,
+            count: occupants.len(),
+            occupants,
+        })
+        .collect();
+    Ok(Occupancy {
+        at: now,
+        total: roles.iter().map(|role| role.count).sum(),
+        roles,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interactions::person::PersonInteractor;
+    use crate::models::{Entry, Person};
+    use diesel::{Connection, SqliteConnection};
+
+    fn at(hour: u32) -> NaiveDateTime {
+        chrono::NaiveDate::from_ymd_opt(2026, 10, 18)
+            .unwrap()
+            .and_hms_opt(hour, 0, 0)
+            .unwrap()
+    }
+
+    fn person_with(conn: &mut DbConnection, email: &str, entries: &[(Action, u32)]) -> String {
+        let person = Person::new("Ada", "Lovelace", email, Role::Alumno, None);
+        PersonInteractor::new(conn, &person).unwrap();
+        for (action, hour) in entries {
+            let entry = Entry::new_with_timestamp(&person.id, *action, at(*hour));
+            EntriesInteractor::new(conn, &entry).unwrap();
+        }
+        person.id
+    }
+
+    #[test]
+    fn entries_after_now_are_ignored() {
+        let mut conn = DbConnection::Sqlite(SqliteConnection::establish(":memory:").unwrap());
+        crate::migrations::run_pending(&mut conn).unwrap();
+        let inside = person_with(
+            &mut conn,
+            "ada@example.com",
+            &[(Action::Enter, 8), (Action::Exit, 10)],
+        );
+        person_with(&mut conn, "grace@example.com", &[(Action::Enter, 10)]);
+
+        let occupancy = occupancy(&mut conn, at(9), Tz::UTC).unwrap();
+        assert_eq!(occupancy.total, 1);
+        let occupant = &occupancy
+            .roles
+            .iter()
+            .find(|role| role.role == "Alumno")
+            .unwrap()
+            .occupants[0];
+        assert_eq!(occupant.person_id, inside);
+        assert_eq!(occupant.since, at(8));
+    }
+}
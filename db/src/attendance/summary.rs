@@ -0,0 +1,289 @@
+use super::Stay;
+use crate::DbConnection;
+use crate::date::{self, DateRange};
+use crate::interactions::entries::{Action, EntriesInteractor};
+use crate::interactions::person::PersonInteractor;
+use crate::models::{Entry, Person, Role};
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime};
+use chrono_tz::Tz;
+use diesel::QueryResult;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Day,
+    Week,
+    Month,
+}
+
+impl std::str::FromStr for Period {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "day" => Ok(Period::Day),
+            "week" => Ok(Period::Week),
+            "month" => Ok(Period::Month),
+            _ => Err(format!("Invalid period: {s}")),
+        }
+    }
+}
+
+impl Period {
+    /// First day of the period containing `day`; weeks start on Monday
+    fn start_of(&self, day: NaiveDate) -> NaiveDate {
+        match self {
+            Period::Day => day,
+            Period::Week => day - Duration::days(day.weekday().num_days_from_monday() as i64),
+            Period::Month => day.with_day(1).unwrap(),
+        }
+    }
+}
+
+#[derive(Serialize, JsonSchema, Debug, Clone)]
+pub struct PeriodTotal {
+    /// First day of the period, in `SYN_TIMEZONE`
+    pub start: NaiveDate,
+    pub seconds: i64,
+}
+
+#[derive(Serialize, JsonSchema, Debug, Clone)]
+pub struct Summary {
+    pub person_id: String,
+    pub period: Period,
+    pub totals: Vec<PeriodTotal>,
+    pub total_seconds: i64,
+    /// Stays left out because one of their ends is unknown
+    pub incomplete_stays: usize,
+}
+
+/// Adds up the time inside per period. Stays are split at the midnights of `tz` so each day
+/// only gets the part between its first and last instant, and are clipped to `range`, whose
+/// bare dates have to be days in `tz` too (see [`DateRange::parse`]) for the first and last
+/// days to be whole. Ongoing stays count until `now`.
+pub fn summarize(
+    stays: &[Stay],
+    period: Period,
+    range: DateRange,
+    now: NaiveDateTime,
+    tz: Tz,
+) -> (Vec<PeriodTotal>, usize) {
+    let mut totals: BTreeMap<NaiveDate, i64> = BTreeMap::new();
+    let mut incomplete = 0;
+
+    for stay in stays {
+        let (mut start, mut end) = match (stay.enter, stay.exit) {
+            (Some(enter), Some(exit)) => (enter, exit),
+            (Some(enter), None) if stay.ongoing => (enter, now),
+            _ => {
+                incomplete += 1;
+                continue;
+            }
+        };
+        if let Some(from) = range.from {
+            start = start.max(from);
+        }
+        if let Some(to) = range.to {
+            end = end.min(to);
+        }
+
+        while start < end {
+            let day = date::local_date(start, tz);
+            let next_midnight = date::local_midnight(day + Duration::days(1), tz);
+            let segment_end = end.min(next_midnight);
+            *totals.entry(period.start_of(day)).or_default() += (segment_end - start).num_seconds();
+            start = segment_end;
+        }
+    }
+
+    let totals = totals
+        .into_iter()
+        .map(|(start, seconds)| PeriodTotal { start, seconds })
+        .collect();
+    (totals, incomplete)
+}
+
+pub fn person_summary(
+    conn: &mut DbConnection,
+    person_id: &str,
+    period: Period,
+    range: DateRange,
+) -> QueryResult<Summary> {
+    let stays = super::stays(conn, person_id, range)?;
+    Ok(summary_of(person_id, &stays, period, range))
+}
+
+fn summary_of(person_id: &str, stays: &[Stay], period: Period, range: DateRange) -> Summary {
+    let (totals, incomplete_stays) = summarize(
+        stays,
+        period,
+        range,
+        chrono::Utc::now().naive_utc(),
+        date::timezone(),
+    );
+
+    Summary {
+        person_id: person_id.to_string(),
+        period,
+        total_seconds: totals.iter().map(|total| total.seconds).sum(),
+        totals,
+        incomplete_stays,
+    }
+}
+
+/// Summaries of every person with `role`, ordered by surname. The entries of all of them
+/// are loaded at once rather than per person.
+pub fn role_summary(
+    conn: &mut DbConnection,
+    role: &Role,
+    period: Period,
+    range: DateRange,
+) -> QueryResult<Vec<(Person, Summary)>> {
+    let persons = PersonInteractor::get_by_role(conn, role)?;
+    let mut entries: HashMap<String, Vec<Entry>> = HashMap::new();
+    for entry in EntriesInteractor::get_by_role_with_lead_in(conn, role, range)? {
+        // Like `stays`, only a stay entered before the range is carried into it
+        if range.from.is_some_and(|from| entry.instant < from)
+            && entry.action.parse() != Ok(Action::Enter)
+        {
+            continue;
+        }
+        entries
+            .entry(entry.person_id.clone())
+            .or_default()
+            .push(entry);
+    }
+
+    let now = chrono::Utc::now().naive_utc();
+    let tz = date::timezone();
+    Ok(persons
+        .into_iter()
+        .map(|person| {
+            let stays = super::fold(
+                entries
+                    .get(&person.id)
+                    .map(Vec::as_slice)
+                    .unwrap_or_default(),
+                now,
+                tz,
+            );
+            let summary = summary_of(&person.id, &stays, period, range);
+            (person, summary)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(day: u32, hour: u32) -> NaiveDateTime {
+        // 2026-10-12 is a Monday
+        NaiveDate::from_ymd_opt(2026, 10, day)
+            .unwrap()
+            .and_hms_opt(hour, 0, 0)
+            .unwrap()
+    }
+
+    fn stay(enter: NaiveDateTime, exit: NaiveDateTime) -> Stay {
+        Stay::new(Some(enter), Some(exit), Tz::UTC)
+    }
+
+    fn totals(stays: &[Stay], period: Period, tz: Tz) -> Vec<(NaiveDate, i64)> {
+        summarize(stays, period, DateRange::default(), at(31, 0), tz)
+            .0
+            .into_iter()
+            .map(|total| (total.start, total.seconds))
+            .collect()
+    }
+
+    fn day(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2026, 10, day).unwrap()
+    }
+
+    #[test]
+    fn splits_stays_at_midnight() {
+        let stays = [stay(at(12, 22), at(13, 2))];
+        assert_eq!(
+            totals(&stays, Period::Day, Tz::UTC),
+            [(day(12), 2 * 3600), (day(13), 2 * 3600)]
+        );
+    }
+
+    #[test]
+    fn splits_days_at_the_local_midnight() {
+        // 20:00 to 23:00 UTC is 22:00 to 01:00 in Madrid before the switch to winter time
+        let stays = [stay(at(12, 20), at(12, 23))];
+        assert_eq!(totals(&stays, Period::Day, Tz::UTC), [(day(12), 3 * 3600)]);
+        assert_eq!(
+            totals(&stays, Period::Day, Tz::Europe__Madrid),
+            [(day(12), 2 * 3600), (day(13), 3600)]
+        );
+    }
+
+    #[test]
+    fn groups_days_into_weeks_starting_on_monday() {
+        let stays = [
+            stay(at(12, 8), at(12, 10)),
+            stay(at(18, 8), at(18, 9)),
+            // Sunday night into the next week
+            stay(at(18, 23), at(19, 1)),
+        ];
+        assert_eq!(
+            totals(&stays, Period::Week, Tz::UTC),
+            [(day(12), 4 * 3600), (day(19), 3600)]
+        );
+    }
+
+    #[test]
+    fn clips_to_the_range_and_counts_incomplete_stays() {
+        let stays = [
+            stay(at(12, 8), at(12, 12)),
+            Stay::new(Some(at(13, 8)), None, Tz::UTC),
+        ];
+        let range = DateRange {
+            from: Some(at(12, 10)),
+            to: None,
+        };
+        let (totals, incomplete) = summarize(&stays, Period::Day, range, at(31, 0), Tz::UTC);
+        assert_eq!(totals.len(), 1);
+        assert_eq!(totals[0].seconds, 2 * 3600);
+        assert_eq!(incomplete, 1);
+    }
+
+    #[test]
+    fn date_only_ranges_cover_local_days() {
+        let madrid = Tz::Europe__Madrid;
+        let stays = [
+            // 01:00 to 03:00 on the 12th in Madrid
+            stay(at(11, 23), at(12, 1)),
+            // 23:00 on the 12th to 01:00 on the 13th in Madrid
+            stay(at(12, 21), at(12, 23)),
+        ];
+        let range = DateRange::parse(Some("2026-10-12"), Some("2026-10-12"), madrid).unwrap();
+        let (totals, _) = summarize(&stays, Period::Day, range, at(31, 0), madrid);
+        let totals: Vec<_> = totals
+            .into_iter()
+            .map(|total| (total.start, total.seconds))
+            .collect();
+        assert_eq!(totals, [(day(12), 3 * 3600)]);
+    }
+
+    #[test]
+    fn ongoing_stays_count_until_now() {
+        let mut ongoing = Stay::new(Some(at(12, 8)), None, Tz::UTC);
+        ongoing.ongoing = true;
+        let (totals, incomplete) = summarize(
+            &[ongoing],
+            Period::Day,
+            DateRange::default(),
+            at(12, 11),
+            Tz::UTC,
+        );
+        assert_eq!(totals[0].seconds, 3 * 3600);
+        assert_eq!(incomplete, 0);
+    }
+}
@@ -21,3 +21,70 @@ pub fn check_hash(password: &str, hash: &str) -> bool {
         .verify_password(password.as_bytes(), &parsed_hash)
         .is_ok()
 }
+
+/// Like [`check_hash`], but takes as long when there is no hash to check against (unknown
+/// account, or one without password), so response times don't tell which accounts exist
+pub fn check_password(password: &str, hash: Option<&str>) -> bool {
+    static DUMMY_HASH: std::sync::LazyLock<String> =
+        std::sync::LazyLock::new(|| to_hash(&random_token(32)));
+    match hash {
+        Some(hash) => check_hash(password, hash),
+        None => {
+            check_hash(password, &DUMMY_HASH);
+            false
+        }
+    }
+}
+
+/// Random alphanumeric string, used for reset tokens and API client secrets
+pub fn random_token(len: usize) -> String {
+    use rand::Rng;
+    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
+                           abcdefghijklmnopqrstuvwxyz\
+                           0123456789";
+
+    let mut rng = rand::thread_rng();
+
+    (0..len)
+        .map(|_| {
+            let idx = rng.gen_range(0..CHARSET.len());
+            CHARSET[idx] as char
+        })
+        .collect()
+}
+
+/// Signing secret of an API client: the hex HMAC-SHA256 of its stored salt keyed with the
+/// server's pepper. The pepper never enters the database, so a copy of it can't sign requests.
+pub fn api_client_secret(pepper: &str, salt: &str) -> String {
+    use hmac::{Hmac, Mac};
+    let mut mac =
+        Hmac::<sha2::Sha256>::new_from_slice(pepper.as_bytes()).expect("HMAC takes any key size");
+    mac.update(salt.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Hex encoded SHA-256 of a recovery code, ignoring case and dashes. The codes are random,
+/// so unlike passwords a fast digest is enough and lets them be looked up directly.
+pub fn recovery_code_hash(code: &str) -> String {
+    use sha2::{Digest, Sha256};
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hex::encode(Sha256::digest(normalized.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn api_client_secret_depends_on_pepper_and_salt() {
+        let secret = api_client_secret("pepper", "salt");
+        assert_eq!(secret, api_client_secret("pepper", "salt"));
+        assert_eq!(secret.len(), 64);
+        assert_ne!(secret, api_client_secret("other pepper", "salt"));
+        assert_ne!(secret, api_client_secret("pepper", "other salt"));
+    }
+}
@@ -1,4 +1,37 @@
+use chrono::{NaiveDate, NaiveDateTime, TimeZone};
+use chrono_tz::Tz;
 use log::warn;
+use std::sync::LazyLock;
+
+/// Time zone attendance is split into days on, from `SYN_TIMEZONE` as an IANA name such as
+/// `Europe/Madrid`. Instants are stored in UTC; UTC is used when it is unset or unknown.
+pub fn timezone() -> Tz {
+    static TIMEZONE: LazyLock<Tz> = LazyLock::new(|| match std::env::var("SYN_TIMEZONE") {
+        Ok(name) => name.parse().unwrap_or_else(|_| {
+            warn!("Unknown SYN_TIMEZONE {}, using UTC", name);
+            Tz::UTC
+        }),
+        Err(_) => Tz::UTC,
+    });
+    *TIMEZONE
+}
+
+/// The day a stored (UTC) instant falls on in `tz`
+pub fn local_date(instant: NaiveDateTime, tz: Tz) -> NaiveDate {
+    tz.from_utc_datetime(&instant).date_naive()
+}
+
+/// The stored (UTC) instant `day` starts at in `tz`, at the first hour that exists when a
+/// daylight saving change skips midnight
+pub fn local_midnight(day: NaiveDate, tz: Tz) -> NaiveDateTime {
+    (0..24)
+        .find_map(|hour| {
+            tz.from_local_datetime(&day.and_hms_opt(hour, 0, 0)?)
+                .earliest()
+        })
+        .map(|start| start.naive_utc())
+        .unwrap_or_else(|| day.and_hms_opt(0, 0, 0).unwrap())
+}
 
 pub fn parse(date: &str) -> Option<chrono::NaiveDateTime> {
     if let Some(date_time) = parse_with_time(date) {
@@ -51,3 +84,194 @@ pub fn parse_date(date: &str) -> Option<chrono::NaiveDate> {
     warn!("Unable to parse date: {}", date);
     None
 }
+
+/// Parses the strict ISO forms accepted in query strings: `2023-05-12` or `2023-05-12T14:30:00`.
+/// A time is taken as UTC, like the stored instants. A bare date is a day in `tz` and starts at
+/// its local midnight; used as an upper bound (`end_of_range`) it means the whole day, i.e. up
+/// to the next local midnight.
+pub fn parse_iso(date: &str, end_of_range: bool, tz: Tz) -> Option<chrono::NaiveDateTime> {
+    if let Ok(date_time) = chrono::NaiveDateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S") {
+        return Some(date_time);
+    }
+    let day = parse_iso_date(date)?;
+    let day = if end_of_range { day.succ_opt()? } else { day };
+    Some(local_midnight(day, tz))
+}
+
+pub fn parse_iso_date(date: &str) -> Option<chrono::NaiveDate> {
+    chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()
+}
+
+/// Half-open range `from <= instant < to`; a missing bound is open
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DateRange {
+    pub from: Option<chrono::NaiveDateTime>,
+    pub to: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DateRangeError {
+    Invalid { param: &'static str, value: String },
+    Reversed,
+}
+
+impl std::error::Error for DateRangeError {}
+
+impl std::fmt::Display for DateRangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DateRangeError::Invalid { param, value } => write!(
+                f,
+                "Invalid '{}' date '{}', expected YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS",
+                param, value
+            ),
+            DateRangeError::Reversed => write!(f, "'from' must be before 'to'"),
+        }
+    }
+}
+
+impl DateRange {
+    /// Parses ISO `from`/`to` query values, bare dates being days in `tz`, see [`parse_iso`]
+    pub fn parse(from: Option<&str>, to: Option<&str>, tz: Tz) -> Result<Self, DateRangeError> {
+        let bound = |param, value: Option<&str>, end_of_range| match value {
+            None => Ok(None),
+            Some(value) => parse_iso(value, end_of_range, tz).map(Some).ok_or_else(|| {
+                DateRangeError::Invalid {
+                    param,
+                    value: value.to_string(),
+                }
+            }),
+        };
+        let range = DateRange {
+            from: bound("from", from, false)?,
+            to: bound("to", to, true)?,
+        };
+        if let (Some(from), Some(to)) = (range.from, range.to)
+            && from >= to
+        {
+            return Err(DateRangeError::Reversed);
+        }
+        Ok(range)
+    }
+
+    /// The whole of `day` in `tz`, from its local midnight up to the next one
+    pub fn day(day: chrono::NaiveDate, tz: Tz) -> Self {
+        DateRange {
+            from: Some(local_midnight(day, tz)),
+            to: day.succ_opt().map(|next| local_midnight(next, tz)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2023, 5, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn open_range_without_bounds() {
+        assert_eq!(
+            DateRange::parse(None, None, Tz::UTC),
+            Ok(DateRange::default())
+        );
+    }
+
+    #[test]
+    fn bare_dates_cover_whole_days() {
+        let range = DateRange::parse(Some("2023-05-12"), Some("2023-05-12"), Tz::UTC).unwrap();
+        assert_eq!(range.from, Some(at(12, 0, 0)));
+        assert_eq!(range.to, Some(at(13, 0, 0)));
+    }
+
+    #[test]
+    fn bare_dates_are_local_days() {
+        // Madrid is UTC+2 in May: its 12th runs from 22:00 UTC on the 11th
+        let range =
+            DateRange::parse(Some("2023-05-12"), Some("2023-05-12"), Tz::Europe__Madrid).unwrap();
+        assert_eq!(range.from, Some(at(11, 22, 0)));
+        assert_eq!(range.to, Some(at(12, 22, 0)));
+        assert_eq!(
+            DateRange::day(at(12, 0, 0).date(), Tz::Europe__Madrid),
+            range
+        );
+    }
+
+    #[test]
+    fn times_stay_utc_in_any_zone() {
+        let range = DateRange::parse(
+            Some("2023-05-12T08:30:00"),
+            Some("2023-05-12"),
+            Tz::Europe__Madrid,
+        )
+        .unwrap();
+        assert_eq!(range.from, Some(at(12, 8, 30)));
+        assert_eq!(range.to, Some(at(12, 22, 0)));
+    }
+
+    #[test]
+    fn times_are_kept() {
+        let range = DateRange::parse(
+            Some("2023-05-12T08:30:00"),
+            Some("2023-05-12T14:30:00"),
+            Tz::UTC,
+        );
+        assert_eq!(
+            range,
+            Ok(DateRange {
+                from: Some(at(12, 8, 30)),
+                to: Some(at(12, 14, 30)),
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_other_formats() {
+        assert_eq!(
+            DateRange::parse(Some("12/05/2023"), None, Tz::UTC),
+            Err(DateRangeError::Invalid {
+                param: "from",
+                value: "12/05/2023".to_string(),
+            })
+        );
+        assert!(matches!(
+            DateRange::parse(None, Some("2023-05-12 14:30:00"), Tz::UTC),
+            Err(DateRangeError::Invalid { param: "to", .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_reversed_and_empty_ranges() {
+        assert_eq!(
+            DateRange::parse(Some("2023-05-13"), Some("2023-05-12"), Tz::UTC),
+            Err(DateRangeError::Reversed)
+        );
+        assert_eq!(
+            DateRange::parse(
+                Some("2023-05-12T10:00:00"),
+                Some("2023-05-12T10:00:00"),
+                Tz::UTC
+            ),
+            Err(DateRangeError::Reversed)
+        );
+    }
+
+    #[test]
+    fn local_days_follow_the_time_zone() {
+        // Madrid is UTC+2 in May
+        assert_eq!(local_date(at(12, 22, 30), Tz::UTC), at(12, 0, 0).date());
+        assert_eq!(
+            local_date(at(12, 22, 30), Tz::Europe__Madrid),
+            at(13, 0, 0).date()
+        );
+        assert_eq!(
+            local_midnight(at(13, 0, 0).date(), Tz::Europe__Madrid),
+            at(12, 22, 0)
+        );
+    }
+}
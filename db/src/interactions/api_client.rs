@@ -0,0 +1,112 @@
+use crate::DbConnection;
+use crate::models::ApiClient;
+use crate::schema::api_clients;
+use diesel::prelude::*;
+use log::{debug, error, info};
+
+pub struct ApiClientInteractor;
+
+impl ApiClientInteractor {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(conn: &mut DbConnection, client: &ApiClient) -> QueryResult<usize> {
+        debug!("Creating API client: {}", client.name);
+        let result = match conn {
+            DbConnection::Sqlite(conn) => diesel::insert_into(api_clients::table)
+                .values(client)
+                .execute(conn),
+            DbConnection::Pg(conn) => diesel::insert_into(api_clients::table)
+                .values(client)
+                .execute(conn),
+        };
+
+        match &result {
+            Ok(_) => info!("Created API client {} with ID: {}", client.name, client.id),
+            Err(e) => error!("Failed to create API client {}: {}", client.name, e),
+        }
+
+        result
+    }
+
+    pub fn get(conn: &mut DbConnection) -> QueryResult<Vec<ApiClient>> {
+        match conn {
+            DbConnection::Sqlite(conn) => api_clients::table
+                .order(api_clients::created_at.asc())
+                .select(ApiClient::as_select())
+                .load(conn),
+            DbConnection::Pg(conn) => api_clients::table
+                .order(api_clients::created_at.asc())
+                .select(ApiClient::as_select())
+                .load(conn),
+        }
+    }
+
+    pub fn get_by_id(conn: &mut DbConnection, c_id: &str) -> QueryResult<ApiClient> {
+        match conn {
+            DbConnection::Sqlite(conn) => api_clients::table
+                .filter(api_clients::id.eq(c_id))
+                .select(ApiClient::as_select())
+                .first(conn),
+            DbConnection::Pg(conn) => api_clients::table
+                .filter(api_clients::id.eq(c_id))
+                .select(ApiClient::as_select())
+                .first(conn),
+        }
+    }
+
+    /// Records that the client just made an authenticated request
+    pub fn touch(conn: &mut DbConnection, c_id: &str) -> QueryResult<usize> {
+        let now = chrono::Utc::now().naive_utc();
+        match conn {
+            DbConnection::Sqlite(conn) => {
+                diesel::update(api_clients::table.filter(api_clients::id.eq(c_id)))
+                    .set(api_clients::last_used_at.eq(now))
+                    .execute(conn)
+            }
+            DbConnection::Pg(conn) => {
+                diesel::update(api_clients::table.filter(api_clients::id.eq(c_id)))
+                    .set(api_clients::last_used_at.eq(now))
+                    .execute(conn)
+            }
+        }
+    }
+
+    pub fn revoke(conn: &mut DbConnection, c_id: &str) -> QueryResult<usize> {
+        info!("Revoking API client with ID: {}", c_id);
+        match conn {
+            DbConnection::Sqlite(conn) => {
+                diesel::update(api_clients::table.filter(api_clients::id.eq(c_id)))
+                    .set(api_clients::revoked.eq(true))
+                    .execute(conn)
+            }
+            DbConnection::Pg(conn) => {
+                diesel::update(api_clients::table.filter(api_clients::id.eq(c_id)))
+                    .set(api_clients::revoked.eq(true))
+                    .execute(conn)
+            }
+        }
+    }
+
+    /// Replaces the client's secret and returns the new one
+    pub fn rotate(conn: &mut DbConnection, c_id: &str, pepper: &str) -> QueryResult<String> {
+        info!("Rotating secret of API client with ID: {}", c_id);
+        let secret_salt = crate::crypto::random_token(48);
+
+        let rows = match conn {
+            DbConnection::Sqlite(conn) => {
+                diesel::update(api_clients::table.filter(api_clients::id.eq(c_id)))
+                    .set(api_clients::secret_salt.eq(&secret_salt))
+                    .execute(conn)?
+            }
+            DbConnection::Pg(conn) => {
+                diesel::update(api_clients::table.filter(api_clients::id.eq(c_id)))
+                    .set(api_clients::secret_salt.eq(&secret_salt))
+                    .execute(conn)?
+            }
+        };
+
+        if rows == 0 {
+            return Err(diesel::result::Error::NotFound);
+        }
+        Ok(crate::crypto::api_client_secret(pepper, &secret_salt))
+    }
+}
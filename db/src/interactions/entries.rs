@@ -1,11 +1,40 @@
 use crate::DbConnection;
-use crate::date;
+use crate::date::DateRange;
+use crate::interactions::person::PersonInteractor;
+use crate::interactions::versioned_update;
 use crate::models;
+use crate::pagination::{Page, PageRequest, Sort, SortOrder, sort_columns};
 use diesel::prelude::*;
 use log::error;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
 pub struct EntriesInteractor {}
 
+// The boxed query type depends on the backend, so it is built once per match arm
+macro_rules! filtered_entries {
+    ($filter:expr) => {{
+        let filter: &EntryFilter = $filter;
+        let mut query = entries.into_boxed();
+        if let Some(from) = filter.range.from {
+            query = query.filter(instant.ge(from));
+        }
+        if let Some(to) = filter.range.to {
+            query = query.filter(instant.lt(to));
+        }
+        if let Some(p_id) = &filter.person_id {
+            query = query.filter(person_id.eq(p_id));
+        }
+        if let Some(req_action) = filter.action {
+            query = query.filter(action.eq(req_action.to_string()));
+        }
+        if let Some(req_flagged) = filter.flagged {
+            query = query.filter(flagged.eq(req_flagged));
+        }
+        query
+    }};
+}
+
 impl EntriesInteractor {
     #[allow(clippy::new_ret_no_self)]
     pub fn new(conn: &mut DbConnection, entries: &models::Entry) -> QueryResult<usize> {
@@ -20,6 +49,155 @@ impl EntriesInteractor {
         }
     }
 
+    /// Inserts `entry` unless it repeats the last action of its person (or is an Exit with no
+    /// entry before it), in which case `policy` decides. Corrections are inserted a millisecond
+    /// away from the entry they complete, so they never add time inside.
+    ///
+    /// The person is locked until the entry is in, so concurrent entries of one person are
+    /// checked one at a time. As they are stamped before that, one may land before an entry
+    /// that got in first: repeating the action of that later entry is out of sequence too, and
+    /// as nothing is missing before `entry`, `Autocorrect` only flags it.
+    pub fn create_in_sequence(
+        conn: &mut DbConnection,
+        mut entry: models::Entry,
+        policy: SequencePolicy,
+    ) -> QueryResult<SequencedEntry> {
+        conn.immediate_transaction(|conn| {
+            if PersonInteractor::get_for_update(conn, &entry.person_id)
+                .optional()?
+                .is_none()
+            {
+                return Ok(SequencedEntry::UnknownPerson);
+            }
+
+            let last = Self::get_last_before(conn, &entry.person_id, entry.instant).optional()?;
+            let last_action = last.as_ref().and_then(|last| last.action.parse().ok());
+            let action = entry.action.parse::<Action>().ok();
+            let follows_last = match action {
+                Some(Action::Enter) => last_action != Some(Action::Enter),
+                Some(Action::Exit) => last_action == Some(Action::Enter),
+                None => true,
+            };
+            let next = Self::get_first_from(conn, &entry.person_id, entry.instant).optional()?;
+            let repeated_next =
+                next.filter(|next| action.is_some() && next.action.parse().ok() == action);
+            if follows_last && repeated_next.is_none() {
+                Self::new(conn, &entry)?;
+                return Ok(SequencedEntry::Created {
+                    entry,
+                    correction: None,
+                });
+            }
+
+            let correction = match policy {
+                SequencePolicy::Reject if !follows_last => {
+                    return Ok(SequencedEntry::OutOfSequence { last });
+                }
+                SequencePolicy::Reject => {
+                    return Ok(SequencedEntry::OutOfSequence {
+                        last: repeated_next,
+                    });
+                }
+                SequencePolicy::Flag => None,
+                SequencePolicy::Autocorrect if follows_last => None,
+                // An Enter after an Enter closes the previous stay right away, an Exit
+                // without an Enter opens its stay right before it
+                SequencePolicy::Autocorrect => Some(match &last {
+                    Some(last) if last_action == Some(Action::Enter) => {
+                        models::Entry::new_with_timestamp(
+                            &entry.person_id,
+                            Action::Exit,
+                            last.instant + chrono::Duration::milliseconds(1),
+                        )
+                    }
+                    _ => models::Entry::new_with_timestamp(
+                        &entry.person_id,
+                        Action::Enter,
+                        entry.instant - chrono::Duration::milliseconds(1),
+                    ),
+                }),
+            };
+            let correction = correction.map(|correction| models::Entry {
+                flagged: true,
+                ..correction
+            });
+            if let Some(correction) = &correction {
+                Self::new(conn, correction)?;
+            }
+            entry.flagged = true;
+            Self::new(conn, &entry)?;
+            Ok(SequencedEntry::Created { entry, correction })
+        })
+    }
+
+    /// Applies `changes` unless they add an Enter after an Enter or an Exit after an Exit
+    /// (or a leading Exit) to the history of the person before or after the change. Histories
+    /// that are already out of sequence can still be edited, as long as it gets no worse.
+    /// Like [`create_in_sequence`](Self::create_in_sequence), the persons are locked meanwhile.
+    pub fn update_in_sequence(
+        conn: &mut DbConnection,
+        e_id: &str,
+        changes: &models::EntryChanges,
+        expected_versions: Option<&[i32]>,
+    ) -> QueryResult<SequencedUpdate> {
+        conn.immediate_transaction(|conn| {
+            let Some(current) = Self::get_by_id(conn, e_id).optional()? else {
+                return Ok(SequencedUpdate::Missed { exists: false });
+            };
+            // Its person may be deleted, which doesn't stop fixing its entries
+            PersonInteractor::get_for_update(conn, &current.person_id).optional()?;
+            if expected_versions.is_some_and(|expected| !expected.contains(&current.version)) {
+                return Ok(SequencedUpdate::Missed { exists: true });
+            }
+
+            let updated = models::Entry {
+                id: current.id.clone(),
+                person_id: changes
+                    .person_id
+                    .clone()
+                    .unwrap_or_else(|| current.person_id.clone()),
+                instant: changes.instant.unwrap_or(current.instant),
+                action: changes
+                    .action
+                    .clone()
+                    .unwrap_or_else(|| current.action.clone()),
+                version: current.version,
+                flagged: changes.flagged.unwrap_or(current.flagged),
+            };
+            if updated.person_id != current.person_id
+                && PersonInteractor::get_for_update(conn, &updated.person_id)
+                    .optional()?
+                    .is_none()
+            {
+                return Ok(SequencedUpdate::UnknownPerson);
+            }
+
+            let moved = updated.person_id != current.person_id
+                || updated.instant != current.instant
+                || updated.action != current.action;
+            let mut persons = vec![&current.person_id];
+            if updated.person_id != current.person_id {
+                persons.push(&updated.person_id);
+            }
+            for p_id in persons.into_iter().filter(|_| moved) {
+                let before = Self::get_by_p_id(conn, p_id)?;
+                let mut after: Vec<&models::Entry> =
+                    before.iter().filter(|entry| entry.id != e_id).collect();
+                if updated.person_id == *p_id {
+                    after.push(&updated);
+                }
+                if sequence_breaks(after) > sequence_breaks(before.iter().collect()) {
+                    return Ok(SequencedUpdate::OutOfSequence);
+                }
+            }
+
+            if Self::patch(conn, e_id, changes, Some(&[current.version]))? == 0 {
+                return Ok(SequencedUpdate::Missed { exists: true });
+            }
+            Ok(SequencedUpdate::Updated(Self::get_by_id(conn, e_id)?))
+        })
+    }
+
     pub fn get(conn: &mut DbConnection) -> QueryResult<Vec<models::Entry>> {
         use crate::schema::entries::dsl::*;
         match conn {
@@ -48,19 +226,23 @@ impl EntriesInteractor {
             DbConnection::Pg(conn) => entries.filter(id.eq(e_id)).first(conn),
         }
     }
-    pub fn update(
+    /// Updates only the columns set in `changes`
+    pub fn patch(
         conn: &mut DbConnection,
         e_id: &str,
-        entries_changes: &models::Entry,
+        changes: &models::EntryChanges,
+        expected_versions: Option<&[i32]>,
     ) -> QueryResult<usize> {
         use crate::schema::entries::dsl::*;
         match conn {
-            DbConnection::Sqlite(conn) => diesel::update(entries.filter(id.eq(e_id)))
-                .set(entries_changes)
-                .execute(conn),
-            DbConnection::Pg(conn) => diesel::update(entries.filter(id.eq(e_id)))
-                .set(entries_changes)
-                .execute(conn),
+            DbConnection::Sqlite(conn) => {
+                versioned_update!(entries.filter(id.eq(e_id)), expected_versions, changes)
+                    .execute(conn)
+            }
+            DbConnection::Pg(conn) => {
+                versioned_update!(entries.filter(id.eq(e_id)), expected_versions, changes)
+                    .execute(conn)
+            }
         }
     }
     pub fn delete(conn: &mut DbConnection, e_id: &str) -> QueryResult<usize> {
@@ -71,90 +253,674 @@ impl EntriesInteractor {
         }
     }
 
-    pub fn get_by_date(conn: &mut DbConnection, date: &str) -> QueryResult<Vec<models::Entry>> {
+    /// Entries matching every set field of `filter`, oldest first, an Enter before an Exit on
+    /// the same instant
+    pub fn filter(
+        conn: &mut DbConnection,
+        filter: &EntryFilter,
+    ) -> QueryResult<Vec<models::Entry>> {
         use crate::schema::entries::dsl::*;
-        let req_instant = match date::parse_with_time(&format!("{} 23:59:59", date)) {
-            Some(i) => i,
-            None => {
-                error!("Failed to parse date: {}", date);
-                return Err(diesel::result::Error::NotFound);
-            }
-        };
-        match conn {
-            DbConnection::Sqlite(conn) => entries
-                .filter(instant.le(req_instant))
+        let result = match conn {
+            DbConnection::Sqlite(conn) => filtered_entries!(filter)
+                .order((instant.asc(), action.asc(), id.asc()))
                 .select(models::Entry::as_select())
                 .load(conn),
-            DbConnection::Pg(conn) => entries
-                .filter(instant.le(req_instant))
+            DbConnection::Pg(conn) => filtered_entries!(filter)
+                .order((instant.asc(), action.asc(), id.asc()))
                 .select(models::Entry::as_select())
                 .load(conn),
+        };
+        if let Err(e) = &result {
+            error!("Failed to filter entries with {:?}: {}", filter, e);
         }
+        result
     }
 
-    pub fn get_by_date_and_p_id(
+    /// One page of the entries matching `filter`
+    pub fn page(
         conn: &mut DbConnection,
-        date: &str,
-        p_id: &str,
-    ) -> QueryResult<Vec<models::Entry>> {
+        filter: &EntryFilter,
+        sort: Sort<EntrySort>,
+        page: &PageRequest,
+    ) -> QueryResult<Page<models::Entry>> {
         use crate::schema::entries::dsl::*;
 
-        let req_instant = match date::parse_with_time(&format!("{} 23:59:59", date)) {
-            Some(i) => i,
-            None => {
-                error!("Failed to parse date: {}", date);
-                return Err(diesel::result::Error::NotFound);
-            }
-        };
-        match conn {
-            DbConnection::Sqlite(conn) => entries
-                .filter(instant.le(req_instant).and(person_id.eq(p_id)))
-                .select(models::Entry::as_select())
-                .load(conn),
-            DbConnection::Pg(conn) => entries
-                .filter(instant.le(req_instant).and(person_id.eq(p_id)))
-                .select(models::Entry::as_select())
-                .load(conn),
+        macro_rules! sorted {
+            () => {{
+                let query = filtered_entries!(filter);
+                let query = match (sort.column, sort.order) {
+                    (EntrySort::Instant, SortOrder::Asc) => query.order(instant.asc()),
+                    (EntrySort::Instant, SortOrder::Desc) => query.order(instant.desc()),
+                    (EntrySort::Action, SortOrder::Asc) => query.order(action.asc()),
+                    (EntrySort::Action, SortOrder::Desc) => query.order(action.desc()),
+                    (EntrySort::PersonId, SortOrder::Asc) => query.order(person_id.asc()),
+                    (EntrySort::PersonId, SortOrder::Desc) => query.order(person_id.desc()),
+                };
+                // Tie-break on the primary key so pages don't overlap
+                query
+                    .then_order_by(id.asc())
+                    .limit(page.limit)
+                    .offset(page.offset)
+                    .select(models::Entry::as_select())
+            }};
         }
+
+        let (items, total) = match conn {
+            DbConnection::Sqlite(conn) => (
+                sorted!().load(conn)?,
+                filtered_entries!(filter).count().get_result(conn)?,
+            ),
+            DbConnection::Pg(conn) => (
+                sorted!().load(conn)?,
+                filtered_entries!(filter).count().get_result(conn)?,
+            ),
+        };
+        Ok(Page::new(items, total, page))
     }
 
-    pub fn get_by_action(
+    /// The earliest entry of a person at or after `from`
+    pub fn get_first_from(
         conn: &mut DbConnection,
-        req_action: &str,
-    ) -> QueryResult<Vec<models::Entry>> {
+        p_id: &str,
+        from: chrono::NaiveDateTime,
+    ) -> QueryResult<models::Entry> {
         use crate::schema::entries::dsl::*;
         match conn {
             DbConnection::Sqlite(conn) => entries
-                .filter(action.eq(req_action))
+                .filter(person_id.eq(p_id).and(instant.ge(from)))
+                .order((instant.asc(), action.asc()))
                 .select(models::Entry::as_select())
-                .load(conn),
+                .first(conn),
             DbConnection::Pg(conn) => entries
-                .filter(action.eq(req_action))
+                .filter(person_id.eq(p_id).and(instant.ge(from)))
+                .order((instant.asc(), action.asc()))
                 .select(models::Entry::as_select())
-                .load(conn),
+                .first(conn),
         }
     }
 
-    pub fn get_by_action_and_p_id(
+    /// The most recent entry of a person strictly before `before`
+    pub fn get_last_before(
         conn: &mut DbConnection,
-        req_action: &str,
         p_id: &str,
-    ) -> QueryResult<Vec<models::Entry>> {
+        before: chrono::NaiveDateTime,
+    ) -> QueryResult<models::Entry> {
         use crate::schema::entries::dsl::*;
         match conn {
             DbConnection::Sqlite(conn) => entries
-                .filter(action.eq(req_action).and(person_id.eq(p_id)))
+                .filter(person_id.eq(p_id).and(instant.lt(before)))
+                .order((instant.desc(), action.desc(), id.desc()))
                 .select(models::Entry::as_select())
-                .load(conn),
+                .first(conn),
             DbConnection::Pg(conn) => entries
-                .filter(action.eq(req_action).and(person_id.eq(p_id)))
+                .filter(person_id.eq(p_id).and(instant.lt(before)))
+                .order((instant.desc(), action.desc(), id.desc()))
                 .select(models::Entry::as_select())
-                .load(conn),
+                .first(conn),
+        }
+    }
+
+    /// Entries inside `range` of the active persons with `role`, each person's preceded by
+    /// their last one before `range.from`, in a single query. Ordered by person, then like the
+    /// entries of a single person: by instant, an Enter before an Exit on the same one.
+    pub fn get_by_role_with_lead_in(
+        conn: &mut DbConnection,
+        req_role: &models::Role,
+        range: DateRange,
+    ) -> QueryResult<Vec<models::Entry>> {
+        use crate::schema::{entries, person};
+        use diesel::dsl::{exists, not};
+        let later = diesel::alias!(entries as later);
+        let req_role = req_role.to_string();
+
+        macro_rules! with_lead_in {
+            () => {{
+                let mut query = entries::table
+                    .inner_join(person::table)
+                    .filter(person::role.eq(&req_role))
+                    .filter(person::deleted_at.is_null())
+                    .into_boxed();
+                if let Some(to) = range.to {
+                    query = query.filter(entries::instant.lt(to));
+                }
+                if let Some(from) = range.from {
+                    // Inside the range, or the last one before it
+                    query = query.filter(
+                        entries::instant
+                            .ge(from)
+                            .or(entries::instant.lt(from).and(not(exists(
+                                later
+                                    .filter(later.field(entries::person_id).eq(entries::person_id))
+                                    .filter(later.field(entries::instant).lt(from))
+                                    // Later by (instant, action, id), like the order below
+                                    .filter(
+                                        later
+                                            .field(entries::instant)
+                                            .gt(entries::instant)
+                                            .or(later
+                                                .field(entries::instant)
+                                                .eq(entries::instant)
+                                                .and(
+                                                    later
+                                                        .field(entries::action)
+                                                        .gt(entries::action),
+                                                ))
+                                            .or(later
+                                                .field(entries::instant)
+                                                .eq(entries::instant)
+                                                .and(
+                                                    later
+                                                        .field(entries::action)
+                                                        .eq(entries::action),
+                                                )
+                                                .and(later.field(entries::id).gt(entries::id))),
+                                    ),
+                            )))),
+                    );
+                }
+                query
+                    .order((
+                        entries::person_id.asc(),
+                        entries::instant.asc(),
+                        entries::action.asc(),
+                        entries::id.asc(),
+                    ))
+                    .select(models::Entry::as_select())
+            }};
+        }
+
+        let result = match conn {
+            DbConnection::Sqlite(conn) => with_lead_in!().load(conn),
+            DbConnection::Pg(conn) => with_lead_in!().load(conn),
+        };
+        if let Err(e) = &result {
+            error!("Failed to retrieve the entries of role {}: {}", req_role, e);
+        }
+        result
+    }
+
+    /// The latest entry up to `now` of every active person who has any, with the person,
+    /// ordered by surname; entries stamped after `now` are left out. An entry is the latest
+    /// when no entry of the same person is later, so it is a single query on both backends.
+    /// On the same instant an Exit counts as later than an Enter (`"Exit" > "Enter"`), so a
+    /// zero-length stay leaves the person outside; only entries repeating the action at the
+    /// same instant fall back to the (random) id.
+    pub fn get_latest_per_person(
+        conn: &mut DbConnection,
+        now: chrono::NaiveDateTime,
+    ) -> QueryResult<Vec<(models::Entry, models::Person)>> {
+        use crate::schema::{entries, person};
+        use diesel::dsl::{exists, not};
+        let later = diesel::alias!(entries as later);
+
+        macro_rules! latest {
+            () => {
+                entries::table
+                    .inner_join(person::table)
+                    .filter(person::deleted_at.is_null())
+                    .filter(entries::instant.le(now))
+                    .filter(not(exists(
+                        later
+                            .filter(later.field(entries::person_id).eq(entries::person_id))
+                            .filter(later.field(entries::instant).le(now))
+                            .filter(
+                                later.field(entries::instant).gt(entries::instant).or(later
+                                    .field(entries::instant)
+                                    .eq(entries::instant)
+                                    .and(
+                                        later.field(entries::action).gt(entries::action).or(later
+                                            .field(entries::action)
+                                            .eq(entries::action)
+                                            .and(later.field(entries::id).gt(entries::id))),
+                                    )),
+                            ),
+                    )))
+                    .order((person::surname.asc(), person::name.asc()))
+                    .select((models::Entry::as_select(), models::Person::as_select()))
+            };
+        }
+
+        let result = match conn {
+            DbConnection::Sqlite(conn) => latest!().load(conn),
+            DbConnection::Pg(conn) => latest!().load(conn),
+        };
+        if let Err(e) = &result {
+            error!("Failed to retrieve the latest entry per person: {}", e);
+        }
+        result
+    }
+}
+
+sort_columns!(EntrySort {
+    Instant => "instant",
+    Action => "action",
+    PersonId => "person_id",
+});
+
+/// Criteria for [`EntriesInteractor::filter`]; `None` fields match everything
+#[derive(Debug, Clone, Default)]
+pub struct EntryFilter {
+    pub range: DateRange,
+    pub person_id: Option<String>,
+    pub action: Option<Action>,
+    pub flagged: Option<bool>,
+}
+
+impl EntryFilter {
+    pub fn range(range: DateRange) -> Self {
+        EntryFilter {
+            range,
+            ..Default::default()
+        }
+    }
+}
+
+/// What [`EntriesInteractor::create_in_sequence`] does with an entry that repeats the last
+/// action of its person
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SequencePolicy {
+    /// Refuse the entry
+    #[default]
+    Reject,
+    /// Insert the missing Exit or Enter before it; both are flagged
+    Autocorrect,
+    /// Accept it flagged
+    Flag,
+}
+
+impl std::str::FromStr for SequencePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "reject" => Ok(SequencePolicy::Reject),
+            "autocorrect" => Ok(SequencePolicy::Autocorrect),
+            "flag" => Ok(SequencePolicy::Flag),
+            _ => Err(format!("Invalid sequence policy: {s}")),
         }
     }
 }
 
+/// Outcome of [`EntriesInteractor::create_in_sequence`]
+#[derive(Debug)]
+pub enum SequencedEntry {
+    /// The entry was stored, after the correction inserted before it if any
+    Created {
+        entry: models::Entry,
+        correction: Option<models::Entry>,
+    },
+    /// Rejected; `last` is the entry it repeats (the one before it, or one recorded after it
+    /// meanwhile), `None` for an Exit with nothing before it
+    OutOfSequence {
+        last: Option<models::Entry>,
+    },
+    UnknownPerson,
+}
+
+/// Outcome of [`EntriesInteractor::update_in_sequence`]
+#[derive(Debug)]
+pub enum SequencedUpdate {
+    Updated(models::Entry),
+    /// Nothing was changed: the entry is gone, or no longer at the expected version
+    Missed {
+        exists: bool,
+    },
+    UnknownPerson,
+    /// The change would put the history of a person out of sequence
+    OutOfSequence,
+}
+
+/// How many times the history of a person repeats an action, counting a leading Exit. Entries
+/// on the same instant are ordered like in [`EntriesInteractor::get_latest_per_person`].
+fn sequence_breaks(mut entries: Vec<&models::Entry>) -> usize {
+    entries.sort_by(|a, b| (a.instant, &a.action, &a.id).cmp(&(b.instant, &b.action, &b.id)));
+    let mut last = None;
+    let mut breaks = 0;
+    for entry in entries {
+        let Ok(action) = entry.action.parse::<Action>() else {
+            continue;
+        };
+        let repeated = match last {
+            Some(last) => last == action,
+            None => action == Action::Exit,
+        };
+        if repeated {
+            breaks += 1;
+        }
+        last = Some(action);
+    }
+    breaks
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 pub enum Action {
     Enter,
     Exit,
 }
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Enter => write!(f, "Enter"),
+            Action::Exit => write!(f, "Exit"),
+        }
+    }
+}
+
+impl std::str::FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "entrada" | "enter" => Ok(Action::Enter),
+            "salida" | "exit" => Ok(Action::Exit),
+            _ => Err(format!("Invalid action: {s}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Person, Role};
+    use diesel::Connection;
+    use diesel::SqliteConnection;
+
+    fn db_with_person() -> (DbConnection, String) {
+        let mut conn = DbConnection::Sqlite(SqliteConnection::establish(":memory:").unwrap());
+        crate::migrations::run_pending(&mut conn).unwrap();
+        let person = Person::new("Ada", "Lovelace", "ada@example.com", Role::Alumno, None);
+        PersonInteractor::new(&mut conn, &person).unwrap();
+        (conn, person.id)
+    }
+
+    fn at(minute: u32) -> chrono::NaiveDateTime {
+        chrono::NaiveDate::from_ymd_opt(2026, 10, 18)
+            .unwrap()
+            .and_hms_opt(8, minute, 0)
+            .unwrap()
+    }
+
+    fn create(
+        conn: &mut DbConnection,
+        p_id: &str,
+        action: Action,
+        minute: u32,
+        policy: SequencePolicy,
+    ) -> SequencedEntry {
+        let entry = models::Entry::new_with_timestamp(p_id, action, at(minute));
+        EntriesInteractor::create_in_sequence(conn, entry, policy).unwrap()
+    }
+
+    fn entry(id: &str, minute: u32, action: Action) -> models::Entry {
+        models::Entry {
+            id: id.to_string(),
+            person_id: "p".to_string(),
+            instant: chrono::NaiveDate::from_ymd_opt(2026, 10, 18)
+                .unwrap()
+                .and_hms_opt(8, minute, 0)
+                .unwrap(),
+            action: action.to_string(),
+            version: 1,
+            flagged: false,
+        }
+    }
+
+    #[test]
+    fn alternating_history_has_no_breaks() {
+        let enter = entry("a", 0, Action::Enter);
+        let exit = entry("b", 30, Action::Exit);
+        let again = entry("c", 45, Action::Enter);
+        assert_eq!(sequence_breaks(vec![&again, &exit, &enter]), 0);
+        assert_eq!(sequence_breaks(vec![]), 0);
+    }
+
+    #[test]
+    fn counts_repeats_and_a_leading_exit() {
+        let exit = entry("a", 0, Action::Exit);
+        let enter = entry("b", 10, Action::Enter);
+        let enter_again = entry("c", 20, Action::Enter);
+        assert_eq!(sequence_breaks(vec![&exit]), 1);
+        assert_eq!(sequence_breaks(vec![&enter, &enter_again]), 1);
+        assert_eq!(sequence_breaks(vec![&enter_again, &exit, &enter]), 2);
+    }
+
+    #[test]
+    fn an_exit_follows_an_enter_on_the_same_instant() {
+        let enter = entry("b", 0, Action::Enter);
+        let exit = entry("a", 0, Action::Exit);
+        assert_eq!(sequence_breaks(vec![&exit, &enter]), 0);
+        let enter_again = entry("c", 0, Action::Enter);
+        assert_eq!(sequence_breaks(vec![&enter_again, &exit, &enter]), 1);
+    }
+
+    #[test]
+    fn role_entries_put_an_enter_before_an_exit_on_the_same_instant() {
+        let (mut conn, p_id) = db_with_person();
+        // Stored exit first, and with the lower id
+        for (id, minute, action) in [("a", 0, Action::Exit), ("b", 0, Action::Enter)] {
+            let entry = models::Entry {
+                person_id: p_id.clone(),
+                ..entry(id, minute, action)
+            };
+            EntriesInteractor::new(&mut conn, &entry).unwrap();
+        }
+        let actions = |conn: &mut DbConnection, range| {
+            EntriesInteractor::get_by_role_with_lead_in(conn, &Role::Alumno, range)
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.action)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(actions(&mut conn, DateRange::default()), ["Enter", "Exit"]);
+        // The exit is the last one before the range
+        let after = DateRange {
+            from: Some(at(30)),
+            to: None,
+        };
+        assert_eq!(actions(&mut conn, after), ["Exit"]);
+    }
+
+    #[test]
+    fn reject_refuses_a_repeated_action() {
+        let (mut conn, p_id) = db_with_person();
+        let policy = SequencePolicy::Reject;
+        assert!(matches!(
+            create(&mut conn, &p_id, Action::Exit, 0, policy),
+            SequencedEntry::OutOfSequence { last: None }
+        ));
+        let SequencedEntry::Created { entry: enter, .. } =
+            create(&mut conn, &p_id, Action::Enter, 1, policy)
+        else {
+            panic!("the first Enter is in sequence");
+        };
+        match create(&mut conn, &p_id, Action::Enter, 2, policy) {
+            SequencedEntry::OutOfSequence { last: Some(last) } => assert_eq!(last.id, enter.id),
+            other => panic!("expected OutOfSequence, got {:?}", other),
+        }
+        assert!(matches!(
+            create(&mut conn, &p_id, Action::Exit, 3, policy),
+            SequencedEntry::Created {
+                correction: None,
+                ..
+            }
+        ));
+        assert_eq!(
+            EntriesInteractor::get_by_p_id(&mut conn, &p_id)
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn an_entry_stamped_before_a_stored_one_is_checked_against_it() {
+        let (mut conn, p_id) = db_with_person();
+        let SequencedEntry::Created { entry: later, .. } =
+            create(&mut conn, &p_id, Action::Enter, 1, SequencePolicy::Reject)
+        else {
+            panic!("the first Enter is in sequence");
+        };
+        match create(&mut conn, &p_id, Action::Enter, 0, SequencePolicy::Reject) {
+            SequencedEntry::OutOfSequence { last: Some(last) } => assert_eq!(last.id, later.id),
+            other => panic!("expected OutOfSequence, got {:?}", other),
+        }
+        let SequencedEntry::Created { entry, correction } = create(
+            &mut conn,
+            &p_id,
+            Action::Enter,
+            0,
+            SequencePolicy::Autocorrect,
+        ) else {
+            panic!("autocorrect accepts the entry");
+        };
+        assert!(entry.flagged && correction.is_none());
+    }
+
+    #[test]
+    fn autocorrect_closes_and_opens_stays() {
+        let (mut conn, p_id) = db_with_person();
+        let policy = SequencePolicy::Autocorrect;
+        create(&mut conn, &p_id, Action::Enter, 0, policy);
+        let SequencedEntry::Created { entry, correction } =
+            create(&mut conn, &p_id, Action::Enter, 30, policy)
+        else {
+            panic!("autocorrect accepts the entry");
+        };
+        let correction = correction.expect("the first stay is closed");
+        assert_eq!(correction.action, Action::Exit.to_string());
+        assert_eq!(
+            correction.instant,
+            at(0) + chrono::Duration::milliseconds(1)
+        );
+        assert!(correction.flagged && entry.flagged);
+
+        create(&mut conn, &p_id, Action::Exit, 40, policy);
+        let SequencedEntry::Created { correction, .. } =
+            create(&mut conn, &p_id, Action::Exit, 50, policy)
+        else {
+            panic!("autocorrect accepts the entry");
+        };
+        let correction = correction.expect("the second Exit gets its Enter");
+        assert_eq!(correction.action, Action::Enter.to_string());
+        assert_eq!(
+            correction.instant,
+            at(50) - chrono::Duration::milliseconds(1)
+        );
+
+        let history = EntriesInteractor::get_by_p_id(&mut conn, &p_id).unwrap();
+        assert_eq!(history.len(), 6);
+        assert_eq!(sequence_breaks(history.iter().collect()), 0);
+    }
+
+    #[test]
+    fn flag_keeps_the_entry_as_it_is() {
+        let (mut conn, p_id) = db_with_person();
+        let SequencedEntry::Created { entry, correction } =
+            create(&mut conn, &p_id, Action::Exit, 0, SequencePolicy::Flag)
+        else {
+            panic!("flag accepts the entry");
+        };
+        assert!(entry.flagged && correction.is_none());
+    }
+
+    #[test]
+    fn unknown_persons_get_no_entries() {
+        let (mut conn, _) = db_with_person();
+        assert!(matches!(
+            create(&mut conn, "nobody", Action::Enter, 0, SequencePolicy::Flag),
+            SequencedEntry::UnknownPerson
+        ));
+    }
+
+    #[test]
+    fn updates_keep_the_sequence() {
+        let (mut conn, p_id) = db_with_person();
+        let policy = SequencePolicy::Reject;
+        create(&mut conn, &p_id, Action::Enter, 0, policy);
+        let SequencedEntry::Created { entry: exit, .. } =
+            create(&mut conn, &p_id, Action::Exit, 10, policy)
+        else {
+            panic!("the Exit is in sequence");
+        };
+
+        let to_enter = models::EntryChanges {
+            action: Some(Action::Enter.to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            EntriesInteractor::update_in_sequence(&mut conn, &exit.id, &to_enter, None).unwrap(),
+            SequencedUpdate::OutOfSequence
+        ));
+
+        let later = models::EntryChanges {
+            instant: Some(at(20)),
+            ..Default::default()
+        };
+        assert!(matches!(
+            EntriesInteractor::update_in_sequence(&mut conn, &exit.id, &later, Some(&[2])).unwrap(),
+            SequencedUpdate::Missed { exists: true }
+        ));
+        match EntriesInteractor::update_in_sequence(&mut conn, &exit.id, &later, Some(&[1])) {
+            Ok(SequencedUpdate::Updated(updated)) => {
+                assert_eq!((updated.instant, updated.version), (at(20), 2))
+            }
+            other => panic!("expected Updated, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn concurrent_entries_are_checked_one_at_a_time() {
+        let path = std::env::temp_dir().join(format!("entries-{}.db", uuid::Uuid::new_v4()));
+        let url = path.to_str().unwrap().to_string();
+        let connect = || {
+            let mut conn = SqliteConnection::establish(&url).unwrap();
+            diesel::connection::SimpleConnection::batch_execute(
+                &mut conn,
+                "PRAGMA busy_timeout = 5000;",
+            )
+            .unwrap();
+            DbConnection::Sqlite(conn)
+        };
+        let mut conn = connect();
+        crate::migrations::run_pending(&mut conn).unwrap();
+        let person = Person::new("Ada", "Lovelace", "ada@example.com", Role::Alumno, None);
+        PersonInteractor::new(&mut conn, &person).unwrap();
+
+        let barrier = std::sync::Barrier::new(2);
+        let outcomes: Vec<SequencedEntry> = std::thread::scope(|scope| {
+            let racers: Vec<_> = (0..2)
+                .map(|minute| {
+                    let (barrier, p_id, mut conn) = (&barrier, &person.id, connect());
+                    scope.spawn(move || {
+                        barrier.wait();
+                        create(
+                            &mut conn,
+                            p_id,
+                            Action::Enter,
+                            minute,
+                            SequencePolicy::Reject,
+                        )
+                    })
+                })
+                .collect();
+            racers
+                .into_iter()
+                .map(|racer| racer.join().unwrap())
+                .collect()
+        });
+        let created = outcomes
+            .iter()
+            .filter(|outcome| matches!(outcome, SequencedEntry::Created { .. }))
+            .count();
+        assert_eq!(created, 1, "{:?}", outcomes);
+        assert_eq!(
+            EntriesInteractor::get_by_p_id(&mut conn, &person.id)
+                .unwrap()
+                .len(),
+            1
+        );
+        let _ = std::fs::remove_file(path);
+    }
+}
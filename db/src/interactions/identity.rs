@@ -0,0 +1,186 @@
+use crate::DbConnection;
+use crate::interactions::person::PersonInteractor;
+use crate::models::{Permissions, Person, PersonIdentity};
+use crate::schema::{person, person_identities};
+use diesel::prelude::*;
+use log::{debug, error, info};
+
+pub struct IdentityInteractor;
+
+/// Outcome of [`IdentityInteractor::unlink`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unlink {
+    Done,
+    /// The person has no identity at that provider
+    NotLinked,
+    /// It is the only way left for the person to sign in, they have no password nor other identity
+    LastLoginMethod,
+}
+
+impl IdentityInteractor {
+    /// The active person signing in as `subject` at `provider`
+    pub fn get_person(
+        conn: &mut DbConnection,
+        provider: &str,
+        subject: &str,
+    ) -> QueryResult<Person> {
+        debug!("Retrieving person with {} identity {}", provider, subject);
+        let result = match conn {
+            DbConnection::Sqlite(conn) => person_identities::table
+                .inner_join(person::table)
+                .filter(person_identities::provider.eq(provider))
+                .filter(person_identities::subject.eq(subject))
+                .filter(person::deleted_at.is_null())
+                .select(Person::as_select())
+                .first(conn),
+            DbConnection::Pg(conn) => person_identities::table
+                .inner_join(person::table)
+                .filter(person_identities::provider.eq(provider))
+                .filter(person_identities::subject.eq(subject))
+                .filter(person::deleted_at.is_null())
+                .select(Person::as_select())
+                .first(conn),
+        };
+
+        if let Err(e) = &result
+            && *e != diesel::result::Error::NotFound
+        {
+            error!(
+                "Failed to retrieve person with {} identity {}: {}",
+                provider, subject, e
+            );
+        }
+
+        result
+    }
+
+    /// The identity a person has at `provider`, if any
+    pub fn get_for_person(
+        conn: &mut DbConnection,
+        p_id: &str,
+        provider: &str,
+    ) -> QueryResult<Option<PersonIdentity>> {
+        match conn {
+            DbConnection::Sqlite(conn) => person_identities::table
+                .filter(person_identities::person_id.eq(p_id))
+                .filter(person_identities::provider.eq(provider))
+                .select(PersonIdentity::as_select())
+                .first(conn)
+                .optional(),
+            DbConnection::Pg(conn) => person_identities::table
+                .filter(person_identities::person_id.eq(p_id))
+                .filter(person_identities::provider.eq(provider))
+                .select(PersonIdentity::as_select())
+                .first(conn)
+                .optional(),
+        }
+    }
+
+    /// The identities linked to a person, oldest first
+    pub fn get_by_person(conn: &mut DbConnection, p_id: &str) -> QueryResult<Vec<PersonIdentity>> {
+        match conn {
+            DbConnection::Sqlite(conn) => person_identities::table
+                .filter(person_identities::person_id.eq(p_id))
+                .order(person_identities::linked_at.asc())
+                .select(PersonIdentity::as_select())
+                .load(conn),
+            DbConnection::Pg(conn) => person_identities::table
+                .filter(person_identities::person_id.eq(p_id))
+                .order(person_identities::linked_at.asc())
+                .select(PersonIdentity::as_select())
+                .load(conn),
+        }
+    }
+
+    /// Links `identity` to its person, replacing the one they had at the same provider
+    pub fn link(conn: &mut DbConnection, identity: &PersonIdentity) -> QueryResult<usize> {
+        info!(
+            "Linking {} identity {} to person {}",
+            identity.provider, identity.subject, identity.person_id
+        );
+        let result = conn.transaction(|conn| match conn {
+            DbConnection::Sqlite(conn) => {
+                diesel::delete(
+                    person_identities::table
+                        .filter(person_identities::person_id.eq(&identity.person_id))
+                        .filter(person_identities::provider.eq(&identity.provider)),
+                )
+                .execute(conn)?;
+                diesel::insert_into(person_identities::table)
+                    .values(identity)
+                    .execute(conn)
+            }
+            DbConnection::Pg(conn) => {
+                diesel::delete(
+                    person_identities::table
+                        .filter(person_identities::person_id.eq(&identity.person_id))
+                        .filter(person_identities::provider.eq(&identity.provider)),
+                )
+                .execute(conn)?;
+                diesel::insert_into(person_identities::table)
+                    .values(identity)
+                    .execute(conn)
+            }
+        });
+
+        if let Err(e) = &result {
+            error!(
+                "Failed to link {} identity to person {}: {}",
+                identity.provider, identity.person_id, e
+            );
+        }
+
+        result
+    }
+
+    /// Creates a person who signs in with `identity`, all or nothing
+    pub fn create_person(
+        conn: &mut DbConnection,
+        new_person: &Person,
+        permissions: &Permissions,
+        identity: &PersonIdentity,
+    ) -> QueryResult<()> {
+        conn.transaction(|conn| {
+            PersonInteractor::create_with_permissions(conn, new_person, permissions)?;
+            Self::link(conn, identity)?;
+            Ok(())
+        })
+    }
+
+    /// Removes the identity a person has at `provider`, unless they could no longer sign in
+    /// without it. Fails with `NotFound` when the person doesn't exist.
+    pub fn unlink(conn: &mut DbConnection, p_id: &str, provider: &str) -> QueryResult<Unlink> {
+        info!("Unlinking {} identity from person {}", provider, p_id);
+        let result = conn.transaction(|conn| {
+            let person = PersonInteractor::get_by_id(conn, p_id)?;
+            let identities = Self::get_by_person(conn, p_id)?;
+            if !identities
+                .iter()
+                .any(|identity| identity.provider == provider)
+            {
+                return Ok(Unlink::NotLinked);
+            }
+            if person.password_hash.is_none() && identities.len() == 1 {
+                return Ok(Unlink::LastLoginMethod);
+            }
+
+            let target = person_identities::table
+                .filter(person_identities::person_id.eq(p_id))
+                .filter(person_identities::provider.eq(provider));
+            match conn {
+                DbConnection::Sqlite(conn) => diesel::delete(target).execute(conn)?,
+                DbConnection::Pg(conn) => diesel::delete(target).execute(conn)?,
+            };
+            Ok(Unlink::Done)
+        });
+
+        if let Err(e) = &result {
+            error!(
+                "Failed to unlink {} identity from person {}: {}",
+                provider, p_id, e
+            );
+        }
+
+        result
+    }
+}
@@ -0,0 +1,417 @@
+use crate::DbConnection;
+use crate::models::LoginThrottle;
+use crate::schema::login_throttles;
+use chrono::{Duration, NaiveDateTime};
+use diesel::prelude::*;
+use log::error;
+
+pub struct LoginThrottleInteractor;
+
+/// What failed sign-ins are counted against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThrottleScope {
+    /// An account, by lowercase email, whether it exists or not
+    Account,
+    /// A client address
+    Ip,
+}
+
+impl ThrottleScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ThrottleScope::Account => "account",
+            ThrottleScope::Ip => "ip",
+        }
+    }
+}
+
+/// How failed sign-ins slow down the next attempts of an account or address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThrottlePolicy {
+    /// Failures allowed before any wait
+    pub free_failures: i32,
+    /// Wait after the first failure past the free ones, doubled by each further failure
+    pub base_delay: Duration,
+    /// Failures that lock the account or address for `lockout`
+    pub lockout_after: i32,
+    pub lockout: Duration,
+    /// Failures are forgotten once none happened for this long
+    pub window: Duration,
+}
+
+impl Default for ThrottlePolicy {
+    fn default() -> Self {
+        ThrottlePolicy {
+            free_failures: 3,
+            base_delay: Duration::seconds(1),
+            lockout_after: 10,
+            lockout: Duration::minutes(15),
+            window: Duration::hours(1),
+        }
+    }
+}
+
+impl ThrottlePolicy {
+    /// How long to refuse attempts after `failures` recent failures
+    pub fn delay(&self, failures: i32) -> Option<Duration> {
+        if failures >= self.lockout_after {
+            return Some(self.lockout);
+        }
+        if failures < self.free_failures {
+            return None;
+        }
+        let doublings = (failures - self.free_failures).min(20) as u32;
+        Some((self.base_delay * 2i32.pow(doublings)).min(self.lockout))
+    }
+}
+
+/// An attempt counted as failed by [`LoginThrottleInteractor::start_attempt`]
+pub struct CountedAttempt {
+    pub scope: ThrottleScope,
+    /// Recent failures of the subject, this attempt included
+    pub failures: i32,
+    /// The row before the attempt was counted
+    previous: LoginThrottle,
+}
+
+impl CountedAttempt {
+    pub fn subject(&self) -> &str {
+        &self.previous.subject
+    }
+}
+
+impl LoginThrottleInteractor {
+    pub fn get(
+        conn: &mut DbConnection,
+        scope: ThrottleScope,
+        subject: &str,
+    ) -> QueryResult<Option<LoginThrottle>> {
+        let target = login_throttles::table
+            .filter(login_throttles::scope.eq(scope.as_str()))
+            .filter(login_throttles::subject.eq(subject));
+        match conn {
+            DbConnection::Sqlite(conn) => target
+                .select(LoginThrottle::as_select())
+                .first(conn)
+                .optional(),
+            DbConnection::Pg(conn) => target
+                .select(LoginThrottle::as_select())
+                .first(conn)
+                .optional(),
+        }
+    }
+
+    /// Counts an attempt of each `(scope, subject, policy)` as failed before it is made, and
+    /// sets how long they have to wait as if it fails; take it back with [`Self::take_back`]
+    /// once it turns out otherwise. The rows are locked while counting, so attempts made at
+    /// the same time are each refused or counted in turn. Returns until when the attempt is
+    /// refused instead, counting nothing, while any of them has to wait.
+    pub fn start_attempt(
+        conn: &mut DbConnection,
+        keys: &[(ThrottleScope, &str, &ThrottlePolicy)],
+    ) -> QueryResult<Result<Vec<CountedAttempt>, NaiveDateTime>> {
+        let now = chrono::Utc::now().naive_utc();
+        let result = conn.immediate_transaction(|conn| {
+            let mut previous = Vec::with_capacity(keys.len());
+            for (scope, subject, _) in keys {
+                let current = Self::get_for_update(conn, *scope, subject, now)?;
+                if let Some(locked_until) = current.locked_until.filter(|until| *until > now) {
+                    return Ok(Err(locked_until));
+                }
+                previous.push(current);
+            }
+
+            let mut counted = Vec::with_capacity(keys.len());
+            for ((scope, subject, policy), previous) in keys.iter().zip(previous) {
+                let failures = if previous.last_failure_at + policy.window > now {
+                    previous.failures + 1
+                } else {
+                    1
+                };
+                let throttle = LoginThrottle {
+                    failures,
+                    last_failure_at: now,
+                    locked_until: policy.delay(failures).map(|delay| now + delay),
+                    ..previous.clone()
+                };
+                let target = login_throttles::table
+                    .filter(login_throttles::scope.eq(scope.as_str()))
+                    .filter(login_throttles::subject.eq(subject));
+                match conn {
+                    DbConnection::Sqlite(conn) => {
+                        diesel::update(target).set(&throttle).execute(conn)?
+                    }
+                    DbConnection::Pg(conn) => {
+                        diesel::update(target).set(&throttle).execute(conn)?
+                    }
+                };
+                counted.push(CountedAttempt {
+                    scope: *scope,
+                    failures,
+                    previous,
+                });
+            }
+            Ok(Ok(counted))
+        });
+
+        if let Err(e) = &result {
+            error!("Failed to count sign-in attempt: {}", e);
+        }
+        result
+    }
+
+    /// The row of `subject`, locked until the end of the transaction; one without failures is
+    /// created first when there is none
+    fn get_for_update(
+        conn: &mut DbConnection,
+        scope: ThrottleScope,
+        subject: &str,
+        now: NaiveDateTime,
+    ) -> QueryResult<LoginThrottle> {
+        let empty = LoginThrottle {
+            scope: scope.as_str().to_string(),
+            subject: subject.to_string(),
+            failures: 0,
+            last_failure_at: now,
+            locked_until: None,
+        };
+        let target = login_throttles::table
+            .filter(login_throttles::scope.eq(scope.as_str()))
+            .filter(login_throttles::subject.eq(subject));
+        match conn {
+            DbConnection::Sqlite(conn) => {
+                diesel::insert_into(login_throttles::table)
+                    .values(&empty)
+                    .on_conflict_do_nothing()
+                    .execute(conn)?;
+                target.select(LoginThrottle::as_select()).first(conn)
+            }
+            DbConnection::Pg(conn) => {
+                diesel::insert_into(login_throttles::table)
+                    .values(&empty)
+                    .on_conflict_do_nothing()
+                    .execute(conn)?;
+                target
+                    .select(LoginThrottle::as_select())
+                    .for_update()
+                    .first(conn)
+            }
+        }
+    }
+
+    /// Takes back an attempt counted by [`Self::start_attempt`] that didn't fail. The row is
+    /// put back as it was, unless other attempts were counted since, which only lose this one.
+    pub fn take_back(conn: &mut DbConnection, attempt: &CountedAttempt) -> QueryResult<()> {
+        let previous = &attempt.previous;
+        let target = login_throttles::table
+            .filter(login_throttles::scope.eq(attempt.scope.as_str()))
+            .filter(login_throttles::subject.eq(&previous.subject));
+        let result = conn.transaction(|conn| {
+            let untouched = target.filter(login_throttles::failures.eq(attempt.failures));
+            let restored = match conn {
+                DbConnection::Sqlite(conn) => {
+                    diesel::update(untouched).set(previous).execute(conn)?
+                }
+                DbConnection::Pg(conn) => diesel::update(untouched).set(previous).execute(conn)?,
+            };
+            if restored == 0 {
+                let counted = target.filter(login_throttles::failures.gt(0));
+                let decrement = login_throttles::failures.eq(login_throttles::failures - 1);
+                match conn {
+                    DbConnection::Sqlite(conn) => {
+                        diesel::update(counted).set(decrement).execute(conn)?
+                    }
+                    DbConnection::Pg(conn) => {
+                        diesel::update(counted).set(decrement).execute(conn)?
+                    }
+                };
+            }
+            Ok(())
+        });
+
+        if let Err(e) = &result {
+            error!(
+                "Failed to take back sign-in attempt of {} {}: {}",
+                attempt.scope.as_str(),
+                previous.subject,
+                e
+            );
+        }
+        result
+    }
+
+    /// Forgets the failures of `subject`, lifting any lockout
+    pub fn clear(
+        conn: &mut DbConnection,
+        scope: ThrottleScope,
+        subject: &str,
+    ) -> QueryResult<usize> {
+        let target = login_throttles::table
+            .filter(login_throttles::scope.eq(scope.as_str()))
+            .filter(login_throttles::subject.eq(subject));
+        match conn {
+            DbConnection::Sqlite(conn) => diesel::delete(target).execute(conn),
+            DbConnection::Pg(conn) => diesel::delete(target).execute(conn),
+        }
+    }
+
+    /// Removes the rows no longer locked whose last failure is older than `window`
+    pub fn delete_stale(conn: &mut DbConnection, window: Duration) -> QueryResult<usize> {
+        let now = chrono::Utc::now().naive_utc();
+        let stale = login_throttles::table
+            .filter(login_throttles::last_failure_at.lt(now - window))
+            .filter(
+                login_throttles::locked_until
+                    .is_null()
+                    .or(login_throttles::locked_until.lt(now)),
+            );
+        match conn {
+            DbConnection::Sqlite(conn) => diesel::delete(stale).execute(conn),
+            DbConnection::Pg(conn) => diesel::delete(stale).execute(conn),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::SqliteConnection;
+
+    fn db() -> DbConnection {
+        let mut conn = DbConnection::Sqlite(SqliteConnection::establish(":memory:").unwrap());
+        crate::migrations::run_pending(&mut conn).unwrap();
+        conn
+    }
+
+    /// The first failure already makes the next attempt wait
+    fn strict() -> ThrottlePolicy {
+        ThrottlePolicy {
+            free_failures: 1,
+            ..ThrottlePolicy::default()
+        }
+    }
+
+    fn failures(conn: &mut DbConnection, scope: ThrottleScope, subject: &str) -> i32 {
+        LoginThrottleInteractor::get(conn, scope, subject)
+            .unwrap()
+            .map_or(0, |throttle| throttle.failures)
+    }
+
+    #[test]
+    fn free_failures_have_no_delay() {
+        let policy = ThrottlePolicy::default();
+        for failures in 0..policy.free_failures {
+            assert_eq!(policy.delay(failures), None);
+        }
+    }
+
+    #[test]
+    fn each_failure_past_the_free_ones_doubles_the_delay() {
+        let policy = ThrottlePolicy::default();
+        assert_eq!(policy.delay(3), Some(Duration::seconds(1)));
+        assert_eq!(policy.delay(4), Some(Duration::seconds(2)));
+        assert_eq!(policy.delay(5), Some(Duration::seconds(4)));
+        assert_eq!(policy.delay(9), Some(Duration::seconds(64)));
+    }
+
+    #[test]
+    fn enough_failures_lock_out() {
+        let policy = ThrottlePolicy::default();
+        assert_eq!(policy.delay(10), Some(Duration::minutes(15)));
+        assert_eq!(policy.delay(1000), Some(Duration::minutes(15)));
+    }
+
+    #[test]
+    fn delays_never_exceed_the_lockout() {
+        let policy = ThrottlePolicy {
+            free_failures: 0,
+            lockout_after: i32::MAX,
+            lockout: Duration::minutes(1),
+            ..ThrottlePolicy::default()
+        };
+        assert_eq!(policy.delay(0), Some(Duration::seconds(1)));
+        assert_eq!(policy.delay(6), Some(Duration::minutes(1)));
+        assert_eq!(policy.delay(i32::MAX - 1), Some(Duration::minutes(1)));
+    }
+
+    #[test]
+    fn an_unfinished_attempt_makes_the_next_one_wait() {
+        let mut conn = db();
+        let policy = strict();
+        let keys = [(ThrottleScope::Account, "ada@example.com", &policy)];
+        let counted = LoginThrottleInteractor::start_attempt(&mut conn, &keys)
+            .unwrap()
+            .unwrap();
+        assert_eq!(counted[0].failures, 1);
+        // Made before the first one found out whether the password was right
+        assert!(
+            LoginThrottleInteractor::start_attempt(&mut conn, &keys)
+                .unwrap()
+                .is_err()
+        );
+        assert_eq!(
+            failures(&mut conn, ThrottleScope::Account, "ada@example.com"),
+            1
+        );
+    }
+
+    #[test]
+    fn taking_back_an_attempt_lifts_its_wait() {
+        let mut conn = db();
+        let policy = strict();
+        let keys = [(ThrottleScope::Account, "ada@example.com", &policy)];
+        let counted = LoginThrottleInteractor::start_attempt(&mut conn, &keys)
+            .unwrap()
+            .unwrap();
+        LoginThrottleInteractor::take_back(&mut conn, &counted[0]).unwrap();
+        assert_eq!(
+            failures(&mut conn, ThrottleScope::Account, "ada@example.com"),
+            0
+        );
+        assert!(
+            LoginThrottleInteractor::start_attempt(&mut conn, &keys)
+                .unwrap()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn taking_back_keeps_attempts_counted_since() {
+        let mut conn = db();
+        let policy = ThrottlePolicy::default();
+        let keys = [(ThrottleScope::Ip, "192.0.2.1", &policy)];
+        let first = LoginThrottleInteractor::start_attempt(&mut conn, &keys)
+            .unwrap()
+            .unwrap();
+        LoginThrottleInteractor::start_attempt(&mut conn, &keys)
+            .unwrap()
+            .unwrap();
+        LoginThrottleInteractor::take_back(&mut conn, &first[0]).unwrap();
+        assert_eq!(failures(&mut conn, ThrottleScope::Ip, "192.0.2.1"), 1);
+    }
+
+    #[test]
+    fn a_refused_attempt_counts_nothing() {
+        let mut conn = db();
+        let policy = strict();
+        let ip = [(ThrottleScope::Ip, "192.0.2.1", &policy)];
+        LoginThrottleInteractor::start_attempt(&mut conn, &ip)
+            .unwrap()
+            .unwrap();
+
+        let both = [
+            (ThrottleScope::Account, "ada@example.com", &policy),
+            (ThrottleScope::Ip, "192.0.2.1", &policy),
+        ];
+        assert!(
+            LoginThrottleInteractor::start_attempt(&mut conn, &both)
+                .unwrap()
+                .is_err()
+        );
+        assert_eq!(
+            failures(&mut conn, ThrottleScope::Account, "ada@example.com"),
+            0
+        );
+        assert_eq!(failures(&mut conn, ThrottleScope::Ip, "192.0.2.1"), 1);
+    }
+}
@@ -1,4 +1,23 @@
+pub mod api_client;
 pub mod entries;
+pub mod identity;
+pub mod login_throttle;
 pub mod password_reset;
 pub mod permissions;
 pub mod person;
+pub mod refresh_token;
+pub mod two_factor;
+pub mod webhook;
+
+// Builds a per-backend UPDATE that bumps `version`, optionally only when the row is still at
+// one of the expected versions; callers tell a stale version from a missing row by re-reading it
+macro_rules! versioned_update {
+    ($target:expr, $expected:expr, $changes:expr) => {{
+        let mut query = diesel::update($target).into_boxed();
+        if let Some(expected) = $expected {
+            query = query.filter(version.eq_any(expected));
+        }
+        query.set(($changes, version.eq(version + 1)))
+    }};
+}
+pub(crate) use versioned_update;
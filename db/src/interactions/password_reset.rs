@@ -1,10 +1,26 @@
 use crate::DbConnection;
-use crate::models::PasswordResetToken;
+use crate::interactions::person::PersonInteractor;
+use crate::models::{PasswordResetToken, PersonChanges};
 use crate::schema::password_reset_tokens;
 use diesel::prelude::*;
 
 pub struct PasswordResetTokenInteractor;
 
+/// Outcome of [`PasswordResetTokenInteractor::reset_password`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PasswordReset {
+    /// The password of this person was changed
+    Done {
+        person_id: String,
+        email: String,
+    },
+    InvalidToken,
+    /// The token had expired and has been deleted
+    Expired,
+    /// Nobody has the email the token was issued for
+    UnknownPerson,
+}
+
 impl PasswordResetTokenInteractor {
     pub fn create(
         conn: &mut DbConnection,
@@ -72,4 +88,37 @@ impl PasswordResetTokenInteractor {
                 .execute(conn),
         }
     }
+
+    /// Sets the password of the person a valid token was issued for and consumes the token,
+    /// both or neither
+    pub fn reset_password(
+        conn: &mut DbConnection,
+        token_str: &str,
+        password_hash: &str,
+    ) -> QueryResult<PasswordReset> {
+        conn.transaction(|conn| {
+            let Some(token) = Self::find_by_token(conn, token_str).optional()? else {
+                return Ok(PasswordReset::InvalidToken);
+            };
+            if !token.is_valid() {
+                Self::delete_by_token(conn, token_str)?;
+                return Ok(PasswordReset::Expired);
+            }
+            let Some(person) = PersonInteractor::get_by_email(conn, &token.email).optional()?
+            else {
+                return Ok(PasswordReset::UnknownPerson);
+            };
+
+            let changes = PersonChanges {
+                password_hash: Some(password_hash.to_string()),
+                ..Default::default()
+            };
+            PersonInteractor::patch(conn, &person.id, &changes, None)?;
+            Self::delete_by_token(conn, token_str)?;
+            Ok(PasswordReset::Done {
+                person_id: person.id,
+                email: person.email,
+            })
+        })
+    }
 }
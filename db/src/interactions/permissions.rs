@@ -1,9 +1,53 @@
 use crate::DbConnection;
+use crate::interactions::versioned_update;
 use crate::models;
+use crate::pagination::{Page, PageRequest, Sort, SortOrder, sort_columns};
 use diesel::prelude::*;
 
 pub struct PermissionsInteractor {}
 
+sort_columns!(PermissionsSort {
+    PersonId => "person_id",
+    Id => "id",
+});
+
+/// Criteria for [`PermissionsInteractor::page`]; `None` fields match everything
+#[derive(Debug, Clone, Default)]
+pub struct PermissionsFilter {
+    pub person_id: Option<String>,
+    pub dashboard: Option<bool>,
+    pub see_self_history: Option<bool>,
+    pub see_others_history: Option<bool>,
+    pub admin_panel: Option<bool>,
+    pub edit_permissions: Option<bool>,
+}
+
+macro_rules! filtered_permissions {
+    ($filter:expr) => {{
+        let filter: &PermissionsFilter = $filter;
+        let mut query = permissions.into_boxed();
+        if let Some(p_id) = &filter.person_id {
+            query = query.filter(person_id.eq(p_id));
+        }
+        if let Some(flag) = filter.dashboard {
+            query = query.filter(dashboard.eq(flag));
+        }
+        if let Some(flag) = filter.see_self_history {
+            query = query.filter(see_self_history.eq(flag));
+        }
+        if let Some(flag) = filter.see_others_history {
+            query = query.filter(see_others_history.eq(flag));
+        }
+        if let Some(flag) = filter.admin_panel {
+            query = query.filter(admin_panel.eq(flag));
+        }
+        if let Some(flag) = filter.edit_permissions {
+            query = query.filter(edit_permissions.eq(flag));
+        }
+        query
+    }};
+}
+
 impl PermissionsInteractor {
     #[allow(clippy::new_ret_no_self)]
     pub fn new(conn: &mut DbConnection, permissions: &models::Permissions) -> QueryResult<usize> {
@@ -59,15 +103,41 @@ impl PermissionsInteractor {
         conn: &mut DbConnection,
         p_id: &str,
         permissions_changes: &models::Permissions,
+        expected_versions: Option<&[i32]>,
     ) -> QueryResult<usize> {
         use crate::schema::permissions::dsl::*;
         match conn {
-            DbConnection::Sqlite(conn) => diesel::update(permissions.filter(id.eq(p_id)))
-                .set(permissions_changes)
-                .execute(conn),
-            DbConnection::Pg(conn) => diesel::update(permissions.filter(id.eq(p_id)))
-                .set(permissions_changes)
-                .execute(conn),
+            DbConnection::Sqlite(conn) => versioned_update!(
+                permissions.filter(id.eq(p_id)),
+                expected_versions,
+                permissions_changes
+            )
+            .execute(conn),
+            DbConnection::Pg(conn) => versioned_update!(
+                permissions.filter(id.eq(p_id)),
+                expected_versions,
+                permissions_changes
+            )
+            .execute(conn),
+        }
+    }
+    /// Updates only the columns set in `changes`
+    pub fn patch(
+        conn: &mut DbConnection,
+        p_id: &str,
+        changes: &models::PermissionsChanges,
+        expected_versions: Option<&[i32]>,
+    ) -> QueryResult<usize> {
+        use crate::schema::permissions::dsl::*;
+        match conn {
+            DbConnection::Sqlite(conn) => {
+                versioned_update!(permissions.filter(id.eq(p_id)), expected_versions, changes)
+                    .execute(conn)
+            }
+            DbConnection::Pg(conn) => {
+                versioned_update!(permissions.filter(id.eq(p_id)), expected_versions, changes)
+                    .execute(conn)
+            }
         }
     }
     pub fn delete(conn: &mut DbConnection, p_id: &str) -> QueryResult<usize> {
@@ -79,4 +149,42 @@ impl PermissionsInteractor {
             DbConnection::Pg(conn) => diesel::delete(permissions.filter(id.eq(p_id))).execute(conn),
         }
     }
+
+    pub fn page(
+        conn: &mut DbConnection,
+        filter: &PermissionsFilter,
+        sort: Sort<PermissionsSort>,
+        page: &PageRequest,
+    ) -> QueryResult<Page<models::Permissions>> {
+        use crate::schema::permissions::dsl::*;
+
+        macro_rules! sorted {
+            () => {{
+                let query = filtered_permissions!(filter);
+                let query = match (sort.column, sort.order) {
+                    (PermissionsSort::PersonId, SortOrder::Asc) => query.order(person_id.asc()),
+                    (PermissionsSort::PersonId, SortOrder::Desc) => query.order(person_id.desc()),
+                    (PermissionsSort::Id, SortOrder::Asc) => query.order(id.asc()),
+                    (PermissionsSort::Id, SortOrder::Desc) => query.order(id.desc()),
+                };
+                query
+                    .then_order_by(id.asc())
+                    .limit(page.limit)
+                    .offset(page.offset)
+                    .select(models::Permissions::as_select())
+            }};
+        }
+
+        let (items, total) = match conn {
+            DbConnection::Sqlite(conn) => (
+                sorted!().load(conn)?,
+                filtered_permissions!(filter).count().get_result(conn)?,
+            ),
+            DbConnection::Pg(conn) => (
+                sorted!().load(conn)?,
+                filtered_permissions!(filter).count().get_result(conn)?,
+            ),
+        };
+        Ok(Page::new(items, total, page))
+    }
 }
@@ -1,10 +1,88 @@
 use crate::DbConnection;
+use crate::interactions::permissions::PermissionsInteractor;
+use crate::interactions::refresh_token::RefreshTokenInteractor;
+use crate::interactions::versioned_update;
 use crate::models;
+use crate::pagination::{Page, PageRequest, Sort, SortOrder, sort_columns};
 use diesel::prelude::*;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 
 pub struct PersonInteractor {}
 
+sort_columns!(PersonSort {
+    Surname => "surname",
+    Name => "name",
+    Email => "email",
+    Role => "role",
+});
+
+/// How [`PersonInteractor::page`] treats soft-deleted persons
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DeletedFilter {
+    /// Only active persons
+    #[default]
+    Exclude,
+    /// Active and soft-deleted persons
+    Include,
+    /// Only soft-deleted persons
+    Only,
+}
+
+/// Criteria for [`PersonInteractor::page`]; `None` fields match everything
+#[derive(Debug, Clone, Default)]
+pub struct PersonFilter {
+    pub role: Option<models::Role>,
+    /// Matches part of the name, surname or email
+    pub search: Option<String>,
+    pub deleted: DeletedFilter,
+}
+
+/// The person with the given ID, unless it is soft-deleted
+macro_rules! active {
+    ($p_id:expr) => {
+        person.filter(id.eq($p_id)).filter(deleted_at.is_null())
+    };
+}
+
+macro_rules! filtered_persons {
+    ($filter:expr) => {{
+        let filter: &PersonFilter = $filter;
+        let mut query = person.into_boxed();
+        match filter.deleted {
+            DeletedFilter::Exclude => query = query.filter(deleted_at.is_null()),
+            DeletedFilter::Include => {}
+            DeletedFilter::Only => query = query.filter(deleted_at.is_not_null()),
+        }
+        if let Some(req_role) = &filter.role {
+            query = query.filter(role.eq(req_role.to_string()));
+        }
+        if let Some(search) = &filter.search {
+            let pattern = contains_pattern(search);
+            query = query.filter(
+                name.like(pattern.clone())
+                    .escape('\\')
+                    .or(surname.like(pattern.clone()).escape('\\'))
+                    .or(email.like(pattern).escape('\\')),
+            );
+        }
+        query
+    }};
+}
+
+/// LIKE pattern matching `search` anywhere, with its wildcards escaped by `\`
+fn contains_pattern(search: &str) -> String {
+    let mut pattern = String::with_capacity(search.len() + 2);
+    pattern.push('%');
+    for c in search.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
 impl PersonInteractor {
     #[allow(clippy::new_ret_no_self)]
     pub fn new(conn: &mut DbConnection, person: &models::Person) -> QueryResult<usize> {
@@ -30,12 +108,29 @@ impl PersonInteractor {
         result
     }
 
+    /// Inserts a person together with its permissions, or neither of them
+    pub fn create_with_permissions(
+        conn: &mut DbConnection,
+        new_person: &models::Person,
+        permissions: &models::Permissions,
+    ) -> QueryResult<()> {
+        conn.transaction(|conn| {
+            Self::new(conn, new_person)?;
+            PermissionsInteractor::new(conn, permissions)?;
+            Ok(())
+        })
+    }
+
     pub fn get(conn: &mut DbConnection) -> QueryResult<Vec<models::Person>> {
         use crate::schema::person::dsl::*;
         debug!("Retrieving all persons");
         let result = match conn {
-            DbConnection::Sqlite(conn) => person.load::<models::Person>(conn),
-            DbConnection::Pg(conn) => person.load::<models::Person>(conn),
+            DbConnection::Sqlite(conn) => person
+                .filter(deleted_at.is_null())
+                .load::<models::Person>(conn),
+            DbConnection::Pg(conn) => person
+                .filter(deleted_at.is_null())
+                .load::<models::Person>(conn),
         };
 
         match &result {
@@ -46,57 +141,77 @@ impl PersonInteractor {
         result
     }
 
-    pub fn get_by_id(conn: &mut DbConnection, p_id: &str) -> QueryResult<models::Person> {
+    pub fn get_by_role(
+        conn: &mut DbConnection,
+        req_role: &models::Role,
+    ) -> QueryResult<Vec<models::Person>> {
         use crate::schema::person::dsl::*;
-        debug!("Retrieving person with ID: {}", p_id);
+        debug!("Retrieving persons with role: {}", req_role);
+        let req_role = req_role.to_string();
         let result = match conn {
-            DbConnection::Sqlite(conn) => person.filter(id.eq(p_id)).first::<models::Person>(conn),
-            DbConnection::Pg(conn) => person.filter(id.eq(p_id)).first::<models::Person>(conn),
+            DbConnection::Sqlite(conn) => person
+                .filter(role.eq(&req_role))
+                .filter(deleted_at.is_null())
+                .order(surname.asc())
+                .load::<models::Person>(conn),
+            DbConnection::Pg(conn) => person
+                .filter(role.eq(&req_role))
+                .filter(deleted_at.is_null())
+                .order(surname.asc())
+                .load::<models::Person>(conn),
         };
 
         match &result {
-            Ok(p) => debug!("Retrieved person: {} <{}>", p.name, p.email),
-            Err(e) => error!("Failed to retrieve person with ID {}: {}", p_id, e),
+            Ok(persons) => debug!("Retrieved {} persons with role {}", persons.len(), req_role),
+            Err(e) => error!("Failed to retrieve persons by role: {}", e),
         }
 
         result
     }
 
-    pub fn get_by_email(conn: &mut DbConnection, req_email: &str) -> QueryResult<models::Person> {
+    /// Like [`get_by_id`](Self::get_by_id), locking the row until the transaction ends on
+    /// PostgreSQL; SQLite relies on [`DbConnection::immediate_transaction`] instead
+    pub fn get_for_update(conn: &mut DbConnection, p_id: &str) -> QueryResult<models::Person> {
         use crate::schema::person::dsl::*;
-        debug!("Retrieving person with email: {}", req_email);
+        match conn {
+            DbConnection::Sqlite(conn) => active!(p_id).first::<models::Person>(conn),
+            DbConnection::Pg(conn) => active!(p_id).for_update().first::<models::Person>(conn),
+        }
+    }
+
+    pub fn get_by_id(conn: &mut DbConnection, p_id: &str) -> QueryResult<models::Person> {
+        use crate::schema::person::dsl::*;
+        debug!("Retrieving person with ID: {}", p_id);
         let result = match conn {
-            DbConnection::Sqlite(conn) => person
-                .filter(email.eq(req_email))
-                .first::<models::Person>(conn),
-            DbConnection::Pg(conn) => person
-                .filter(email.eq(req_email))
-                .first::<models::Person>(conn),
+            DbConnection::Sqlite(conn) => active!(p_id).first::<models::Person>(conn),
+            DbConnection::Pg(conn) => active!(p_id).first::<models::Person>(conn),
         };
 
         match &result {
-            Ok(p) => debug!("Retrieved person with ID: {} and name: {}", p.id, p.name),
-            Err(e) => error!("Failed to retrieve person with email {}: {}", req_email, e),
+            Ok(p) => debug!("Retrieved person: {} <{}>", p.name, p.email),
+            Err(e) => error!("Failed to retrieve person with ID {}: {}", p_id, e),
         }
 
         result
     }
 
-    pub fn get_by_google_id(conn: &mut DbConnection, g_id: &str) -> QueryResult<models::Person> {
+    pub fn get_by_email(conn: &mut DbConnection, req_email: &str) -> QueryResult<models::Person> {
         use crate::schema::person::dsl::*;
-        debug!("Retrieving person with Google ID: {}", g_id);
+        debug!("Retrieving person with email: {}", req_email);
         let result = match conn {
             DbConnection::Sqlite(conn) => person
-                .filter(google_id.eq(g_id))
+                .filter(email.eq(req_email))
+                .filter(deleted_at.is_null())
                 .first::<models::Person>(conn),
             DbConnection::Pg(conn) => person
-                .filter(google_id.eq(g_id))
+                .filter(email.eq(req_email))
+                .filter(deleted_at.is_null())
                 .first::<models::Person>(conn),
         };
 
         match &result {
             Ok(p) => debug!("Retrieved person with ID: {} and name: {}", p.id, p.name),
-            Err(e) => error!("Failed to retrieve person with Google ID {}: {}", g_id, e),
+            Err(e) => error!("Failed to retrieve person with email {}: {}", req_email, e),
         }
 
         result
@@ -106,6 +221,7 @@ impl PersonInteractor {
         conn: &mut DbConnection,
         p_id: &str,
         person_changes: &models::Person,
+        expected_versions: Option<&[i32]>,
     ) -> QueryResult<usize> {
         use crate::schema::person::dsl::*;
         info!(
@@ -113,14 +229,33 @@ impl PersonInteractor {
             p_id, person_changes.name, person_changes.email
         );
 
-        let result = match conn {
-            DbConnection::Sqlite(conn) => diesel::update(person.filter(id.eq(p_id)))
-                .set(person_changes)
-                .execute(conn),
-            DbConnection::Pg(conn) => diesel::update(person.filter(id.eq(p_id)))
-                .set(person_changes)
-                .execute(conn),
-        };
+        // A new password signs the person out of every session
+        let result = conn.transaction(|conn| {
+            let previous_hash = match conn {
+                DbConnection::Sqlite(conn) => active!(p_id)
+                    .select(password_hash)
+                    .first::<Option<String>>(conn)
+                    .optional()?,
+                DbConnection::Pg(conn) => active!(p_id)
+                    .select(password_hash)
+                    .first::<Option<String>>(conn)
+                    .optional()?,
+            };
+            let rows = match conn {
+                DbConnection::Sqlite(conn) => {
+                    versioned_update!(active!(p_id), expected_versions, person_changes)
+                        .execute(conn)?
+                }
+                DbConnection::Pg(conn) => {
+                    versioned_update!(active!(p_id), expected_versions, person_changes)
+                        .execute(conn)?
+                }
+            };
+            if rows > 0 && previous_hash.flatten() != person_changes.password_hash {
+                RefreshTokenInteractor::revoke_for_person(conn, p_id)?;
+            }
+            Ok(rows)
+        });
 
         match &result {
             Ok(rows) => info!("Updated person with ID: {}, affected {} rows", p_id, rows),
@@ -130,44 +265,293 @@ impl PersonInteractor {
         result
     }
 
-    pub fn delete(conn: &mut DbConnection, p_id: &str) -> QueryResult<usize> {
+    /// Updates only the columns set in `changes`
+    pub fn patch(
+        conn: &mut DbConnection,
+        p_id: &str,
+        changes: &models::PersonChanges,
+        expected_versions: Option<&[i32]>,
+    ) -> QueryResult<usize> {
         use crate::schema::person::dsl::*;
-        info!("Deleting person with ID: {}", p_id);
+        info!("Patching person with ID: {}", p_id);
+
+        let result = conn.transaction(|conn| {
+            let rows = match conn {
+                DbConnection::Sqlite(conn) => {
+                    versioned_update!(active!(p_id), expected_versions, changes).execute(conn)?
+                }
+                DbConnection::Pg(conn) => {
+                    versioned_update!(active!(p_id), expected_versions, changes).execute(conn)?
+                }
+            };
+            // A new password signs the person out of every session
+            if rows > 0 && changes.password_hash.is_some() {
+                RefreshTokenInteractor::revoke_for_person(conn, p_id)?;
+            }
+            Ok(rows)
+        });
+
+        match &result {
+            Ok(rows) => info!("Patched person with ID: {}, affected {} rows", p_id, rows),
+            Err(e) => error!("Failed to patch person with ID {}: {}", p_id, e),
+        }
+
+        result
+    }
+
+    /// Marks an active person as deleted, keeping their entries and permissions. Default
+    /// queries skip them from then on, until [`Self::restore`] is called.
+    pub fn soft_delete(conn: &mut DbConnection, p_id: &str) -> QueryResult<usize> {
+        use crate::schema::person::dsl::*;
+        info!("Soft-deleting person with ID: {}", p_id);
+        let now = chrono::Utc::now().naive_utc();
+
+        let result = conn.transaction(|conn| {
+            let rows = match conn {
+                DbConnection::Sqlite(conn) => diesel::update(active!(p_id))
+                    .set((deleted_at.eq(now), version.eq(version + 1)))
+                    .execute(conn)?,
+                DbConnection::Pg(conn) => diesel::update(active!(p_id))
+                    .set((deleted_at.eq(now), version.eq(version + 1)))
+                    .execute(conn)?,
+            };
+            RefreshTokenInteractor::revoke_for_person(conn, p_id)?;
+            Ok(rows)
+        });
+
+        match &result {
+            Ok(rows) => info!(
+                "Soft-deleted person with ID: {}, affected {} rows",
+                p_id, rows
+            ),
+            Err(e) => error!("Failed to soft-delete person with ID {}: {}", p_id, e),
+        }
+
+        result
+    }
+
+    /// Brings back a soft-deleted person; 0 rows when there is no deleted person with that ID
+    pub fn restore(conn: &mut DbConnection, p_id: &str) -> QueryResult<usize> {
+        use crate::schema::person::dsl::*;
+        info!("Restoring person with ID: {}", p_id);
+        let target = person.filter(id.eq(p_id)).filter(deleted_at.is_not_null());
 
         let result = match conn {
-            DbConnection::Sqlite(conn) => diesel::delete(person.filter(id.eq(p_id))).execute(conn),
-            DbConnection::Pg(conn) => diesel::delete(person.filter(id.eq(p_id))).execute(conn),
+            DbConnection::Sqlite(conn) => diesel::update(target)
+                .set((
+                    deleted_at.eq(None::<chrono::NaiveDateTime>),
+                    version.eq(version + 1),
+                ))
+                .execute(conn),
+            DbConnection::Pg(conn) => diesel::update(target)
+                .set((
+                    deleted_at.eq(None::<chrono::NaiveDateTime>),
+                    version.eq(version + 1),
+                ))
+                .execute(conn),
         };
 
         match &result {
-            Ok(rows) => info!("Deleted person with ID: {}, affected {} rows", p_id, rows),
-            Err(e) => error!("Failed to delete person with ID {}: {}", p_id, e),
+            Ok(rows) => info!("Restored person with ID: {}, affected {} rows", p_id, rows),
+            Err(e) => error!("Failed to restore person with ID {}: {}", p_id, e),
         }
 
         result
     }
 
-    pub fn update_google_id(
-        conn: &mut DbConnection,
-        p_id: &str,
-        g_id: &str,
-    ) -> QueryResult<models::Person> {
-        use crate::schema::person::dsl::*;
-        debug!("Updating Google ID for person with ID: {}", p_id);
+    /// Permanently removes a person, active or soft-deleted, together with their entries,
+    /// permissions, linked identities, two-factor secrets, refresh tokens, password reset tokens
+    /// and failed sign-ins, all or nothing. Returns the person rows removed.
+    pub fn purge(conn: &mut DbConnection, p_id: &str) -> QueryResult<usize> {
+        use crate::interactions::login_throttle::ThrottleScope;
+        use crate::schema::{
+            entries, login_throttles, password_reset_tokens, permissions, person,
+            person_identities, recovery_codes, refresh_tokens, totp_secrets,
+        };
+        warn!("Purging person with ID: {}", p_id);
 
-        match conn {
-            DbConnection::Sqlite(conn) => {
-                diesel::update(person.filter(id.eq(p_id)))
-                    .set(google_id.eq(g_id))
+        let result = conn.transaction(|conn| {
+            let Some(p_email) = (match conn {
+                DbConnection::Sqlite(conn) => person::table
+                    .filter(person::id.eq(p_id))
+                    .select(person::email)
+                    .first::<String>(conn),
+                DbConnection::Pg(conn) => person::table
+                    .filter(person::id.eq(p_id))
+                    .select(person::email)
+                    .first::<String>(conn),
+            })
+            .optional()?
+            else {
+                return Ok(0);
+            };
+            // Failed sign-ins are counted by lowercase email, see `ThrottleScope::Account`
+            let account = p_email.trim().to_lowercase();
+
+            match conn {
+                DbConnection::Sqlite(conn) => {
+                    diesel::delete(entries::table.filter(entries::person_id.eq(p_id)))
+                        .execute(conn)?;
+                    diesel::delete(permissions::table.filter(permissions::person_id.eq(p_id)))
+                        .execute(conn)?;
+                    diesel::delete(
+                        person_identities::table.filter(person_identities::person_id.eq(p_id)),
+                    )
                     .execute(conn)?;
-            }
-            DbConnection::Pg(conn) => {
-                diesel::update(person.filter(id.eq(p_id)))
-                    .set(google_id.eq(g_id))
+                    diesel::delete(
+                        recovery_codes::table.filter(recovery_codes::person_id.eq(p_id)),
+                    )
+                    .execute(conn)?;
+                    diesel::delete(totp_secrets::table.filter(totp_secrets::person_id.eq(p_id)))
+                        .execute(conn)?;
+                    diesel::delete(
+                        refresh_tokens::table.filter(refresh_tokens::person_id.eq(p_id)),
+                    )
                     .execute(conn)?;
+                    diesel::delete(
+                        password_reset_tokens::table
+                            .filter(password_reset_tokens::email.eq(&p_email)),
+                    )
+                    .execute(conn)?;
+                    diesel::delete(
+                        login_throttles::table
+                            .filter(login_throttles::scope.eq(ThrottleScope::Account.as_str()))
+                            .filter(login_throttles::subject.eq(&account)),
+                    )
+                    .execute(conn)?;
+                    diesel::delete(person::table.filter(person::id.eq(p_id))).execute(conn)
+                }
+                DbConnection::Pg(conn) => {
+                    diesel::delete(entries::table.filter(entries::person_id.eq(p_id)))
+                        .execute(conn)?;
+                    diesel::delete(permissions::table.filter(permissions::person_id.eq(p_id)))
+                        .execute(conn)?;
+                    diesel::delete(
+                        person_identities::table.filter(person_identities::person_id.eq(p_id)),
+                    )
+                    .execute(conn)?;
+                    diesel::delete(
+                        recovery_codes::table.filter(recovery_codes::person_id.eq(p_id)),
+                    )
+                    .execute(conn)?;
+                    diesel::delete(totp_secrets::table.filter(totp_secrets::person_id.eq(p_id)))
+                        .execute(conn)?;
+                    diesel::delete(
+                        refresh_tokens::table.filter(refresh_tokens::person_id.eq(p_id)),
+                    )
+                    .execute(conn)?;
+                    diesel::delete(
+                        password_reset_tokens::table
+                            .filter(password_reset_tokens::email.eq(&p_email)),
+                    )
+                    .execute(conn)?;
+                    diesel::delete(
+                        login_throttles::table
+                            .filter(login_throttles::scope.eq(ThrottleScope::Account.as_str()))
+                            .filter(login_throttles::subject.eq(&account)),
+                    )
+                    .execute(conn)?;
+                    diesel::delete(person::table.filter(person::id.eq(p_id))).execute(conn)
+                }
             }
+        });
+
+        match &result {
+            Ok(rows) => info!("Purged person with ID: {}, affected {} rows", p_id, rows),
+            Err(e) => error!("Failed to purge person with ID {}: {}", p_id, e),
+        }
+
+        result
+    }
+
+    pub fn page(
+        conn: &mut DbConnection,
+        filter: &PersonFilter,
+        sort: Sort<PersonSort>,
+        page: &PageRequest,
+    ) -> QueryResult<Page<models::Person>> {
+        use crate::schema::person::dsl::*;
+        debug!("Retrieving persons page {:?} with {:?}", page, filter);
+
+        macro_rules! sorted {
+            () => {{
+                let query = filtered_persons!(filter);
+                let query = match (sort.column, sort.order) {
+                    (PersonSort::Surname, SortOrder::Asc) => query.order(surname.asc()),
+                    (PersonSort::Surname, SortOrder::Desc) => query.order(surname.desc()),
+                    (PersonSort::Name, SortOrder::Asc) => query.order(name.asc()),
+                    (PersonSort::Name, SortOrder::Desc) => query.order(name.desc()),
+                    (PersonSort::Email, SortOrder::Asc) => query.order(email.asc()),
+                    (PersonSort::Email, SortOrder::Desc) => query.order(email.desc()),
+                    (PersonSort::Role, SortOrder::Asc) => query.order(role.asc()),
+                    (PersonSort::Role, SortOrder::Desc) => query.order(role.desc()),
+                };
+                query
+                    .then_order_by(id.asc())
+                    .limit(page.limit)
+                    .offset(page.offset)
+                    .select(models::Person::as_select())
+            }};
         }
 
-        Self::get_by_id(conn, p_id)
+        let (items, total) = match conn {
+            DbConnection::Sqlite(conn) => (
+                sorted!().load(conn)?,
+                filtered_persons!(filter).count().get_result(conn)?,
+            ),
+            DbConnection::Pg(conn) => (
+                sorted!().load(conn)?,
+                filtered_persons!(filter).count().get_result(conn)?,
+            ),
+        };
+        Ok(Page::new(items, total, page))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn purge_forgets_failed_sign_ins() {
+        use crate::interactions::login_throttle::{
+            LoginThrottleInteractor, ThrottlePolicy, ThrottleScope,
+        };
+        use diesel::{Connection, SqliteConnection};
+
+        let mut conn = DbConnection::Sqlite(SqliteConnection::establish(":memory:").unwrap());
+        crate::migrations::run_pending(&mut conn).unwrap();
+        let person = models::Person::new(
+            "Ada",
+            "Lovelace",
+            "Ada@Example.com",
+            models::Role::Alumno,
+            None,
+        );
+        PersonInteractor::new(&mut conn, &person).unwrap();
+        let policy = ThrottlePolicy::default();
+        let keys = [
+            (ThrottleScope::Account, "ada@example.com", &policy),
+            (ThrottleScope::Ip, "192.0.2.1", &policy),
+        ];
+        LoginThrottleInteractor::start_attempt(&mut conn, &keys)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(PersonInteractor::purge(&mut conn, &person.id).unwrap(), 1);
+        let account =
+            LoginThrottleInteractor::get(&mut conn, ThrottleScope::Account, "ada@example.com");
+        assert!(account.unwrap().is_none());
+        // The address may be someone else's too
+        let ip = LoginThrottleInteractor::get(&mut conn, ThrottleScope::Ip, "192.0.2.1");
+        assert!(ip.unwrap().is_some());
+    }
+
+    #[test]
+    fn search_wildcards_are_literal() {
+        assert_eq!(contains_pattern("ana"), "%ana%");
+        assert_eq!(contains_pattern("100%"), "%100\\%%");
+        assert_eq!(contains_pattern("a_b"), "%a\\_b%");
+        assert_eq!(contains_pattern("c:\\x"), "%c:\\\\x%");
     }
 }
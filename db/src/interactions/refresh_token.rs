@@ -0,0 +1,118 @@
+use crate::DbConnection;
+use crate::models::RefreshToken;
+use crate::schema::refresh_tokens;
+use diesel::prelude::*;
+use log::{error, warn};
+
+pub struct RefreshTokenInteractor;
+
+/// Outcome of [`RefreshTokenInteractor::use_token`]
+pub enum RefreshUse {
+    /// The token was unused and is now used up; rotate it within its family
+    Valid(RefreshToken),
+    /// The token had already been used, so its family has been revoked
+    Reused,
+    /// The token expired or was revoked
+    Unknown,
+}
+
+impl RefreshTokenInteractor {
+    pub fn create(conn: &mut DbConnection, token: &RefreshToken) -> QueryResult<usize> {
+        match conn {
+            DbConnection::Sqlite(conn) => diesel::insert_into(refresh_tokens::table)
+                .values(token)
+                .execute(conn),
+            DbConnection::Pg(conn) => diesel::insert_into(refresh_tokens::table)
+                .values(token)
+                .execute(conn),
+        }
+    }
+
+    pub fn get(conn: &mut DbConnection, token_id: &str) -> QueryResult<Option<RefreshToken>> {
+        let target = refresh_tokens::table.filter(refresh_tokens::id.eq(token_id));
+        match conn {
+            DbConnection::Sqlite(conn) => target
+                .select(RefreshToken::as_select())
+                .first(conn)
+                .optional(),
+            DbConnection::Pg(conn) => target
+                .select(RefreshToken::as_select())
+                .first(conn)
+                .optional(),
+        }
+    }
+
+    /// Uses up the token, unless it was used before: then whoever holds a copy of it is racing
+    /// the legitimate owner, and every token of the family is revoked
+    pub fn use_token(conn: &mut DbConnection, token_id: &str) -> QueryResult<RefreshUse> {
+        let now = chrono::Utc::now().naive_utc();
+        conn.transaction(|conn| {
+            let unused = refresh_tokens::table
+                .filter(refresh_tokens::id.eq(token_id))
+                .filter(refresh_tokens::used_at.is_null())
+                .filter(refresh_tokens::expires_at.gt(now));
+            let used = match conn {
+                DbConnection::Sqlite(conn) => diesel::update(unused)
+                    .set(refresh_tokens::used_at.eq(now))
+                    .execute(conn)?,
+                DbConnection::Pg(conn) => diesel::update(unused)
+                    .set(refresh_tokens::used_at.eq(now))
+                    .execute(conn)?,
+            };
+
+            let Some(token) = Self::get(conn, token_id)? else {
+                return Ok(RefreshUse::Unknown);
+            };
+            if used == 1 {
+                return Ok(RefreshUse::Valid(token));
+            }
+            if token.used_at.is_none() {
+                return Ok(RefreshUse::Unknown);
+            }
+            warn!(
+                "Refresh token {} of person {} was reused, revoking its family",
+                token.id, token.person_id
+            );
+            Self::revoke_family(conn, &token.family_id)?;
+            Ok(RefreshUse::Reused)
+        })
+    }
+
+    /// Revokes the token with `token_id` and every token rotated from the same sign-in
+    pub fn revoke_family_of(conn: &mut DbConnection, token_id: &str) -> QueryResult<usize> {
+        conn.transaction(|conn| match Self::get(conn, token_id)? {
+            Some(token) => Self::revoke_family(conn, &token.family_id),
+            None => Ok(0),
+        })
+    }
+
+    pub fn revoke_family(conn: &mut DbConnection, family: &str) -> QueryResult<usize> {
+        let target = refresh_tokens::table.filter(refresh_tokens::family_id.eq(family));
+        match conn {
+            DbConnection::Sqlite(conn) => diesel::delete(target).execute(conn),
+            DbConnection::Pg(conn) => diesel::delete(target).execute(conn),
+        }
+    }
+
+    /// Signs the person out of every session
+    pub fn revoke_for_person(conn: &mut DbConnection, p_id: &str) -> QueryResult<usize> {
+        let target = refresh_tokens::table.filter(refresh_tokens::person_id.eq(p_id));
+        let result = match conn {
+            DbConnection::Sqlite(conn) => diesel::delete(target).execute(conn),
+            DbConnection::Pg(conn) => diesel::delete(target).execute(conn),
+        };
+        if let Err(e) = &result {
+            error!("Failed to revoke refresh tokens of person {}: {}", p_id, e);
+        }
+        result
+    }
+
+    pub fn delete_expired(conn: &mut DbConnection) -> QueryResult<usize> {
+        let now = chrono::Utc::now().naive_utc();
+        let expired = refresh_tokens::table.filter(refresh_tokens::expires_at.lt(now));
+        match conn {
+            DbConnection::Sqlite(conn) => diesel::delete(expired).execute(conn),
+            DbConnection::Pg(conn) => diesel::delete(expired).execute(conn),
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use crate::DbConnection;
+use crate::models::{RecoveryCode, TotpSecret};
+use crate::schema::{recovery_codes, totp_secrets};
+use diesel::prelude::*;
+use log::{error, info, warn};
+
+pub struct TwoFactorInteractor;
+
+impl TwoFactorInteractor {
+    /// The TOTP secret of a person, confirmed or not
+    pub fn get(conn: &mut DbConnection, p_id: &str) -> QueryResult<Option<TotpSecret>> {
+        match conn {
+            DbConnection::Sqlite(conn) => totp_secrets::table
+                .filter(totp_secrets::person_id.eq(p_id))
+                .select(TotpSecret::as_select())
+                .first(conn)
+                .optional(),
+            DbConnection::Pg(conn) => totp_secrets::table
+                .filter(totp_secrets::person_id.eq(p_id))
+                .select(TotpSecret::as_select())
+                .first(conn)
+                .optional(),
+        }
+    }
+
+    /// Stores a new unconfirmed secret in place of any previous unconfirmed one. Returns false,
+    /// changing nothing, when the person already has two-factor authentication on.
+    pub fn begin_enrollment(conn: &mut DbConnection, secret: &TotpSecret) -> QueryResult<bool> {
+        info!("Starting TOTP enrollment of person {}", secret.person_id);
+        conn.transaction(|conn| {
+            if Self::get(conn, &secret.person_id)?.is_some_and(|current| current.is_confirmed()) {
+                return Ok(false);
+            }
+            let previous =
+                totp_secrets::table.filter(totp_secrets::person_id.eq(&secret.person_id));
+            match conn {
+                DbConnection::Sqlite(conn) => {
+                    diesel::delete(previous).execute(conn)?;
+                    diesel::insert_into(totp_secrets::table)
+                        .values(secret)
+                        .execute(conn)?;
+                }
+                DbConnection::Pg(conn) => {
+                    diesel::delete(previous).execute(conn)?;
+                    diesel::insert_into(totp_secrets::table)
+                        .values(secret)
+                        .execute(conn)?;
+                }
+            }
+            Ok(true)
+        })
+    }
+
+    /// Turns two-factor authentication on with the first valid code, at time step `step`, and
+    /// replaces the recovery codes. Returns false when there is no unconfirmed secret.
+    pub fn confirm(
+        conn: &mut DbConnection,
+        p_id: &str,
+        step: i64,
+        codes: &[RecoveryCode],
+    ) -> QueryResult<bool> {
+        let now = chrono::Utc::now().naive_utc();
+        let result = conn.transaction(|conn| {
+            let pending = totp_secrets::table
+                .filter(totp_secrets::person_id.eq(p_id))
+                .filter(totp_secrets::confirmed_at.is_null());
+            let changes = (
+                totp_secrets::confirmed_at.eq(now),
+                totp_secrets::last_used_step.eq(step),
+            );
+            let confirmed = match conn {
+                DbConnection::Sqlite(conn) => diesel::update(pending).set(changes).execute(conn)?,
+                DbConnection::Pg(conn) => diesel::update(pending).set(changes).execute(conn)?,
+            };
+            if confirmed == 0 {
+                return Ok(false);
+            }
+            Self::replace_recovery_codes(conn, p_id, codes)?;
+            Ok(true)
+        });
+
+        match &result {
+            Ok(true) => info!("Two-factor authentication enabled for person {}", p_id),
+            Ok(false) => warn!("Person {} has no pending TOTP enrollment", p_id),
+            Err(e) => error!("Failed to confirm TOTP of person {}: {}", p_id, e),
+        }
+
+        result
+    }
+
+    fn replace_recovery_codes(
+        conn: &mut DbConnection,
+        p_id: &str,
+        codes: &[RecoveryCode],
+    ) -> QueryResult<()> {
+        let previous = recovery_codes::table.filter(recovery_codes::person_id.eq(p_id));
+        match conn {
+            DbConnection::Sqlite(conn) => {
+                diesel::delete(previous).execute(conn)?;
+                diesel::insert_into(recovery_codes::table)
+                    .values(codes)
+                    .execute(conn)?;
+            }
+            DbConnection::Pg(conn) => {
+                diesel::delete(previous).execute(conn)?;
+                diesel::insert_into(recovery_codes::table)
+                    .values(codes)
+                    .execute(conn)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Records that the code of time step `step` was used. Returns false when that step, or a
+    /// later one, was already used, or two-factor authentication is off.
+    pub fn use_step(conn: &mut DbConnection, p_id: &str, step: i64) -> QueryResult<bool> {
+        let target = totp_secrets::table
+            .filter(totp_secrets::person_id.eq(p_id))
+            .filter(totp_secrets::confirmed_at.is_not_null())
+            .filter(
+                totp_secrets::last_used_step
+                    .is_null()
+                    .or(totp_secrets::last_used_step.lt(step)),
+            );
+        let updated = match conn {
+            DbConnection::Sqlite(conn) => diesel::update(target)
+                .set(totp_secrets::last_used_step.eq(step))
+                .execute(conn)?,
+            DbConnection::Pg(conn) => diesel::update(target)
+                .set(totp_secrets::last_used_step.eq(step))
+                .execute(conn)?,
+        };
+        Ok(updated == 1)
+    }
+
+    /// Spends one of the person's unused recovery codes, false when it doesn't match any
+    pub fn use_recovery_code(conn: &mut DbConnection, p_id: &str, code: &str) -> QueryResult<bool> {
+        let target = recovery_codes::table
+            .filter(recovery_codes::person_id.eq(p_id))
+            .filter(recovery_codes::code_hash.eq(crate::crypto::recovery_code_hash(code)))
+            .filter(recovery_codes::used_at.is_null());
+        let now = chrono::Utc::now().naive_utc();
+        let updated = match conn {
+            DbConnection::Sqlite(conn) => diesel::update(target)
+                .set(recovery_codes::used_at.eq(now))
+                .execute(conn)?,
+            DbConnection::Pg(conn) => diesel::update(target)
+                .set(recovery_codes::used_at.eq(now))
+                .execute(conn)?,
+        };
+        if updated == 1 {
+            warn!("Person {} signed in with a recovery code", p_id);
+        }
+        Ok(updated == 1)
+    }
+
+    pub fn count_unused_recovery_codes(conn: &mut DbConnection, p_id: &str) -> QueryResult<i64> {
+        let unused = recovery_codes::table
+            .filter(recovery_codes::person_id.eq(p_id))
+            .filter(recovery_codes::used_at.is_null())
+            .count();
+        match conn {
+            DbConnection::Sqlite(conn) => unused.get_result(conn),
+            DbConnection::Pg(conn) => unused.get_result(conn),
+        }
+    }
+
+    /// Turns two-factor authentication off, removing the secret and the recovery codes
+    pub fn disable(conn: &mut DbConnection, p_id: &str) -> QueryResult<usize> {
+        info!("Disabling two-factor authentication of person {}", p_id);
+        conn.transaction(|conn| match conn {
+            DbConnection::Sqlite(conn) => {
+                diesel::delete(recovery_codes::table.filter(recovery_codes::person_id.eq(p_id)))
+                    .execute(conn)?;
+                diesel::delete(totp_secrets::table.filter(totp_secrets::person_id.eq(p_id)))
+                    .execute(conn)
+            }
+            DbConnection::Pg(conn) => {
+                diesel::delete(recovery_codes::table.filter(recovery_codes::person_id.eq(p_id)))
+                    .execute(conn)?;
+                diesel::delete(totp_secrets::table.filter(totp_secrets::person_id.eq(p_id)))
+                    .execute(conn)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interactions::person::PersonInteractor;
+    use crate::models::{Person, Role};
+    use diesel::SqliteConnection;
+
+    /// A person who has started enrolling
+    fn enrolling_person() -> (DbConnection, String) {
+        let mut conn = DbConnection::Sqlite(SqliteConnection::establish(":memory:").unwrap());
+        crate::migrations::run_pending(&mut conn).unwrap();
+        let person = Person::new("Ada", "Lovelace", "ada@example.com", Role::Admin, None);
+        PersonInteractor::new(&mut conn, &person).unwrap();
+        let secret = TotpSecret::new(&person.id, "JBSWY3DPEHPK3PXP");
+        assert!(TwoFactorInteractor::begin_enrollment(&mut conn, &secret).unwrap());
+        (conn, person.id)
+    }
+
+    /// A person who confirmed their enrollment with the code of `step`
+    fn enrolled_person(step: i64) -> (DbConnection, String) {
+        let (mut conn, p_id) = enrolling_person();
+        assert!(TwoFactorInteractor::confirm(&mut conn, &p_id, step, &[]).unwrap());
+        (conn, p_id)
+    }
+
+    #[test]
+    fn a_step_is_used_once() {
+        let (mut conn, p_id) = enrolled_person(100);
+        // The code that confirmed the enrollment can't sign in
+        assert!(!TwoFactorInteractor::use_step(&mut conn, &p_id, 100).unwrap());
+        assert!(TwoFactorInteractor::use_step(&mut conn, &p_id, 101).unwrap());
+        assert!(!TwoFactorInteractor::use_step(&mut conn, &p_id, 101).unwrap());
+    }
+
+    #[test]
+    fn earlier_steps_are_refused_after_a_later_one() {
+        let (mut conn, p_id) = enrolled_person(100);
+        assert!(TwoFactorInteractor::use_step(&mut conn, &p_id, 102).unwrap());
+        // Still within the skew of the next sign-in, but older than the last code used
+        assert!(!TwoFactorInteractor::use_step(&mut conn, &p_id, 101).unwrap());
+    }
+
+    #[test]
+    fn steps_are_refused_until_enrollment_is_confirmed() {
+        let (mut conn, p_id) = enrolling_person();
+        assert!(!TwoFactorInteractor::use_step(&mut conn, &p_id, 100).unwrap());
+    }
+}
@@ -0,0 +1,221 @@
+use crate::DbConnection;
+use crate::models::{Webhook, WebhookChanges, WebhookDelivery};
+use crate::schema::{webhook_deliveries, webhooks};
+use diesel::prelude::*;
+use log::{debug, error, info};
+
+pub struct WebhookInteractor;
+
+impl WebhookInteractor {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(conn: &mut DbConnection, webhook: &Webhook) -> QueryResult<usize> {
+        debug!("Creating webhook for {}", webhook.url);
+        let result = match conn {
+            DbConnection::Sqlite(conn) => diesel::insert_into(webhooks::table)
+                .values(webhook)
+                .execute(conn),
+            DbConnection::Pg(conn) => diesel::insert_into(webhooks::table)
+                .values(webhook)
+                .execute(conn),
+        };
+
+        match &result {
+            Ok(_) => info!("Created webhook {} for {}", webhook.id, webhook.url),
+            Err(e) => error!("Failed to create webhook for {}: {}", webhook.url, e),
+        }
+
+        result
+    }
+
+    pub fn get(conn: &mut DbConnection) -> QueryResult<Vec<Webhook>> {
+        match conn {
+            DbConnection::Sqlite(conn) => webhooks::table
+                .order(webhooks::created_at.asc())
+                .select(Webhook::as_select())
+                .load(conn),
+            DbConnection::Pg(conn) => webhooks::table
+                .order(webhooks::created_at.asc())
+                .select(Webhook::as_select())
+                .load(conn),
+        }
+    }
+
+    pub fn get_by_id(conn: &mut DbConnection, w_id: &str) -> QueryResult<Webhook> {
+        match conn {
+            DbConnection::Sqlite(conn) => webhooks::table
+                .filter(webhooks::id.eq(w_id))
+                .select(Webhook::as_select())
+                .first(conn),
+            DbConnection::Pg(conn) => webhooks::table
+                .filter(webhooks::id.eq(w_id))
+                .select(Webhook::as_select())
+                .first(conn),
+        }
+    }
+
+    /// The active webhooks subscribed to `event`
+    pub fn get_subscribed(conn: &mut DbConnection, event: &str) -> QueryResult<Vec<Webhook>> {
+        let active = match conn {
+            DbConnection::Sqlite(conn) => webhooks::table
+                .filter(webhooks::active.eq(true))
+                .select(Webhook::as_select())
+                .load(conn)?,
+            DbConnection::Pg(conn) => webhooks::table
+                .filter(webhooks::active.eq(true))
+                .select(Webhook::as_select())
+                .load(conn)?,
+        };
+        Ok(active
+            .into_iter()
+            .filter(|webhook| webhook.subscribes_to(event))
+            .collect())
+    }
+
+    /// Updates only the columns set in `changes`
+    pub fn patch(
+        conn: &mut DbConnection,
+        w_id: &str,
+        changes: &WebhookChanges,
+    ) -> QueryResult<usize> {
+        info!("Patching webhook with ID: {}", w_id);
+        match conn {
+            DbConnection::Sqlite(conn) => {
+                diesel::update(webhooks::table.filter(webhooks::id.eq(w_id)))
+                    .set(changes)
+                    .execute(conn)
+            }
+            DbConnection::Pg(conn) => diesel::update(webhooks::table.filter(webhooks::id.eq(w_id)))
+                .set(changes)
+                .execute(conn),
+        }
+    }
+
+    /// Removes a webhook together with its delivery log
+    pub fn delete(conn: &mut DbConnection, w_id: &str) -> QueryResult<usize> {
+        info!("Deleting webhook with ID: {}", w_id);
+        conn.transaction(|conn| match conn {
+            DbConnection::Sqlite(conn) => {
+                diesel::delete(
+                    webhook_deliveries::table.filter(webhook_deliveries::webhook_id.eq(w_id)),
+                )
+                .execute(conn)?;
+                diesel::delete(webhooks::table.filter(webhooks::id.eq(w_id))).execute(conn)
+            }
+            DbConnection::Pg(conn) => {
+                diesel::delete(
+                    webhook_deliveries::table.filter(webhook_deliveries::webhook_id.eq(w_id)),
+                )
+                .execute(conn)?;
+                diesel::delete(webhooks::table.filter(webhooks::id.eq(w_id))).execute(conn)
+            }
+        })
+    }
+
+    pub fn record_delivery(
+        conn: &mut DbConnection,
+        delivery: &WebhookDelivery,
+    ) -> QueryResult<usize> {
+        match conn {
+            DbConnection::Sqlite(conn) => diesel::insert_into(webhook_deliveries::table)
+                .values(delivery)
+                .execute(conn),
+            DbConnection::Pg(conn) => diesel::insert_into(webhook_deliveries::table)
+                .values(delivery)
+                .execute(conn),
+        }
+    }
+
+    /// Claims up to `limit` pending attempts that are due, with their webhooks. A claimed
+    /// attempt is due again at `lease_until`, so it is retried if it is never finished.
+    pub fn claim_due(
+        conn: &mut DbConnection,
+        lease_until: chrono::NaiveDateTime,
+        limit: i64,
+    ) -> QueryResult<Vec<(WebhookDelivery, Webhook)>> {
+        let now = chrono::Utc::now().naive_utc();
+        let due = webhook_deliveries::table
+            .inner_join(webhooks::table)
+            .filter(webhook_deliveries::next_attempt_at.le(now))
+            .order(webhook_deliveries::next_attempt_at.asc())
+            .limit(limit);
+        let due: Vec<(WebhookDelivery, Webhook)> = match conn {
+            DbConnection::Sqlite(conn) => due
+                .select((WebhookDelivery::as_select(), Webhook::as_select()))
+                .load(conn)?,
+            DbConnection::Pg(conn) => due
+                .select((WebhookDelivery::as_select(), Webhook::as_select()))
+                .load(conn)?,
+        };
+
+        let mut claimed = Vec::with_capacity(due.len());
+        for (mut delivery, webhook) in due {
+            // Another worker may have claimed it since it was loaded
+            let unclaimed = webhook_deliveries::table
+                .filter(webhook_deliveries::id.eq(&delivery.id))
+                .filter(webhook_deliveries::next_attempt_at.le(now));
+            let updated = match conn {
+                DbConnection::Sqlite(conn) => diesel::update(unclaimed)
+                    .set(webhook_deliveries::next_attempt_at.eq(lease_until))
+                    .execute(conn)?,
+                DbConnection::Pg(conn) => diesel::update(unclaimed)
+                    .set(webhook_deliveries::next_attempt_at.eq(lease_until))
+                    .execute(conn)?,
+            };
+            if updated == 1 {
+                delivery.next_attempt_at = Some(lease_until);
+                claimed.push((delivery, webhook));
+            }
+        }
+        Ok(claimed)
+    }
+
+    /// Records the outcome of a claimed attempt, queueing `retry` if it failed and may be retried
+    pub fn finish_attempt(
+        conn: &mut DbConnection,
+        d_id: &str,
+        status_code: Option<i32>,
+        error: Option<&str>,
+        retry: Option<&WebhookDelivery>,
+    ) -> QueryResult<()> {
+        let outcome = (
+            webhook_deliveries::status_code.eq(status_code),
+            webhook_deliveries::error.eq(error),
+            webhook_deliveries::success.eq(error.is_none()),
+            webhook_deliveries::body.eq(None::<String>),
+            webhook_deliveries::next_attempt_at.eq(None::<chrono::NaiveDateTime>),
+        );
+        conn.transaction(|conn| {
+            let target = webhook_deliveries::table.filter(webhook_deliveries::id.eq(d_id));
+            match conn {
+                DbConnection::Sqlite(conn) => diesel::update(target).set(outcome).execute(conn)?,
+                DbConnection::Pg(conn) => diesel::update(target).set(outcome).execute(conn)?,
+            };
+            if let Some(retry) = retry {
+                Self::record_delivery(conn, retry)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// The latest delivery attempts of a webhook, newest first
+    pub fn get_deliveries(
+        conn: &mut DbConnection,
+        w_id: &str,
+        limit: i64,
+    ) -> QueryResult<Vec<WebhookDelivery>> {
+        match conn {
+            DbConnection::Sqlite(conn) => webhook_deliveries::table
+                .filter(webhook_deliveries::webhook_id.eq(w_id))
+                .order(webhook_deliveries::created_at.desc())
+                .limit(limit)
+                .select(WebhookDelivery::as_select())
+                .load(conn),
+            DbConnection::Pg(conn) => webhook_deliveries::table
+                .filter(webhook_deliveries::webhook_id.eq(w_id))
+                .order(webhook_deliveries::created_at.desc())
+                .limit(limit)
+                .select(WebhookDelivery::as_select())
+                .load(conn),
+        }
+    }
+}
@@ -1,5 +1,7 @@
-use diesel::connection::Connection;
+use diesel::ConnectionError;
+use diesel::connection::{AnsiTransactionManager, Connection, TransactionManager};
 use diesel::prelude::{PgConnection, SqliteConnection};
+use diesel::result::{ConnectionResult, QueryResult};
 use interactions::entries::Action;
 use interactions::{
     entries::EntriesInteractor, permissions::PermissionsInteractor, person::PersonInteractor,
@@ -8,10 +10,14 @@ use log::{debug, error, info, trace, warn};
 use models::Role;
 use std::path::Path;
 use std::time::Duration;
+pub mod attendance;
 pub mod crypto;
 pub mod date;
 pub mod interactions;
+pub mod migrations;
 pub mod models;
+pub mod pagination;
+pub mod pool;
 pub mod schema;
 
 pub enum DbConnection {
@@ -19,57 +25,155 @@ pub enum DbConnection {
     Pg(PgConnection),
 }
 
+impl DbConnection {
+    /// Runs `f` inside a transaction on either backend: committed when `f` returns `Ok`,
+    /// rolled back when it returns `Err`. Nested calls use savepoints.
+    pub fn transaction<T, E, F>(&mut self, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&mut DbConnection) -> Result<T, E>,
+        E: From<diesel::result::Error>,
+    {
+        self.begin_transaction()?;
+        match f(self) {
+            Ok(value) => {
+                self.commit_transaction()?;
+                Ok(value)
+            }
+            Err(e) => {
+                if let Err(rollback) = self.rollback_transaction() {
+                    error!("Failed to roll back transaction: {}", rollback);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Like [`transaction`](Self::transaction), but a SQLite one takes the write lock up front
+    /// (`BEGIN IMMEDIATE`), so two of them can't both read before either writes. PostgreSQL
+    /// callers lock the rows they read with `FOR UPDATE` instead.
+    pub fn immediate_transaction<T, E, F>(&mut self, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&mut DbConnection) -> Result<T, E>,
+        E: From<diesel::result::Error>,
+    {
+        match self {
+            DbConnection::Sqlite(conn) => {
+                let depth = AnsiTransactionManager::transaction_manager_status_mut(conn)
+                    .transaction_depth()?;
+                match depth {
+                    None => AnsiTransactionManager::begin_transaction_sql(conn, "BEGIN IMMEDIATE")?,
+                    // Nested in another transaction, which decided how to lock
+                    Some(_) => AnsiTransactionManager::begin_transaction(conn)?,
+                }
+            }
+            DbConnection::Pg(conn) => AnsiTransactionManager::begin_transaction(conn)?,
+        }
+        match f(self) {
+            Ok(value) => {
+                self.commit_transaction()?;
+                Ok(value)
+            }
+            Err(e) => {
+                if let Err(rollback) = self.rollback_transaction() {
+                    error!("Failed to roll back transaction: {}", rollback);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn begin_transaction(&mut self) -> QueryResult<()> {
+        match self {
+            DbConnection::Sqlite(conn) => AnsiTransactionManager::begin_transaction(conn),
+            DbConnection::Pg(conn) => AnsiTransactionManager::begin_transaction(conn),
+        }
+    }
+
+    fn commit_transaction(&mut self) -> QueryResult<()> {
+        match self {
+            DbConnection::Sqlite(conn) => AnsiTransactionManager::commit_transaction(conn),
+            DbConnection::Pg(conn) => AnsiTransactionManager::commit_transaction(conn),
+        }
+    }
+
+    fn rollback_transaction(&mut self) -> QueryResult<()> {
+        match self {
+            DbConnection::Sqlite(conn) => AnsiTransactionManager::rollback_transaction(conn),
+            DbConnection::Pg(conn) => AnsiTransactionManager::rollback_transaction(conn),
+        }
+    }
+}
+
 pub fn establish_connection(db_url: &str) -> DbConnection {
+    match try_establish_connection(db_url) {
+        Ok(conn) => conn,
+        Err(e) => panic!("Error connecting to {db_url}: {e}"),
+    }
+}
+
+/// Like [`establish_connection`], but returns the error instead of panicking
+pub fn try_establish_connection(db_url: &str) -> ConnectionResult<DbConnection> {
     let database_url = db_url.to_string();
     trace!("Establishing database connection to: {}", database_url);
     if Path::new(&database_url).exists() {
         trace!("Detected SQLite database");
-        return DbConnection::Sqlite(establish_sqlite_connection(&database_url));
+        return establish_sqlite_connection(&database_url).map(DbConnection::Sqlite);
     }
     if database_url.starts_with("postgres://") || database_url.starts_with("postgresql://") {
         trace!("Detected PostgreSQL database");
-        return DbConnection::Pg(establish_pg_connection(&database_url));
+        return establish_pg_connection(&database_url).map(DbConnection::Pg);
     }
     log::error!("Invalid database URL: {}", database_url);
-    panic!("Invalid database URL: {database_url}");
+    Err(ConnectionError::InvalidConnectionUrl(format!(
+        "Invalid database URL: {database_url}"
+    )))
 }
 
-fn establish_sqlite_connection(db_url: &str) -> SqliteConnection {
+/// Like [`try_establish_connection`], but creates the SQLite file (and its directory) when
+/// the URL is not a PostgreSQL one and nothing exists at that path yet
+pub fn establish_or_create_connection(db_url: &str) -> ConnectionResult<DbConnection> {
+    let is_pg = db_url.starts_with("postgres://") || db_url.starts_with("postgresql://");
+    let path = Path::new(db_url);
+    if !is_pg && !path.exists() {
+        info!("Creating SQLite database at: {}", db_url);
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).map_err(|e| {
+                ConnectionError::BadConnection(format!("Cannot create {}: {}", dir.display(), e))
+            })?;
+        }
+        return establish_sqlite_connection(db_url).map(DbConnection::Sqlite);
+    }
+    try_establish_connection(db_url)
+}
+
+fn establish_sqlite_connection(db_url: &str) -> ConnectionResult<SqliteConnection> {
     let database_url = db_url.to_string();
     trace!("Connecting to SQLite database at: {}", database_url);
-    match SqliteConnection::establish(&database_url) {
-        Ok(conn) => {
-            trace!("Successfully connected to SQLite database");
-            conn
-        }
-        Err(e) => {
-            log::error!(
-                "Failed to connect to SQLite database at {}: {}",
-                database_url,
-                e
-            );
-            panic!("Error connecting to {database_url}: {}", e);
-        }
+    let result = SqliteConnection::establish(&database_url);
+    match &result {
+        Ok(_) => trace!("Successfully connected to SQLite database"),
+        Err(e) => log::error!(
+            "Failed to connect to SQLite database at {}: {}",
+            database_url,
+            e
+        ),
     }
+    result
 }
 
-fn establish_pg_connection(db_url: &str) -> PgConnection {
+fn establish_pg_connection(db_url: &str) -> ConnectionResult<PgConnection> {
     let database_url = db_url.to_string();
     trace!("Connecting to PostgreSQL database at: {}", database_url);
-    match PgConnection::establish(&database_url) {
-        Ok(conn) => {
-            trace!("Successfully connected to PostgreSQL database");
-            conn
-        }
-        Err(e) => {
-            log::error!(
-                "Failed to connect to PostgreSQL database at {}: {}",
-                database_url,
-                e
-            );
-            panic!("Error connecting to {database_url}: {}", e);
-        }
+    let result = PgConnection::establish(&database_url);
+    match &result {
+        Ok(_) => trace!("Successfully connected to PostgreSQL database"),
+        Err(e) => log::error!(
+            "Failed to connect to PostgreSQL database at {}: {}",
+            database_url,
+            e
+        ),
     }
+    result
 }
 
 pub fn seed(db_url: &str) -> Result<(), Box<dyn std::error::Error>> {
@@ -83,7 +187,6 @@ pub fn seed(db_url: &str) -> Result<(), Box<dyn std::error::Error>> {
         "admin@cpifplosenlaces.com",
         Role::Admin,
         Some(&crypto::to_hash("admin")),
-        None,
     );
 
     let permission = models::Permissions::new(&person.id, true, true, true, true, true);
@@ -113,7 +216,6 @@ pub fn seed(db_url: &str) -> Result<(), Box<dyn std::error::Error>> {
             &format!("user{i}@example.com"),
             Role::Alumno,
             Some(&crypto::to_hash(&format!("user{i}"))),
-            None,
         );
 
         let permission = models::Permissions::new(&person.id, true, true, true, true, true);
@@ -0,0 +1,90 @@
+use crate::DbConnection;
+use diesel::backend::Backend;
+use diesel::migration::{Migration, MigrationSource, MigrationVersion};
+use diesel::pg::Pg;
+use diesel::sqlite::Sqlite;
+use diesel_migrations::{EmbeddedMigrations, MigrationHarness, embed_migrations};
+use log::info;
+
+pub type MigrationResult<T> = diesel::migration::Result<T>;
+
+pub const PG_MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+/// Same migrations as [`PG_MIGRATIONS`] with the same versions, rewritten where SQLite
+/// lacks the syntax (e.g. `ALTER COLUMN`)
+pub const SQLITE_MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations_sqlite");
+
+/// An embedded migration and whether it has been applied
+pub struct MigrationState {
+    pub name: String,
+    pub applied: bool,
+}
+
+/// Applies every pending migration and returns their versions
+pub fn run_pending(conn: &mut DbConnection) -> MigrationResult<Vec<String>> {
+    let applied = match conn {
+        DbConnection::Sqlite(conn) => conn.run_pending_migrations(SQLITE_MIGRATIONS)?,
+        DbConnection::Pg(conn) => conn.run_pending_migrations(PG_MIGRATIONS)?,
+    };
+    let applied = versions(applied);
+    for version in &applied {
+        info!("Applied migration {}", version);
+    }
+    Ok(applied)
+}
+
+/// Reverts the most recently applied migration and returns its version
+pub fn revert_last(conn: &mut DbConnection) -> MigrationResult<String> {
+    let reverted = match conn {
+        DbConnection::Sqlite(conn) => conn.revert_last_migration(SQLITE_MIGRATIONS)?,
+        DbConnection::Pg(conn) => conn.revert_last_migration(PG_MIGRATIONS)?,
+    };
+    info!("Reverted migration {}", reverted);
+    Ok(reverted.to_string())
+}
+
+/// Reverts the most recently applied migration and applies it again
+pub fn redo(conn: &mut DbConnection) -> MigrationResult<String> {
+    let reverted = revert_last(conn)?;
+    let applied = match conn {
+        DbConnection::Sqlite(conn) => conn.run_next_migration(SQLITE_MIGRATIONS)?,
+        DbConnection::Pg(conn) => conn.run_next_migration(PG_MIGRATIONS)?,
+    };
+    info!("Applied migration {} again", applied);
+    Ok(reverted)
+}
+
+/// Every embedded migration for the backend of `conn`, oldest first
+pub fn status(conn: &mut DbConnection) -> MigrationResult<Vec<MigrationState>> {
+    match conn {
+        DbConnection::Sqlite(conn) => {
+            let applied = conn.applied_migrations()?;
+            states::<Sqlite>(
+                MigrationSource::<Sqlite>::migrations(&SQLITE_MIGRATIONS)?,
+                &applied,
+            )
+        }
+        DbConnection::Pg(conn) => {
+            let applied = conn.applied_migrations()?;
+            states::<Pg>(MigrationSource::<Pg>::migrations(&PG_MIGRATIONS)?, &applied)
+        }
+    }
+}
+
+fn states<DB: Backend>(
+    migrations: Vec<Box<dyn Migration<DB>>>,
+    applied: &[MigrationVersion],
+) -> MigrationResult<Vec<MigrationState>> {
+    let mut states: Vec<MigrationState> = migrations
+        .iter()
+        .map(|m| MigrationState {
+            name: m.name().to_string(),
+            applied: applied.contains(&m.name().version()),
+        })
+        .collect();
+    states.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(states)
+}
+
+fn versions(versions: Vec<MigrationVersion>) -> Vec<String> {
+    versions.iter().map(|v| v.to_string()).collect()
+}
@@ -5,6 +5,11 @@ use diesel::{expression::AsExpression, prelude::*, sql_types::Text};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+/// Version of a freshly inserted row
+pub fn initial_version() -> i32 {
+    1
+}
+
 #[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, AsExpression)]
 #[diesel(sql_type = Text)]
 pub enum Role {
@@ -23,6 +28,19 @@ impl Display for Role {
     }
 }
 
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "admin" => Ok(Role::Admin),
+            "profesor" => Ok(Role::Profesor),
+            "alumno" => Ok(Role::Alumno),
+            _ => Err(format!("Invalid role: {s}")),
+        }
+    }
+}
+
 #[derive(Queryable, Selectable, Insertable, Serialize, Deserialize, AsChangeset, JsonSchema)]
 #[diesel(table_name = crate::schema::person)]
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
@@ -33,7 +51,14 @@ pub struct Person {
     pub email: String,
     pub role: String,
     pub password_hash: Option<String>,
-    pub google_id: Option<String>,
+    /// Bumped by every update
+    #[diesel(skip_update)]
+    #[serde(default = "initial_version")]
+    pub version: i32,
+    /// Set while the person is soft-deleted
+    #[diesel(skip_update)]
+    #[serde(default)]
+    pub deleted_at: Option<chrono::NaiveDateTime>,
 }
 
 impl Person {
@@ -43,13 +68,11 @@ impl Person {
         email: &str,
         role: Role,
         password_hash: Option<&str>,
-        google_id: Option<&str>,
     ) -> Self {
         let name = name.to_string();
         let surname = surname.to_string();
         let email = email.to_string();
         let password_hash = password_hash.map(|s| s.to_string());
-        let google_id = google_id.map(|s| s.to_string());
 
         Self {
             id: uuid::Uuid::new_v4().to_string(),
@@ -58,11 +81,146 @@ impl Person {
             email,
             role: role.to_string(),
             password_hash,
-            google_id,
+            version: initial_version(),
+            deleted_at: None,
+        }
+    }
+}
+
+/// An account at an external identity provider that signs in as a person
+#[derive(Queryable, Selectable, Insertable, Serialize, Deserialize, JsonSchema)]
+#[diesel(table_name = crate::schema::person_identities)]
+pub struct PersonIdentity {
+    pub id: String,
+    pub person_id: String,
+    /// Name of the provider in `SYN_OIDC_PROVIDERS`, such as `google`
+    pub provider: String,
+    /// The `sub` claim of the provider's ID tokens
+    pub subject: String,
+    /// Email of the account when it was linked
+    pub email: Option<String>,
+    pub linked_at: chrono::NaiveDateTime,
+}
+
+impl PersonIdentity {
+    pub fn new(person_id: &str, provider: &str, subject: &str, email: Option<&str>) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            person_id: person_id.to_string(),
+            provider: provider.to_string(),
+            subject: subject.to_string(),
+            email: email.map(|s| s.to_string()),
+            linked_at: chrono::Utc::now().naive_utc(),
+        }
+    }
+}
+
+/// TOTP secret of a person; two-factor authentication is on once it is confirmed
+#[derive(Queryable, Selectable, Insertable)]
+#[diesel(table_name = crate::schema::totp_secrets)]
+pub struct TotpSecret {
+    pub person_id: String,
+    /// Base32, as shown to authenticator apps
+    pub secret: String,
+    pub confirmed_at: Option<chrono::NaiveDateTime>,
+    /// Time step of the last accepted code
+    pub last_used_step: Option<i64>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+impl TotpSecret {
+    pub fn new(person_id: &str, secret: &str) -> Self {
+        Self {
+            person_id: person_id.to_string(),
+            secret: secret.to_string(),
+            confirmed_at: None,
+            last_used_step: None,
+            created_at: chrono::Utc::now().naive_utc(),
+        }
+    }
+
+    pub fn is_confirmed(&self) -> bool {
+        self.confirmed_at.is_some()
+    }
+}
+
+/// One-time code that replaces a TOTP code when the authenticator app is lost
+#[derive(Queryable, Selectable, Insertable)]
+#[diesel(table_name = crate::schema::recovery_codes)]
+pub struct RecoveryCode {
+    pub id: String,
+    pub person_id: String,
+    pub code_hash: String,
+    pub used_at: Option<chrono::NaiveDateTime>,
+}
+
+impl RecoveryCode {
+    pub fn new(person_id: &str, code: &str) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            person_id: person_id.to_string(),
+            code_hash: crate::crypto::recovery_code_hash(code),
+            used_at: None,
+        }
+    }
+}
+
+/// A refresh token handed out; it is used up when exchanged for a new one
+#[derive(Queryable, Selectable, Insertable)]
+#[diesel(table_name = crate::schema::refresh_tokens)]
+pub struct RefreshToken {
+    /// The `jti` of the token
+    pub id: String,
+    /// Shared by the tokens rotated from the same sign-in
+    pub family_id: String,
+    pub person_id: String,
+    pub expires_at: chrono::NaiveDateTime,
+    pub used_at: Option<chrono::NaiveDateTime>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+impl RefreshToken {
+    /// A token for `person_id` valid for `ttl`, rotated from `family_id` or starting a new family
+    pub fn new(person_id: &str, family_id: Option<&str>, ttl: chrono::Duration) -> Self {
+        let now = chrono::Utc::now().naive_utc();
+        let id = uuid::Uuid::new_v4().to_string();
+        Self {
+            family_id: family_id.map_or_else(|| id.clone(), |family| family.to_string()),
+            id,
+            person_id: person_id.to_string(),
+            expires_at: now + ttl,
+            used_at: None,
+            created_at: now,
         }
     }
 }
 
+/// Recent failed sign-ins counted against an account or a client address
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Clone)]
+#[diesel(table_name = crate::schema::login_throttles)]
+#[diesel(primary_key(scope, subject), treat_none_as_null = true)]
+pub struct LoginThrottle {
+    /// `account` or `ip`
+    pub scope: String,
+    /// Lowercase email of the account, or the address
+    pub subject: String,
+    pub failures: i32,
+    pub last_failure_at: chrono::NaiveDateTime,
+    /// Attempts are refused until then
+    pub locked_until: Option<chrono::NaiveDateTime>,
+}
+
+/// Columns of a person to change; `None` fields are left untouched
+#[derive(AsChangeset, Default)]
+#[diesel(table_name = crate::schema::person)]
+pub struct PersonChanges {
+    pub name: Option<String>,
+    pub surname: Option<String>,
+    pub email: Option<String>,
+    pub role: Option<String>,
+    pub password_hash: Option<String>,
+}
+
 #[derive(Queryable, Selectable, Insertable, Serialize, Deserialize, AsChangeset, JsonSchema)]
 #[diesel(table_name = crate::schema::permissions)]
 pub struct Permissions {
@@ -73,6 +231,10 @@ pub struct Permissions {
     pub see_others_history: bool,
     pub admin_panel: bool,
     pub edit_permissions: bool,
+    /// Bumped by every update
+    #[diesel(skip_update)]
+    #[serde(default = "initial_version")]
+    pub version: i32,
 }
 
 impl Permissions {
@@ -93,41 +255,27 @@ impl Permissions {
             see_others_history,
             admin_panel,
             edit_permissions,
+            version: initial_version(),
         }
     }
 
-    pub fn update(&mut self, db_url: &str) {
-        use crate::schema::permissions::dsl::*;
-        let conn = &mut crate::establish_connection(db_url);
-        match conn {
-            crate::DbConnection::Sqlite(conn) => {
-                diesel::update(permissions.filter(id.eq(&self.id)))
-                    .set((
-                        dashboard.eq(&self.dashboard),
-                        see_self_history.eq(&self.see_self_history),
-                        see_others_history.eq(&self.see_others_history),
-                        admin_panel.eq(&self.admin_panel),
-                        edit_permissions.eq(&self.edit_permissions),
-                    ))
-                    .execute(conn)
-                    .expect("Error updating permissions");
-            }
-            crate::DbConnection::Pg(conn) => {
-                diesel::update(permissions.filter(id.eq(&self.id)))
-                    .set((
-                        dashboard.eq(&self.dashboard),
-                        see_self_history.eq(&self.see_self_history),
-                        see_others_history.eq(&self.see_others_history),
-                        admin_panel.eq(&self.admin_panel),
-                        edit_permissions.eq(&self.edit_permissions),
-                    ))
-                    .execute(conn)
-                    .expect("Error updating permissions");
-            }
-        }
+    /// What self-registered accounts start with: the dashboard and their own history
+    pub fn for_new_account(person_id: &str) -> Self {
+        Self::new(person_id, true, true, false, false, false)
     }
 }
 
+/// Columns of a permission set to change; `None` fields are left untouched
+#[derive(AsChangeset, Default)]
+#[diesel(table_name = crate::schema::permissions)]
+pub struct PermissionsChanges {
+    pub dashboard: Option<bool>,
+    pub see_self_history: Option<bool>,
+    pub see_others_history: Option<bool>,
+    pub admin_panel: Option<bool>,
+    pub edit_permissions: Option<bool>,
+}
+
 #[derive(
     Queryable, Selectable, Insertable, Serialize, Deserialize, AsChangeset, JsonSchema, Debug,
 )]
@@ -137,20 +285,26 @@ pub struct Entry {
     pub person_id: String,
     pub instant: chrono::NaiveDateTime,
     pub action: String,
+    /// Bumped by every update
+    #[diesel(skip_update)]
+    #[serde(default = "initial_version")]
+    pub version: i32,
+    /// Accepted out of sequence or inserted to correct one, left for an admin to review
+    #[serde(default)]
+    pub flagged: bool,
 }
 
 impl Entry {
     pub fn new(person_id: &str, action: Action) -> Self {
         let person_id = person_id.to_string();
-        let action = match action {
-            Action::Enter => "Enter".to_string(),
-            Action::Exit => "Exit".to_string(),
-        };
+        let action = action.to_string();
         Self {
             id: uuid::Uuid::new_v4().to_string(),
             person_id,
             instant: chrono::Local::now().naive_utc(),
             action,
+            version: initial_version(),
+            flagged: false,
         }
     }
 
@@ -160,15 +314,14 @@ impl Entry {
         timestamp: chrono::NaiveDateTime,
     ) -> Self {
         let person_id = person_id.to_string();
-        let action = match action {
-            Action::Enter => "Enter".to_string(),
-            Action::Exit => "Exit".to_string(),
-        };
+        let action = action.to_string();
         Self {
             id: uuid::Uuid::new_v4().to_string(),
             person_id,
             instant: timestamp,
             action,
+            version: initial_version(),
+            flagged: false,
         }
     }
 }
@@ -200,20 +353,7 @@ impl PasswordResetToken {
     }
 
     pub fn generate_token() -> String {
-        use rand::Rng;
-        const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
-                               abcdefghijklmnopqrstuvwxyz\
-                               0123456789";
-        const TOKEN_LEN: usize = 32;
-
-        let mut rng = rand::thread_rng();
-
-        (0..TOKEN_LEN)
-            .map(|_| {
-                let idx = rng.gen_range(0..CHARSET.len());
-                CHARSET[idx] as char
-            })
-            .collect()
+        crate::crypto::random_token(32)
     }
 
     pub fn is_valid(&self) -> bool {
@@ -221,3 +361,158 @@ impl PasswordResetToken {
         self.expires_at > now
     }
 }
+
+#[derive(Queryable, Selectable, Insertable, Serialize, Deserialize, AsChangeset, JsonSchema)]
+#[diesel(table_name = crate::schema::api_clients)]
+pub struct ApiClient {
+    pub id: String,
+    pub name: String,
+    /// Derives the signing secret with the server's pepper, see [`crate::crypto::api_client_secret`]
+    #[serde(skip_serializing)]
+    pub secret_salt: String,
+    /// Space separated list such as `entries:write persons:read`, `*` grants everything
+    pub scopes: String,
+    pub created_at: chrono::NaiveDateTime,
+    pub last_used_at: Option<chrono::NaiveDateTime>,
+    pub expires_at: Option<chrono::NaiveDateTime>,
+    pub revoked: bool,
+}
+
+impl ApiClient {
+    /// Creates a client and returns it along with its secret, which is not stored
+    pub fn new(
+        name: &str,
+        scopes: &[String],
+        expires_at: Option<chrono::NaiveDateTime>,
+        pepper: &str,
+    ) -> (Self, String) {
+        let secret_salt = crate::crypto::random_token(48);
+        let secret = crate::crypto::api_client_secret(pepper, &secret_salt);
+        let client = Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            secret_salt,
+            scopes: scopes.join(" "),
+            created_at: chrono::Utc::now().naive_utc(),
+            last_used_at: None,
+            expires_at,
+            revoked: false,
+        };
+        (client, secret)
+    }
+
+    pub fn is_active(&self) -> bool {
+        let now = chrono::Utc::now().naive_utc();
+        !self.revoked && self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+
+    /// Whether the client was granted `scope` (e.g. `entries:write`), directly or by wildcard
+    pub fn has_scope(&self, scope: &str) -> bool {
+        let resource = scope.split(':').next().unwrap_or(scope);
+        self.scopes
+            .split_whitespace()
+            .any(|granted| granted == "*" || granted == scope || granted == format!("{resource}:*"))
+    }
+}
+
+/// Columns of an entry to change; `None` fields are left untouched
+#[derive(AsChangeset, Default)]
+#[diesel(table_name = crate::schema::entries)]
+pub struct EntryChanges {
+    pub person_id: Option<String>,
+    pub instant: Option<chrono::NaiveDateTime>,
+    pub action: Option<String>,
+    pub flagged: Option<bool>,
+}
+
+#[derive(Queryable, Selectable, Insertable, Serialize, Deserialize, JsonSchema)]
+#[diesel(table_name = crate::schema::webhooks)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    /// Key of the HMAC signature sent with every delivery
+    #[serde(skip_serializing)]
+    pub secret: String,
+    /// Space separated event names such as `entry-created password-reset`, `*` subscribes to all
+    pub events: String,
+    pub active: bool,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+impl Webhook {
+    /// Creates an active webhook with a freshly generated signing secret
+    pub fn new(url: &str, events: &[String]) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            url: url.to_string(),
+            secret: crate::crypto::random_token(48),
+            events: events.join(" "),
+            active: true,
+            created_at: chrono::Utc::now().naive_utc(),
+        }
+    }
+
+    pub fn subscribes_to(&self, event: &str) -> bool {
+        self.events
+            .split_whitespace()
+            .any(|subscribed| subscribed == "*" || subscribed == event)
+    }
+}
+
+/// Columns of a webhook to change; `None` fields are left untouched
+#[derive(AsChangeset, Default)]
+#[diesel(table_name = crate::schema::webhooks)]
+pub struct WebhookChanges {
+    pub url: Option<String>,
+    pub events: Option<String>,
+    pub active: Option<bool>,
+}
+
+/// One attempt at delivering an event to a webhook, made or pending
+#[derive(Queryable, Selectable, Insertable, Serialize, Deserialize, JsonSchema)]
+#[diesel(table_name = crate::schema::webhook_deliveries)]
+pub struct WebhookDelivery {
+    pub id: String,
+    pub webhook_id: String,
+    /// Shared by all the attempts at delivering the same event
+    pub delivery_id: String,
+    pub event: String,
+    /// Starts at 1
+    pub attempt: i32,
+    /// HTTP status answered by the endpoint, if it answered at all
+    pub status_code: Option<i32>,
+    pub error: Option<String>,
+    pub success: bool,
+    pub created_at: chrono::NaiveDateTime,
+    /// What to POST, kept until the attempt is made
+    #[serde(skip)]
+    pub body: Option<String>,
+    /// When the attempt is due, set until it is made
+    pub next_attempt_at: Option<chrono::NaiveDateTime>,
+}
+
+impl WebhookDelivery {
+    /// An attempt to be made at `due`
+    pub fn pending(
+        webhook_id: &str,
+        delivery_id: &str,
+        event: &str,
+        attempt: i32,
+        body: &str,
+        due: chrono::NaiveDateTime,
+    ) -> Self {
+        WebhookDelivery {
+            id: uuid::Uuid::new_v4().to_string(),
+            webhook_id: webhook_id.to_string(),
+            delivery_id: delivery_id.to_string(),
+            event: event.to_string(),
+            attempt,
+            status_code: None,
+            error: None,
+            success: false,
+            created_at: chrono::Utc::now().naive_utc(),
+            body: Some(body.to_string()),
+            next_attempt_at: Some(due),
+        }
+    }
+}
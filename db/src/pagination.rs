@@ -0,0 +1,276 @@
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+
+pub const DEFAULT_LIMIT: i64 = 50;
+pub const MAX_LIMIT: i64 = 500;
+
+/// Which slice of a list to load. `cursor` is the `next_cursor` of a previous page and wins
+/// over `offset`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageRequest {
+    pub limit: i64,
+    pub offset: i64,
+}
+
+impl Default for PageRequest {
+    fn default() -> Self {
+        PageRequest {
+            limit: DEFAULT_LIMIT,
+            offset: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PageError {
+    Limit(i64),
+    Offset(i64),
+    Cursor(String),
+    Sort(String),
+}
+
+impl std::error::Error for PageError {}
+
+impl fmt::Display for PageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PageError::Limit(limit) => write!(
+                f,
+                "Invalid limit {}, expected a value between 1 and {}",
+                limit, MAX_LIMIT
+            ),
+            PageError::Offset(offset) => {
+                write!(
+                    f,
+                    "Invalid offset {}, expected a non-negative value",
+                    offset
+                )
+            }
+            PageError::Cursor(cursor) => write!(f, "Invalid cursor '{}'", cursor),
+            PageError::Sort(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl PageRequest {
+    pub fn new(
+        limit: Option<i64>,
+        offset: Option<i64>,
+        cursor: Option<&str>,
+    ) -> Result<Self, PageError> {
+        let limit = limit.unwrap_or(DEFAULT_LIMIT);
+        if !(1..=MAX_LIMIT).contains(&limit) {
+            return Err(PageError::Limit(limit));
+        }
+        let offset = match cursor {
+            Some(cursor) => decode_cursor(cursor)?,
+            None => offset.unwrap_or(0),
+        };
+        if offset < 0 {
+            return Err(PageError::Offset(offset));
+        }
+        Ok(PageRequest { limit, offset })
+    }
+}
+
+/// One page of a list, with the total number of matching rows
+#[derive(Serialize, JsonSchema, Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: i64,
+    pub limit: i64,
+    pub offset: i64,
+    /// Pass as `cursor` to get the following page; absent on the last page
+    pub next_cursor: Option<String>,
+}
+
+impl<T> Page<T> {
+    pub fn new(items: Vec<T>, total: i64, request: &PageRequest) -> Self {
+        let next_offset = request.offset + items.len() as i64;
+        let next_cursor = if !items.is_empty() && next_offset < total {
+            Some(encode_cursor(next_offset))
+        } else {
+            None
+        };
+        Page {
+            items,
+            total,
+            limit: request.limit,
+            offset: request.offset,
+            next_cursor,
+        }
+    }
+
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> Page<U> {
+        Page {
+            items: self.items.into_iter().map(f).collect(),
+            total: self.total,
+            limit: self.limit,
+            offset: self.offset,
+            next_cursor: self.next_cursor,
+        }
+    }
+}
+
+// Cursors are opaque to clients so the encoding can change without breaking them
+fn encode_cursor(offset: i64) -> String {
+    format!("o{:x}", offset)
+}
+
+fn decode_cursor(cursor: &str) -> Result<i64, PageError> {
+    cursor
+        .strip_prefix('o')
+        .and_then(|offset| i64::from_str_radix(offset, 16).ok())
+        .ok_or_else(|| PageError::Cursor(cursor.to_string()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// A whitelisted column to sort by, written `column` or `-column` for descending order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Sort<C> {
+    pub column: C,
+    pub order: SortOrder,
+}
+
+impl<C: FromStr<Err = String>> FromStr for Sort<C> {
+    type Err = PageError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (order, column) = match s.strip_prefix('-') {
+            Some(column) => (SortOrder::Desc, column),
+            None => (SortOrder::Asc, s),
+        };
+        Ok(Sort {
+            column: column.parse().map_err(PageError::Sort)?,
+            order,
+        })
+    }
+}
+
+/// Declares an enum of the columns a list may be sorted by, parsed from their snake_case names.
+/// The first column is the default.
+macro_rules! sort_columns {
+    ($(#[$meta:meta])* $name:ident { $first:ident => $first_column:literal $(, $variant:ident => $column:literal)* $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+        pub enum $name {
+            #[default]
+            $first,
+            $($variant),*
+        }
+
+        impl std::str::FromStr for $name {
+            type Err = String;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                match s {
+                    $first_column => Ok($name::$first),
+                    $($column => Ok($name::$variant),)*
+                    _ => Err(format!(
+                        "Invalid sort column '{}', expected one of: {}",
+                        s,
+                        [$first_column, $($column),*].join(", ")
+                    )),
+                }
+            }
+        }
+    };
+}
+pub(crate) use sort_columns;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_round_trips() {
+        for offset in [0, 1, 50, 12345] {
+            assert_eq!(decode_cursor(&encode_cursor(offset)), Ok(offset));
+        }
+    }
+
+    #[test]
+    fn rejects_foreign_cursors() {
+        for cursor in ["", "o", "50", "ozz", "x10"] {
+            assert_eq!(
+                PageRequest::new(None, None, Some(cursor)),
+                Err(PageError::Cursor(cursor.to_string()))
+            );
+        }
+        assert_eq!(
+            PageRequest::new(None, None, Some("o-1")),
+            Err(PageError::Offset(-1))
+        );
+    }
+
+    #[test]
+    fn cursor_wins_over_offset() {
+        let page = PageRequest::new(Some(10), Some(3), Some(&encode_cursor(20))).unwrap();
+        assert_eq!(
+            page,
+            PageRequest {
+                limit: 10,
+                offset: 20
+            }
+        );
+    }
+
+    #[test]
+    fn limits_are_bounded() {
+        assert_eq!(
+            PageRequest::new(None, None, None),
+            Ok(PageRequest::default())
+        );
+        assert_eq!(
+            PageRequest::new(Some(0), None, None),
+            Err(PageError::Limit(0))
+        );
+        assert_eq!(
+            PageRequest::new(Some(MAX_LIMIT + 1), None, None),
+            Err(PageError::Limit(MAX_LIMIT + 1))
+        );
+        assert_eq!(
+            PageRequest::new(None, Some(-1), None),
+            Err(PageError::Offset(-1))
+        );
+    }
+
+    #[test]
+    fn next_cursor_until_the_last_page() {
+        let request = PageRequest {
+            limit: 2,
+            offset: 0,
+        };
+        let first = Page::new(vec![1, 2], 3, &request);
+        assert_eq!(first.next_cursor, Some(encode_cursor(2)));
+
+        let request = PageRequest::new(Some(2), None, first.next_cursor.as_deref()).unwrap();
+        let last = Page::new(vec![3], 3, &request);
+        assert_eq!(last.offset, 2);
+        assert_eq!(last.next_cursor, None);
+    }
+
+    #[test]
+    fn sort_parses_direction() {
+        sort_columns!(Columns { Name => "name", Email => "email" });
+        assert_eq!(
+            "-email".parse::<Sort<Columns>>(),
+            Ok(Sort {
+                column: Columns::Email,
+                order: SortOrder::Desc,
+            })
+        );
+        assert!(matches!(
+            "age".parse::<Sort<Columns>>(),
+            Err(PageError::Sort(_))
+        ));
+    }
+}
@@ -0,0 +1,109 @@
+use crate::{DbConnection, try_establish_connection};
+use diesel::connection::SimpleConnection;
+use diesel::r2d2::{self, ManageConnection, R2D2Connection};
+use log::{info, warn};
+use std::env::var;
+use std::time::Duration;
+
+pub type DbPool = r2d2::Pool<ConnectionManager>;
+pub type PooledConnection = r2d2::PooledConnection<ConnectionManager>;
+pub use diesel::r2d2::PoolError;
+
+/// Hands out [`DbConnection`]s of whichever backend the URL points to
+#[derive(Debug)]
+pub struct ConnectionManager {
+    db_url: String,
+}
+
+impl ConnectionManager {
+    pub fn new(db_url: &str) -> Self {
+        ConnectionManager {
+            db_url: db_url.to_string(),
+        }
+    }
+}
+
+impl ManageConnection for ConnectionManager {
+    type Connection = DbConnection;
+    type Error = r2d2::Error;
+
+    fn connect(&self) -> Result<DbConnection, r2d2::Error> {
+        let mut conn =
+            try_establish_connection(&self.db_url).map_err(r2d2::Error::ConnectionError)?;
+        if let DbConnection::Sqlite(conn) = &mut conn {
+            // Several pooled connections share the file; wait for locks instead of failing
+            conn.batch_execute("PRAGMA busy_timeout = 5000;")
+                .map_err(r2d2::Error::QueryError)?;
+        }
+        Ok(conn)
+    }
+
+    fn is_valid(&self, conn: &mut DbConnection) -> Result<(), r2d2::Error> {
+        match conn {
+            DbConnection::Sqlite(conn) => conn.ping(),
+            DbConnection::Pg(conn) => conn.ping(),
+        }
+        .map_err(r2d2::Error::QueryError)
+    }
+
+    fn has_broken(&self, conn: &mut DbConnection) -> bool {
+        std::thread::panicking()
+            || match conn {
+                DbConnection::Sqlite(conn) => conn.is_broken(),
+                DbConnection::Pg(conn) => conn.is_broken(),
+            }
+    }
+}
+
+/// Pool settings, read from the `SYN_DB_*` environment variables
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    pub max_size: u32,
+    pub min_idle: Option<u32>,
+    pub connection_timeout: Duration,
+    pub idle_timeout: Option<Duration>,
+    /// Ping connections before handing them out
+    pub test_on_check_out: bool,
+}
+
+impl PoolConfig {
+    pub fn from_env() -> Self {
+        fn parse<T: std::str::FromStr>(name: &str) -> Option<T> {
+            let value = var(name).ok()?;
+            match value.parse() {
+                Ok(value) => Some(value),
+                Err(_) => {
+                    warn!("Ignoring invalid {}: {}", name, value);
+                    None
+                }
+            }
+        }
+
+        PoolConfig {
+            max_size: parse("SYN_DB_POOL_SIZE").unwrap_or(10),
+            min_idle: parse("SYN_DB_POOL_MIN_IDLE"),
+            connection_timeout: Duration::from_secs(parse("SYN_DB_CONNECT_TIMEOUT").unwrap_or(5)),
+            idle_timeout: match parse::<u64>("SYN_DB_IDLE_TIMEOUT").unwrap_or(600) {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            test_on_check_out: parse::<u8>("SYN_DB_TEST_ON_CHECK_OUT").unwrap_or(1) == 1,
+        }
+    }
+}
+
+/// Builds the pool without waiting for the database, so the server can start (and answer
+/// 503) while it is unreachable
+pub fn build_pool(db_url: &str, config: &PoolConfig) -> DbPool {
+    info!(
+        "Creating database pool of up to {} connections",
+        config.max_size
+    );
+    r2d2::Pool::builder()
+        .max_size(config.max_size)
+        .min_idle(config.min_idle)
+        .connection_timeout(config.connection_timeout)
+        .idle_timeout(config.idle_timeout)
+        .test_on_check_out(config.test_on_check_out)
+        .build_unchecked(ConnectionManager::new(db_url))
+}
@@ -1,5 +1,22 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    api_clients (id) {
+        #[max_length = 36]
+        id -> Bpchar,
+        #[max_length = 100]
+        name -> Varchar,
+        #[max_length = 64]
+        secret_salt -> Varchar,
+        #[max_length = 255]
+        scopes -> Varchar,
+        created_at -> Timestamp,
+        last_used_at -> Nullable<Timestamp>,
+        expires_at -> Nullable<Timestamp>,
+        revoked -> Bool,
+    }
+}
+
 diesel::table! {
     entries (id) {
         #[max_length = 36]
@@ -9,6 +26,20 @@ diesel::table! {
         instant -> Timestamp,
         #[max_length = 100]
         action -> Varchar,
+        version -> Int4,
+        flagged -> Bool,
+    }
+}
+
+diesel::table! {
+    login_throttles (scope, subject) {
+        #[max_length = 10]
+        scope -> Varchar,
+        #[max_length = 255]
+        subject -> Varchar,
+        failures -> Int4,
+        last_failure_at -> Timestamp,
+        locked_until -> Nullable<Timestamp>,
     }
 }
 
@@ -36,6 +67,7 @@ diesel::table! {
         see_others_history -> Bool,
         admin_panel -> Bool,
         edit_permissions -> Bool,
+        version -> Int4,
     }
 }
 
@@ -53,17 +85,119 @@ diesel::table! {
         role -> Varchar,
         #[max_length = 100]
         password_hash -> Nullable<Varchar>,
+        version -> Int4,
+        deleted_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    person_identities (id) {
+        #[max_length = 36]
+        id -> Bpchar,
+        #[max_length = 36]
+        person_id -> Bpchar,
+        #[max_length = 50]
+        provider -> Varchar,
+        #[max_length = 255]
+        subject -> Varchar,
         #[max_length = 100]
-        google_id -> Nullable<Varchar>,
+        email -> Nullable<Varchar>,
+        linked_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    recovery_codes (id) {
+        #[max_length = 36]
+        id -> Bpchar,
+        #[max_length = 36]
+        person_id -> Bpchar,
+        #[max_length = 64]
+        code_hash -> Bpchar,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    refresh_tokens (id) {
+        #[max_length = 36]
+        id -> Bpchar,
+        #[max_length = 36]
+        family_id -> Bpchar,
+        #[max_length = 36]
+        person_id -> Bpchar,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    totp_secrets (person_id) {
+        #[max_length = 36]
+        person_id -> Bpchar,
+        #[max_length = 64]
+        secret -> Varchar,
+        confirmed_at -> Nullable<Timestamp>,
+        last_used_step -> Nullable<Int8>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    webhook_deliveries (id) {
+        #[max_length = 36]
+        id -> Bpchar,
+        #[max_length = 36]
+        webhook_id -> Bpchar,
+        #[max_length = 36]
+        delivery_id -> Bpchar,
+        #[max_length = 50]
+        event -> Varchar,
+        attempt -> Int4,
+        status_code -> Nullable<Int4>,
+        error -> Nullable<Text>,
+        success -> Bool,
+        created_at -> Timestamp,
+        body -> Nullable<Text>,
+        next_attempt_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    webhooks (id) {
+        #[max_length = 36]
+        id -> Bpchar,
+        #[max_length = 500]
+        url -> Varchar,
+        #[max_length = 64]
+        secret -> Varchar,
+        #[max_length = 255]
+        events -> Varchar,
+        active -> Bool,
+        created_at -> Timestamp,
     }
 }
 
 diesel::joinable!(entries -> person (person_id));
 diesel::joinable!(permissions -> person (person_id));
+diesel::joinable!(person_identities -> person (person_id));
+diesel::joinable!(recovery_codes -> person (person_id));
+diesel::joinable!(refresh_tokens -> person (person_id));
+diesel::joinable!(totp_secrets -> person (person_id));
+diesel::joinable!(webhook_deliveries -> webhooks (webhook_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    api_clients,
     entries,
+    login_throttles,
     password_reset_tokens,
     permissions,
     person,
+    person_identities,
+    recovery_codes,
+    refresh_tokens,
+    totp_secrets,
+    webhook_deliveries,
+    webhooks,
 );
@@ -1,7 +1,9 @@
 use std::env;
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use db::establish_connection;
+use db::interactions::api_client::ApiClientInteractor;
+use db::models::ApiClient;
 use dotenv::dotenv;
 use fstdout_logger::{LoggerConfig, init_logger_with_config};
 use log::{LevelFilter, debug, error, info, warn};
@@ -25,6 +27,21 @@ enum Subcommands {
         /// The path to the SQLite database file
         #[arg()]
         database_url: Option<String>,
+
+        /// Apply pending migrations (creating the SQLite file if needed) before starting
+        #[arg(long)]
+        migrate: bool,
+    },
+
+    /// Apply, revert or list the embedded database migrations
+    Migrate {
+        /// What to do with the migrations
+        #[arg(value_enum, default_value_t = MigrateAction::Up)]
+        action: MigrateAction,
+
+        /// The path to the SQLite database file
+        #[arg()]
+        database_url: Option<String>,
     },
 
     /// Show the database
@@ -40,6 +57,99 @@ enum Subcommands {
         #[arg()]
         database_url: Option<String>,
     },
+
+    /// Manage the API clients allowed to sign requests
+    Clients {
+        #[command(subcommand)]
+        action: ClientAction,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum MigrateAction {
+    /// Apply all pending migrations, creating the SQLite file if needed
+    Up,
+    /// Revert the last applied migration
+    Down,
+    /// List the migrations and whether they are applied
+    Status,
+    /// Revert the last applied migration and apply it again
+    Redo,
+}
+
+#[derive(Parser)]
+enum ClientAction {
+    /// Create a client and print its secret
+    Create {
+        /// A unique name for the client, e.g. "kiosk-entrance"
+        name: String,
+
+        /// Comma separated scopes, e.g. "entries:write" or "entries:*,persons:read"; "*" grants all
+        #[arg(long, value_delimiter = ',', required = true)]
+        scopes: Vec<String>,
+
+        /// Days until the client expires, never if omitted
+        #[arg(long)]
+        expires_in_days: Option<i64>,
+
+        /// The path to the SQLite database file
+        #[arg()]
+        database_url: Option<String>,
+    },
+
+    /// List all clients
+    List {
+        /// The path to the SQLite database file
+        #[arg()]
+        database_url: Option<String>,
+    },
+
+    /// Revoke a client so its signatures are no longer accepted
+    Revoke {
+        /// The client ID
+        client_id: String,
+
+        /// The path to the SQLite database file
+        #[arg()]
+        database_url: Option<String>,
+    },
+
+    /// Replace the secret of a client and print the new one
+    Rotate {
+        /// The client ID
+        client_id: String,
+
+        /// The path to the SQLite database file
+        #[arg()]
+        database_url: Option<String>,
+    },
+}
+
+/// Use the given database URL or fall back to `DATABASE_URL`
+fn resolve_database_url(
+    database_url: Option<String>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    match database_url {
+        Some(url) => Ok(url),
+        None => match std::env::var("DATABASE_URL") {
+            Ok(url) => {
+                info!("Using DATABASE_URL from environment: {}", url);
+                Ok(url)
+            }
+            Err(_) => {
+                warn!("No DATABASE_URL found in environment");
+
+                let command = std::env::args()
+                    .next()
+                    .unwrap_or_else(|| "synnapse-db-api-cli".to_string());
+                println!(
+                    "DATABASE_URL not set.\nUsage: {} serve <DATABASE_URL>",
+                    command
+                );
+                Err("No database URL provided".into())
+            }
+        },
+    }
 }
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -81,28 +191,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     debug!("Logger initialized");
 
     match args.action {
-        Subcommands::Serve { database_url } => {
-            let database_url = match database_url {
-                Some(url) => url,
-                None => match std::env::var("DATABASE_URL") {
-                    Ok(url) => {
-                        info!("Using DATABASE_URL from environment: {}", url);
-                        url
-                    }
-                    Err(_) => {
-                        warn!("No DATABASE_URL found in environment");
-
-                        let command = std::env::args()
-                            .next()
-                            .unwrap_or_else(|| "synnapse-db-api-cli".to_string());
-                        println!(
-                            "DATABASE_URL not set.\nUsage: {} serve <DATABASE_URL>",
-                            command
-                        );
-                        return Err("No database URL provided".into());
-                    }
-                },
-            };
+        Subcommands::Serve {
+            database_url,
+            migrate,
+        } => {
+            let database_url = resolve_database_url(database_url)?;
+            if migrate {
+                run_migrations(MigrateAction::Up, &database_url)
+                    .map_err(|e| e as Box<dyn std::error::Error>)?;
+            }
             info!("Starting server with database at: {}", database_url);
             if let Err(e) = api::run_server(&database_url).await {
                 error!("Server error: {}", e);
@@ -111,27 +208,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             info!("Server shut down gracefully");
         }
         Subcommands::Show { database_url } => {
-            let database_url = match database_url {
-                Some(url) => url,
-                None => match std::env::var("DATABASE_URL") {
-                    Ok(url) => {
-                        info!("Using DATABASE_URL from environment: {}", url);
-                        url
-                    }
-                    Err(_) => {
-                        warn!("No DATABASE_URL found in environment");
-
-                        let command = std::env::args()
-                            .next()
-                            .unwrap_or_else(|| "synnapse-db-api-cli".to_string());
-                        println!(
-                            "DATABASE_URL not set.\nUsage: {} serve <DATABASE_URL>",
-                            command
-                        );
-                        return Err("No database URL provided".into());
-                    }
-                },
-            };
+            let database_url = resolve_database_url(database_url)?;
             info!("Showing database contents from: {}", database_url);
             let conn = &mut establish_connection(&database_url);
             match db::interactions::person::PersonInteractor::get(conn) {
@@ -152,27 +229,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         }
         Subcommands::Seed { database_url } => {
-            let database_url = match database_url {
-                Some(url) => url,
-                None => match std::env::var("DATABASE_URL") {
-                    Ok(url) => {
-                        info!("Using DATABASE_URL from environment: {}", url);
-                        url
-                    }
-                    Err(_) => {
-                        warn!("No DATABASE_URL found in environment");
-
-                        let command = std::env::args()
-                            .next()
-                            .unwrap_or_else(|| "synnapse-db-api-cli".to_string());
-                        println!(
-                            "DATABASE_URL not set.\nUsage: {} serve <DATABASE_URL>",
-                            command
-                        );
-                        return Err("No database URL provided".into());
-                    }
-                },
-            };
+            let database_url = resolve_database_url(database_url)?;
             info!("Seeding database at: {}", database_url);
             if let Err(e) = db::seed(&database_url) {
                 error!("Failed to seed database: {}", e);
@@ -180,7 +237,124 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
             info!("Database seeded successfully");
         }
+        Subcommands::Migrate {
+            action,
+            database_url,
+        } => run_migrations(action, &resolve_database_url(database_url)?)
+            .map_err(|e| e as Box<dyn std::error::Error>)?,
+        Subcommands::Clients { action } => manage_clients(action)?,
     }
     info!("Program completed successfully");
     Ok(())
 }
+
+fn run_migrations(
+    action: MigrateAction,
+    database_url: &str,
+) -> db::migrations::MigrationResult<()> {
+    let conn = &mut match action {
+        MigrateAction::Up => db::establish_or_create_connection(database_url)?,
+        _ => db::try_establish_connection(database_url)?,
+    };
+    match action {
+        MigrateAction::Up => {
+            let applied = db::migrations::run_pending(conn)?;
+            if applied.is_empty() {
+                println!("Database is up to date");
+            }
+            for version in applied {
+                println!("Applied {}", version);
+            }
+        }
+        MigrateAction::Down => println!("Reverted {}", db::migrations::revert_last(conn)?),
+        MigrateAction::Redo => println!("Redid {}", db::migrations::redo(conn)?),
+        MigrateAction::Status => {
+            for migration in db::migrations::status(conn)? {
+                let state = if migration.applied {
+                    "applied"
+                } else {
+                    "pending"
+                };
+                println!("[{}] {}", state, migration.name);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The pepper client secrets are derived with, which the server has to share
+fn client_pepper() -> Result<String, Box<dyn std::error::Error>> {
+    match env::var("SYN_API_CLIENT_PEPPER") {
+        Ok(pepper) if !pepper.is_empty() => Ok(pepper),
+        _ => Err("SYN_API_CLIENT_PEPPER must be set to create or rotate API clients".into()),
+    }
+}
+
+fn manage_clients(action: ClientAction) -> Result<(), Box<dyn std::error::Error>> {
+    match action {
+        ClientAction::Create {
+            name,
+            scopes,
+            expires_in_days,
+            database_url,
+        } => {
+            let conn = &mut db::try_establish_connection(&resolve_database_url(database_url)?)?;
+            let expires_at = expires_in_days
+                .map(|days| chrono::Utc::now().naive_utc() + chrono::Duration::days(days));
+            let (client, secret) = ApiClient::new(&name, &scopes, expires_at, &client_pepper()?);
+            ApiClientInteractor::new(conn, &client)?;
+
+            println!("Client ID: {}", client.id);
+            println!("Secret:    {}", secret);
+            println!("Scopes:    {}", client.scopes);
+            println!("Store the secret now, it cannot be shown again.");
+        }
+        ClientAction::List { database_url } => {
+            let conn = &mut db::try_establish_connection(&resolve_database_url(database_url)?)?;
+            let clients = ApiClientInteractor::get(conn)?;
+            if clients.is_empty() {
+                println!("No API clients");
+            }
+            for client in clients {
+                let status = if client.revoked {
+                    "revoked"
+                } else if client.is_active() {
+                    "active"
+                } else {
+                    "expired"
+                };
+                println!(
+                    "{}  {:<24} {:<8} scopes: {:<32} last used: {}",
+                    client.id,
+                    client.name,
+                    status,
+                    client.scopes,
+                    client
+                        .last_used_at
+                        .map(|t| t.to_string())
+                        .unwrap_or_else(|| "never".to_string())
+                );
+            }
+        }
+        ClientAction::Revoke {
+            client_id,
+            database_url,
+        } => {
+            let conn = &mut db::try_establish_connection(&resolve_database_url(database_url)?)?;
+            if ApiClientInteractor::revoke(conn, &client_id)? == 0 {
+                return Err(format!("No API client with ID {client_id}").into());
+            }
+            println!("Revoked client {}", client_id);
+        }
+        ClientAction::Rotate {
+            client_id,
+            database_url,
+        } => {
+            let conn = &mut db::try_establish_connection(&resolve_database_url(database_url)?)?;
+            let secret = ApiClientInteractor::rotate(conn, &client_id, &client_pepper()?)?;
+            println!("New secret: {}", secret);
+            println!("Store the secret now, it cannot be shown again.");
+        }
+    }
+    Ok(())
+}